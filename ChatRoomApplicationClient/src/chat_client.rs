@@ -1,58 +1,146 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
 use reqwest::Client;
-use serde::{Serialize};
-use futures_util::{SinkExt, StreamExt};
-use futures_util::stream::{SplitSink, SplitStream};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use futures_util::{SinkExt, Stream, StreamExt};
+use futures_util::stream::SplitSink;
+use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest, http::HeaderValue};
 use tokio_tungstenite::{connect_async, tungstenite::Message, WebSocketStream};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tokio_tungstenite::MaybeTlsStream;
 
-use crate::color_formatting::*;
+use crate::error::ClientError;
 use crate::messages::*;
 
+/*
+ * chat_client.rs
+ *
+ * ChatClient talks to the server over HTTP and, while in a room, over that room's WebSocket. It
+ * never prints anything: every call returns a Result with what the server answered, and whatever
+ * the room broadcasts arrives on the RoomEvents stream handed out by ChatClient::init.
+ *
+ *      let (mut client, mut events) = ChatClient::init("http://127.0.0.1:3000", "ws://127.0.0.1:3000");
+ *      client.login("alice", "Secret#1234").await?;   // or client.resume(refresh_token)
+ *      client.join_room("lounge", None).await?;
+ *      client.send_message("hello").await?;
+ *      while let Some(event) = events.next().await { ... }
+ *
+ * The stream outlives rooms: it is the same one before, during and after every join, so it can
+ * sit in a select! next to code that uses the client.
+ */
+
+type WsSender = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
+
+// What the room's WebSocket delivered
+#[derive(Debug, Clone)]
+pub enum RoomEvent {
+    Message(ServerWsMessage),
+    // the server closed the connection to this room or it dropped
+    Closed { room_id: String },
+}
+
+// Every RoomEvent of every room the client joins, in order
+pub struct RoomEvents {
+    receiver: mpsc::UnboundedReceiver<RoomEvent>,
+}
+
+impl Stream for RoomEvents {
+    type Item = RoomEvent;
+
+    // Never ends while the ChatClient is alive, between rooms it just has nothing to deliver
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<RoomEvent>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
 pub struct ChatClient {
-    pub server_url: String,
-    pub server_url_ws: String,
-    pub http: Client,
-    pub auth_token: Option<String>,
-    pub username: Option<String>,
-    pub current_room: Option<String>,
-    pub ws_sender: Option<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>>,
-    pub ws_receiver: Option<SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>>,
+    server_url: String,
+    server_url_ws: String,
+    http: Client,
+    auth_token: Option<String>,
+    // for ChatClient::resume, the caller decides whether and where to keep it
+    refresh_token: Option<String>,
+    username: Option<String>,
+    // the message of the day from the latest login or resume
+    motd: Option<String>,
+    current_room: Option<String>,
+    // the room last joined with a password and that password, so reconnect() can get back into it
+    room_password: Option<(String, String)>,
+    ws_sender: Option<WsSender>,
+    // forwards the room's WebSocket into RoomEvents
+    ws_reader: Option<JoinHandle<()>>,
+    events: mpsc::UnboundedSender<RoomEvent>,
 }
 
 impl ChatClient {
-    pub fn init(server_url: &str, ws_url: &str) -> Self {
-        ChatClient {
+    pub fn init(server_url: &str, ws_url: &str) -> (Self, RoomEvents) {
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        let client = ChatClient {
             server_url: server_url.to_string(),
             server_url_ws: ws_url.to_string(),
             http: Client::new(),
             auth_token: None,
+            refresh_token: None,
             username: None,
+            motd: None,
             current_room: None,
+            room_password: None,
             ws_sender: None,
-            ws_receiver: None,
-        }
+            ws_reader: None,
+            events: events_tx,
+        };
+        (client, RoomEvents { receiver: events_rx })
     }
 
-    pub async fn chat_message(&mut self, content: &str) {
-        if let Some(sender) = &mut self.ws_sender {
-            let msg = ClientWsMessage::SendMessage {
-                room_id: self.current_room.clone().unwrap_or_default(),
-                content: content.to_string(),
-            };
-            let serialized = serde_json::to_string(&msg).unwrap();
-            if sender.send(Message::Text(serialized.into())).await.is_ok() {
-                my_message(content);
-            } else {
-                error("Failed to send message through WebSocket");
-            }
-        } else {
-            error("Not connected to WebSocket server");
-        }
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
     }
 
+    pub fn motd(&self) -> Option<&str> {
+        self.motd.as_deref()
+    }
+
+    pub fn current_room(&self) -> Option<&str> {
+        self.current_room.as_deref()
+    }
+
+    pub fn is_logged_in(&self) -> bool {
+        self.username.is_some()
+    }
 
-    pub async fn send_json_to_server<T: Serialize>( &self, endpoint: &str, msg: &T,) -> Result<String, reqwest::Error> {
+    // Changes every time a session is started or resumed, only the latest one works
+    pub fn refresh_token(&self) -> Option<&str> {
+        self.refresh_token.as_deref()
+    }
+
+    pub async fn send_message(&mut self, content: &str) -> Result<(), ClientError> {
+        let room_id = self.current_room.clone().ok_or(ClientError::NotInRoom)?;
+        self.send_ws(&ClientWsMessage::SendMessage {
+            room_id,
+            content: content.to_string(),
+        }).await
+    }
+
+    // Keeps the connection alive and finds out early if it dropped
+    pub async fn ping(&mut self) -> Result<(), ClientError> {
+        self.send_ws(&ClientWsMessage::Ping {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        }).await
+    }
+
+    async fn send_ws(&mut self, msg: &ClientWsMessage) -> Result<(), ClientError> {
+        let sender = self.ws_sender.as_mut().ok_or(ClientError::NotInRoom)?;
+        let serialized = serde_json::to_string(msg).unwrap();
+        sender.send(Message::Text(serialized.into())).await?;
+        Ok(())
+    }
+
+    // Posts the request and reads the answer as Resp, or as the ErrorResponse the server sent instead
+    async fn post<Req: Serialize, Resp: DeserializeOwned>(&self, endpoint: &str, msg: &Req) -> Result<Resp, ClientError> {
         let mut request = self.http.post(format!("{}/{}", self.server_url, endpoint)).json(msg);
 
         if let Some(token) = &self.auth_token {
@@ -60,411 +148,356 @@ impl ChatClient {
         }
 
         let response = request.send().await?.text().await?;
-        Ok(response)
-    }
 
+        // Errors are tagged with error_type, so they can't be mistaken for a response that happens
+        // to have the same fields (eg SuccessResponse and a message)
+        if let Ok(err) = serde_json::from_str::<ErrorResponse>(&response) {
+            return Err(ClientError::Server(err));
+        }
+        serde_json::from_str::<Resp>(&response).map_err(|_| ClientError::UnexpectedResponse(response))
+    }
 
-    pub async fn create_user(&mut self, username: &str, password: &str) -> bool {
+    // Returns the new user's id. Doesn't log in, that is still up to login()
+    pub async fn create_user(&mut self, username: &str, password: &str) -> Result<String, ClientError> {
         let req = RegisterRequest {
             user_id: username.to_string(),
             password: password.to_string(),
         };
 
-        match self.send_json_to_server("create_user", &req).await {
-            Ok(resp_str) => {
-                if let Ok(resp) = serde_json::from_str::<AuthSuccessResponse>(&resp_str) {
-                    success(&format!("User '{}' created successfully!", resp.user_id));
-                    self.auth_token = Some(resp.token);
-                    return true;
-                } else if let Ok(err) = serde_json::from_str::<ErrorResponse>(&resp_str) {
-                    match err {
-                        ErrorResponse::AuthenticationFailed { message } => {
-                            error(&format!("Error: Authentication failed: {}", message));
-                        }
-                        ErrorResponse::ServerError { message } => {
-                            error(&format!("Server error: {}", message));
-                        }
-                        ErrorResponse::UserAlreadyExists { user_id } => {
-                            error(&format!("Error: User {} already exists", user_id));
-                        }
-                        _ => {
-                            error(&format!("Error: {:?}", err));
-                        }
-                    }
-                } else {
-                    error("Unexpected server response");
-                }
-            }
-            Err(e) => {
-                error(&format!("Connection error: {}", e));
-            }
-        }
-
-        false
+        let resp: AuthSuccessResponse = self.post("create_user", &req).await?;
+        self.auth_token = Some(resp.token);
+        Ok(resp.user_id)
     }
 
-    pub async fn login(&mut self, username: &str, password: &str) -> bool {
+    pub async fn login(&mut self, username: &str, password: &str) -> Result<(), ClientError> {
         let req = LoginRequest {
             user_id: username.to_string(),
             password: password.to_string()
         };
-          self.username = Some(username.to_string());
-          true
-        /*
-        match self.send_json_to_server("login", &req).await {
-            Ok(resp_str) => {
-                if let Ok(resp) = serde_json::from_str::<AuthSuccessResponse>(&resp_str) {
-                    success(&format!("Welcome {}!", resp.user_id));
-                    self.auth_token = Some(resp.token);
-                    self.username = Some(resp.user_id);
-                    true
-                } else if let Ok(err) = serde_json::from_str::<ErrorResponse>(&resp_str) {
-                    match err {
-                        ErrorResponse::AuthenticationFailed { message } => {
-                            error(&format!("Error: Authentication failed: {}", message));
-                        }
-                        ErrorResponse::InvalidPassword {..} | ErrorResponse::UserNotFound {..} => {
-                            error("Error: Invalid username or password");
-                        }
-                        ErrorResponse::ServerError { message } => {
-                            error(&format!("Server error: {}", message));
-                        }
-                        _ => {
-                            error(&format!("Error: {:?}", err));
-                        }
-                    }
-                    false
-                } else {
-                    error("Unexpected server response");
-                    false
-                }
-            }
-            Err(e) => {
-                error(&format!("Connection error: {}", e));
-                false
-            }
-        }
-            */
+        let resp: AuthSuccessResponse = self.post("login", &req).await?;
+        self.start_session(resp);
+        Ok(())
     }
 
-    pub async fn join_room(&mut self, room_id: &str, password: &str) -> bool {
-        let req = JoinRoomRequest {
-            room_id: room_id.to_string(),
-            room_password: password.to_string(),
-            user_id: self.username.clone().unwrap_or_default(),
+    // Logs back in with the refresh token from an earlier session instead of the password
+    pub async fn resume(&mut self, refresh_token: &str) -> Result<(), ClientError> {
+        let req = RefreshSessionRequest {
+            refresh_token: refresh_token.to_string(),
         };
+        let resp: AuthSuccessResponse = self.post("refresh_session", &req).await?;
+        self.start_session(resp);
+        Ok(())
+    }
 
-        match self.send_json_to_server("join_room", &req).await {
-            Ok(resp_str) => {
-                if let Ok(resp) = serde_json::from_str::<JoinRoomResponse>(&resp_str) {
-                    self.current_room = Some(resp.room_id.clone());
-
-                    // Connect WebSocket
-                    if !self.connect_ws_for_room(&resp.room_id).await {
-                        return false;
-                    }
-
-                    // Chat History
-                    if !resp.chat_history.is_empty() {
-                        header("Chat History");
-                        for msg in resp.chat_history {
-                            if msg.user_id == self.username.clone().unwrap_or_default() {
-                                my_message(&msg.content);
-                            } else {
-                                user_message(&msg.timestamp, &msg.user_id, &msg.content);
-                            }
-                        }
-                    }
-
-                    true
-                } else if let Ok(err) = serde_json::from_str::<ErrorResponse>(&resp_str) {
-                    match err {
-                        ErrorResponse::AuthenticationFailed { message } => {
-                            error(&format!("Error: Authentication failed: {}", message));
-                        }
-                        ErrorResponse::InvalidPassword { .. } => {
-                            error("Error: Invalid password");
-                        }
-                        ErrorResponse::RoomNotFound { room_id } => {
-                            error(&format!("Error: Room {} not found", room_id));
-                        }
-                        _ => {
-                            error(&format!("Error: {:?}", err));
-                        }
-                    }
-                    false
-                } else {
-                    error("Unexpected server response");
-                    false
-                }
-            }
-            Err(e) => {
-                error(&format!("Connection error: {}", e));
-                false
-            }
-        }
+    fn start_session(&mut self, resp: AuthSuccessResponse) {
+        self.auth_token = Some(resp.token);
+        self.refresh_token = Some(resp.refresh_token);
+        self.username = Some(resp.user_id);
+        self.motd = resp.motd;
     }
 
-    pub async fn leave_room(&mut self, room_id: &str) {
-        let msg = ClientWsMessage::LeaveRoom {
+    // On success the client is in the room and its events arrive on RoomEvents. The response
+    // carries the room's recent chat history.
+    pub async fn join_room(&mut self, room_id: &str, password: Option<&str>) -> Result<JoinRoomResponse, ClientError> {
+        let req = JoinRoomRequest {
             room_id: room_id.to_string(),
+            room_password: password.map(|p| p.to_string()),
         };
 
-        let _ = self.send_json_to_server("leave_room", &msg).await;
+        let resp: JoinRoomResponse = self.post("join_room", &req).await?;
+        self.room_password = password.map(|p| (room_id.to_string(), p.to_string()));
+        self.enter_room(resp).await
+    }
 
-        // Close WebSocket
-        if let Some(mut sender) = self.ws_sender.take() {
-            if let Err(e) = sender.close().await {
-                error(&format!("Failed to close WebSocket: {}", e));
-            }
-        }
-        self.ws_receiver = None;
-        self.current_room = None;
+    // Joins the room an invite code is for, without needing the room password
+    pub async fn join_invite(&mut self, code: &str) -> Result<JoinRoomResponse, ClientError> {
+        let req = JoinInviteRequest {
+            code: code.to_string(),
+        };
 
-        system_message(&format!("[Left {}]", room_id));
+        let resp: JoinRoomResponse = self.post("join_invite", &req).await?;
+        self.enter_room(resp).await
     }
 
+    async fn enter_room(&mut self, resp: JoinRoomResponse) -> Result<JoinRoomResponse, ClientError> {
+        self.connect_ws_for_room(&resp.room_id).await?;
+        self.current_room = Some(resp.room_id.clone());
+        Ok(resp)
+    }
 
+    // Gets back into a room after the server went away and came back (ServerShutdown), with the
+    // password it was joined with if it needed one. The session is resumed first, in case the
+    // server restarted and no longer knows the old one.
+    pub async fn reconnect(&mut self, room_id: &str) -> Result<JoinRoomResponse, ClientError> {
+        let refresh_token = self.refresh_token.clone().ok_or(ClientError::NotLoggedIn)?;
+        let password = self.room_password.clone().filter(|(joined, _)| joined == room_id).map(|(_, p)| p);
+        self.forget_room();
+        self.resume(&refresh_token).await?;
+        self.join_room(room_id, password.as_deref()).await
+    }
 
-    pub async fn show_all_rooms(&mut self, active_room_only: bool) {
+    // Leaves the current room. The client is out of the room afterwards even if telling the server failed.
+    pub async fn leave_room(&mut self) -> Result<(), ClientError> {
+        let room_id = self.current_room.clone().ok_or(ClientError::NotInRoom)?;
 
-        let req = ListRoomsRequest {
-            only_active: active_room_only,
-        };
+        // Closing the connection is what takes us out of the room, this only says it is on purpose
+        let _ = self.send_ws(&ClientWsMessage::LeaveRoom { room_id }).await;
 
-        let response = match self.send_json_to_server("all_rooms", &req).await {
-            Ok(resp) => resp,
-            Err(e) => {
-                error(&format!("Connection error: {}", e));
-                return;
-            }
+        // Close WebSocket
+        let closed = match self.ws_sender.take() {
+            Some(mut sender) => sender.close().await,
+            None => Ok(()),
         };
+        self.forget_room();
+        closed.map_err(ClientError::from)
+    }
 
-        header("[All Rooms]");
-        let parsed: Result<ListRoomsResponse, _> = serde_json::from_str(&response);
-
-        match parsed {
-            Ok(list_resp) => {
-                if list_resp.rooms.is_empty() {
-                    info(" - No chat rooms exist");
-                } else {
-                    for room in list_resp.rooms { //TODO - change to user .iter
-                        if active_room_only {
-                            info(&format!( " - {} [{} users]", room.room_id, room.users_count));
-                        }else{
-                            info(&format!( " - {}", room.room_id));
-                        }
-                    }
-                }
-            }
-            Err(_) => error("Failed to parse server response"),
+    // Drops the current room without telling the server, for when it is already gone: we were
+    // kicked, it was deleted or the connection dropped
+    pub fn forget_room(&mut self) {
+        if let Some(reader) = self.ws_reader.take() {
+            reader.abort();
         }
+        self.ws_sender = None;
+        self.current_room = None;
     }
-    
-    pub async fn create_room(&mut self, room_id: &str, password: &str){
 
-      let req = CreateRoomRequest {
-        room_id: room_id.to_string(),
-        room_password: password.to_string(),
-        user_id: self.username.clone().unwrap_or_default(),
-        };
-
-        let response = match self.send_json_to_server("create_room", &req).await {
-            Ok(resp) => resp,
-            Err(e) => {
-                error(&format!("Connection error: {}", e));
-                return;
-            }
+    pub async fn list_rooms(&self, active_room_only: bool) -> Result<Vec<RoomInfo>, ClientError> {
+        let req = ListRoomsRequest {
+            only_active: active_room_only,
         };
 
-        if let Ok(resp) = serde_json::from_str::<CreateRoomResponse>(&response) {
-            success(&format!("Room Created - {}", resp.room_id));
-        }else if let Ok(err) = serde_json::from_str::<ErrorResponse>(&response) {
-            match err {
-                ErrorResponse::RoomAlreadyExists { room_id } => {
-                    error(&format!("Error: Room '{}' already exists", room_id));
-                }
-                ErrorResponse::AuthenticationFailed { message } => {
-                    error(&format!("Error: Authentication failed: {}", message));
-                }
-                ErrorResponse::ServerError { message } => {
-                    error(&format!("Server error: {}", message));
-                }
-                _ => {
-                    error(&format!("Error: {:?}", err));
-                }
-            }
-        }else {
-            error(&format!("Unexpected server response: {}", response));
-        }
+        let resp: ListRoomsResponse = self.post("all_rooms", &req).await?;
+        Ok(resp.rooms)
     }
 
-    pub async fn delete_room(&mut self, room_id: &str){
-        let req = DeleteRoomRequest {
+    pub async fn create_room(&mut self, room_id: &str, password: Option<&str>, visibility: RoomVisibility) -> Result<CreateRoomResponse, ClientError> {
+        let req = CreateRoomRequest {
             room_id: room_id.to_string(),
+            room_password: password.map(|p| p.to_string()),
+            visibility,
         };
 
-        let response = match self.send_json_to_server("delete_room", &req).await {
-            Ok(resp) => resp,
-            Err(e) => {
-                error(&format!("Connection error: {}", e));
-                return;
-            }
+        self.post("create_room", &req).await
+    }
+
+    pub async fn create_invite(&mut self, room_id: &str, max_uses: Option<u32>, expires_in_secs: Option<u64>) -> Result<InviteInfo, ClientError> {
+        let req = CreateInviteRequest {
+            room_id: room_id.to_string(),
+            max_uses,
+            expires_in_secs,
         };
 
-        if let Ok(resp) = serde_json::from_str::<SuccessResponse>(&response) {
-                success(&format!("{}", resp.message));
+        let resp: CreateInviteResponse = self.post("create_invite", &req).await?;
+        Ok(resp.invite)
+    }
 
-        } else if let Ok(err) = serde_json::from_str::<ErrorResponse>(&response) {
-            match err {
-                ErrorResponse::RoomNotFound { room_id } => {
-                    error(&format!("Error: Room '{}' does not exist", room_id));
-                }
-                ErrorResponse::InvalidPermissions { .. } => {
-                    error(&format!("Error:  You are not the owner of '{}'", room_id));
-                }
-                ErrorResponse::ServerError { message } => {
-                    error(&format!("Server error: {}", message));
-                }
-                _ => {
-                    error(&format!("Error: {:?}", err));
-                }
-            }
-        } else {
-            error(&format!("Unexpected server response: {}", response));
-        }
+    pub async fn list_invites(&self, room_id: &str) -> Result<Vec<InviteInfo>, ClientError> {
+        let req = ListInvitesRequest {
+            room_id: room_id.to_string(),
+        };
 
+        let resp: ListInvitesResponse = self.post("list_invites", &req).await?;
+        Ok(resp.invites)
     }
 
-    pub async fn kick_user(&mut self, username: &str){
-        let current_room = self.current_room.clone().unwrap_or_else(|| "unknown_room".to_string());
-        let req = ClientWsMessage::KickUser {
-            room_id: current_room.clone(),
-            user_id: username.to_string()
+    // Returns the server's confirmation message
+    pub async fn revoke_invite(&mut self, code: &str) -> Result<String, ClientError> {
+        let req = RevokeInviteRequest {
+            code: code.to_string(),
         };
 
-        let response = match self.send_json_to_server("kick_user", &req).await {
-            Ok(resp) => resp,
-            Err(e) => {
-                error(&format!("Connection error: {}", e));
-                return;
-            }
+        let resp: SuccessResponse = self.post("revoke_invite", &req).await?;
+        Ok(resp.message)
+    }
+
+    // Owner only. Returns the room's settings with the change made; everyone in the room also
+    // gets them as a RoomUpdated event.
+    pub async fn update_room_settings(&mut self, room_id: &str, settings: RoomSettingsChange) -> Result<RoomDetails, ClientError> {
+        let req = UpdateRoomSettingsRequest {
+            room_id: room_id.to_string(),
+            settings,
         };
 
-        if let Ok(_resp) = serde_json::from_str::<SuccessResponse>(&response) {
-            success(&format!("User '{}' has been kicked from room", username));
+        let resp: UpdateRoomSettingsResponse = self.post("update_room_settings", &req).await?;
+        Ok(resp.details)
+    }
 
-        } else if let Ok(err) = serde_json::from_str::<ErrorResponse>(&response) {
-            match err {
-                ErrorResponse::AuthenticationFailed { message } => {
-                    error(&format!("Error: Authentication failed: {}", message));
-                }
-                ErrorResponse::NotInRoom {room_id } => {
-                    error(&format!("Error: User '{}' not in room {}", username, room_id));
-                }
-                ErrorResponse::ServerError { message } => {
-                    error(&format!("Error: Server error: {}", message));
-                }
-                ErrorResponse::InvalidPermissions { ..} => {
-                    error(&format!("Error: You dont own room {}; cannot kick {}", current_room, username));
-                } 
-                _ => {
-                    error(&format!("Error: {:?}", err));
-                }
-            }
+    // Returns the server's confirmation message
+    pub async fn delete_room(&mut self, room_id: &str) -> Result<String, ClientError> {
+        let req = DeleteRoomRequest {
+            room_id: room_id.to_string(),
+        };
 
-        }else {
-            error(&format!("Unexpected server response: {}", response));
-        }
+        let resp: SuccessResponse = self.post("delete_room", &req).await?;
+        Ok(resp.message)
     }
 
-  
-    pub async fn get_active_users(&mut self) {
-        let room = match &self.current_room {
-            Some(current_room) => current_room,
-            None => return, 
-        };
+    // Kicks a user from the current room. Owner only: the room tells everyone, us included, with a
+    // UserKicked event, or sends us an Error event saying why it didn't.
+    pub async fn kick_user(&mut self, username: &str) -> Result<(), ClientError> {
+        let room_id = self.current_room.clone().ok_or(ClientError::NotInRoom)?;
+        self.send_ws(&ClientWsMessage::KickUser {
+            room_id,
+            user_id: username.to_string()
+        }).await
+    }
 
+    pub async fn room_users(&self, room_id: &str) -> Result<Vec<String>, ClientError> {
         let req = ListRoomUsersRequest {
-            room_id: room.to_string(),
+            room_id: room_id.to_string(),
         };
 
-        let response = match self.send_json_to_server("list_room_users", &req).await {
-            Ok(resp) => resp,
-            Err(e) => {
-                error(&format!("Connection error: {}", e));
-                return;
-            }
-        };
+        let resp: ListRoomUsersResponse = self.post("list_room_users", &req).await?;
+        Ok(resp.active_users)
+    }
 
-        if let Ok(users_resp) = serde_json::from_str::<ListRoomUsersResponse>(&response) {
-            header(&format!("Active Users in '{}'", room));
-            if users_resp.active_users.is_empty() {
-                info(" - No active users");
-            } else {
-                for user in users_resp.active_users {
-                    println!(" - {}", user);
-                }
-            }
-        } else if let Ok(err_resp) = serde_json::from_str::<ErrorResponse>(&response) {
-            match err_resp {
-                ErrorResponse::ServerError { message } => {
-                    error(&format!("Server error: {}", message));
+    pub async fn logout(&mut self) -> Result<(), ClientError> {
+        if self.username.is_none() {
+            return Err(ClientError::NotLoggedIn);
+        }
+
+        let _: SuccessResponse = self.post("logout", &LogoutRequest {}).await?;
+        self.forget_room();
+        self.room_password = None;
+        self.username = None;
+        self.motd = None;
+        self.auth_token = None;
+        self.refresh_token = None;
+        Ok(())
+    }
+
+    // The server tells who we are from the session token, like for every other request
+    async fn connect_ws_for_room(&mut self, room_id: &str) -> Result<(), ClientError> {
+        let token = self.auth_token.as_deref().ok_or(ClientError::NotLoggedIn)?;
+        let mut request = format!("{}/ws?room_id={}", self.server_url_ws, room_id).into_client_request()?;
+        let authorization = HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|e| tungstenite::Error::HttpFormat(e.into()))?;
+        request.headers_mut().insert("authorization", authorization);
+
+        let (ws_stream, _) = connect_async(request).await?;
+        let (sender, mut receiver) = ws_stream.split();
+
+        // Anything still running belongs to a room we are no longer in
+        self.forget_room();
+
+        // Skips anything that isn't a message we understand, and reports the end of the
+        // connection unless forget_room() stopped it first
+        let events = self.events.clone();
+        let room_id = room_id.to_string();
+        self.ws_reader = Some(tokio::spawn(async move {
+            while let Some(Ok(msg)) = receiver.next().await {
+                if let Ok(text) = msg.to_text()
+                    && let Ok(parsed) = serde_json::from_str::<ServerWsMessage>(text)
+                    && events.send(RoomEvent::Message(parsed)).is_err() {
+                    return;
                 }
-                _ => error(&format!("Unexpected error: {:?}", err_resp)),
             }
-        } else {
-            error(&format!("Unexpected server response: {}", response));
+            let _ = events.send(RoomEvent::Closed { room_id });
+        }));
+        self.ws_sender = Some(sender);
+        Ok(())
+    }
+}
+
+impl Drop for ChatClient {
+    fn drop(&mut self) {
+        if let Some(reader) = self.ws_reader.take() {
+            reader.abort();
         }
     }
+}
 
-    pub async fn logout(&mut self){
-
-        if let Some(username) = &self.username {
-            let req = LogoutRequest {}; 
-
-            match self.send_json_to_server("logout", &req).await {
-
-                Ok(resp_str) => {
-                    if let Ok(_resp) = serde_json::from_str::<SuccessResponse>(&resp_str) {
-                        success(&format!("User '{}' logged out successfully", username));
-                        self.username = None;
-                        self.current_room = None;
-                    } else if let Ok(err) = serde_json::from_str::<ErrorResponse>(&resp_str) {
-                        match err {
-                            ErrorResponse::AuthenticationFailed { message } => {
-                                error(&format!("Logout failed: {}", message));
-                            }
-                            _ => {
-                                error(&format!("Logout failed: {:?}", err));
-                            }
-                        }
-                    } else {
-                        error(&format!("Unexpected server response: {}", resp_str));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    // Stands in for the server: answers every request with what `answer` gives for its path, and
+    // passes on the body of every join_room request. Websockets are answered the same way, so
+    // connecting one fails.
+    async fn fake_server(answer: fn(&str) -> String) -> (String, mpsc::UnboundedReceiver<JoinRoomRequest>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (joins, joins_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut stream = BufReader::new(stream);
+                let mut request_line = String::new();
+                stream.read_line(&mut request_line).await.unwrap();
+                let path = request_line.split_whitespace().nth(1).unwrap_or_default().to_string();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    stream.read_line(&mut header).await.unwrap();
+                    if header.trim().is_empty() {
+                        break;
                     }
+                    if let Some((name, value)) = header.split_once(':')
+                        && name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                stream.read_exact(&mut body).await.unwrap();
+                if path == "/join_room" {
+                    let _ = joins.send(serde_json::from_slice(&body).unwrap());
                 }
-                Err(e) => error(&format!("Connection error during logout: {}", e)),
+                let answer = answer(&path);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    answer.len(),
+                    answer
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
             }
-        }
+        });
+        (format!("{}", addr), joins_rx)
     }
 
-    pub async fn connect_ws_for_room(&mut self, room_id: &str) -> bool {
-        let user_id = self.username.clone().unwrap_or_default();
-        let ws_url = format!("{}/ws?user_id={}&room_id={}", self.server_url_ws, user_id, room_id);
-
-        match connect_async(&ws_url).await {
-            Ok((ws_stream, _)) => {
-                let (sender, receiver) = ws_stream.split();
-                self.ws_sender = Some(sender);
-                self.ws_receiver = Some(receiver);
-                true
-            }
-            Err(e) => {
-                error(&format!("WebSocket connection failed: {}", e));
-                false
-            }
+    fn answer(path: &str) -> String {
+        match path {
+            "/refresh_session" => serde_json::to_string(&AuthSuccessResponse {
+                token: "token".to_string(),
+                user_id: "alice".to_string(),
+                refresh_token: "refresh".to_string(),
+                motd: None,
+            }).unwrap(),
+            "/join_room" => serde_json::to_string(&JoinRoomResponse {
+                room_id: "vault".to_string(),
+                chat_history: Vec::new(),
+                details: RoomDetails {
+                    visibility: RoomVisibility::Private,
+                    topic: None,
+                    description: None,
+                    created_at: String::new(),
+                    max_members: None,
+                    slow_mode_secs: 0,
+                    history_len: 50,
+                },
+            }).unwrap(),
+            _ => "{}".to_string(),
         }
     }
 
+    #[tokio::test]
+    async fn reconnecting_to_a_private_room_sends_its_password_again() {
+        let (addr, mut joins) = fake_server(answer).await;
+        let (mut client, _events) = ChatClient::init(&format!("http://{}", addr), &format!("ws://{}", addr));
+        client.resume("refresh").await.unwrap();
 
-}
\ No newline at end of file
+        // the websocket can't connect, but the server let us in
+        assert!(client.join_room("vault", Some("Secret#1234")).await.is_err());
+        assert_eq!(joins.recv().await.unwrap().room_password.as_deref(), Some("Secret#1234"));
+
+        assert!(client.reconnect("vault").await.is_err());
+        let rejoin = joins.recv().await.unwrap();
+        assert_eq!(rejoin.room_id, "vault");
+        assert_eq!(rejoin.room_password.as_deref(), Some("Secret#1234"));
+
+        // only ever for the room it was for
+        assert!(client.reconnect("lounge").await.is_err());
+        assert_eq!(joins.recv().await.unwrap().room_password, None);
+    }
+}
@@ -1,6 +1,15 @@
 use colored::*;
 use std::io::{self, Write};
-use chrono::{DateTime, Local};
+use std::sync::OnceLock;
+use tokio::sync::mpsc;
+use ratatui::style::Modifier;
+use ratatui::text::Line;
+
+use chat_room_client::messages::ValidationError;
+use chat_room_shared::sanitize::strip_control_sequences as clean;
+use crate::markdown::render_message;
+use crate::message_format::{format, Layout};
+use crate::theme::{theme, ThemeColor};
 
 /*
  * color_formatting.rs
@@ -25,7 +34,7 @@ use chrono::{DateTime, Local};
  *  - user_message(timestamp: &str, username: &str, message: &str):
  *      Prints a chat room message that is recieved
  *
- *  - my_message(message: &str):
+ *  - my_message(timestamp: &str, message: &str):
  *      Prints a chat room message that you sent
  *
  *  - system_prompt(text: &str):
@@ -33,47 +42,190 @@ use chrono::{DateTime, Local};
  *
  *  - system_message(message: &str):
  *      Prints a message from the system on a change in state (eg user joined a room)
+ *
+ *  - validation_errors(errors: &[ValidationError]):
+ *      Lists every naming/password policy rule that was broken
+ *
+ * Colors come from the theme in the config file (see theme.rs), and the timestamp format and
+ * the cozy or compact layout from its [format] section (see message_format.rs).
+ *
+ * Chat messages are drawn with a little markdown (bold, code, links...), see markdown.rs.
+ *
+ * Text often contains usernames, room names or messages from other users, so every helper strips
+ * terminal escape sequences from its input before printing (see sanitize.rs).
+ *
+ * The full-screen UI can't have anything printed over it, so it calls redirect_output() at startup
+ * and from then on every helper sends an Output to the UI instead of printing it.
  */
 
+// One line of output, as the full-screen UI receives it
+pub enum Output {
+    Header(String),
+    Success(String),
+    Error(String),
+    Warning(String),
+    Info(String),
+    UserMessage { time: String, username: String, message: String },
+    MyMessage { time: String, message: String },
+    SystemMessage(String),
+    // one " - field: message" line per broken rule
+    ValidationFailed(Vec<String>),
+}
+
+static OUTPUT_SINK: OnceLock<mpsc::UnboundedSender<Output>> = OnceLock::new();
+
+pub fn redirect_output(sink: mpsc::UnboundedSender<Output>) {
+    let _ = OUTPUT_SINK.set(sink);
+}
+
+// Hands the output to the UI if it was redirected, otherwise the caller prints it
+fn redirected(output: impl FnOnce() -> Output) -> bool {
+    match OUTPUT_SINK.get() {
+        Some(sink) => {
+            let _ = sink.send(output());
+            true
+        }
+        None => false,
+    }
+}
+
 pub fn header(text: &str) {
-    println!("");
-    println!("{}", format!("[{}]", text).magenta().bold());
+    if redirected(|| Output::Header(clean(text))) {
+        return;
+    }
+    spacer();
+    println!("{}", paint(format!("[{}]", clean(text)), theme().header).bold());
 }
 
 pub fn success(text: &str) {
-    println!("{}", format!("[{}]", text).green());
-    println!("");
+    if redirected(|| Output::Success(clean(text))) {
+        return;
+    }
+    println!("{}", paint(format!("[{}]", clean(text)), theme().success));
+    spacer();
 }
 
 pub fn error(text: &str) {
-    println!("{}", format!("[{}]", text).red());
-    println!("");
+    if redirected(|| Output::Error(clean(text))) {
+        return;
+    }
+    println!("{}", paint(format!("[{}]", clean(text)), theme().error));
+    spacer();
 }
 
 pub fn warning(text: &str) {
-    println!("{}", format!("[{}]", text).yellow());
-    println!("");
+    if redirected(|| Output::Warning(clean(text))) {
+        return;
+    }
+    println!("{}", paint(format!("[{}]", clean(text)), theme().warning));
+    spacer();
 }
 
 pub fn info(text: &str) {
-    println!("{}", text);
+    if redirected(|| Output::Info(clean(text))) {
+        return;
+    }
+    println!("{}", clean(text));
 }
 
 pub fn user_message(timestamp: &str, username: &str, message: &str) {
-    let short_time = DateTime::parse_from_rfc3339(timestamp).map(|dt| dt.with_timezone(&Local).format("%m-%d %H:%M").to_string()).unwrap_or_else(|_| timestamp.to_string());
-    println!("[{}] {}: {}", short_time.dimmed(), username.green().bold(),message.white());
+    let time = format().time(timestamp);
+    if redirected(|| Output::UserMessage { time: clean(&time), username: clean(username), message: clean(message) }) {
+        return;
+    }
+    println!("[{}] {}: {}", clean(&time).dimmed(), paint(clean(username), theme().nick_color(username)).bold(), rendered(message));
 }
 
-pub fn my_message(message: &str) {
-    let width = 80;
-    println!("{:>width$} {}", "You:".blue().bold(), message.white(),width = width);
+pub fn my_message(timestamp: &str, message: &str) {
+    let time = format().time(timestamp);
+    if redirected(|| Output::MyMessage { time: clean(&time), message: clean(message) }) {
+        return;
+    }
+    let label = paint("You:".to_string(), theme().my_name).bold();
+    if format().layout == Layout::Compact {
+        println!("[{}] {} {}", clean(&time).dimmed(), label, rendered(message));
+        return;
+    }
+
+    // Cozy: every line pushed against the right edge of the terminal, or left as it is when it
+    // doesn't fit
+    let width = crossterm::terminal::size().map(|(cols, _)| cols as usize).unwrap_or(80);
+    for (i, line) in render_message(&clean(message)).iter().enumerate() {
+        let label = if i == 0 { format!("{} ", label) } else { String::new() };
+        let used = if i == 0 { "You: ".len() } else { 0 } + line.width();
+        println!("{}{}{}", " ".repeat(width.saturating_sub(used)), label, ansi(line));
+    }
 }
 
+// The full-screen UI draws its own prompt
 pub fn system_prompt(text: &str) {
-    print!("{}", text.cyan());
+    if OUTPUT_SINK.get().is_some() {
+        return;
+    }
+    print!("{}", paint(clean(text), theme().prompt));
     io::stdout().flush().unwrap();
 }
 
 pub fn system_message(message: &str){
-    println!("{}", message.dimmed());
-}
\ No newline at end of file
+    if redirected(|| Output::SystemMessage(clean(message))) {
+        return;
+    }
+    println!("{}", clean(message).dimmed());
+}
+
+pub fn validation_errors(errors: &[ValidationError]) {
+    let lines: Vec<String> = errors.iter().map(|err| format!(" - {}: {}", clean(&err.field), clean(&err.message))).collect();
+    if redirected(|| Output::ValidationFailed(lines.clone())) {
+        return;
+    }
+    println!("{}", paint("[Does not meet the requirements:]".to_string(), theme().error));
+    for line in lines {
+        println!("{}", paint(line, theme().error));
+    }
+    println!();
+}
+
+// The blank line that separates status messages from the rest in the cozy layout
+fn spacer() {
+    if format().layout == Layout::Cozy {
+        println!();
+    }
+}
+
+// A message with its markdown drawn (see markdown.rs), the way the terminal prints it
+fn rendered(message: &str) -> String {
+    render_message(&clean(message)).iter().map(ansi).collect::<Vec<_>>().join("\n")
+}
+
+fn ansi(line: &Line) -> String {
+    line.spans
+        .iter()
+        .map(|span| {
+            let mut text = span.content.to_string().normal();
+            if let Some(color) = span.style.fg.and_then(|color| ThemeColor(color).colored()) {
+                text = text.color(color);
+            }
+            let modifiers = span.style.add_modifier;
+            if modifiers.contains(Modifier::BOLD) {
+                text = text.bold();
+            }
+            if modifiers.contains(Modifier::ITALIC) {
+                text = text.italic();
+            }
+            if modifiers.contains(Modifier::UNDERLINED) {
+                text = text.underline();
+            }
+            if modifiers.contains(Modifier::DIM) {
+                text = text.dimmed();
+            }
+            text.to_string()
+        })
+        .collect()
+}
+
+fn paint(text: String, color: ThemeColor) -> ColoredString {
+    match color.colored() {
+        Some(color) => text.color(color),
+        None => text.normal(),
+    }
+}
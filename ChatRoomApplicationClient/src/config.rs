@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::keybindings::KeyBindings;
+use crate::message_format::MessageFormat;
+use crate::theme::ThemeConfig;
+
+// The client's settings, read once at startup from config.toml in the config directory (eg
+// ~/.config/chat_client/config.toml on Linux). Every setting is optional and there doesn't have
+// to be a file at all:
+//
+//      # the profile used when --server isn't given
+//      default_server = "home"
+//      # join the room you were last in after logging back in
+//      auto_rejoin = true
+//
+//      [servers.home]
+//      url = "http://127.0.0.1:3000"
+//
+//      [servers.work]
+//      url = "http://chat.example.com:3000"
+//      ws_url = "ws://chat.example.com:3000"    # worked out from url when left out
+//
+//      [theme]                       # see theme.rs
+//      header = "magenta"
+//      username = "#5fd7ff"
+//
+//      [format]                      # see message_format.rs
+//      timestamp = "%H:%M"
+//      layout = "compact"
+//
+//      [keybindings]
+//      quit = "ctrl+q"
+//      scroll_up = "alt+k"
+//
+// Each server profile has its own saved session (see session_store.rs), so being logged in on
+// one doesn't log you in anywhere else.
+
+const DEFAULT_PROFILE: &str = "default";
+const DEFAULT_URL: &str = "http://127.0.0.1:3000";
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub default_server: Option<String>,
+    pub auto_rejoin: bool,
+    pub servers: BTreeMap<String, ServerProfile>,
+    pub theme: ThemeConfig,
+    pub format: MessageFormat,
+    pub keybindings: KeyBindings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            default_server: None,
+            auto_rejoin: true,
+            servers: BTreeMap::new(),
+            theme: ThemeConfig::default(),
+            format: MessageFormat::default(),
+            keybindings: KeyBindings::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServerProfile {
+    pub url: String,
+    pub ws_url: Option<String>,
+}
+
+impl ServerProfile {
+    // http://host -> ws://host, https://host -> wss://host
+    pub fn ws_url(&self) -> String {
+        if let Some(ws_url) = &self.ws_url {
+            return ws_url.clone();
+        }
+        match self.url.split_once("://") {
+            Some(("https", rest)) => format!("wss://{}", rest),
+            Some((_, rest)) => format!("ws://{}", rest),
+            None => format!("ws://{}", self.url),
+        }
+    }
+}
+
+impl Config {
+    // A missing file gives the defaults. A broken one is reported and ignored rather than
+    // stopping the client from starting.
+    pub fn load() -> Config {
+        let Some(path) = config_path() else {
+            return Config::default();
+        };
+        let Ok(contents) = fs::read_to_string(&path) else {
+            return Config::default();
+        };
+        match toml::from_str(&contents) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Ignoring {}: {}", path.display(), e);
+                Config::default()
+            }
+        }
+    }
+
+    // The profile to connect with and its name: the one asked for with --server, or else
+    // default_server, or else the only one there is. Without any profiles it is the local server.
+    pub fn profile(&self, name: Option<&str>) -> Result<(String, ServerProfile), String> {
+        let name = name.or(self.default_server.as_deref());
+        match name {
+            Some(name) => match self.servers.get(name) {
+                Some(profile) => Ok((name.to_string(), profile.clone())),
+                None if self.servers.is_empty() => Err(format!("No server profile named '{}', there are none in the config file", name)),
+                None => Err(format!(
+                    "No server profile named '{}' (try one of: {})",
+                    name,
+                    self.servers.keys().cloned().collect::<Vec<_>>().join(", ")
+                )),
+            },
+            None if self.servers.len() > 1 => Err(format!(
+                "Pick a server with --server <{}>, or set default_server in the config file",
+                self.servers.keys().cloned().collect::<Vec<_>>().join("|")
+            )),
+            None if self.servers.len() == 1 => {
+                let (name, profile) = self.servers.iter().next().unwrap();
+                Ok((name.clone(), profile.clone()))
+            }
+            None => Ok((
+                DEFAULT_PROFILE.to_string(),
+                ServerProfile {
+                    url: DEFAULT_URL.to_string(),
+                    ws_url: None,
+                },
+            )),
+        }
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    Some(dirs::config_dir()?.join("chat_client").join("config.toml"))
+}
@@ -0,0 +1,24 @@
+use std::env;
+use std::fs;
+
+// Credentials for logging in without a prompt, for scripts and bots:
+//
+//  - CHAT_USER:           the username
+//  - CHAT_PASSWORD:       the password
+//  - CHAT_PASSWORD_FILE:  a file holding the password, used when CHAT_PASSWORD isn't set. Keeps
+//                         the password out of the environment of every child process.
+
+pub fn username_from_env() -> Option<String> {
+    env::var("CHAT_USER").ok().filter(|user| !user.is_empty())
+}
+
+pub fn password_from_env() -> Option<String> {
+    if let Ok(password) = env::var("CHAT_PASSWORD") {
+        return Some(password);
+    }
+    let path = env::var("CHAT_PASSWORD_FILE").ok()?;
+    // a trailing newline is almost always an accident of how the file was written
+    fs::read_to_string(path)
+        .ok()
+        .map(|password| password.trim_end_matches(['\n', '\r']).to_string())
+}
@@ -0,0 +1,85 @@
+use std::fmt;
+
+use tokio_tungstenite::tungstenite;
+
+use crate::messages::ErrorResponse;
+
+// Everything a ChatClient call can fail with. The server's own errors are passed on as they were
+// sent (Server), so callers can match on them, eg to show each broken ValidationFailed rule.
+#[derive(Debug)]
+pub enum ClientError {
+    // the request didn't make it to the server or the answer didn't make it back
+    Http(reqwest::Error),
+    WebSocket(tungstenite::Error),
+    // the server refused the request
+    Server(ErrorResponse),
+    // the server answered with something that isn't part of the protocol
+    UnexpectedResponse(String),
+    NotLoggedIn,
+    NotInRoom,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Http(e) => write!(f, "Connection error: {}", e),
+            ClientError::WebSocket(e) => write!(f, "WebSocket error: {}", e),
+            ClientError::Server(e) => write!(f, "{}", e),
+            ClientError::UnexpectedResponse(response) => write!(f, "Unexpected server response: {}", response),
+            ClientError::NotLoggedIn => write!(f, "Not logged in"),
+            ClientError::NotInRoom => write!(f, "Not in a room"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Http(e) => Some(e),
+            ClientError::WebSocket(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        ClientError::Http(e)
+    }
+}
+
+impl From<tungstenite::Error> for ClientError {
+    fn from(e: tungstenite::Error) -> Self {
+        ClientError::WebSocket(e)
+    }
+}
+
+impl fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorResponse::AuthenticationFailed { message } => write!(f, "Authentication failed: {}", message),
+            ErrorResponse::UserAlreadyExists { user_id } => write!(f, "User {} already exists", user_id),
+            ErrorResponse::UserNotFound { user_id } => write!(f, "User {} not found", user_id),
+            ErrorResponse::InvalidPassword { .. } => write!(f, "Invalid password"),
+            ErrorResponse::RoomNotFound { room_id } => write!(f, "Room '{}' does not exist", room_id),
+            ErrorResponse::RoomAlreadyExists { room_id } => write!(f, "Room '{}' already exists", room_id),
+            ErrorResponse::RoomFull { room_id } => write!(f, "Room '{}' is full", room_id),
+            ErrorResponse::InviteInvalid { message } => write!(f, "{}", message),
+            ErrorResponse::CommandNotFound { command } => write!(f, "Unknown command /{}", command),
+            ErrorResponse::AnnouncementNotFound { announcement_id } => {
+                write!(f, "No announcement {} waiting to be sent", announcement_id)
+            }
+            ErrorResponse::NotInRoom { room_id } => write!(f, "Not in room {}", room_id),
+            ErrorResponse::ServerError { message } => write!(f, "Server error: {}", message),
+            ErrorResponse::Unavailable { message } => write!(f, "{}", message),
+            ErrorResponse::RateLimited { message, retry_after_secs } => {
+                write!(f, "{} (try again in {}s)", message, retry_after_secs)
+            }
+            ErrorResponse::ValidationFailed { errors } => {
+                let broken: Vec<String> = errors.iter().map(|err| format!("{}: {}", err.field, err.message)).collect();
+                write!(f, "Does not meet the requirements: {}", broken.join("; "))
+            }
+            ErrorResponse::InvalidPermissions { message } => write!(f, "{}", message),
+        }
+    }
+}
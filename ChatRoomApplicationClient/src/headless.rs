@@ -0,0 +1,397 @@
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use chat_room_client::{ChatClient, ClientError, RoomEvent, RoomEvents};
+use chat_room_client::messages::{ErrorResponse, RoomSettingsChange, RoomVisibility, ServerWsMessage};
+use crate::credentials::{password_from_env, username_from_env};
+use crate::reconnect::{self, Attempt, Reconnect};
+use crate::stdin_reader::StdinReader;
+
+/*
+ * headless.rs
+ *
+ * --json mode, for bots and automated tests. Reads one command per line on stdin and writes one
+ * JSON object per line on stdout, nothing else is ever printed.
+ *
+ * Commands are tagged with "command" and can carry an "id", which is copied into their result:
+ *      {"id": 1, "command": "login", "user_id": "bot", "password": "..."}
+ *      {"id": 2, "command": "join_room", "room_id": "lounge"}
+ *      {"command": "send", "content": "hello"}
+ *
+ * login falls back to CHAT_USER and CHAT_PASSWORD/CHAT_PASSWORD_FILE (see credentials.rs), and
+ * with CHAT_USER set the client logs in on its own at startup, so passwords don't have to be
+ * written into the command stream.
+ *
+ * Output lines are tagged with "kind":
+ *      {"kind": "result", "id": 2, "command": "join_room", "ok": true, "data": {"room_id": "lounge", "chat_history": [...]}}
+ *      {"kind": "result", "id": 3, "command": "create_room", "ok": false, "error": "...", "server_error": {"error_type": ...}}
+ *      {"kind": "event", "event": {"type": "MessageBroadcast", ...}}
+ *      {"kind": "disconnected", "room_id": "lounge"}
+ *      {"kind": "reconnected", "room_id": "lounge", "data": {"room_id": "lounge", "chat_history": [...]}}
+ *      {"kind": "reconnect_failed", "room_id": "lounge", "error": "..."}
+ *
+ * A result's "data" is whatever the ChatClient call returned (rooms, invites, chat history...).
+ * Every ServerWsMessage from the current room is passed on as an event, our own messages included.
+ * After a ServerShutdown event the connection still ends with "disconnected", and the client then
+ * rejoins the room on its own once the server is back (see reconnect.rs), which ends with
+ * "reconnected" or "reconnect_failed".
+ */
+
+// How often to ping the server while in a room, same as the plain interface
+const KEEPALIVE: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Option<Value>,
+    #[serde(flatten)]
+    command: JsonCommand,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum JsonCommand {
+    SignUp { user_id: String, password: String },
+    Login { user_id: Option<String>, password: Option<String> },
+    Logout,
+    AllRooms {
+        #[serde(default)]
+        only_active: bool,
+    },
+    CreateRoom {
+        room_id: String,
+        password: Option<String>,
+        #[serde(default)]
+        visibility: RoomVisibility,
+    },
+    DeleteRoom { room_id: String },
+    JoinRoom { room_id: String, password: Option<String> },
+    JoinInvite { code: String },
+    Leave,
+    Send { content: String },
+    ActiveUsers,
+    Kick { user_id: String },
+    CreateInvite {
+        room_id: Option<String>,
+        max_uses: Option<u32>,
+        expires_in_secs: Option<u64>,
+    },
+    ListInvites { room_id: Option<String> },
+    RevokeInvite { code: String },
+    // the fields of RoomSettingsChange next to room_id, eg {"command": "update_room_settings", "topic": "..."}
+    UpdateRoomSettings {
+        room_id: Option<String>,
+        #[serde(flatten)]
+        settings: RoomSettingsChange,
+    },
+    Quit,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum JsonOutput {
+    Result {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<Value>,
+        command: String,
+        ok: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        // the error exactly as the server sent it, when it was the server that refused
+        #[serde(skip_serializing_if = "Option::is_none")]
+        server_error: Option<ErrorResponse>,
+    },
+    Event { event: ServerWsMessage },
+    Disconnected { room_id: String },
+    // back in the room after the server restarted, "data" is what join_room would have returned
+    Reconnected { room_id: String, data: Value },
+    ReconnectFailed { room_id: String, error: String },
+}
+
+// A command that succeeded, with whatever it returned for "data"
+type Outcome = Result<Option<Value>, Failure>;
+
+enum Failure {
+    Client(ClientError),
+    // the command itself can't be run as given
+    Usage(&'static str),
+}
+
+impl From<ClientError> for Failure {
+    fn from(e: ClientError) -> Self {
+        Failure::Client(e)
+    }
+}
+
+struct Headless {
+    client: ChatClient,
+    // the room to get back into after the server restarts
+    reconnect: Option<Reconnect>,
+    quit: bool,
+}
+
+pub async fn run(client: ChatClient, mut events: RoomEvents) {
+    let mut headless = Headless {
+        client,
+        reconnect: None,
+        quit: false,
+    };
+    let mut stdin = StdinReader::spawn();
+    let mut keepalive = tokio::time::interval(KEEPALIVE);
+
+    if username_from_env().is_some() {
+        headless.run_command(None, JsonCommand::Login { user_id: None, password: None }).await;
+    }
+
+    while !headless.quit {
+        tokio::select! {
+            line = stdin.next_line() => match line {
+                Some(line) => headless.handle_line(&line).await,
+                None => break,
+            },
+            Some(event) = events.next() => headless.handle_room_event(event),
+            _ = keepalive.tick() => headless.keepalive().await,
+            _ = reconnect::due(&headless.reconnect) => headless.reconnect().await,
+        }
+    }
+
+    if headless.client.current_room().is_some() {
+        let _ = headless.client.leave_room().await;
+    }
+}
+
+fn emit(output: &JsonOutput) {
+    if let Ok(line) = serde_json::to_string(output) {
+        println!("{}", line);
+    }
+}
+
+fn emit_result(id: Option<Value>, command: &str, outcome: Outcome) {
+    let (ok, data, error, server_error) = match outcome {
+        Ok(data) => (true, data, None, None),
+        Err(Failure::Usage(message)) => (false, None, Some(message.to_string()), None),
+        Err(Failure::Client(e)) => {
+            let server_error = match &e {
+                ClientError::Server(err) => Some(err.clone()),
+                _ => None,
+            };
+            (false, None, Some(e.to_string()), server_error)
+        }
+    };
+    emit(&JsonOutput::Result {
+        id,
+        command: command.to_string(),
+        ok,
+        data,
+        error,
+        server_error,
+    });
+}
+
+// What goes in "data", none of it can fail to serialize
+fn data(value: impl Serialize) -> Outcome {
+    Ok(Some(serde_json::to_value(value).unwrap_or_default()))
+}
+
+impl Headless {
+    async fn handle_line(&mut self, line: &str) {
+        if line.trim().is_empty() {
+            return;
+        }
+
+        match serde_json::from_str::<Request>(line) {
+            Ok(request) => self.run_command(request.id, request.command).await,
+            Err(e) => {
+                // still pass the id back if there is one, so the caller knows which command failed
+                let id = serde_json::from_str::<Value>(line)
+                    .ok()
+                    .and_then(|value| value.get("id").cloned());
+                emit(&JsonOutput::Result {
+                    id,
+                    command: "invalid".to_string(),
+                    ok: false,
+                    data: None,
+                    error: Some(format!("Invalid command: {}", e)),
+                    server_error: None,
+                });
+            }
+        }
+    }
+
+    async fn run_command(&mut self, id: Option<Value>, command: JsonCommand) {
+        let name = command_name(&command);
+        let outcome = self.execute(command).await;
+        emit_result(id, name, outcome);
+    }
+
+    async fn execute(&mut self, command: JsonCommand) -> Outcome {
+        let client = &mut self.client;
+        let in_room = client.current_room().map(str::to_string);
+
+        match command {
+            JsonCommand::Quit => {
+                self.quit = true;
+                Ok(None)
+            }
+            JsonCommand::SignUp { user_id, password } => {
+                let user_id = client.create_user(&user_id, &password).await?;
+                data(json!({ "user_id": user_id }))
+            }
+            JsonCommand::Login { user_id, password } => {
+                let (Some(user_id), Some(password)) = (user_id.or_else(username_from_env), password.or_else(password_from_env)) else {
+                    return Err(Failure::Usage("No credentials: pass user_id and password, or set CHAT_USER and CHAT_PASSWORD"));
+                };
+                client.login(&user_id, &password).await?;
+                match client.motd() {
+                    Some(motd) => data(json!({ "motd": motd })),
+                    None => Ok(None),
+                }
+            }
+            JsonCommand::Logout => {
+                self.reconnect = None;
+                client.logout().await?;
+                Ok(None)
+            }
+            JsonCommand::AllRooms { only_active } => data(json!({ "rooms": client.list_rooms(only_active).await? })),
+            JsonCommand::CreateRoom { room_id, password, visibility } => {
+                data(client.create_room(&room_id, password.as_deref(), visibility).await?)
+            }
+            JsonCommand::DeleteRoom { room_id } => data(json!({ "message": client.delete_room(&room_id).await? })),
+            JsonCommand::JoinRoom { room_id, password } => {
+                leave_first(client).await?;
+                data(client.join_room(&room_id, password.as_deref()).await?)
+            }
+            JsonCommand::JoinInvite { code } => {
+                leave_first(client).await?;
+                data(client.join_invite(&code).await?)
+            }
+            JsonCommand::CreateInvite { room_id, max_uses, expires_in_secs } => {
+                let room_id = room_id.or(in_room).ok_or(Failure::Usage("room_id is needed outside of a room"))?;
+                data(client.create_invite(&room_id, max_uses, expires_in_secs).await?)
+            }
+            JsonCommand::ListInvites { room_id } => {
+                let room_id = room_id.or(in_room).ok_or(Failure::Usage("room_id is needed outside of a room"))?;
+                let invites = client.list_invites(&room_id).await?;
+                data(json!({ "room_id": room_id, "invites": invites }))
+            }
+            JsonCommand::RevokeInvite { code } => data(json!({ "message": client.revoke_invite(&code).await? })),
+            JsonCommand::UpdateRoomSettings { room_id, settings } => {
+                let room_id = room_id.or(in_room).ok_or(Failure::Usage("room_id is needed outside of a room"))?;
+                let details = client.update_room_settings(&room_id, settings).await?;
+                data(json!({ "room_id": room_id, "details": details }))
+            }
+            JsonCommand::Leave => {
+                client.leave_room().await?;
+                Ok(None)
+            }
+            JsonCommand::Send { content } => {
+                client.send_message(&content).await?;
+                Ok(None)
+            }
+            JsonCommand::ActiveUsers => {
+                let room_id = in_room.ok_or(ClientError::NotInRoom)?;
+                let active_users = client.room_users(&room_id).await?;
+                data(json!({ "room_id": room_id, "active_users": active_users }))
+            }
+            JsonCommand::Kick { user_id } => {
+                client.kick_user(&user_id).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn handle_room_event(&mut self, event: RoomEvent) {
+        let me = self.client.username().unwrap_or_default().to_string();
+        let current_room = self.client.current_room().unwrap_or_default().to_string();
+
+        match event {
+            RoomEvent::Message(msg) => {
+                // Being kicked or the room going away ends our time in it, like in the other interfaces
+                let gone = match &msg {
+                    ServerWsMessage::RoomDeleted { room_id } => *room_id == current_room,
+                    ServerWsMessage::UserKicked { room_id, user_id } => *room_id == current_room && *user_id == me,
+                    ServerWsMessage::ServerShutdown { reconnect_after_ms } => {
+                        self.reconnect = Some(Reconnect::after(&current_room, *reconnect_after_ms));
+                        false
+                    }
+                    _ => false,
+                };
+                emit(&JsonOutput::Event { event: msg });
+                if gone {
+                    self.client.forget_room();
+                }
+            }
+            RoomEvent::Closed { room_id } if room_id == current_room => {
+                self.client.forget_room();
+                emit(&JsonOutput::Disconnected { room_id });
+            }
+            // a room we already left
+            RoomEvent::Closed { .. } => {}
+        }
+    }
+
+    async fn reconnect(&mut self) {
+        let Some(mut reconnect) = self.reconnect.take() else {
+            return;
+        };
+        // joined another room while waiting
+        if self.client.current_room().is_some() {
+            return;
+        }
+        let room_id = reconnect.room_id.clone();
+        match reconnect.attempt(&mut self.client).await {
+            Attempt::Rejoined(resp) => emit(&JsonOutput::Reconnected {
+                room_id,
+                data: serde_json::to_value(resp).unwrap_or_default(),
+            }),
+            Attempt::Retrying => self.reconnect = Some(reconnect),
+            Attempt::GaveUp(e) => emit(&JsonOutput::ReconnectFailed {
+                room_id,
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    async fn keepalive(&mut self) {
+        if let Some(room_id) = self.client.current_room().map(str::to_string)
+            && self.client.ping().await.is_err() {
+            self.client.forget_room();
+            emit(&JsonOutput::Disconnected { room_id });
+        }
+    }
+}
+
+// Joining another room leaves the current one, so a bot can't end up connected to two
+async fn leave_first(client: &mut ChatClient) -> Result<(), ClientError> {
+    if client.current_room().is_some() {
+        client.leave_room().await?;
+    }
+    Ok(())
+}
+
+fn command_name(command: &JsonCommand) -> &'static str {
+    match command {
+        JsonCommand::SignUp { .. } => "sign_up",
+        JsonCommand::Login { .. } => "login",
+        JsonCommand::Logout => "logout",
+        JsonCommand::AllRooms { .. } => "all_rooms",
+        JsonCommand::CreateRoom { .. } => "create_room",
+        JsonCommand::DeleteRoom { .. } => "delete_room",
+        JsonCommand::JoinRoom { .. } => "join_room",
+        JsonCommand::JoinInvite { .. } => "join_invite",
+        JsonCommand::Leave => "leave",
+        JsonCommand::Send { .. } => "send",
+        JsonCommand::ActiveUsers => "active_users",
+        JsonCommand::Kick { .. } => "kick",
+        JsonCommand::CreateInvite { .. } => "create_invite",
+        JsonCommand::ListInvites { .. } => "list_invites",
+        JsonCommand::RevokeInvite { .. } => "revoke_invite",
+        JsonCommand::UpdateRoomSettings { .. } => "update_room_settings",
+        JsonCommand::Quit => "quit",
+    }
+}
@@ -0,0 +1,86 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use chat_room_client::validation::validate_user_id;
+
+// Input history for the line editor. Before logging in it only lives in memory; once a user logs
+// in it is loaded from and appended to a file of their own, eg
+// ~/.local/share/chat_client/history/alice on Linux.
+//
+// History is a convenience, so failing to read or write the file is silently ignored.
+
+const MAX_ENTRIES: usize = 500;
+
+#[derive(Default)]
+pub struct History {
+    entries: Vec<String>,
+    // None until a user is logged in
+    path: Option<PathBuf>,
+}
+
+impl History {
+    // Switches to `user_id`'s history file, or back to in-memory history with None (on logout)
+    pub fn load_for_user(&mut self, user_id: Option<&str>) {
+        self.entries.clear();
+        self.path = user_id.and_then(history_path);
+
+        let Some(path) = &self.path else {
+            return;
+        };
+        let Ok(contents) = fs::read_to_string(path) else {
+            return;
+        };
+        self.entries = contents.lines().map(str::to_string).collect();
+
+        // compact the file once it has grown past the limit
+        if self.entries.len() > MAX_ENTRIES {
+            self.entries.drain(..self.entries.len() - MAX_ENTRIES);
+            let _ = fs::write(path, self.entries.join("\n") + "\n");
+        }
+    }
+
+    pub fn add(&mut self, line: &str) {
+        if line.trim().is_empty() || self.entries.last().is_some_and(|last| last == line) {
+            return;
+        }
+        self.entries.push(line.to_string());
+        if self.entries.len() > MAX_ENTRIES {
+            self.entries.remove(0);
+        }
+
+        if contains_password(line) {
+            return;
+        }
+        if let Some(path) = &self.path
+            && let Some(dir) = path.parent()
+            && fs::create_dir_all(dir).is_ok()
+            && let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path)
+        {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+
+    // Oldest first
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+}
+
+// User ids are checked against the naming policy first so they are always safe as a file name
+fn history_path(user_id: &str) -> Option<PathBuf> {
+    if !validate_user_id(user_id).is_empty() {
+        return None;
+    }
+    Some(dirs::data_dir()?.join("chat_client").join("history").join(user_id))
+}
+
+// Room passwords typed as arguments are kept in memory for up-arrow, but never written to disk
+fn contains_password(line: &str) -> bool {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    match args.first() {
+        Some(&"/join") => args.len() > 2,
+        Some(&"/create") => args.iter().skip(2).any(|arg| !arg.starts_with("--")),
+        _ => false,
+    }
+}
@@ -0,0 +1,119 @@
+use std::fmt;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde::{Deserialize, Deserializer};
+
+// Keys of the full-screen interface that can be changed in the [keybindings] section of the
+// config file (see config.rs). A key is written like "ctrl+q", "alt+k", "pageup", "f2" or "tab".
+//
+// Typing keys, Enter and the line editor's own keys (see line_editor.rs) can't be rebound.
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyBindings {
+    pub quit: KeyBinding,
+    pub cancel: KeyBinding,
+    pub complete: KeyBinding,
+    pub scroll_up: KeyBinding,
+    pub scroll_down: KeyBinding,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            quit: KeyBinding::new(KeyCode::Char('c'), KeyModifiers::CONTROL),
+            cancel: KeyBinding::new(KeyCode::Esc, KeyModifiers::NONE),
+            complete: KeyBinding::new(KeyCode::Tab, KeyModifiers::NONE),
+            scroll_up: KeyBinding::new(KeyCode::PageUp, KeyModifiers::NONE),
+            scroll_down: KeyBinding::new(KeyCode::PageDown, KeyModifiers::NONE),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct KeyBinding {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl KeyBinding {
+    fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        KeyBinding { code, modifiers }
+    }
+
+    pub fn matches(&self, key: &KeyEvent) -> bool {
+        // Shift is part of the character for letters, "shift+a" arrives as 'A'
+        let modifiers = match key.code {
+            KeyCode::Char(_) => key.modifiers - KeyModifiers::SHIFT,
+            _ => key.modifiers,
+        };
+        let code = match key.code {
+            KeyCode::Char(c) => KeyCode::Char(c.to_ascii_lowercase()),
+            code => code,
+        };
+        code == self.code && modifiers == self.modifiers
+    }
+
+    fn parse(text: &str) -> Option<KeyBinding> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut parts: Vec<&str> = text.split('+').map(str::trim).collect();
+        let key = parts.pop()?.to_ascii_lowercase();
+        for modifier in parts {
+            modifiers |= match modifier.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => KeyModifiers::CONTROL,
+                "alt" | "meta" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => return None,
+            };
+        }
+
+        let code = match key.as_str() {
+            "tab" => KeyCode::Tab,
+            "esc" | "escape" => KeyCode::Esc,
+            "pageup" | "pgup" => KeyCode::PageUp,
+            "pagedown" | "pgdn" => KeyCode::PageDown,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "insert" => KeyCode::Insert,
+            "delete" => KeyCode::Delete,
+            "space" => KeyCode::Char(' '),
+            _ if key.chars().count() == 1 => KeyCode::Char(key.chars().next()?),
+            _ => KeyCode::F(key.strip_prefix('f')?.parse().ok().filter(|n| (1..=12).contains(n))?),
+        };
+        Some(KeyBinding::new(code, modifiers))
+    }
+}
+
+// eg "Ctrl+C" or "PgUp", for the status bar
+impl fmt::Display for KeyBinding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.modifiers.contains(KeyModifiers::CONTROL) {
+            write!(f, "Ctrl+")?;
+        }
+        if self.modifiers.contains(KeyModifiers::ALT) {
+            write!(f, "Alt+")?;
+        }
+        if self.modifiers.contains(KeyModifiers::SHIFT) {
+            write!(f, "Shift+")?;
+        }
+        match self.code {
+            KeyCode::Char(' ') => write!(f, "Space"),
+            KeyCode::Char(c) => write!(f, "{}", c.to_ascii_uppercase()),
+            KeyCode::PageUp => write!(f, "PgUp"),
+            KeyCode::PageDown => write!(f, "PgDn"),
+            KeyCode::F(n) => write!(f, "F{}", n),
+            code => write!(f, "{:?}", code),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyBinding {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        KeyBinding::parse(&text).ok_or_else(|| serde::de::Error::custom(format!("unknown key '{}'", text)))
+    }
+}
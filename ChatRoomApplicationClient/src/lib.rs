@@ -0,0 +1,12 @@
+// The chat client as a library. ChatClient talks to the server and hands back typed results and a
+// stream of room events without printing anything, so bots, integration tests and other front
+// ends can be built on it. The terminal client in main.rs is one of them.
+
+pub mod chat_client;
+pub mod error;
+pub mod messages;
+// the naming and password policy, the same code the server enforces it with
+pub use chat_room_shared::validation;
+
+pub use chat_client::{ChatClient, RoomEvent, RoomEvents};
+pub use error::ClientError;
@@ -0,0 +1,244 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use unicode_width::UnicodeWidthChar;
+
+use crate::history::History;
+
+// The input line of the full-screen UI. Supports the usual readline movement and deletion keys:
+//
+//  - Left/Right, Home/End (or Ctrl+A/Ctrl+E) move the cursor
+//  - Ctrl+Left/Ctrl+Right move by word
+//  - Backspace/Delete delete a character, Ctrl+W deletes the word before the cursor
+//  - Ctrl+U deletes everything before the cursor, Ctrl+K everything after it
+//  - Up/Down go through the input history
+//
+// Tab completion is started by the caller with complete(), since only the UI knows which
+// commands, rooms and users there are. Enter, Esc and the other keys the UI cares about are
+// left to the caller too.
+
+#[derive(Default)]
+pub struct LineEditor {
+    buffer: Vec<char>,
+    // index into buffer, 0..=buffer.len()
+    cursor: usize,
+    pub history: History,
+    // The history entry being shown, None when editing a new line
+    history_index: Option<usize>,
+    // What was typed before going up into the history, restored when coming back down
+    draft: Vec<char>,
+    // Set while Tab is cycling through several matches
+    completion: Option<Completion>,
+}
+
+struct Completion {
+    // where the word being completed starts in the buffer
+    start: usize,
+    matches: Vec<String>,
+    index: usize,
+}
+
+impl LineEditor {
+    // Returns false if the key isn't an editing key
+    pub fn handle_key(&mut self, key: KeyEvent) -> bool {
+        // any other key accepts the completion that is showing
+        self.completion = None;
+
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('a') if ctrl => self.cursor = 0,
+            KeyCode::Char('e') if ctrl => self.cursor = self.buffer.len(),
+            KeyCode::Char('w') if ctrl => self.delete_word_before_cursor(),
+            KeyCode::Char('u') if ctrl => {
+                self.buffer.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            KeyCode::Char('k') if ctrl => self.buffer.truncate(self.cursor),
+            KeyCode::Char(_) if ctrl => return false,
+            KeyCode::Char(c) => self.insert(c),
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.buffer.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.buffer.len() => {
+                self.buffer.remove(self.cursor);
+            }
+            KeyCode::Left if ctrl => self.cursor = self.word_start_before_cursor(),
+            KeyCode::Right if ctrl => self.cursor = self.word_end_after_cursor(),
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.buffer.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.buffer.len(),
+            KeyCode::Up => self.history_prev(),
+            KeyCode::Down => self.history_next(),
+            KeyCode::Backspace | KeyCode::Delete => {}
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn insert(&mut self, c: char) {
+        // pasted newlines and other control characters would end up in the message
+        if c.is_control() {
+            return;
+        }
+        self.buffer.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    pub fn text(&self) -> String {
+        self.buffer.iter().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    // Empties the line and returns what was in it
+    pub fn take(&mut self) -> String {
+        let text = self.text();
+        self.clear();
+        text
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.cursor = 0;
+        self.history_index = None;
+        self.draft.clear();
+        self.completion = None;
+    }
+
+    // Completes the word before the cursor. `candidates` is given the words before that one and
+    // returns everything that could go in its place; those starting with what has been typed so
+    // far are the matches. A single match is filled in with a space after it, with several the
+    // first is filled in and pressing Tab again cycles through the rest.
+    pub fn complete(&mut self, candidates: impl FnOnce(&[String]) -> Vec<String>) {
+        if let Some(completion) = &mut self.completion {
+            completion.index = (completion.index + 1) % completion.matches.len();
+            let (start, next) = (completion.start, completion.matches[completion.index].clone());
+            self.replace_word(start, &next);
+            return;
+        }
+
+        let start = self.current_word_start();
+        let prefix: String = self.buffer[start..self.cursor].iter().collect();
+        let before: String = self.buffer[..start].iter().collect();
+        let words_before: Vec<String> = before.split_whitespace().map(str::to_string).collect();
+
+        let mut matches: Vec<String> = candidates(&words_before)
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(&prefix))
+            .collect();
+        matches.sort();
+        matches.dedup();
+
+        match matches.len() {
+            0 => {}
+            1 => self.replace_word(start, &format!("{} ", matches[0])),
+            _ => {
+                self.replace_word(start, &matches[0]);
+                self.completion = Some(Completion { start, matches, index: 0 });
+            }
+        }
+    }
+
+    fn replace_word(&mut self, start: usize, text: &str) {
+        self.buffer.splice(start..self.cursor, text.chars());
+        self.cursor = start + text.chars().count();
+    }
+
+    fn history_prev(&mut self) {
+        let index = self.history_index.unwrap_or(self.history.entries().len());
+        if index == 0 {
+            return;
+        }
+        if self.history_index.is_none() {
+            self.draft = self.buffer.clone();
+        }
+        self.show_history(Some(index - 1));
+    }
+
+    fn history_next(&mut self) {
+        let Some(index) = self.history_index else {
+            return;
+        };
+        let next = index + 1;
+        self.show_history((next < self.history.entries().len()).then_some(next));
+    }
+
+    fn show_history(&mut self, index: Option<usize>) {
+        self.buffer = match index {
+            Some(index) => self.history.entries()[index].chars().collect(),
+            None => std::mem::take(&mut self.draft),
+        };
+        self.cursor = self.buffer.len();
+        self.history_index = index;
+    }
+
+    // What to draw in a box `width` columns wide, and the column to put the terminal cursor in.
+    // Scrolls horizontally so the cursor is always visible. Passwords are drawn as '*'.
+    pub fn view(&self, width: usize, masked: bool) -> (String, usize) {
+        let shown = |c: &char| if masked { '*' } else { *c };
+        let char_width = |c: char| c.width().unwrap_or(0);
+
+        // walk back from the cursor until the line is full
+        let mut start = self.cursor;
+        let mut cursor_col = 0;
+        while start > 0 {
+            let w = char_width(shown(&self.buffer[start - 1]));
+            if cursor_col + w >= width {
+                break;
+            }
+            cursor_col += w;
+            start -= 1;
+        }
+
+        let mut text = String::new();
+        let mut used = 0;
+        for c in self.buffer[start..].iter().map(shown) {
+            let w = char_width(c);
+            if used + w > width {
+                break;
+            }
+            text.push(c);
+            used += w;
+        }
+        (text, cursor_col)
+    }
+
+    fn delete_word_before_cursor(&mut self) {
+        let start = self.word_start_before_cursor();
+        self.buffer.drain(start..self.cursor);
+        self.cursor = start;
+    }
+
+    // Start of the word the cursor is at the end of (the cursor itself after a space)
+    fn current_word_start(&self) -> usize {
+        let mut i = self.cursor;
+        while i > 0 && !self.buffer[i - 1].is_whitespace() {
+            i -= 1;
+        }
+        i
+    }
+
+    fn word_start_before_cursor(&self) -> usize {
+        let mut i = self.cursor;
+        while i > 0 && self.buffer[i - 1].is_whitespace() {
+            i -= 1;
+        }
+        while i > 0 && !self.buffer[i - 1].is_whitespace() {
+            i -= 1;
+        }
+        i
+    }
+
+    fn word_end_after_cursor(&self) -> usize {
+        let mut i = self.cursor;
+        while i < self.buffer.len() && self.buffer[i].is_whitespace() {
+            i += 1;
+        }
+        while i < self.buffer.len() && !self.buffer[i].is_whitespace() {
+            i += 1;
+        }
+        i
+    }
+}
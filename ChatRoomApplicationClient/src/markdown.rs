@@ -0,0 +1,377 @@
+use std::sync::LazyLock;
+
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{FontStyle, ThemeSet};
+use syntect::parsing::SyntaxSet;
+
+use crate::message_format;
+use crate::theme::{theme, Theme};
+
+// Draws chat messages with a small, safe subset of markdown:
+//
+//      **bold** or __bold__, *italic* or _italic_, `inline code`
+//      [text](https://example.com) and bare https:// links
+//      > block quotes
+//      ```rust
+//      fenced code blocks, highlighted when the language is known
+//      ```
+//
+// Only the styling is drawn, the message itself (ChatMessage::content) is left as it was sent, so
+// the raw text is what gets stored, logged and seen by bots. Nothing here can produce an escape
+// sequence of its own: messages have already been through sanitize.rs and the styles are turned
+// into escape sequences by ratatui or `colored`. Links are shown with their address next to them,
+// never hidden behind the text.
+//
+// Rendering can be turned off with /markdown off, or for good with markdown = false in the
+// [format] section of the config file.
+
+static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+static CODE_THEMES: LazyLock<ThemeSet> = LazyLock::new(ThemeSet::load_defaults);
+
+const QUOTE_BAR: &str = "│ ";
+const CODE_INDENT: &str = "  ";
+
+// The lines of a message as they should be drawn, plain text when markdown is turned off
+pub fn render_message(text: &str) -> Vec<Line<'static>> {
+    if !message_format::markdown_enabled() {
+        return text.split('\n').map(|line| Line::from(line.to_string())).collect();
+    }
+    render(text, theme())
+}
+
+fn render(text: &str, theme: &Theme) -> Vec<Line<'static>> {
+    let mut lines = Vec::new();
+    let mut rest = text.split('\n');
+    while let Some(line) = rest.next() {
+        if let Some(language) = line.trim_start().strip_prefix("```") {
+            // everything up to the closing fence, or the end of the message if there isn't one
+            let code: Vec<&str> = rest.by_ref().take_while(|line| line.trim() != "```").collect();
+            code_block(language.trim(), &code, theme, &mut lines);
+        } else if let Some(quoted) = line.strip_prefix('>') {
+            let quoted = quoted.strip_prefix(' ').unwrap_or(quoted);
+            let mut spans = vec![Span::styled(QUOTE_BAR, Style::default().add_modifier(Modifier::DIM))];
+            inline(quoted, Style::default().add_modifier(Modifier::ITALIC), theme, &mut spans);
+            lines.push(Line::from(spans));
+        } else {
+            let mut spans = Vec::new();
+            inline(line, Style::default(), theme, &mut spans);
+            lines.push(Line::from(spans));
+        }
+    }
+    lines
+}
+
+fn code_block(language: &str, code: &[&str], theme: &Theme, lines: &mut Vec<Line<'static>>) {
+    let plain = theme.code.style();
+    let syntax = SYNTAXES.find_syntax_by_token(language);
+    let code_theme = theme.code_theme.as_deref().and_then(|name| CODE_THEMES.themes.get(name));
+    let (Some(syntax), Some(code_theme)) = (syntax, code_theme) else {
+        for line in code {
+            lines.push(Line::from(vec![Span::from(CODE_INDENT), Span::styled(line.to_string(), plain)]));
+        }
+        return;
+    };
+
+    let mut highlighter = HighlightLines::new(syntax, code_theme);
+    for line in code {
+        let mut spans = vec![Span::from(CODE_INDENT)];
+        // the newline lets syntaxes that look for the end of a line (eg comments) see it
+        match highlighter.highlight_line(&format!("{}\n", line), &SYNTAXES) {
+            Ok(regions) => {
+                for (style, text) in regions {
+                    let text = text.trim_end_matches('\n');
+                    if text.is_empty() {
+                        continue;
+                    }
+                    let mut span_style = Style::default().fg(Color::Rgb(style.foreground.r, style.foreground.g, style.foreground.b));
+                    if style.font_style.contains(FontStyle::BOLD) {
+                        span_style = span_style.add_modifier(Modifier::BOLD);
+                    }
+                    if style.font_style.contains(FontStyle::ITALIC) {
+                        span_style = span_style.add_modifier(Modifier::ITALIC);
+                    }
+                    spans.push(Span::styled(text.to_string(), span_style));
+                }
+            }
+            Err(_) => spans.push(Span::styled(line.to_string(), plain)),
+        }
+        lines.push(Line::from(spans));
+    }
+}
+
+enum Markup<'a> {
+    Escaped(char),
+    Code(&'a str),
+    Bold(&'a str),
+    Italic(&'a str),
+    Link { text: &'a str, url: &'a str },
+    Url(&'a str),
+}
+
+// Appends one line of text to `spans`, with `style` under whatever the markup adds
+fn inline(text: &str, style: Style, theme: &Theme, spans: &mut Vec<Span<'static>>) {
+    let mut plain = String::new();
+    let mut previous = None;
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        let Some((markup, after)) = markup(rest, previous) else {
+            plain.push(c);
+            previous = Some(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        };
+
+        if !plain.is_empty() {
+            spans.push(Span::styled(std::mem::take(&mut plain), style));
+        }
+        let link = style.patch(theme.link.style()).add_modifier(Modifier::UNDERLINED);
+        match markup {
+            Markup::Escaped(c) => plain.push(c),
+            Markup::Code(code) => spans.push(Span::styled(code.to_string(), style.patch(theme.code.style()))),
+            Markup::Bold(inner) => inline(inner, style.add_modifier(Modifier::BOLD), theme, spans),
+            Markup::Italic(inner) => inline(inner, style.add_modifier(Modifier::ITALIC), theme, spans),
+            Markup::Link { text, url } if text == url => spans.push(Span::styled(url.to_string(), link)),
+            Markup::Link { text, url } => {
+                inline(text, link, theme, spans);
+                spans.push(Span::styled(format!(" ({})", url), style.add_modifier(Modifier::DIM)));
+            }
+            Markup::Url(url) => spans.push(Span::styled(url.to_string(), link)),
+        }
+        previous = rest[..rest.len() - after.len()].chars().next_back();
+        rest = after;
+    }
+
+    if !plain.is_empty() {
+        spans.push(Span::styled(plain, style));
+    }
+}
+
+// The markup starting at the beginning of `text`, and what comes after it. `previous` is the
+// character before, so that snake_case names and the middle of words aren't taken as italics.
+fn markup(text: &str, previous: Option<char>) -> Option<(Markup<'_>, &str)> {
+    let after_word = previous.is_some_and(char::is_alphanumeric);
+    let mut chars = text.chars();
+    let first = chars.next()?;
+
+    match first {
+        '\\' => {
+            let escaped = chars.next().filter(char::is_ascii_punctuation)?;
+            Some((Markup::Escaped(escaped), &text[1 + escaped.len_utf8()..]))
+        }
+        '`' => {
+            let end = text[1..].find('`')? + 1;
+            (end > 1).then(|| (Markup::Code(&text[1..end]), &text[end + 1..]))
+        }
+        '*' | '_' if text[1..].starts_with(first) => {
+            if first == '_' && after_word {
+                return None;
+            }
+            let delimiter = &text[..2];
+            let end = text[2..].find(delimiter)? + 2;
+            let inner = &text[2..end];
+            if !is_emphasis(inner) {
+                return None;
+            }
+            let after = &text[end + 2..];
+            if first == '_' && after.starts_with(char::is_alphanumeric) {
+                return None;
+            }
+            Some((Markup::Bold(inner), after))
+        }
+        '*' | '_' => {
+            if first == '_' && after_word {
+                return None;
+            }
+            let end = text[1..].find(first)? + 1;
+            let inner = &text[1..end];
+            if !is_emphasis(inner) {
+                return None;
+            }
+            let after = &text[end + 1..];
+            if first == '_' && after.starts_with(char::is_alphanumeric) {
+                return None;
+            }
+            Some((Markup::Italic(inner), after))
+        }
+        '[' => {
+            let close = text.find("](")?;
+            let end = text[close..].find(')')? + close;
+            let (link_text, url) = (&text[1..close], &text[close + 2..end]);
+            (!link_text.is_empty() && is_url(url) && !url.contains(char::is_whitespace)).then(|| (Markup::Link { text: link_text, url }, &text[end + 1..]))
+        }
+        'h' if !after_word && is_url(text) => {
+            let end = text.find(char::is_whitespace).unwrap_or(text.len());
+            // punctuation at the end is more likely the sentence's than the link's
+            let url = text[..end].trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '\'', '"']);
+            Some((Markup::Url(url), &text[url.len()..]))
+        }
+        _ => None,
+    }
+}
+
+// "* not emphasis *" and "**" on their own are left as they are
+fn is_emphasis(inner: &str) -> bool {
+    !inner.is_empty() && !inner.starts_with(char::is_whitespace) && !inner.ends_with(char::is_whitespace)
+}
+
+// Only web and mail links, so a message can't make a link to a file or a script look clickable
+fn is_url(text: &str) -> bool {
+    ["https://", "http://", "mailto:"]
+        .iter()
+        .any(|scheme| text.strip_prefix(scheme).is_some_and(|rest| rest.starts_with(|c: char| !c.is_whitespace())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::theme::ThemeConfig;
+
+    fn colors() -> Theme {
+        ThemeConfig::default().resolve_with(false)
+    }
+
+    fn no_color() -> Theme {
+        ThemeConfig::default().resolve_with(true)
+    }
+
+    fn text(line: &Line) -> String {
+        line.spans.iter().map(|span| span.content.as_ref()).collect()
+    }
+
+    // The spans of a one line message, with their styles
+    fn spans(message: &str) -> Vec<(String, Style)> {
+        let lines = render(message, &colors());
+        assert_eq!(lines.len(), 1, "{:?}", lines);
+        lines[0].spans.iter().map(|span| (span.content.to_string(), span.style)).collect()
+    }
+
+    fn plain(text: &str) -> (String, Style) {
+        (text.to_string(), Style::default())
+    }
+
+    #[test]
+    fn plain_text_is_one_span_per_line() {
+        assert_eq!(spans("just words, nothing else"), [plain("just words, nothing else")]);
+        let lines = render("one\ntwo", &colors());
+        assert_eq!(lines.iter().map(text).collect::<Vec<_>>(), ["one", "two"]);
+    }
+
+    #[test]
+    fn code_fence_with_a_language_is_highlighted() {
+        let lines = render("before\n```rust\nfn main() {}\n```\nafter", &colors());
+        assert_eq!(lines.iter().map(text).collect::<Vec<_>>(), ["before", "  fn main() {}", "after"]);
+        let code = &lines[1].spans;
+        assert_eq!(code[0].content, CODE_INDENT);
+        assert!(code.len() > 2, "not split into tokens: {:?}", code);
+        assert!(code[1..].iter().all(|span| matches!(span.style.fg, Some(Color::Rgb(..)))));
+    }
+
+    #[test]
+    fn code_fence_without_a_language_uses_the_code_color() {
+        let lines = render("```\nlet x = **1**;\n```", &colors());
+        assert_eq!(lines.len(), 1);
+        assert_eq!(text(&lines[0]), "  let x = **1**;");
+        assert_eq!(lines[0].spans[1].style, colors().code.style());
+
+        // an unknown language is drawn the same way
+        let lines = render("```klingon\nqapla'\n```", &colors());
+        assert_eq!(lines[0].spans[1].style, colors().code.style());
+    }
+
+    #[test]
+    fn unclosed_code_fence_runs_to_the_end() {
+        let lines = render("```\nfirst\nsecond", &colors());
+        assert_eq!(lines.iter().map(text).collect::<Vec<_>>(), ["  first", "  second"]);
+    }
+
+    #[test]
+    fn inline_code() {
+        let code = colors().code.style();
+        assert_eq!(spans("run `cargo test` now"), [plain("run "), ("cargo test".to_string(), code), plain(" now")]);
+        // nothing inside is markup
+        assert_eq!(spans("`**not bold**`"), [("**not bold**".to_string(), code)]);
+        // a lone backtick, or an empty pair, is left as it is
+        assert_eq!(spans("a lone ` backtick"), [plain("a lone ` backtick")]);
+        assert_eq!(spans("``"), [plain("``")]);
+    }
+
+    #[test]
+    fn links_show_their_address() {
+        let link = colors().link.style().add_modifier(Modifier::UNDERLINED);
+        assert_eq!(
+            spans("see [the docs](https://docs.rs)"),
+            [
+                plain("see "),
+                ("the docs".to_string(), link),
+                (" (https://docs.rs)".to_string(), Style::default().add_modifier(Modifier::DIM)),
+            ]
+        );
+        assert_eq!(spans("[https://docs.rs](https://docs.rs)"), [("https://docs.rs".to_string(), link)]);
+        // punctuation after a bare link belongs to the sentence
+        assert_eq!(spans("at https://a.example/x."), [plain("at "), ("https://a.example/x".to_string(), link), plain(".")]);
+    }
+
+    #[test]
+    fn only_web_and_mail_links() {
+        assert_eq!(spans("[click](javascript:alert(1))"), [plain("[click](javascript:alert(1))")]);
+        assert_eq!(spans("[file](file:///etc/passwd)"), [plain("[file](file:///etc/passwd)")]);
+    }
+
+    #[test]
+    fn emphasis() {
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        let italic = Style::default().add_modifier(Modifier::ITALIC);
+        assert_eq!(spans("**bold**"), [("bold".to_string(), bold)]);
+        assert_eq!(spans("__bold__"), [("bold".to_string(), bold)]);
+        assert_eq!(spans("*italic*"), [("italic".to_string(), italic)]);
+        assert_eq!(spans("_italic_"), [("italic".to_string(), italic)]);
+    }
+
+    #[test]
+    fn nested_emphasis() {
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        assert_eq!(
+            spans("**bold *both* bold**"),
+            [
+                ("bold ".to_string(), bold),
+                ("both".to_string(), bold.add_modifier(Modifier::ITALIC)),
+                (" bold".to_string(), bold),
+            ]
+        );
+        let code = colors().code.style().add_modifier(Modifier::BOLD);
+        assert_eq!(spans("**`code`**"), [("code".to_string(), code)]);
+    }
+
+    #[test]
+    fn unclosed_or_loose_emphasis_is_left_alone() {
+        assert_eq!(spans("**never closed"), [plain("**never closed")]);
+        assert_eq!(spans("*also never"), [plain("*also never")]);
+        assert_eq!(spans("2 * 3 * 4"), [plain("2 * 3 * 4")]);
+        assert_eq!(spans("call snake_case_name"), [plain("call snake_case_name")]);
+        assert_eq!(spans("****"), [plain("****")]);
+        // escaped, the characters are kept without their backslashes
+        let escaped = render(r"\*not italic\*", &colors());
+        assert_eq!(text(&escaped[0]), "*not italic*");
+        assert!(escaped[0].spans.iter().all(|span| span.style == Style::default()));
+    }
+
+    #[test]
+    fn block_quotes() {
+        let lines = render("> quoted **text**", &colors());
+        assert_eq!(text(&lines[0]), format!("{}quoted text", QUOTE_BAR));
+        assert!(lines[0].spans[1..].iter().all(|span| span.style.add_modifier.contains(Modifier::ITALIC)));
+    }
+
+    #[test]
+    fn no_color_keeps_the_styling_but_not_the_colors() {
+        let message = "**bold** `code` [link](https://a.example)\n> quote\n```rust\nfn main() {}\n```";
+        let lines = render(message, &no_color());
+        let spans: Vec<&Span> = lines.iter().flat_map(|line| &line.spans).collect();
+        assert!(spans.iter().all(|span| span.style.fg.is_none() && span.style.bg.is_none()), "{:?}", spans);
+        assert!(spans.iter().any(|span| span.style.add_modifier.contains(Modifier::BOLD)));
+        assert_eq!(text(&lines[2]), "  fn main() {}");
+    }
+}
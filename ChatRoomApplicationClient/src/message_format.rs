@@ -0,0 +1,131 @@
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, FixedOffset, Local, Utc};
+use serde::{Deserialize, Deserializer};
+
+// How chat messages are laid out, set from the [format] section of the config file (see
+// config.rs):
+//
+//      [format]
+//      timestamp = "%H:%M:%S"        # strftime, see the chrono docs; default "%m-%d %H:%M"
+//      timezone = "utc"              # "local" (the default), "utc" or an offset like "+05:30"
+//      layout = "compact"            # or "cozy"
+//      markdown = false              # show messages as they were typed, see markdown.rs
+//
+// The cozy layout (the default) puts your own messages on the right and leaves a blank line after
+// status messages. The compact one puts every message on the left, your own with your name and
+// time like everybody else's, and leaves out the blank lines.
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    #[default]
+    Cozy,
+    Compact,
+}
+
+#[derive(Clone, Copy, Default)]
+pub enum TimeZone {
+    #[default]
+    Local,
+    Utc,
+    Fixed(FixedOffset),
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessageFormat {
+    pub timestamp: TimestampFormat,
+    pub timezone: TimeZone,
+    pub layout: Layout,
+    pub markdown: bool,
+}
+
+impl Default for MessageFormat {
+    fn default() -> Self {
+        MessageFormat {
+            timestamp: TimestampFormat::default(),
+            timezone: TimeZone::default(),
+            layout: Layout::default(),
+            markdown: true,
+        }
+    }
+}
+
+// A strftime string that chrono can actually format, checked while the config is read so a typo
+// is reported at startup instead of panicking on the first message
+#[derive(Clone)]
+pub struct TimestampFormat(String);
+
+impl Default for TimestampFormat {
+    fn default() -> Self {
+        TimestampFormat("%m-%d %H:%M".to_string())
+    }
+}
+
+impl MessageFormat {
+    // A server timestamp (RFC 3339) in the configured format and zone. Anything that doesn't parse
+    // is shown as it is.
+    pub fn time(&self, timestamp: &str) -> String {
+        match DateTime::parse_from_rfc3339(timestamp) {
+            Ok(time) => self.format(time.with_timezone(&Utc)),
+            Err(_) => timestamp.to_string(),
+        }
+    }
+
+    pub fn format(&self, time: DateTime<Utc>) -> String {
+        let format = self.timestamp.0.as_str();
+        match self.timezone {
+            TimeZone::Local => time.with_timezone(&Local).format(format).to_string(),
+            TimeZone::Utc => time.format(format).to_string(),
+            TimeZone::Fixed(offset) => time.with_timezone(&offset).format(format).to_string(),
+        }
+    }
+}
+
+static FORMAT: OnceLock<MessageFormat> = OnceLock::new();
+// starts out as the config says, /markdown changes it for the rest of the session
+static MARKDOWN: AtomicBool = AtomicBool::new(true);
+
+// Called once at startup, before anything is printed
+pub fn set_format(format: MessageFormat) {
+    MARKDOWN.store(format.markdown, Ordering::Relaxed);
+    let _ = FORMAT.set(format);
+}
+
+pub fn format() -> &'static MessageFormat {
+    FORMAT.get_or_init(MessageFormat::default)
+}
+
+pub fn markdown_enabled() -> bool {
+    MARKDOWN.load(Ordering::Relaxed)
+}
+
+pub fn set_markdown(enabled: bool) {
+    MARKDOWN.store(enabled, Ordering::Relaxed);
+}
+
+impl<'de> Deserialize<'de> for TimestampFormat {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let format = String::deserialize(deserializer)?;
+        if StrftimeItems::new(&format).any(|item| matches!(item, Item::Error)) {
+            return Err(serde::de::Error::custom(format!("invalid timestamp format '{}'", format)));
+        }
+        Ok(TimestampFormat(format))
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeZone {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let zone = String::deserialize(deserializer)?;
+        match zone.to_ascii_lowercase().as_str() {
+            "local" => Ok(TimeZone::Local),
+            "utc" | "z" => Ok(TimeZone::Utc),
+            _ => zone.parse::<FixedOffset>().map(TimeZone::Fixed).map_err(|_| {
+                serde::de::Error::custom(format!("unknown timezone '{}', use \"local\", \"utc\" or an offset like \"+02:00\"", zone))
+            }),
+        }
+    }
+}
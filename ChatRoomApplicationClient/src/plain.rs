@@ -0,0 +1,306 @@
+use std::io::{self, IsTerminal};
+use std::time::Duration;
+
+use futures_util::StreamExt;
+
+use chat_room_client::{ChatClient, RoomEvent, RoomEvents};
+use chat_room_client::messages::ServerWsMessage;
+use chat_room_client::validation::SYSTEM_USER;
+use crate::color_formatting::*;
+use crate::commands::{parse, CommandId, Context, Input};
+use crate::config::Config;
+use crate::reconnect::{self, Reconnect};
+use crate::session_store::SessionStore;
+use crate::stdin_reader::StdinReader;
+use crate::terminal_erasing::*;
+use crate::user_commands::*;
+
+/*
+ * plain.rs
+ *
+ * Line by line interface (--plain, or whenever output isn't a terminal): commands are read from
+ * stdin and everything is printed as it happens.
+ *
+ * A single select! loop waits on the next line of input, the room's WebSocket and a keepalive
+ * timer, so being kicked, the room being deleted or the connection dropping takes effect right
+ * away instead of after the next Enter.
+ *
+ * A login is saved for the next launch (see session_store.rs), which starts back in the lobby or
+ * in the room that was open. When the server says it is shutting down, the room is rejoined once
+ * it is back (see reconnect.rs).
+ */
+
+// How often to ping the server while in a room, a dropped connection shows up at the latest then
+const KEEPALIVE: Duration = Duration::from_secs(30);
+
+struct Plain {
+    client: ChatClient,
+    stdin: StdinReader,
+    session: SessionStore,
+    logged_in: bool,
+    // the room to get back into after the server restarts
+    reconnect: Option<Reconnect>,
+    quit: bool,
+}
+
+pub async fn run(client: ChatClient, mut events: RoomEvents, session: SessionStore, config: &Config) {
+    let mut plain = Plain {
+        client,
+        stdin: StdinReader::spawn(),
+        session,
+        logged_in: false,
+        reconnect: None,
+        quit: false,
+    };
+    let mut keepalive = tokio::time::interval(KEEPALIVE);
+
+    success("Welcome to the Rust Chat Room!");
+    plain.logged_in = resume_session(&mut plain.client, &plain.session, config.auto_rejoin).await;
+    if let Some(room_id) = plain.client.current_room() {
+        success(&format!("Connected to {}", room_id));
+    }
+    plain.prompt();
+
+    while !plain.quit {
+        tokio::select! {
+            line = plain.stdin.next_line() => match line {
+                Some(line) => plain.handle_line(&line).await,
+                // stdin closed, eg the end of a script
+                None => break,
+            },
+            Some(event) = events.next() => plain.handle_room_event(event),
+            _ = keepalive.tick() => plain.keepalive().await,
+            _ = reconnect::due(&plain.reconnect) => plain.reconnect().await,
+        }
+    }
+
+    leave_room(&mut plain.client).await;
+}
+
+impl Plain {
+    fn context(&self) -> Context {
+        if !self.logged_in {
+            Context::Auth
+        } else if self.client.current_room().is_some() {
+            Context::Room
+        } else {
+            Context::Lobby
+        }
+    }
+
+    fn prompt(&self) {
+        match self.client.current_room() {
+            _ if !self.logged_in => {
+                info("[Please /login or /sign_up or /help]");
+                system_prompt(">");
+            }
+            Some(room_id) => system_prompt(&format!("[{}]> ", room_id)),
+            None => system_prompt("[Lobby]> "),
+        }
+    }
+
+    async fn handle_line(&mut self, line: &str) {
+        let context = self.context();
+        let input = line.trim();
+
+        if context == Context::Room {
+            if input.is_empty() {
+                self.prompt();
+                return;
+            }
+            // Remove the prompt line for cleanliness of output, the message is printed again
+            // right aligned once it has been sent. Piped input isn't echoed, so there is nothing to remove.
+            if io::stdin().is_terminal() {
+                erase_last_line();
+            }
+        }
+
+        let cmd = match parse(input, context) {
+            Ok(Input::Command(cmd)) => cmd,
+            Ok(Input::Message("")) if context == Context::Lobby => {
+                self.prompt();
+                return;
+            }
+            Ok(Input::Message(message)) => {
+                if context == Context::Room {
+                    send_message(&mut self.client, message).await;
+                } else {
+                    error("Unknown Command - try /help");
+                }
+                self.prompt();
+                return;
+            }
+            Err(e) => {
+                command_error(&e);
+                self.prompt();
+                return;
+            }
+        };
+
+        // parse() already checked the command can be used in this context
+        match cmd.id() {
+            CommandId::Help => print_help(context, &cmd),
+            CommandId::Markdown => toggle_markdown(&cmd),
+            CommandId::Quit => {
+                warning("Quitting Program");
+                self.quit = true;
+                return;
+            }
+            CommandId::Login => {
+                self.logged_in = login(&mut self.client, &mut self.stdin).await;
+                if self.logged_in {
+                    self.session.remember(&self.client);
+                    success("Connected to Chat Room Lobby");
+                }
+            }
+            CommandId::SignUp => sign_up(&mut self.client, &mut self.stdin).await,
+            CommandId::Logout => {
+                logout(&mut self.client).await;
+                self.session.forget();
+                self.logged_in = false;
+                self.reconnect = None;
+            }
+            CommandId::AllRooms => show_rooms(&self.client, false).await,
+            CommandId::ActiveRooms => show_rooms(&self.client, true).await,
+            CommandId::Create => create_room(&mut self.client, &cmd).await,
+            CommandId::Delete => delete_room(&mut self.client, &cmd).await,
+            CommandId::Join | CommandId::JoinInvite => {
+                let joined = if cmd.id() == CommandId::Join {
+                    join_room(&mut self.client, &cmd).await
+                } else {
+                    join_invite(&mut self.client, &cmd).await
+                };
+                if joined && let Some(room_id) = self.client.current_room() {
+                    self.session.set_last_room(Some(room_id));
+                    success(&format!("Connected to {}", room_id));
+                }
+            }
+            CommandId::Invite => create_invite(&mut self.client, &cmd).await,
+            CommandId::Invites => list_invites(&mut self.client, &cmd).await,
+            CommandId::RevokeInvite => revoke_invite(&mut self.client, &cmd).await,
+            CommandId::ActiveUsers => show_active_users(&self.client).await,
+            CommandId::Kick => kick_user(&mut self.client, &cmd).await,
+            CommandId::Topic => set_topic(&mut self.client, &cmd).await,
+            CommandId::Leave => {
+                leave_room(&mut self.client).await;
+                self.session.set_last_room(None);
+                success("Returned to Lobby");
+            }
+        }
+        self.prompt();
+    }
+
+    fn handle_room_event(&mut self, event: RoomEvent) {
+        let Some(current_room) = self.client.current_room().map(str::to_string) else {
+            return;
+        };
+        let me = self.client.username().unwrap_or_default().to_string();
+
+        let msg = match event {
+            RoomEvent::Message(msg) => msg,
+            // the server said it was going away, we'll be back once it is up again
+            RoomEvent::Closed { room_id } if self.reconnect.as_ref().is_some_and(|r| r.room_id == room_id) => {
+                self.client.forget_room();
+                return;
+            }
+            RoomEvent::Closed { room_id } if room_id == current_room => {
+                erase_current_line();
+                warning("Lost connection to the room");
+                self.back_to_lobby();
+                return;
+            }
+            // a room we already left
+            RoomEvent::Closed { .. } => return,
+        };
+
+        match msg {
+            // Chat room message from another user
+            ServerWsMessage::MessageBroadcast(chat_msg) => {
+                if chat_msg.user_id == SYSTEM_USER {
+                    erase_current_line();
+                    system_message(&format!("{}: {}", chat_msg.user_id, chat_msg.content));
+                    self.prompt();
+                } else if chat_msg.user_id != me {
+                    erase_current_line();
+                    user_message(&chat_msg.timestamp, &chat_msg.user_id, &chat_msg.content);
+                    self.prompt();
+                }
+            }
+            // If current room was deleted, alert user and go back to the lobby
+            ServerWsMessage::RoomDeleted { room_id } if room_id == current_room => {
+                erase_current_line();
+                warning("[Room has been deleted]");
+                self.session.set_last_room(None);
+                self.back_to_lobby();
+            }
+            // Notify that a new user joined the chat room
+            ServerWsMessage::UserJoined { room_id, user_id } if room_id == current_room && user_id != me => {
+                erase_current_line();
+                system_message(&format!("[{} has joined]", user_id));
+                self.prompt();
+            }
+            // Notify that a user left the room
+            ServerWsMessage::UserLeft { room_id, user_id } if room_id == current_room && user_id != me => {
+                erase_current_line();
+                system_message(&format!("[{} has left]", user_id));
+                self.prompt();
+            }
+            // Handle user being kicked from chat
+            ServerWsMessage::UserKicked { room_id, user_id } if room_id == current_room => {
+                erase_current_line();
+                if user_id == me {
+                    warning("[You have been kicked]");
+                    self.session.set_last_room(None);
+                    self.back_to_lobby();
+                } else {
+                    system_message(&format!("[{} has been kicked]", user_id));
+                    self.prompt();
+                }
+            }
+            ServerWsMessage::RoomUpdated { room_id, updated_by, details } if room_id == current_room => {
+                erase_current_line();
+                room_updated(&updated_by, &details);
+                self.prompt();
+            }
+            ServerWsMessage::ServerShutdown { reconnect_after_ms } => {
+                erase_current_line();
+                warning(&format!("[Server is shutting down, reconnecting in {}s]", reconnect_after_ms.div_ceil(1000)));
+                self.reconnect = Some(Reconnect::after(&current_room, reconnect_after_ms));
+                self.prompt();
+            }
+            // Display error from server
+            ServerWsMessage::Error { error_msg, retry_after_ms } => {
+                erase_current_line();
+                match retry_after_ms {
+                    Some(ms) => error(&format!("{} (try again in {}s)", error_msg, ms.div_ceil(1000))),
+                    None => error(&error_msg),
+                }
+                self.prompt();
+            }
+            _ => {}
+        }
+    }
+
+    // Forced out of the room (kicked, room deleted, connection lost)
+    fn back_to_lobby(&mut self) {
+        self.client.forget_room();
+        success("Returned to Lobby");
+        self.prompt();
+    }
+
+    async fn reconnect(&mut self) {
+        erase_current_line();
+        if !try_reconnect(&mut self.client, &self.session, &mut self.reconnect).await {
+            self.logged_in = false;
+        }
+        self.prompt();
+    }
+
+    async fn keepalive(&mut self) {
+        if self.client.current_room().is_some() && self.client.ping().await.is_err() {
+            erase_current_line();
+            warning("Lost connection to the room");
+            self.back_to_lobby();
+        }
+    }
+}
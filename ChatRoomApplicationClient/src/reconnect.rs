@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use chat_room_client::{ChatClient, ClientError};
+use chat_room_client::messages::{ErrorResponse, JoinRoomResponse};
+
+// Getting back into the room after the server announced it was shutting down (ServerShutdown),
+// eg for a restart. The interfaces keep a Reconnect while they wait, treat the connection closing
+// as expected instead of as "Lost connection", and call attempt() once due() resolves.
+//
+// The first try is after the delay the server asked for. While the server can't be reached yet,
+// or is turning people away (maintenance mode), it is tried again every RETRY_EVERY, up to
+// MAX_ATTEMPTS times, and a rate limited try waits as long as the server says.
+
+const RETRY_EVERY: Duration = Duration::from_secs(3);
+const MAX_ATTEMPTS: u32 = 20;
+
+pub struct Reconnect {
+    pub room_id: String,
+    at: Instant,
+    attempts: u32,
+}
+
+pub enum Attempt {
+    Rejoined(JoinRoomResponse),
+    // not yet, due() resolves again when it is time for the next try
+    Retrying,
+    GaveUp(ClientError),
+}
+
+impl Reconnect {
+    pub fn after(room_id: &str, reconnect_after_ms: u64) -> Self {
+        Reconnect {
+            room_id: room_id.to_string(),
+            at: Instant::now() + Duration::from_millis(reconnect_after_ms),
+            attempts: 0,
+        }
+    }
+
+    pub async fn attempt(&mut self, client: &mut ChatClient) -> Attempt {
+        self.attempts += 1;
+        let e = match client.reconnect(&self.room_id).await {
+            Ok(resp) => return Attempt::Rejoined(resp),
+            Err(e) if self.attempts >= MAX_ATTEMPTS => return Attempt::GaveUp(e),
+            Err(e) => e,
+        };
+        let wait = match &e {
+            ClientError::Http(_) | ClientError::WebSocket(_) | ClientError::Server(ErrorResponse::Unavailable { .. }) => {
+                RETRY_EVERY
+            }
+            ClientError::Server(ErrorResponse::RateLimited { retry_after_secs, .. }) => {
+                Duration::from_secs(*retry_after_secs)
+            }
+            // eg the session or the room didn't survive the restart
+            _ => return Attempt::GaveUp(e),
+        };
+        self.at = Instant::now() + wait;
+        Attempt::Retrying
+    }
+}
+
+// Resolves when the pending reconnect is due, never if there isn't one
+pub async fn due(reconnect: &Option<Reconnect>) {
+    match reconnect {
+        Some(reconnect) => tokio::time::sleep_until(reconnect.at).await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,123 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use chat_room_client::ChatClient;
+
+// Keeps the login of a server profile between launches, eg
+// ~/.local/share/chat_client/sessions/home.json on Linux. It holds the refresh token the server
+// handed out at login, which logs the client straight back in next time, and the room the user
+// was in so it can be rejoined.
+//
+// The refresh token is as good as a password until it is used or expires, so the file is only
+// readable by its owner and is deleted on /logout. The server replaces the token every time it
+// is used, so the file is rewritten on every launch.
+//
+// Like history.rs, failing to read or write the file is ignored: the worst that happens is
+// having to /login again.
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedSession {
+    pub user_id: String,
+    pub refresh_token: String,
+    // None after /leave, so the next launch starts in the lobby
+    #[serde(default)]
+    pub last_room: Option<String>,
+}
+
+pub struct SessionStore {
+    path: Option<PathBuf>,
+}
+
+impl SessionStore {
+    pub fn for_profile(profile: &str) -> Self {
+        // profile names come from the config file, keep them from reaching outside the directory
+        let safe = !profile.is_empty() && profile.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        let path = dirs::data_dir()
+            .filter(|_| safe)
+            .map(|dir| dir.join("chat_client").join("sessions").join(format!("{}.json", profile)));
+        SessionStore { path }
+    }
+
+    pub fn load(&self) -> Option<SavedSession> {
+        let contents = fs::read_to_string(self.path.as_ref()?).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    // Saves the client's current login, keeping the last room if it is the same user as before
+    pub fn remember(&self, client: &ChatClient) {
+        let (Some(user_id), Some(refresh_token)) = (client.username(), client.refresh_token()) else {
+            return;
+        };
+        let last_room = self
+            .load()
+            .filter(|saved| saved.user_id == user_id)
+            .and_then(|saved| saved.last_room);
+        self.save(&SavedSession {
+            user_id: user_id.to_string(),
+            refresh_token: refresh_token.to_string(),
+            last_room,
+        });
+    }
+
+    pub fn set_last_room(&self, room_id: Option<&str>) {
+        if let Some(mut saved) = self.load() {
+            saved.last_room = room_id.map(str::to_string);
+            self.save(&saved);
+        }
+    }
+
+    pub fn forget(&self) {
+        if let Some(path) = &self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    // Written to a temporary file that is renamed over the old one, so a crash can't leave half a
+    // token behind
+    fn save(&self, session: &SavedSession) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let Some(dir) = path.parent() else {
+            return;
+        };
+        let Ok(json) = serde_json::to_string(session) else {
+            return;
+        };
+        if create_private_dir(dir).is_err() {
+            return;
+        }
+
+        let tmp = path.with_extension("json.tmp");
+        let _ = fs::remove_file(&tmp);
+        let written = open_private(&tmp).and_then(|mut file| file.write_all(json.as_bytes()));
+        if written.is_err() || fs::rename(&tmp, path).is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn create_private_dir(dir: &std::path::Path) -> std::io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(dir: &std::path::Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)
+}
+
+#[cfg(unix)]
+fn open_private(path: &std::path::Path) -> std::io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+fn open_private(path: &std::path::Path) -> std::io::Result<fs::File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
@@ -0,0 +1,84 @@
+use std::io::{self, BufRead, IsTerminal};
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+
+use rpassword::read_password;
+use tokio::sync::mpsc;
+
+// Reads stdin for the plain interface without blocking the async runtime.
+//
+// Reading stdin is always blocking, so it happens on a thread of its own. The thread only reads
+// when asked to, which lets a password be read with echo turned off without racing a read of the
+// next line that is already waiting on the terminal.
+//
+// next_line() is cancel safe: if it is dropped in a select! the line it asked for isn't lost, the
+// next call picks it up.
+
+enum Request {
+    Line,
+    Password,
+}
+
+pub struct StdinReader {
+    requests: std_mpsc::Sender<Request>,
+    // None once stdin is closed
+    lines: mpsc::UnboundedReceiver<Option<String>>,
+    // a line has been asked for and hasn't been received yet
+    pending: bool,
+}
+
+impl StdinReader {
+    pub fn spawn() -> Self {
+        let (requests_tx, requests_rx) = std_mpsc::channel();
+        let (lines_tx, lines_rx) = mpsc::unbounded_channel();
+
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            while let Ok(request) = requests_rx.recv() {
+                let line = match request {
+                    Request::Password if stdin.is_terminal() => read_password().ok(),
+                    // scripts pipe the password in like any other line
+                    Request::Password | Request::Line => read_line(&stdin),
+                };
+                let closed = line.is_none();
+                if lines_tx.send(line).is_err() || closed {
+                    break;
+                }
+            }
+        });
+
+        StdinReader {
+            requests: requests_tx,
+            lines: lines_rx,
+            pending: false,
+        }
+    }
+
+    // The next line without its newline, or None when stdin is closed
+    pub async fn next_line(&mut self) -> Option<String> {
+        self.read(Request::Line).await
+    }
+
+    // Like next_line, but not echoed when stdin is a terminal
+    pub async fn password(&mut self) -> Option<String> {
+        self.read(Request::Password).await
+    }
+
+    async fn read(&mut self, request: Request) -> Option<String> {
+        if !self.pending {
+            self.requests.send(request).ok()?;
+            self.pending = true;
+        }
+        let line = self.lines.recv().await.flatten();
+        self.pending = false;
+        line
+    }
+}
+
+fn read_line(stdin: &io::Stdin) -> Option<String> {
+    let mut line = String::new();
+    match stdin.lock().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim_end_matches(['\n', '\r']).to_string()),
+    }
+}
@@ -1,7 +1,13 @@
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Write};
+
+// Both do nothing when output isn't a terminal (eg piped into a script), where the escape codes
+// would only end up as garbage in the output
 
 // Function to erase the last line that was printed to the terminal
 pub fn erase_last_line() {
+    if !io::stdout().is_terminal() {
+        return;
+    }
     print!("\x1b[1A"); // Cursor moves up a line in the terminal
     print!("\x1b[2K"); // Clear the line that the cursor is now on
     io::stdout().flush().unwrap();
@@ -9,6 +15,9 @@ pub fn erase_last_line() {
 
 // Function to clear the current line 
 pub fn erase_current_line() {
+    if !io::stdout().is_terminal() {
+        return;
+    }
     print!("\r\x1B[K");
     io::stdout().flush().unwrap();
-}
\ No newline at end of file
+}
@@ -0,0 +1,241 @@
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use ratatui::style::{Color, Style};
+use serde::{Deserialize, Deserializer};
+
+// Colors for the parts of the output that have one, set from the [theme] section of the config
+// file (see config.rs). Both interfaces draw with them: the plain one through `colored`, the
+// full-screen one through ratatui styles.
+//
+//      [theme]
+//      base = "monochrome"           # or "default"
+//      header = "magenta"            # any color left out comes from the base
+//      nick_colors = false           # every name in `username` instead of one color per person
+//      nick_palette = ["cyan", "#ff8700", "208"]
+//      code_theme = "Solarized (dark)"
+//
+// A color is a name ("red", "lightblue", "darkgray"...), a hex value ("#ff8700"), an index into
+// the terminal's 256 color palette ("208"), or "reset" for the terminal's own color.
+//
+// code_theme colors code blocks in messages (see markdown.rs), and is one of "base16-ocean.dark"
+// (the default), "base16-eighties.dark", "base16-mocha.dark", "base16-ocean.light",
+// "InspiredGitHub", "Solarized (dark)" and "Solarized (light)". "none" leaves them in `code`.
+//
+// Setting NO_COLOR (see no-color.org) always gives the monochrome theme, whatever the config says.
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Base {
+    #[default]
+    Default,
+    // no colors at all, only bold and dim
+    Monochrome,
+}
+
+// The [theme] section as written, turned into a Theme by resolve()
+#[derive(Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeConfig {
+    pub base: Base,
+    pub header: Option<ThemeColor>,
+    pub success: Option<ThemeColor>,
+    pub error: Option<ThemeColor>,
+    pub warning: Option<ThemeColor>,
+    pub prompt: Option<ThemeColor>,
+    pub code: Option<ThemeColor>,
+    pub link: Option<ThemeColor>,
+    pub username: Option<ThemeColor>,
+    pub my_name: Option<ThemeColor>,
+    pub nick_colors: Option<bool>,
+    pub nick_palette: Option<Vec<ThemeColor>>,
+    pub code_theme: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Theme {
+    pub header: ThemeColor,
+    pub success: ThemeColor,
+    pub error: ThemeColor,
+    pub warning: ThemeColor,
+    // the input prompt and the current room in the sidebar
+    pub prompt: ThemeColor,
+    // `inline code`, and code blocks without a code theme
+    pub code: ThemeColor,
+    pub link: ThemeColor,
+    // other people's names when nick colors are off
+    pub username: ThemeColor,
+    // "You:" on your own messages and your name in the member list
+    pub my_name: ThemeColor,
+    // other people's names are spread over these, empty to use `username` for everyone
+    pub nick_palette: Vec<ThemeColor>,
+    // syntax highlighting for code blocks, None to leave them in `code`
+    pub code_theme: Option<String>,
+}
+
+impl Theme {
+    fn default_colors() -> Self {
+        Theme {
+            header: ThemeColor(Color::Magenta),
+            success: ThemeColor(Color::Green),
+            error: ThemeColor(Color::Red),
+            warning: ThemeColor(Color::Yellow),
+            prompt: ThemeColor(Color::Cyan),
+            code: ThemeColor(Color::LightYellow),
+            link: ThemeColor(Color::LightBlue),
+            username: ThemeColor(Color::Green),
+            my_name: ThemeColor(Color::Blue),
+            // red and yellow are left out, they would look like errors and warnings
+            nick_palette: [
+                Color::Green,
+                Color::Cyan,
+                Color::Magenta,
+                Color::LightBlue,
+                Color::LightGreen,
+                Color::LightCyan,
+                Color::LightMagenta,
+                Color::Rgb(0xff, 0x87, 0x00),
+            ]
+            .map(ThemeColor)
+            .to_vec(),
+            code_theme: Some("base16-ocean.dark".to_string()),
+        }
+    }
+
+    fn monochrome() -> Self {
+        let none = ThemeColor(Color::Reset);
+        Theme {
+            header: none,
+            success: none,
+            error: none,
+            warning: none,
+            prompt: none,
+            code: none,
+            link: none,
+            username: none,
+            my_name: none,
+            nick_palette: Vec::new(),
+            code_theme: None,
+        }
+    }
+
+    // The same name always gets the same color, in every room and on every launch
+    pub fn nick_color(&self, username: &str) -> ThemeColor {
+        if self.nick_palette.is_empty() {
+            return self.username;
+        }
+        // FNV-1a, the standard library's hashers are free to change between Rust versions
+        let hash = username
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3));
+        self.nick_palette[(hash % self.nick_palette.len() as u64) as usize]
+    }
+}
+
+impl ThemeConfig {
+    pub fn resolve(&self) -> Theme {
+        self.resolve_with(no_color())
+    }
+
+    // resolve() with NO_COLOR set or not, whatever the environment says
+    pub fn resolve_with(&self, no_color: bool) -> Theme {
+        if no_color || self.base == Base::Monochrome {
+            return Theme::monochrome();
+        }
+
+        let mut theme = Theme::default_colors();
+        let overrides = [
+            (&mut theme.header, self.header),
+            (&mut theme.success, self.success),
+            (&mut theme.error, self.error),
+            (&mut theme.warning, self.warning),
+            (&mut theme.prompt, self.prompt),
+            (&mut theme.code, self.code),
+            (&mut theme.link, self.link),
+            (&mut theme.username, self.username),
+            (&mut theme.my_name, self.my_name),
+        ];
+        for (color, choice) in overrides {
+            if let Some(choice) = choice {
+                *color = choice;
+            }
+        }
+        if let Some(palette) = &self.nick_palette {
+            theme.nick_palette = palette.clone();
+        }
+        if self.nick_colors == Some(false) {
+            theme.nick_palette.clear();
+        }
+        match self.code_theme.as_deref() {
+            Some("none") => theme.code_theme = None,
+            Some(name) => theme.code_theme = Some(name.to_string()),
+            None => {}
+        }
+        theme
+    }
+}
+
+// NO_COLOR counts when it is set to anything but an empty string
+fn no_color() -> bool {
+    std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+}
+
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+// Called once at startup, before anything is printed
+pub fn set_theme(theme: Theme) {
+    let _ = THEME.set(theme);
+}
+
+pub fn theme() -> &'static Theme {
+    THEME.get_or_init(|| ThemeConfig::default().resolve())
+}
+
+#[derive(Clone, Copy)]
+pub struct ThemeColor(pub Color);
+
+impl ThemeColor {
+    pub fn style(self) -> Style {
+        match self.0 {
+            Color::Reset => Style::default(),
+            color => Style::default().fg(color),
+        }
+    }
+
+    // The same color for `colored`, None leaves the text as it is
+    pub fn colored(self) -> Option<colored::Color> {
+        use colored::Color as C;
+        let color = match self.0 {
+            Color::Reset => return None,
+            Color::Black => C::Black,
+            Color::Red => C::Red,
+            Color::Green => C::Green,
+            Color::Yellow => C::Yellow,
+            Color::Blue => C::Blue,
+            Color::Magenta => C::Magenta,
+            Color::Cyan => C::Cyan,
+            Color::Gray => C::White,
+            Color::DarkGray => C::BrightBlack,
+            Color::LightRed => C::BrightRed,
+            Color::LightGreen => C::BrightGreen,
+            Color::LightYellow => C::BrightYellow,
+            Color::LightBlue => C::BrightBlue,
+            Color::LightMagenta => C::BrightMagenta,
+            Color::LightCyan => C::BrightCyan,
+            Color::White => C::BrightWhite,
+            Color::Rgb(r, g, b) => C::TrueColor { r, g, b },
+            // `colored` can't do the 256 color palette
+            Color::Indexed(_) => return None,
+        };
+        Some(color)
+    }
+}
+
+impl<'de> Deserialize<'de> for ThemeColor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Color::from_str(&name)
+            .map(ThemeColor)
+            .map_err(|_| serde::de::Error::custom(format!("unknown color '{}'", name)))
+    }
+}
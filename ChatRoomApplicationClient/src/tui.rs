@@ -0,0 +1,710 @@
+use std::io;
+use std::time::Duration;
+
+use crossterm::event::{Event, EventStream, KeyCode, KeyEventKind, KeyModifiers};
+use futures_util::StreamExt;
+use ratatui::{
+    layout::{Constraint, Layout, Rect},
+    style::{Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, List, ListItem, Paragraph, Wrap},
+    DefaultTerminal, Frame,
+};
+use tokio::sync::mpsc;
+use unicode_width::UnicodeWidthStr;
+
+use chat_room_client::{ChatClient, RoomEvent, RoomEvents};
+use chat_room_client::messages::{RoomInfo, ServerWsMessage};
+use chat_room_client::validation::*;
+use crate::color_formatting::*;
+use crate::commands::{self, parse, ArgKind, CommandId, Context, Input};
+use crate::config::Config;
+use crate::keybindings::KeyBindings;
+use crate::line_editor::LineEditor;
+use crate::markdown::render_message;
+use crate::session_store::SessionStore;
+use crate::message_format;
+use crate::reconnect::{self, Reconnect};
+use crate::theme::theme;
+use crate::user_commands::*;
+
+/*
+ * tui.rs
+ *
+ * Full-screen interface, used by default when running in a terminal (--plain for the line by line one)
+ *
+ *  +----------------------------------+-------------+
+ *  | messages (PgUp/PgDn to scroll)   | rooms       |
+ *  |                                  +-------------+
+ *  |                                  | members     |
+ *  +----------------------------------+-------------+
+ *  | > input line                                   |
+ *  +------------------------------------------------+
+ *   status bar
+ *
+ * The keys for quitting, cancelling, completing and scrolling can be changed in the config file
+ * (see keybindings.rs). A login is saved for the next launch, and the room is rejoined after the
+ * server restarts, like in the plain interface.
+ *
+ * One event loop waits on keyboard input, the current room's WebSocket, output from the
+ * color_formatting helpers and a timer that pings the room and refreshes the sidebar. Commands run the same
+ * ChatClient/user_commands code as the plain interface, with their output redirected into the
+ * message pane (see color_formatting.rs), so nothing is ever printed over the screen.
+ */
+
+// How often the room and member lists are reloaded from the server
+const SIDEBAR_REFRESH: Duration = Duration::from_secs(5);
+const SIDEBAR_WIDTH: u16 = 26;
+// Older lines are dropped from the message pane
+const MAX_MESSAGE_LINES: usize = 5000;
+const SCROLL_STEP: usize = 10;
+
+// /login and /sign_up ask for their input one step at a time, since there is no stdin to prompt on
+enum Prompt {
+    LoginUsername,
+    LoginPassword { username: String },
+    SignUpUsername,
+    SignUpPassword { username: String },
+}
+
+impl Prompt {
+    fn label(&self) -> &'static str {
+        match self {
+            Prompt::LoginUsername | Prompt::SignUpUsername => "Username: ",
+            Prompt::LoginPassword { .. } | Prompt::SignUpPassword { .. } => "Password: ",
+        }
+    }
+
+    fn masked(&self) -> bool {
+        matches!(self, Prompt::LoginPassword { .. } | Prompt::SignUpPassword { .. })
+    }
+}
+
+struct App {
+    client: ChatClient,
+    session: SessionStore,
+    keys: KeyBindings,
+    logged_in: bool,
+    messages: Vec<Line<'static>>,
+    // Lines scrolled up from the bottom, 0 follows new messages
+    scroll: usize,
+    rooms: Vec<RoomInfo>,
+    members: Vec<String>,
+    // false when the last sidebar refresh couldn't reach the server
+    server_reachable: bool,
+    editor: LineEditor,
+    prompt: Option<Prompt>,
+    // the room to get back into after the server restarts
+    reconnect: Option<Reconnect>,
+    quit: bool,
+}
+
+pub async fn run(client: ChatClient, mut events: RoomEvents, session: SessionStore, config: &Config) -> io::Result<()> {
+    let (output_tx, mut output_rx) = mpsc::unbounded_channel();
+    redirect_output(output_tx);
+
+    // ratatui::init also restores the terminal if we panic
+    let mut terminal = ratatui::init();
+    let app = App::new(client, session, config.keybindings.clone());
+    let result = event_loop(&mut terminal, app, config.auto_rejoin, &mut events, &mut output_rx).await;
+    ratatui::restore();
+    result
+}
+
+async fn event_loop(
+    terminal: &mut DefaultTerminal,
+    mut app: App,
+    auto_rejoin: bool,
+    events: &mut RoomEvents,
+    output_rx: &mut mpsc::UnboundedReceiver<Output>,
+) -> io::Result<()> {
+    let mut terminal_events = EventStream::new();
+    let mut refresh = tokio::time::interval(SIDEBAR_REFRESH);
+
+    success("Welcome to the Rust Chat Room!");
+    if resume_session(&mut app.client, &app.session, auto_rejoin).await {
+        app.logged_in().await;
+        if app.client.current_room().is_some() {
+            app.entered_room().await;
+        }
+    } else {
+        info("[Please /login or /sign_up or /help]");
+    }
+
+    while !app.quit {
+        // Commands usually produce several lines at once, draw them in one frame
+        while let Ok(output) = output_rx.try_recv() {
+            app.push_output(output);
+        }
+        terminal.draw(|frame| app.draw(frame))?;
+
+        tokio::select! {
+            event = terminal_events.next() => match event {
+                Some(Ok(event)) => app.handle_terminal_event(event).await,
+                Some(Err(e)) => return Err(e),
+                None => break,
+            },
+            Some(output) = output_rx.recv() => app.push_output(output),
+            Some(event) = events.next() => app.handle_room_event(event).await,
+            _ = refresh.tick() => app.refresh().await,
+            _ = reconnect::due(&app.reconnect) => app.reconnect().await,
+        }
+    }
+
+    leave_room(&mut app.client).await;
+    Ok(())
+}
+
+impl App {
+    fn new(client: ChatClient, session: SessionStore, keys: KeyBindings) -> Self {
+        App {
+            client,
+            session,
+            keys,
+            logged_in: false,
+            messages: Vec::new(),
+            scroll: 0,
+            rooms: Vec::new(),
+            members: Vec::new(),
+            server_reachable: true,
+            editor: LineEditor::default(),
+            prompt: None,
+            reconnect: None,
+            quit: false,
+        }
+    }
+
+    async fn handle_terminal_event(&mut self, event: Event) {
+        // Resizes only need a redraw, which happens on every pass of the event loop
+        let Event::Key(key) = event else {
+            return;
+        };
+        if key.kind != KeyEventKind::Press {
+            return;
+        }
+
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let keys = &self.keys;
+        match key.code {
+            _ if keys.quit.matches(&key) => self.quit = true,
+            KeyCode::Char('d') if ctrl && self.editor.is_empty() => self.quit = true,
+            _ if keys.complete.matches(&key) && self.prompt.is_none() => {
+                let context = self.context();
+                let (rooms, members) = (&self.rooms, &self.members);
+                self.editor.complete(|words| completion_candidates(words, context, rooms, members));
+            }
+            _ if keys.cancel.matches(&key) => {
+                if self.prompt.take().is_some() {
+                    warning("Cancelled");
+                }
+                self.editor.clear();
+            }
+            _ if keys.scroll_up.matches(&key) => self.scroll += SCROLL_STEP,
+            _ if keys.scroll_down.matches(&key) => self.scroll = self.scroll.saturating_sub(SCROLL_STEP),
+            KeyCode::Enter => {
+                let line = self.editor.take();
+                // answers to prompts may be passwords
+                if self.prompt.is_none() {
+                    self.editor.history.add(line.trim());
+                }
+                self.submit(line).await;
+            }
+            _ => {
+                self.editor.handle_key(key);
+            }
+        }
+    }
+
+    async fn submit(&mut self, line: String) {
+        if let Some(prompt) = self.prompt.take() {
+            self.answer_prompt(prompt, line.trim()).await;
+            return;
+        }
+        if line.trim().is_empty() {
+            return;
+        }
+        // Jump back down to see the result
+        self.scroll = 0;
+
+        let context = self.context();
+        let cmd = match parse(&line, context) {
+            Ok(Input::Command(cmd)) => cmd,
+            Ok(Input::Message(message)) => {
+                if context == Context::Room {
+                    send_message(&mut self.client, message).await;
+                } else {
+                    error("Unknown Command - try /help");
+                }
+                return;
+            }
+            Err(e) => {
+                command_error(&e);
+                return;
+            }
+        };
+
+        // parse() already checked the command can be used in this context
+        match cmd.id() {
+            CommandId::Help => print_help(context, &cmd),
+            CommandId::Quit => self.quit = true,
+            CommandId::Markdown => toggle_markdown(&cmd),
+            CommandId::Login => {
+                header("Login");
+                info("Please enter your username and password to log in. (Esc to cancel)");
+                self.prompt = Some(Prompt::LoginUsername);
+            }
+            CommandId::SignUp => {
+                header("Sign Up");
+                info("Please enter a username (Esc to cancel):");
+                self.prompt = Some(Prompt::SignUpUsername);
+            }
+            CommandId::Logout => {
+                logout(&mut self.client).await;
+                self.session.forget();
+                self.logged_in = false;
+                self.reconnect = None;
+                self.rooms.clear();
+                self.editor.history.load_for_user(None);
+            }
+            CommandId::AllRooms => show_rooms(&self.client, false).await,
+            CommandId::ActiveRooms => show_rooms(&self.client, true).await,
+            CommandId::Create => {
+                create_room(&mut self.client, &cmd).await;
+                self.refresh_sidebar().await;
+            }
+            CommandId::Delete => {
+                delete_room(&mut self.client, &cmd).await;
+                self.refresh_sidebar().await;
+            }
+            CommandId::Join => {
+                if join_room(&mut self.client, &cmd).await {
+                    self.entered_room().await;
+                }
+            }
+            CommandId::JoinInvite => {
+                if join_invite(&mut self.client, &cmd).await {
+                    self.entered_room().await;
+                }
+            }
+            CommandId::Invite => create_invite(&mut self.client, &cmd).await,
+            CommandId::Invites => list_invites(&mut self.client, &cmd).await,
+            CommandId::RevokeInvite => revoke_invite(&mut self.client, &cmd).await,
+            CommandId::ActiveUsers => show_active_users(&self.client).await,
+            CommandId::Kick => kick_user(&mut self.client, &cmd).await,
+            CommandId::Topic => set_topic(&mut self.client, &cmd).await,
+            CommandId::Leave => {
+                leave_room(&mut self.client).await;
+                self.session.set_last_room(None);
+                self.left_room();
+            }
+        }
+    }
+
+    async fn answer_prompt(&mut self, prompt: Prompt, answer: &str) {
+        if answer == "/quit" {
+            warning("Cancelled");
+            return;
+        }
+
+        match prompt {
+            Prompt::LoginUsername => {
+                self.prompt = Some(Prompt::LoginPassword { username: answer.to_string() });
+            }
+            Prompt::LoginPassword { username } => {
+                if log_in(&mut self.client, &username, answer).await {
+                    self.session.remember(&self.client);
+                    success("Connected to Chat Room Lobby");
+                    self.logged_in().await;
+                }
+            }
+            Prompt::SignUpUsername => {
+                let errors = validate_user_id(answer);
+                if errors.is_empty() {
+                    print_password_rules();
+                    self.prompt = Some(Prompt::SignUpPassword { username: answer.to_string() });
+                } else {
+                    validation_errors(&errors);
+                    self.prompt = Some(Prompt::SignUpUsername);
+                }
+            }
+            Prompt::SignUpPassword { username } => {
+                let errors = validate_password("password", answer);
+                if errors.is_empty() {
+                    create_user(&mut self.client, &username, answer).await;
+                } else {
+                    validation_errors(&errors);
+                    self.prompt = Some(Prompt::SignUpPassword { username });
+                }
+            }
+        }
+    }
+
+    fn context(&self) -> Context {
+        if !self.logged_in {
+            Context::Auth
+        } else if self.client.current_room().is_some() {
+            Context::Room
+        } else {
+            Context::Lobby
+        }
+    }
+
+    async fn logged_in(&mut self) {
+        self.logged_in = true;
+        self.editor.history.load_for_user(self.client.username());
+        self.refresh_sidebar().await;
+    }
+
+    async fn entered_room(&mut self) {
+        if let Some(room_id) = self.client.current_room() {
+            self.session.set_last_room(Some(room_id));
+            success(&format!("Connected to {}", room_id));
+        }
+        self.refresh_sidebar().await;
+    }
+
+    // Back to the lobby, whether we left, were kicked, or the room is gone
+    fn left_room(&mut self) {
+        self.client.forget_room();
+        self.members.clear();
+        success("Returned to Lobby");
+    }
+
+    async fn handle_room_event(&mut self, event: RoomEvent) {
+        let Some(current_room) = self.client.current_room().map(str::to_string) else {
+            return;
+        };
+        let me = self.client.username().unwrap_or_default().to_string();
+
+        let msg = match event {
+            RoomEvent::Message(msg) => msg,
+            // the server said it was going away, we'll be back once it is up again
+            RoomEvent::Closed { room_id } if self.reconnect.as_ref().is_some_and(|r| r.room_id == room_id) => {
+                self.client.forget_room();
+                self.members.clear();
+                return;
+            }
+            RoomEvent::Closed { room_id } if room_id == current_room => {
+                warning("Lost connection to the room");
+                self.left_room();
+                return;
+            }
+            // a room we already left
+            RoomEvent::Closed { .. } => return,
+        };
+
+        match msg {
+            // Our own messages were already shown when they were sent
+            ServerWsMessage::MessageBroadcast(chat_msg) => {
+                if chat_msg.user_id == SYSTEM_USER {
+                    system_message(&format!("{}: {}", chat_msg.user_id, chat_msg.content));
+                } else if chat_msg.user_id != me {
+                    user_message(&chat_msg.timestamp, &chat_msg.user_id, &chat_msg.content);
+                }
+            }
+            ServerWsMessage::RoomDeleted { room_id } if room_id == current_room => {
+                warning("Room has been deleted");
+                self.session.set_last_room(None);
+                self.left_room();
+                self.refresh_sidebar().await;
+            }
+            ServerWsMessage::UserJoined { room_id, user_id } if room_id == current_room => {
+                if user_id != me {
+                    system_message(&format!("[{} has joined]", user_id));
+                }
+                if !self.members.contains(&user_id) {
+                    self.members.push(user_id);
+                    self.members.sort();
+                }
+            }
+            ServerWsMessage::UserLeft { room_id, user_id } if room_id == current_room => {
+                if user_id != me {
+                    system_message(&format!("[{} has left]", user_id));
+                }
+                self.members.retain(|member| *member != user_id);
+            }
+            ServerWsMessage::UserKicked { room_id, user_id } if room_id == current_room => {
+                if user_id == me {
+                    warning("You have been kicked");
+                    self.session.set_last_room(None);
+                    self.left_room();
+                } else {
+                    system_message(&format!("[{} has been kicked]", user_id));
+                    self.members.retain(|member| *member != user_id);
+                }
+            }
+            ServerWsMessage::RoomUpdated { room_id, updated_by, details } if room_id == current_room => {
+                room_updated(&updated_by, &details);
+            }
+            ServerWsMessage::ServerShutdown { reconnect_after_ms } => {
+                warning(&format!("Server is shutting down, reconnecting in {}s", reconnect_after_ms.div_ceil(1000)));
+                self.reconnect = Some(Reconnect::after(&current_room, reconnect_after_ms));
+            }
+            ServerWsMessage::Error { error_msg, retry_after_ms } => match retry_after_ms {
+                Some(ms) => error(&format!("{} (try again in {}s)", error_msg, ms.div_ceil(1000))),
+                None => error(&error_msg),
+            },
+            _ => {}
+        }
+    }
+
+    async fn reconnect(&mut self) {
+        if !try_reconnect(&mut self.client, &self.session, &mut self.reconnect).await {
+            self.logged_in = false;
+            self.rooms.clear();
+            self.editor.history.load_for_user(None);
+        } else if self.client.current_room().is_some() {
+            self.refresh_sidebar().await;
+        }
+    }
+
+    // Runs every few seconds: pings the room so a dropped connection is noticed, and reloads the sidebar
+    async fn refresh(&mut self) {
+        if self.client.current_room().is_some() && self.client.ping().await.is_err() {
+            warning("Lost connection to the room");
+            self.left_room();
+        }
+        self.refresh_sidebar().await;
+    }
+
+    // Failures only show up in the status bar, this runs in the background every few seconds
+    async fn refresh_sidebar(&mut self) {
+        if !self.logged_in {
+            return;
+        }
+
+        match self.client.list_rooms(false).await {
+            Ok(rooms) => {
+                self.rooms = rooms;
+                self.server_reachable = true;
+            }
+            Err(_) => {
+                self.server_reachable = false;
+                return;
+            }
+        }
+
+        if let Some(room_id) = self.client.current_room()
+            && let Ok(members) = self.client.room_users(room_id).await {
+            self.members = members;
+        }
+    }
+
+    fn push_output(&mut self, output: Output) {
+        let before = self.messages.len();
+        let bracketed = |text: String| Line::from(format!("[{}]", text));
+        let theme = theme();
+        let cozy = message_format::format().layout == message_format::Layout::Cozy;
+
+        match output {
+            Output::Header(text) => {
+                if cozy {
+                    self.messages.push(Line::default());
+                }
+                self.messages.push(bracketed(text).patch_style(theme.header.style()).bold());
+            }
+            Output::Success(text) => self.messages.push(bracketed(text).patch_style(theme.success.style())),
+            Output::Error(text) => self.messages.push(bracketed(text).patch_style(theme.error.style())),
+            Output::Warning(text) => self.messages.push(bracketed(text).patch_style(theme.warning.style())),
+            Output::Info(text) => self.push_text(&text, Style::default()),
+            Output::SystemMessage(text) => self.push_text(&text, Style::default().dim()),
+            Output::UserMessage { time, username, message } => {
+                let name = Span::styled(username.clone(), theme.nick_color(&username).style()).bold();
+                self.push_chat_message(&time, name, &message);
+            }
+            Output::MyMessage { time, message } if !cozy => {
+                self.push_chat_message(&time, Span::styled("You", theme.my_name.style()).bold(), &message);
+            }
+            Output::MyMessage { message, .. } => {
+                for (i, line) in render_message(&expand_tabs(&message)).into_iter().enumerate() {
+                    let label = if i == 0 { "You: " } else { "" };
+                    let mut spans = vec![Span::styled(label, theme.my_name.style()).bold()];
+                    spans.extend(line.spans);
+                    self.messages.push(Line::from(spans).right_aligned());
+                }
+            }
+            Output::ValidationFailed(lines) => {
+                self.messages.push(bracketed("Does not meet the requirements:".to_string()).patch_style(theme.error.style()));
+                for line in lines {
+                    self.messages.push(Line::styled(line, theme.error.style()));
+                }
+            }
+        }
+
+        // Keep what the user scrolled to in place while new lines arrive underneath
+        if self.scroll > 0 {
+            self.scroll += self.messages.len() - before;
+        }
+        if self.messages.len() > MAX_MESSAGE_LINES {
+            let excess = self.messages.len() - MAX_MESSAGE_LINES;
+            self.messages.drain(..excess);
+        }
+    }
+
+    // "[time] name: message", with any further lines indented under it
+    fn push_chat_message(&mut self, time: &str, name: Span<'static>, message: &str) {
+        for (i, line) in render_message(&expand_tabs(message)).into_iter().enumerate() {
+            let mut spans = if i == 0 {
+                vec![Span::from(format!("[{}] ", time)).dim(), name.clone(), Span::from(": ")]
+            } else {
+                vec![Span::from("    ")]
+            };
+            spans.extend(line.spans);
+            self.messages.push(Line::from(spans));
+        }
+    }
+
+    fn push_text(&mut self, text: &str, style: Style) {
+        for part in text.split('\n') {
+            self.messages.push(Line::styled(expand_tabs(part), style));
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, input_area, status_area] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(3), Constraint::Length(1)]).areas(frame.area());
+        let [messages_area, sidebar] =
+            Layout::horizontal([Constraint::Min(20), Constraint::Length(SIDEBAR_WIDTH)]).areas(main);
+        let [rooms_area, members_area] =
+            Layout::vertical([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(sidebar);
+
+        self.draw_messages(frame, messages_area);
+        self.draw_rooms(frame, rooms_area);
+        self.draw_members(frame, members_area);
+        self.draw_input(frame, input_area);
+        self.draw_status(frame, status_area);
+    }
+
+    fn draw_messages(&mut self, frame: &mut Frame, area: Rect) {
+        let mut title = match self.client.current_room() {
+            Some(room_id) => format!(" {} ", room_id),
+            None => " Lobby ".to_string(),
+        };
+        if self.scroll > 0 {
+            title.push_str("(scrolled, PgDn for newer) ");
+        }
+        let block = Block::bordered().title(title);
+        let inner = block.inner(area);
+        let height = inner.height as usize;
+
+        // Only lay out the lines that can be on screen, counting wrapped lines from the bottom up
+        let mut start = self.messages.len();
+        let mut rows = 0;
+        while start > 0 && rows < height + self.scroll {
+            start -= 1;
+            rows += Paragraph::new(self.messages[start].clone())
+                .wrap(Wrap { trim: false })
+                .line_count(inner.width);
+        }
+        // Can't scroll past the first message
+        self.scroll = self.scroll.min(rows.saturating_sub(height));
+        let top = rows.saturating_sub(height + self.scroll);
+
+        let paragraph = Paragraph::new(self.messages[start..].to_vec())
+            .wrap(Wrap { trim: false })
+            .scroll((top as u16, 0))
+            .block(block);
+        frame.render_widget(paragraph, area);
+    }
+
+    fn draw_rooms(&self, frame: &mut Frame, area: Rect) {
+        let current_room = self.client.current_room();
+        let items: Vec<ListItem> = self
+            .rooms
+            .iter()
+            .map(|room| {
+                let item = ListItem::new(format!("{} ({})", room.room_id, room.users_count));
+                if Some(room.room_id.as_str()) == current_room {
+                    item.style(theme().prompt.style()).bold()
+                } else {
+                    item
+                }
+            })
+            .collect();
+        frame.render_widget(List::new(items).block(Block::bordered().title(" Rooms ")), area);
+    }
+
+    fn draw_members(&self, frame: &mut Frame, area: Rect) {
+        let me = self.client.username();
+        let items: Vec<ListItem> = self
+            .members
+            .iter()
+            .map(|member| {
+                if Some(member.as_str()) == me {
+                    ListItem::new(format!("{} (you)", member)).style(theme().my_name.style()).bold()
+                } else {
+                    ListItem::new(member.clone())
+                }
+            })
+            .collect();
+        frame.render_widget(List::new(items).block(Block::bordered().title(" Members ")), area);
+    }
+
+    fn draw_input(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered();
+        let inner = block.inner(area);
+
+        let (label, masked) = match &self.prompt {
+            Some(prompt) => (prompt.label(), prompt.masked()),
+            None => ("> ", false),
+        };
+        let label_width = label.width();
+        let (text, cursor_col) = self
+            .editor
+            .view((inner.width as usize).saturating_sub(label_width + 1), masked);
+
+        let line = Line::from(vec![Span::styled(label, theme().prompt.style()), Span::from(text)]);
+        frame.render_widget(Paragraph::new(line).block(block), area);
+        frame.set_cursor_position((inner.x + (label_width + cursor_col) as u16, inner.y));
+    }
+
+    fn draw_status(&self, frame: &mut Frame, area: Rect) {
+        let mut spans = vec![];
+        match self.client.username() {
+            Some(username) if self.logged_in => spans.push(Span::from(format!(" {} ", username)).bold()),
+            _ => spans.push(Span::from(" not logged in ")),
+        }
+        spans.push(Span::from("| "));
+        match self.client.current_room() {
+            Some(room_id) => spans.push(Span::from(format!("{} ({} members) ", room_id, self.members.len()))),
+            None => spans.push(Span::from("Lobby ")),
+        }
+        if !self.server_reachable {
+            spans.push(Span::styled("| server unreachable ", theme().error.style()));
+        }
+        let keys = &self.keys;
+        spans.push(Span::from(format!(
+            "| {} complete, {}/{} scroll, /help, {} quit ",
+            keys.complete, keys.scroll_up, keys.scroll_down, keys.quit
+        )));
+
+        frame.render_widget(Paragraph::new(Line::from(spans)).reversed(), area);
+    }
+}
+
+// What can go after `words`: a command first, then whatever its next argument is (see
+// commands.rs) plus its flags. Anywhere in a chat message, @ completes the name of someone in the room.
+fn completion_candidates(words: &[String], context: Context, rooms: &[RoomInfo], members: &[String]) -> Vec<String> {
+    let mentions = members.iter().map(|member| format!("@{}", member));
+    let Some(first) = words.first() else {
+        return commands::names(context).into_iter().map(str::to_string).chain(mentions).collect();
+    };
+    if !first.starts_with('/') {
+        return mentions.collect();
+    }
+
+    let mut candidates: Vec<String> = match commands::arg_kind_at(words, context) {
+        Some(ArgKind::RoomId) => rooms.iter().map(|room| room.room_id.clone()).collect(),
+        Some(ArgKind::Username) => members.to_vec(),
+        Some(ArgKind::CommandName) => commands::names(context).into_iter().map(str::to_string).collect(),
+        Some(ArgKind::Switch) => vec!["on".to_string(), "off".to_string()],
+        _ => Vec::new(),
+    };
+    candidates.extend(commands::flag_names(words).into_iter().map(str::to_string));
+    candidates
+}
+
+// Tabs don't have a fixed width in the terminal buffer, draw them as spaces
+fn expand_tabs(text: &str) -> String {
+    text.replace('\t', "    ")
+}
@@ -1,92 +1,307 @@
 use std::io::{self, Write};
 
-use crate::chat_client::ChatClient;
+use chrono::Utc;
+use chat_room_client::{ChatClient, ClientError};
+use chat_room_client::messages::{ErrorResponse, InviteInfo, JoinRoomResponse, RoomDetails, RoomSettingsChange, RoomVisibility};
+use chat_room_client::validation::*;
+use crate::credentials::{password_from_env, username_from_env};
+use crate::commands::{help_lines, Command, CommandError, Context};
 use crate::color_formatting::*;
-use crate::in_chat_room;
-use rpassword::read_password;
+use crate::message_format;
+use crate::reconnect::{Attempt, Reconnect};
+use crate::session_store::SessionStore;
+use crate::stdin_reader::StdinReader;
+
+// Goes through info() so it also shows up in the full-screen UI
+pub fn print_help(context: Context, cmd: &Command) {
+    for line in help_lines(context, cmd.arg(0)) {
+        info(&line);
+    }
+}
 
-pub fn print_help() {
-    println!();
-    println!("==============================");
-    println!("           HELP MENU          ");
-    println!("==============================\n");
+// Only changes how messages are drawn from now on, what was already shown stays as it is
+pub fn toggle_markdown(cmd: &Command) {
+    let enabled = match cmd.arg(0) {
+        Some(switch) => switch == "on",
+        None => !message_format::markdown_enabled(),
+    };
+    message_format::set_markdown(enabled);
+    if enabled {
+        success("Markdown in messages is on");
+    } else {
+        success("Markdown in messages is off, messages are shown as they were typed");
+    }
+}
 
-    println!("General Commands:");
-    println!("  /help              Show this help menu");
-    println!("  /quit              Quit the chat room application\n");
+// Usage mistakes are warnings like they always were, anything else is an error
+pub fn command_error(err: &CommandError) {
+    match err {
+        CommandError::Usage(..) => warning(&err.to_string()),
+        _ => error(&err.to_string()),
+    }
+}
 
-    println!("Authentication Commands:");
-    println!("  /sign_up           Create a new username and password");
-    println!("  /login             Login with your username and password");
-    println!("  /logout            Logout of the chatroom application\n");
+// Server errors read "Error: ..." like they always have, broken naming/password rules are listed one per line
+pub fn client_error(err: &ClientError) {
+    match err {
+        ClientError::Server(ErrorResponse::ValidationFailed { errors }) => validation_errors(errors),
+        ClientError::Server(e) => error(&format!("Error: {}", e)),
+        _ => error(&err.to_string()),
+    }
+}
 
-    println!("Navigation Commands:");
-    println!("  /all_rooms         Show all available chat rooms");
-    println!("  /active_rooms      Show all active chat rooms");
-    println!("  /create            Create a new chat room (usage: /create <room_id> <password>)");
-    println!("  /join              Join an existing chat room (usage: /join <room_id> <password>)");
-    println!("  /delete            Delete your chat room (owner only) (usage: /delete <room_id>)\n");
+// For the commands only a room's owner can use, says which room rather than the server's message
+fn owner_error(err: &ClientError, room_id: &str) {
+    match err {
+        ClientError::Server(ErrorResponse::InvalidPermissions { .. }) => {
+            error(&format!("Error: You are not the owner of '{}'", room_id));
+        }
+        _ => client_error(err),
+    }
+}
 
+pub async fn show_rooms(client: &ChatClient, active_room_only: bool) {
+    let rooms = match client.list_rooms(active_room_only).await {
+        Ok(rooms) => rooms,
+        Err(e) => {
+            client_error(&e);
+            return;
+        }
+    };
 
-    println!("Room Management Commands:");
-    println!("  /active_users      Show all active users in the current room");
-    println!("  /kick              Remove a user from your room. Need to own chat room (usage: /kick <username>)");
-    println!("  /leave             Leave the current chat room\n");
+    header("[All Rooms]");
+    if rooms.is_empty() {
+        info(" - No chat rooms exist");
+    } else {
+        for room in rooms { //TODO - change to user .iter
+            let label = visibility_label(room.visibility);
+            let topic = room.topic.map(|topic| format!(" - {}", topic)).unwrap_or_default();
+            if active_room_only {
+                info(&format!( " - {} ({}) [{} users]{}", room.room_id, label, room.users_count, topic));
+            }else{
+                info(&format!( " - {} ({}){}", room.room_id, label, topic));
+            }
+        }
+    }
+}
 
-    println!("Messaging Commands:");
-    println!("  <message>          Type and send a message to your current room\n");
+pub async fn show_active_users(client: &ChatClient) {
+    let Some(room) = client.current_room() else {
+        return;
+    };
 
-    println!("==============================");
+    match client.room_users(room).await {
+        Ok(active_users) => {
+            header(&format!("Active Users in '{}'", room));
+            if active_users.is_empty() {
+                info(" - No active users");
+            } else {
+                for user in active_users {
+                    info(&format!(" - {}", user));
+                }
+            }
+        }
+        Err(e) => client_error(&e),
+    }
 }
 
-pub async fn delete_room(client: &mut ChatClient, args: Vec<&str>){
-    if args.len() < 2 {
-        warning("Usage: /delete <room_id>");
+pub async fn send_message(client: &mut ChatClient, message: &str) {
+    match client.send_message(message).await {
+        Ok(()) => my_message(&Utc::now().to_rfc3339(), message),
+        Err(e) => error(&format!("Failed to send message: {}", e)),
+    }
+}
+
+pub async fn leave_room(client: &mut ChatClient) {
+    let Some(room_id) = client.current_room().map(str::to_string) else {
         return;
+    };
+    if let Err(e) = client.leave_room().await {
+        client_error(&e);
+    }
+    system_message(&format!("[Left {}]", room_id));
+}
+
+pub async fn logout(client: &mut ChatClient) {
+    let username = client.username().unwrap_or_default().to_string();
+    match client.logout().await {
+        Ok(()) => success(&format!("User '{}' logged out successfully", username)),
+        Err(e) => error(&format!("Logout failed: {}", e)),
+    }
+}
+
+pub async fn delete_room(client: &mut ChatClient, cmd: &Command) {
+    let room_id = cmd.arg(0).unwrap_or_default();
+    match client.delete_room(room_id).await {
+        Ok(message) => success(&message),
+        Err(e) => owner_error(&e, room_id),
+    }
+}
+
+// Both join commands return whether the client is now in a room (see client.current_room())
+pub async fn join_room(client: &mut ChatClient, cmd: &Command) -> bool {
+    // Public rooms don't need a password
+    let joined = client.join_room(cmd.arg(0).unwrap_or_default(), cmd.arg(1)).await;
+    show_joined(client, joined)
+}
+
+pub async fn join_invite(client: &mut ChatClient, cmd: &Command) -> bool {
+    let joined = client.join_invite(cmd.arg(0).unwrap_or_default()).await;
+    show_joined(client, joined)
+}
+
+fn show_joined(client: &ChatClient, joined: Result<JoinRoomResponse, ClientError>) -> bool {
+    let resp = match joined {
+        Ok(resp) => resp,
+        Err(e) => {
+            client_error(&e);
+            return false;
+        }
+    };
+    show_room_details(&resp.details);
+
+    // Chat History
+    if !resp.chat_history.is_empty() {
+        header("Chat History");
+        for msg in resp.chat_history {
+            if Some(msg.user_id.as_str()) == client.username() {
+                my_message(&msg.timestamp, &msg.content);
+            } else if msg.user_id == SYSTEM_USER {
+                system_message(&format!("{}: {}", msg.user_id, msg.content));
+            } else {
+                user_message(&msg.timestamp, &msg.user_id, &msg.content);
+            }
+        }
     }
-    let room_id = args[1];
-    
-    client.delete_room(room_id).await;
+    true
 }
 
+// The parts of a room's settings worth telling the people in it about
+fn show_room_details(details: &RoomDetails) {
+    if let Some(topic) = &details.topic {
+        info(&format!("Topic: {}", topic));
+    }
+    if let Some(description) = &details.description {
+        info(description);
+    }
+    if details.slow_mode_secs > 0 {
+        info(&format!("Slow mode is on, one message every {}s", details.slow_mode_secs));
+    }
+}
 
-pub async fn join_room(client: &mut ChatClient, args: Vec<&str>) {
-    if args.len() < 3 {
-        warning("Usage: /join <room_id> <password>");
+// The owner changed the settings of the room we're in
+pub fn room_updated(updated_by: &str, details: &RoomDetails) {
+    system_message(&format!("[{} changed the room settings]", updated_by));
+    show_room_details(details);
+}
+
+// "" clears the topic. Everyone in the room, us included, sees the change as a RoomUpdated event.
+pub async fn set_topic(client: &mut ChatClient, cmd: &Command) {
+    let Some(room_id) = client.current_room().map(str::to_string) else {
         return;
+    };
+    let settings = RoomSettingsChange { topic: cmd.arg(0).map(str::to_string), ..Default::default() };
+
+    if let Err(e) = client.update_room_settings(&room_id, settings).await {
+        owner_error(&e, &room_id);
     }
+}
+
+// In a room the invite is for the current room, from the lobby the room has to be named
+pub async fn create_invite(client: &mut ChatClient, cmd: &Command) {
+    let room_id = match (client.current_room(), cmd.arg(0)) {
+        (Some(current_room), None) => current_room.to_string(),
+        (None, Some(room_id)) => room_id.to_string(),
+        _ => {
+            warning(&format!("Usage: {}", cmd.spec.usage()));
+            return;
+        }
+    };
 
-    let room_id = args[1];
-    let password = args[2];
+    let max_uses = if cmd.has_flag("--single-use") { Some(1) } else { cmd.flag_value::<u32>("--uses") };
+    let expires_in_secs = cmd.flag_value::<u64>("--expires").map(|minutes| minutes.saturating_mul(60));
 
-    if client.join_room(room_id, password).await {
-        in_chat_room(client, room_id).await;
+    match client.create_invite(&room_id, max_uses, expires_in_secs).await {
+        Ok(invite) => {
+            success(&format!("Invite code for {}: {}", invite.room_id, invite.code));
+            info(&format!(" - {}", invite_limits(&invite)));
+            info(&format!(" - Share it with: /join_invite {}", invite.code));
+        }
+        Err(e) => owner_error(&e, &room_id),
     }
 }
 
-pub async fn kick_user(client: &mut ChatClient, args: Vec<&str>) {
-    if args.len() < 2 {
-        warning("Usage: /kick <username>");
+pub async fn list_invites(client: &mut ChatClient, cmd: &Command) {
+    let Some(room_id) = cmd.arg(0).or(client.current_room()).map(str::to_string) else {
+        warning("Usage: /invites <room_id>");
         return;
+    };
+
+    match client.list_invites(&room_id).await {
+        Ok(invites) => {
+            header(&format!("Invites for '{}'", room_id));
+            if invites.is_empty() {
+                info(" - No active invites");
+            } else {
+                for invite in &invites {
+                    info(&format!(" - {} ({})", invite.code, invite_limits(invite)));
+                }
+            }
+        }
+        Err(e) => owner_error(&e, &room_id),
+    }
+}
+
+pub async fn revoke_invite(client: &mut ChatClient, cmd: &Command) {
+    match client.revoke_invite(cmd.arg(0).unwrap_or_default()).await {
+        Ok(message) => success(&message),
+        Err(e) => client_error(&e),
     }
-    
-    client.kick_user(args[1]).await;
 }
 
+// The room answers with UserKicked or an Error, shown like every other room event
+pub async fn kick_user(client: &mut ChatClient, cmd: &Command) {
+    if let Err(e) = client.kick_user(cmd.arg(0).unwrap_or_default()).await {
+        client_error(&e);
+    }
+}
 
-pub async fn create_room(client: &mut ChatClient, args: Vec<&str>) {
-    if args.len() < 3 {
-        warning("Usage: /create <room_id> <password>");
+pub async fn create_room(client: &mut ChatClient, cmd: &Command) {
+    let room_id = cmd.arg(0).unwrap_or_default();
+    let password = cmd.arg(1);
+    let visibility = match (cmd.has_flag("--public"), cmd.has_flag("--unlisted")) {
+        (false, false) => RoomVisibility::Private,
+        (true, false) => RoomVisibility::Public,
+        (false, true) => RoomVisibility::Unlisted,
+        (true, true) => {
+            warning("A room can't be both --public and --unlisted");
+            return;
+        }
+    };
+
+    let mut errors = validate_room_id(room_id);
+    errors.extend(validate_room_password(visibility, password));
+    if !errors.is_empty() {
+        validation_errors(&errors);
         return;
     }
 
-    let room_id = args[1];
-    let password = args[2];
+    match client.create_room(room_id, password, visibility).await {
+        Ok(resp) => success(&format!("Room Created - {} ({})", resp.room_id, visibility_label(visibility))),
+        Err(e) => client_error(&e),
+    }
+}
 
-    client.create_room(room_id, password).await;
+pub fn print_password_rules() {
+    info("Please enter a password that meets the criteria:");
+    info(&format!("- Minimum {} characters", PASSWORD_MIN_LEN));
+    info("- At least one uppercase letter");
+    info("- At least one special character");
+    info("- No spaces");
 }
 
-pub async fn sign_up(client: &mut ChatClient) { 
+// sign_up and login are only used by the plain interface, the full-screen one asks in its input line
+pub async fn sign_up(client: &mut ChatClient, stdin: &mut StdinReader) { 
     header("Sign Up");
     info("Please enter a username (type /quit to cancel):");
 
@@ -94,10 +309,9 @@ pub async fn sign_up(client: &mut ChatClient) {
         print!("Username: ");
         io::stdout().flush().unwrap();
 
-        let mut input = String::new();
-        if io::stdin().read_line(&mut input).is_err() {
-            continue;
-        }
+        let Some(input) = stdin.next_line().await else {
+            return;
+        };
 
         let username = input.trim();
 
@@ -106,31 +320,26 @@ pub async fn sign_up(client: &mut ChatClient) {
             return;
         }
 
-        if username.is_empty() || username.starts_with('/') {
-            error("Invalid username");
+        let errors = validate_user_id(username);
+        if !errors.is_empty() {
+            validation_errors(&errors);
             continue;
         }
 
         break username.to_string();
     };
 
-    println!("");
-    info("Please enter a password that meets the criteria:");
-    info("- Minimum 8 characters");
-    info("- At least one uppercase letter");
-    info("- At least one special character");
+    println!();
+    print_password_rules();
     info("(Type /quit to cancel)");
 
     let password = loop {
         print!("Password: ");
         io::stdout().flush().unwrap();
 
-        let input = match read_password() {
-            Ok(p) => p,
-            Err(_) => {
-                error("Failed to read password");
-                continue;
-            }
+        let Some(input) = stdin.password().await else {
+            error("Failed to read password");
+            return;
         };
 
         let password = input.trim();
@@ -140,48 +349,71 @@ pub async fn sign_up(client: &mut ChatClient) {
             return;
         }
 
-        let password_valid = password.len() >= 8
-            && password.chars().any(|c| c.is_uppercase())
-            && password.chars().any(|c| !c.is_alphanumeric());
-
-        if !password_valid {
-            error("Password does not meet policy requirements");
+        let errors = validate_password("password", password);
+        if !errors.is_empty() {
+            validation_errors(&errors);
             continue;
         }
 
         break password.to_string();
     };
 
-    client.create_user(&username, &password).await;
+    create_user(client, &username, &password).await;
 }
 
-pub async fn login(client: &mut ChatClient) -> bool {
-    header("Login");
-    info("Please enter your username and password to log in.");
-    info("(Type /quit at any time to cancel)");
-
-    print!("Username: ");
-    io::stdout().flush().unwrap();
+pub async fn create_user(client: &mut ChatClient, username: &str, password: &str) -> bool {
+    match client.create_user(username, password).await {
+        Ok(user_id) => {
+            success(&format!("User '{}' created successfully!", user_id));
+            true
+        }
+        Err(e) => {
+            client_error(&e);
+            false
+        }
+    }
+}
 
-    let mut username = String::new();
-    if io::stdin().read_line(&mut username).is_err() {
-        error("Error reading username");
-        return false;
+// CHAT_USER and CHAT_PASSWORD/CHAT_PASSWORD_FILE skip the prompts (see credentials.rs)
+pub async fn login(client: &mut ChatClient, stdin: &mut StdinReader) -> bool {
+    header("Login");
+    let (env_username, env_password) = (username_from_env(), password_from_env());
+    if env_username.is_none() || env_password.is_none() {
+        info("Please enter your username and password to log in.");
+        info("(Type /quit at any time to cancel)");
     }
-    let username = username.trim();
+
+    let username = match env_username {
+        Some(username) => username,
+        None => {
+            print!("Username: ");
+            io::stdout().flush().unwrap();
+
+            let Some(username) = stdin.next_line().await else {
+                error("Error reading username");
+                return false;
+            };
+            username.trim().to_string()
+        }
+    };
 
     if username == "/quit" {
         warning("Login cancelled");
         return false;
     }
 
-    print!("Password: ");
-    io::stdout().flush().unwrap();
-    let password = match read_password() {
-        Ok(pw) => pw.trim().to_string(),
-        Err(_) => {
-            error("Error reading password");
-            return false;
+    let password = match env_password {
+        Some(password) => password,
+        None => {
+            print!("Password: ");
+            io::stdout().flush().unwrap();
+            match stdin.password().await {
+                Some(pw) => pw.trim().to_string(),
+                None => {
+                    error("Error reading password");
+                    return false;
+                }
+            }
         }
     };
 
@@ -190,9 +422,110 @@ pub async fn login(client: &mut ChatClient) -> bool {
         return false;
     }
 
-    if client.login(username, &password).await {
-        true
-    } else {
-        false
+    log_in(client, &username, &password).await
+}
+
+pub async fn log_in(client: &mut ChatClient, username: &str, password: &str) -> bool {
+    match client.login(username, password).await {
+        Ok(()) => {
+            show_motd(client);
+            true
+        }
+        Err(ClientError::Server(ErrorResponse::AuthenticationFailed { .. } | ErrorResponse::InvalidPassword { .. } | ErrorResponse::UserNotFound { .. })) => {
+            error("Error: Invalid username or password");
+            false
+        }
+        Err(e) => {
+            client_error(&e);
+            false
+        }
+    }
+}
+
+// Once per login, not when the client logs itself back in to reconnect after a restart
+fn show_motd(client: &ChatClient) {
+    if let Some(motd) = client.motd() {
+        system_message(&format!("Message of the day: {}", motd));
+    }
+}
+
+// Logs back in with the session an earlier launch saved, and with `rejoin` goes back into the room
+// that was open then. Returns whether the client is now logged in.
+pub async fn resume_session(client: &mut ChatClient, store: &SessionStore, rejoin: bool) -> bool {
+    let Some(saved) = store.load() else {
+        return false;
+    };
+    match client.resume(&saved.refresh_token).await {
+        Ok(()) => store.remember(client),
+        Err(ClientError::Server(ErrorResponse::AuthenticationFailed { .. })) => {
+            store.forget();
+            info("[Your saved session has expired, please /login]");
+            return false;
+        }
+        // eg the server isn't up yet, the saved session may still work next time
+        Err(e) => {
+            error(&format!("Couldn't log back in as {}: {}", saved.user_id, e));
+            return false;
+        }
+    }
+    success(&format!("Logged back in as {}", saved.user_id));
+    show_motd(client);
+
+    if rejoin && let Some(room_id) = saved.last_room {
+        info(&format!("Rejoining {}...", room_id));
+        let joined = client.join_room(&room_id, None).await;
+        if !show_joined(client, joined) {
+            store.set_last_room(None);
+        }
+    }
+    true
+}
+
+// One try at getting back into the room after the server restarted (see reconnect.rs), keeping
+// `pending` while it is worth trying again. False if the session didn't survive the restart and
+// the user has to /login again.
+pub async fn try_reconnect(client: &mut ChatClient, store: &SessionStore, pending: &mut Option<Reconnect>) -> bool {
+    let Some(mut reconnect) = pending.take() else {
+        return true;
+    };
+    // joined another room while waiting
+    if client.current_room().is_some() {
+        return true;
+    }
+
+    let attempt = reconnect.attempt(client).await;
+    // resuming the session replaced the refresh token, even if joining the room then failed
+    store.remember(client);
+    match attempt {
+        Attempt::Rejoined(_) => success(&format!("Reconnected to {}", reconnect.room_id)),
+        Attempt::Retrying => *pending = Some(reconnect),
+        Attempt::GaveUp(ClientError::Server(ErrorResponse::AuthenticationFailed { .. })) => {
+            store.forget();
+            info("[Your session ended with the server restart, please /login]");
+            return false;
+        }
+        Attempt::GaveUp(e) => error(&format!("Couldn't reconnect to {}: {}", reconnect.room_id, e)),
+    }
+    true
+}
+
+// eg "2/5 uses, expires 10-21 14:30"
+fn invite_limits(invite: &InviteInfo) -> String {
+    let uses = match invite.max_uses {
+        Some(max_uses) => format!("{}/{} uses", invite.uses, max_uses),
+        None => format!("{} uses", invite.uses),
+    };
+    let expires = match &invite.expires_at {
+        Some(expires_at) => format!("expires {}", message_format::format().time(expires_at)),
+        None => "never expires".to_string(),
+    };
+    format!("{}, {}", uses, expires)
+}
+
+fn visibility_label(visibility: RoomVisibility) -> &'static str {
+    match visibility {
+        RoomVisibility::Public => "public",
+        RoomVisibility::Private => "private, password required",
+        RoomVisibility::Unlisted => "unlisted",
     }
 }
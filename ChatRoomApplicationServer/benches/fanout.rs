@@ -0,0 +1,76 @@
+// What it costs to get one chat message to every member of a room, from serializing it to having
+// a websocket frame ready for each connection. Rooms of 10, 100 and 1,000 members, a short message
+// and one as long as the server allows by default (2,000 characters), two ways:
+//
+//      string_per_member   the room sends a String and each connection gets its own copy
+//      shared_frame        the room sends one Utf8Bytes frame that every connection shares
+//                          (what rooms.rs does)
+//
+//      cargo bench --bench fanout
+
+use axum::extract::ws::{Message, Utf8Bytes};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use tokio::sync::broadcast;
+
+// the server is a binary, so the protocol types are pulled in straight from its source
+#[path = "../src/message.rs"]
+mod message;
+use message::{ChatMessage, ServerWsMessage};
+
+fn chat_message(content: String) -> ServerWsMessage {
+    ServerWsMessage::MessageBroadcast(ChatMessage {
+        room_id: "general".to_string(),
+        user_id: "alice".to_string(),
+        message_id: "5f0c6a9e-3b7d-4f41-9a57-2c1e8d0b6f3a".to_string(),
+        content,
+        timestamp: "2024-05-01T12:00:00.000000+00:00".to_string(),
+    })
+}
+
+// Broadcasts one message through a channel with `members` receivers and turns what each receiver
+// gets into a frame, the way every member's connection does
+fn fan_out<T: Clone>(
+    sender: &broadcast::Sender<T>,
+    receivers: &mut [broadcast::Receiver<T>],
+    item: T,
+    frame: impl Fn(T) -> Message,
+) {
+    let _ = sender.send(item);
+    for receiver in receivers {
+        black_box(frame(receiver.try_recv().unwrap()));
+    }
+}
+
+fn fanout(c: &mut Criterion) {
+    let messages = [
+        ("short", chat_message("Has anyone tried the new build yet? It seems a lot faster here.".to_string())),
+        ("long", chat_message("All work and no play makes Jack a dull boy. ".repeat(46))),
+    ];
+    let mut group = c.benchmark_group("fanout");
+
+    for ((size, message), members) in messages.iter().flat_map(|m| [10, 100, 1_000].map(|n| (m, n))) {
+        let input = format!("{}/{}", size, members);
+        group.bench_with_input(BenchmarkId::new("string_per_member", &input), &members, |b, &members| {
+            let (sender, _) = broadcast::channel::<String>(16);
+            let mut receivers: Vec<_> = (0..members).map(|_| sender.subscribe()).collect();
+            b.iter(|| {
+                let json = serde_json::to_string(message).unwrap();
+                fan_out(&sender, &mut receivers, json, |json| Message::Text(json.into()));
+            });
+        });
+
+        group.bench_with_input(BenchmarkId::new("shared_frame", &input), &members, |b, &members| {
+            let (sender, _) = broadcast::channel::<Utf8Bytes>(16);
+            let mut receivers: Vec<_> = (0..members).map(|_| sender.subscribe()).collect();
+            b.iter(|| {
+                let frame = Utf8Bytes::from(serde_json::to_string(message).unwrap());
+                fan_out(&sender, &mut receivers, frame, Message::Text);
+            });
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, fanout);
+criterion_main!(benches);
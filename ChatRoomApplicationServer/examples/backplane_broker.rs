@@ -0,0 +1,423 @@
+// A stand-in for Redis that only knows the commands the Redis backplane uses (see
+// src/redis_backplane.rs) and keeps everything in memory. It is for running several server
+// instances on one machine, to try them out or test them, without installing Redis:
+//
+//      cargo run --example backplane_broker
+//      CHAT_BACKPLANE=redis://127.0.0.1:6379 cargo run
+//      CHAT_BACKPLANE=redis://127.0.0.1:6379 CHAT_BIND_ADDR=127.0.0.1:3001 cargo run
+//
+// --addr listens somewhere else (default 127.0.0.1:6379). Nothing is saved and there are no
+// passwords, so don't let anything but test servers near it.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
+    sync::{broadcast, mpsc},
+};
+
+// Published messages a slow subscriber can fall behind by before it misses some
+const CHANNEL_CAPACITY: usize = 4096;
+
+enum Value {
+    Text { value: String, expires_at: Option<Instant> },
+    Set(HashSet<String>),
+    List(VecDeque<String>),
+}
+
+#[derive(Default)]
+struct Store {
+    values: HashMap<String, Value>,
+}
+
+impl Store {
+    // Expired keys are only noticed when they are next looked at
+    fn live(&mut self, key: &str) -> Option<&mut Value> {
+        let expired = matches!(
+            self.values.get(key),
+            Some(Value::Text { expires_at: Some(at), .. }) if *at <= Instant::now()
+        );
+        if expired {
+            self.values.remove(key);
+        }
+        self.values.get_mut(key)
+    }
+}
+
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Int(i64),
+    Bulk(Option<String>),
+    Array(Vec<String>),
+}
+
+impl Reply {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Reply::Status(status) => format!("+{}\r\n", status).into_bytes(),
+            Reply::Error(message) => format!("-{}\r\n", message).into_bytes(),
+            Reply::Int(n) => format!(":{}\r\n", n).into_bytes(),
+            Reply::Bulk(None) => b"$-1\r\n".to_vec(),
+            Reply::Bulk(Some(value)) => bulk(value),
+            Reply::Array(items) => array(items),
+        }
+    }
+}
+
+fn bulk(value: &str) -> Vec<u8> {
+    format!("${}\r\n{}\r\n", value.len(), value).into_bytes()
+}
+
+fn array(items: &[String]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", items.len()).into_bytes();
+    for item in items {
+        out.extend(bulk(item));
+    }
+    out
+}
+
+// The items from `start` to `stop` (both included) of a list of `len`, counting from the end for
+// negative ones like Redis does. None if that leaves nothing.
+fn range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let resolve = |index: i64| if index < 0 { len + index } else { index };
+    let (start, stop) = (resolve(start).max(0), resolve(stop).min(len - 1));
+    (start <= stop).then_some((start as usize, stop as usize))
+}
+
+// A pub/sub push, whose last item is a count rather than a string
+fn push(kind: &str, channel: &str, count: usize) -> Vec<u8> {
+    let mut out = b"*3\r\n".to_vec();
+    out.extend(bulk(kind));
+    out.extend(bulk(channel));
+    out.extend(format!(":{}\r\n", count).into_bytes());
+    out
+}
+
+#[tokio::main]
+async fn main() {
+    let mut addr = "127.0.0.1:6379".to_string();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => {}
+        [flag, value] if flag == "--addr" => addr = value.clone(),
+        _ => {
+            eprintln!("Usage: backplane_broker [--addr host:port]");
+            std::process::exit(2);
+        }
+    }
+
+    let listener = TcpListener::bind(&addr).await.expect("can't listen there");
+    println!("Backplane broker listening on {}", addr);
+    accept_clients(listener).await;
+}
+
+// Serves everyone who connects, for as long as the broker runs. The server's tests run one of their
+// own (see src/redis_backplane.rs).
+pub async fn accept_clients(listener: TcpListener) {
+    let store = Arc::new(Mutex::new(Store::default()));
+    let (published, _) = broadcast::channel::<(String, String)>(CHANNEL_CAPACITY);
+
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        tokio::spawn(serve(stream, store.clone(), published.clone()));
+    }
+}
+
+async fn serve(stream: TcpStream, store: Arc<Mutex<Store>>, published: broadcast::Sender<(String, String)>) {
+    let (read, mut write) = stream.into_split();
+    // commands are read on their own task, so reading one never races a message being pushed
+    let (commands_tx, mut commands) = mpsc::channel(64);
+    tokio::spawn(read_commands(BufReader::new(read), commands_tx));
+
+    let mut subscriptions = HashSet::new();
+    // commands queued since MULTI, run together on EXEC
+    let mut transaction = None;
+    let mut messages = published.subscribe();
+    loop {
+        let out = tokio::select! {
+            command = commands.recv() => match command {
+                Some(command) => run(&command, &store, &published, &mut subscriptions, &mut transaction),
+                None => return,
+            },
+            message = messages.recv(), if !subscriptions.is_empty() => match message {
+                Ok((channel, message)) if subscriptions.contains(&channel) => {
+                    array(&["message".to_string(), channel, message])
+                }
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+        };
+        if write.write_all(&out).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn read_commands(mut reader: BufReader<OwnedReadHalf>, commands: mpsc::Sender<Vec<String>>) {
+    while let Some(command) = read_command(&mut reader).await {
+        if commands.send(command).await.is_err() {
+            return;
+        }
+    }
+}
+
+// One command as an array of bulk strings, or an inline one ("PING"). None once the client is
+// gone or sends something that isn't either.
+async fn read_command(reader: &mut BufReader<OwnedReadHalf>) -> Option<Vec<String>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await.ok()? == 0 {
+        return None;
+    }
+    let Some(count) = line.strip_prefix('*') else {
+        return Some(line.split_whitespace().map(String::from).collect());
+    };
+
+    let count: usize = count.trim().parse().ok()?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        line.clear();
+        reader.read_line(&mut line).await.ok()?;
+        let len: usize = line.trim().strip_prefix('$')?.parse().ok()?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await.ok()?;
+        arg.truncate(len);
+        args.push(String::from_utf8(arg).ok()?);
+    }
+    Some(args)
+}
+
+fn run(
+    command: &[String],
+    store: &Mutex<Store>,
+    published: &broadcast::Sender<(String, String)>,
+    subscriptions: &mut HashSet<String>,
+    transaction: &mut Option<Vec<Vec<String>>>,
+) -> Vec<u8> {
+    let Some(name) = command.first() else {
+        return Reply::Error("ERR empty command".to_string()).encode();
+    };
+    let args = &command[1..];
+    match name.to_ascii_uppercase().as_str() {
+        "MULTI" => {
+            *transaction = Some(Vec::new());
+            Reply::Status("OK").encode()
+        }
+        // every queued command under one lock, so nothing else runs in between
+        "EXEC" => match transaction.take() {
+            Some(queued) => {
+                let mut store = store.lock().unwrap();
+                let mut out = format!("*{}\r\n", queued.len()).into_bytes();
+                for command in &queued {
+                    out.extend(execute(&command[0], &command[1..], &mut store).encode());
+                }
+                out
+            }
+            None => Reply::Error("ERR EXEC without MULTI".to_string()).encode(),
+        },
+        _ if transaction.is_some() => {
+            transaction.get_or_insert_with(Vec::new).push(command.to_vec());
+            Reply::Status("QUEUED").encode()
+        }
+        "SUBSCRIBE" => {
+            let mut out = Vec::new();
+            for channel in args {
+                subscriptions.insert(channel.clone());
+                out.extend(push("subscribe", channel, subscriptions.len()));
+            }
+            out
+        }
+        "UNSUBSCRIBE" => {
+            let channels: Vec<String> = if args.is_empty() {
+                subscriptions.drain().collect()
+            } else {
+                args.to_vec()
+            };
+            let mut out = Vec::new();
+            for channel in channels {
+                subscriptions.remove(&channel);
+                out.extend(push("unsubscribe", &channel, subscriptions.len()));
+            }
+            out
+        }
+        "PUBLISH" if args.len() == 2 => {
+            let receivers = published.send((args[0].clone(), args[1].clone())).unwrap_or(0);
+            Reply::Int(receivers as i64).encode()
+        }
+        _ => execute(name, args, &mut store.lock().unwrap()).encode(),
+    }
+}
+
+// Redis won't keep a key for no time at all
+fn invalid_expiry(command: &str) -> Reply {
+    Reply::Error(format!("ERR invalid expire time in '{}' command", command))
+}
+
+fn execute(name: &str, args: &[String], store: &mut Store) -> Reply {
+    let wrong_type = || Reply::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string());
+    let text = |value: &str, ttl: Option<Duration>| Value::Text {
+        value: value.to_string(),
+        expires_at: ttl.map(|ttl| Instant::now() + ttl),
+    };
+
+    match (name.to_ascii_uppercase().as_str(), args) {
+        ("PING", _) => Reply::Status("PONG"),
+        ("DBSIZE", []) => Reply::Int(store.values.len() as i64),
+        // CLIENT SETINFO and friends, sent when connecting
+        ("CLIENT", _) => Reply::Status("OK"),
+        ("GET", [key]) => match store.live(key) {
+            Some(Value::Text { value, .. }) => Reply::Bulk(Some(value.clone())),
+            Some(_) => wrong_type(),
+            None => Reply::Bulk(None),
+        },
+        // NX only sets a key that isn't there and XX only one that is, nil when they don't
+        ("SET", [key, value, options @ ..]) => {
+            let mut ttl = None;
+            let mut only_if = None;
+            let mut options = options.iter();
+            while let Some(option) = options.next() {
+                match option.to_ascii_uppercase().as_str() {
+                    "NX" => only_if = Some(false),
+                    "XX" => only_if = Some(true),
+                    "PX" => match options.next().and_then(|millis| millis.parse().ok()) {
+                        Some(0) => return invalid_expiry("set"),
+                        Some(millis) => ttl = Some(Duration::from_millis(millis)),
+                        None => return Reply::Error("ERR value is not an integer or out of range".to_string()),
+                    },
+                    _ => return Reply::Error("ERR syntax error".to_string()),
+                }
+            }
+            if only_if.is_some_and(|exists| store.live(key).is_some() != exists) {
+                return Reply::Bulk(None);
+            }
+            store.values.insert(key.clone(), text(value, ttl));
+            Reply::Status("OK")
+        }
+        ("PSETEX", [key, millis, value]) => match millis.parse() {
+            Ok(0) => invalid_expiry("psetex"),
+            Ok(millis) => {
+                store.values.insert(key.clone(), text(value, Some(Duration::from_millis(millis))));
+                Reply::Status("OK")
+            }
+            Err(_) => Reply::Error("ERR value is not an integer or out of range".to_string()),
+        },
+        ("SETNX", [key, value]) => {
+            if store.live(key).is_some() {
+                return Reply::Int(0);
+            }
+            store.values.insert(key.clone(), text(value, None));
+            Reply::Int(1)
+        }
+        // keeps the expiry of a counter that is already there
+        ("INCR", [key]) => match store.live(key) {
+            Some(Value::Text { value, .. }) => match value.parse::<i64>() {
+                Ok(count) => {
+                    *value = (count + 1).to_string();
+                    Reply::Int(count + 1)
+                }
+                Err(_) => Reply::Error("ERR value is not an integer or out of range".to_string()),
+            },
+            Some(_) => wrong_type(),
+            None => {
+                store.values.insert(key.clone(), text("1", None));
+                Reply::Int(1)
+            }
+        },
+        ("GETDEL", [key]) => match store.live(key) {
+            Some(Value::Text { .. }) => match store.values.remove(key) {
+                Some(Value::Text { value, .. }) => Reply::Bulk(Some(value)),
+                _ => Reply::Bulk(None),
+            },
+            Some(_) => wrong_type(),
+            None => Reply::Bulk(None),
+        },
+        ("DEL", keys) if !keys.is_empty() => {
+            let removed = keys.iter().filter(|key| store.values.remove(*key).is_some()).count();
+            Reply::Int(removed as i64)
+        }
+        ("SADD", [key, members @ ..]) if !members.is_empty() => {
+            let set = store.values.entry(key.clone()).or_insert_with(|| Value::Set(HashSet::new()));
+            let Value::Set(set) = set else {
+                return wrong_type();
+            };
+            let added = members.iter().filter(|member| set.insert((*member).clone())).count();
+            Reply::Int(added as i64)
+        }
+        ("SREM", [key, members @ ..]) if !members.is_empty() => match store.live(key) {
+            Some(Value::Set(set)) => {
+                let removed = members.iter().filter(|member| set.remove(*member)).count();
+                if set.is_empty() {
+                    store.values.remove(key);
+                }
+                Reply::Int(removed as i64)
+            }
+            Some(_) => wrong_type(),
+            None => Reply::Int(0),
+        },
+        ("SMEMBERS", [key]) => match store.live(key) {
+            Some(Value::Set(set)) => Reply::Array(set.iter().cloned().collect()),
+            Some(_) => wrong_type(),
+            None => Reply::Array(Vec::new()),
+        },
+        ("LPUSH", [key, values @ ..]) if !values.is_empty() => {
+            let list = store.values.entry(key.clone()).or_insert_with(|| Value::List(VecDeque::new()));
+            let Value::List(list) = list else {
+                return wrong_type();
+            };
+            for value in values {
+                list.push_front(value.clone());
+            }
+            Reply::Int(list.len() as i64)
+        }
+        ("LRANGE", [key, start, stop]) => {
+            let (Ok(start), Ok(stop)) = (start.parse(), stop.parse()) else {
+                return Reply::Error("ERR value is not an integer or out of range".to_string());
+            };
+            match store.live(key) {
+                Some(Value::List(list)) => match range(start, stop, list.len()) {
+                    Some((start, stop)) => Reply::Array(list.range(start..=stop).cloned().collect()),
+                    None => Reply::Array(Vec::new()),
+                },
+                Some(_) => wrong_type(),
+                None => Reply::Array(Vec::new()),
+            }
+        }
+        // keeps only the items from start to stop, and drops the list if that leaves none
+        ("LTRIM", [key, start, stop]) => {
+            let (Ok(start), Ok(stop)) = (start.parse(), stop.parse()) else {
+                return Reply::Error("ERR value is not an integer or out of range".to_string());
+            };
+            match store.live(key) {
+                Some(Value::List(list)) => {
+                    match range(start, stop, list.len()) {
+                        Some((start, stop)) => {
+                            list.truncate(stop + 1);
+                            list.drain(..start);
+                        }
+                        None => list.clear(),
+                    }
+                    if list.is_empty() {
+                        store.values.remove(key);
+                    }
+                    Reply::Status("OK")
+                }
+                Some(_) => wrong_type(),
+                None => Reply::Status("OK"),
+            }
+        }
+        ("SCARD", [key]) => match store.live(key) {
+            Some(Value::Set(set)) => Reply::Int(set.len() as i64),
+            Some(_) => wrong_type(),
+            None => Reply::Int(0),
+        },
+        (name, _) => Reply::Error(format!("ERR unknown command or wrong number of arguments for '{}'", name)),
+    }
+}
@@ -0,0 +1,280 @@
+// Load test for a running server: fills a number of rooms with websocket clients that all send
+// messages as fast as they can, and reports how many broadcasts were delivered per second and
+// how long they took to arrive. Each client waits for its own message to come back before sending
+// the next one, so a room never has more messages in flight than it has members. --burst lets
+// each client get that many messages ahead instead, enough of them and the clients fall behind the
+// room and have to be resynced by the server. Every client signs up as a new user first, which
+// takes a moment each since passwords are hashed on purpose slowly.
+//
+// The rate limits would stop it almost straight away, so start the server with them turned off:
+//
+//      CHAT_USER_MSG_PER_SEC=0 CHAT_IP_MSG_PER_SEC=0 CHAT_ROOM_MSG_PER_SEC=0 CHAT_IP_REQ_PER_SEC=0 \
+//          cargo run --release
+//
+// and then, from another terminal:
+//
+//      cargo run --release --example load_test -- --rooms 20 --users 10 --messages 100
+//
+// --url points it at another server (default http://127.0.0.1:3000).
+
+use std::time::{Duration, Instant};
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::sync::Barrier;
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message};
+
+// Longest a client waits for the next broadcast before giving up on the rest
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Options {
+    url: String,
+    rooms: usize,
+    users: usize,
+    messages: usize,
+    // messages a client can have sent without seeing them come back
+    burst: usize,
+}
+
+// What one client saw
+struct Received {
+    messages: usize,
+    // send to receive, for every message that arrived
+    latencies: Vec<Duration>,
+    // since the start, when the last of them arrived
+    last_at: Duration,
+    // the connection ended before everything arrived
+    dropped: bool,
+    // the server said it couldn't resend everything this client missed
+    gaps: usize,
+}
+
+#[tokio::main]
+async fn main() {
+    let options = parse_args();
+    let ws_url = options.url.replacen("http", "ws", 1);
+    let http = reqwest::Client::new();
+    // different names every run, so the test can be repeated against the same server
+    let run = chrono::Utc::now().timestamp_millis() % 1_000_000;
+
+    let clients = options.rooms * options.users;
+    let barrier = std::sync::Arc::new(Barrier::new(clients + 1));
+    let start = std::sync::Arc::new(std::sync::OnceLock::<Instant>::new());
+    let expected = options.users * options.messages;
+    let mut tasks = Vec::new();
+
+    println!(
+        "{} rooms x {} users, {} messages each ({} broadcasts to deliver)",
+        options.rooms,
+        options.users,
+        options.messages,
+        clients * expected
+    );
+
+    for room in 0..options.rooms {
+        let room_id = format!("load-{}-{}", run, room);
+        for user in 0..options.users {
+            let user_id = format!("lt{}r{}u{}", run, room, user);
+            let token = sign_up(&http, &options.url, &user_id).await;
+            // the creator is let in by creating the room, everyone else joins it
+            let (path, body) = if user == 0 {
+                ("create_room", json!({"room_id": room_id, "visibility": "Public"}))
+            } else {
+                ("join_room", json!({"room_id": room_id}))
+            };
+            let response = http
+                .post(format!("{}/{}", options.url, path))
+                .bearer_auth(&token)
+                .json(&body)
+                .send()
+                .await
+                .expect("server isn't running");
+            if !response.status().is_success() {
+                eprintln!("{} {} failed: {}", path, room_id, response.text().await.unwrap_or_default());
+                std::process::exit(1);
+            }
+
+            let mut request = format!("{}/ws", ws_url).into_client_request().expect("bad server url");
+            let authorization = format!("Bearer {}", token).parse().expect("tokens are plain ascii");
+            request.headers_mut().insert("authorization", authorization);
+            let (socket, _) = tokio_tungstenite::connect_async(request)
+                .await
+                .expect("websocket connection failed");
+            let (mut sink, mut stream) = socket.split();
+            // the socket is open before the server has subscribed it to the room, and anything
+            // sent in between would never reach it
+            loop {
+                match tokio::time::timeout(IDLE_TIMEOUT, stream.next()).await {
+                    Ok(Some(Ok(Message::Text(text)))) => {
+                        let msg: Value = serde_json::from_str(&text).unwrap_or_default();
+                        if msg["type"] == "UserJoined" && msg["user_id"] == user_id.as_str() {
+                            break;
+                        }
+                    }
+                    Ok(Some(Ok(_))) => {}
+                    _ => {
+                        eprintln!("{} never joined {}", user_id, room_id);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            // the reader tells the sender whenever one of its own messages comes back
+            let (echo_tx, mut echo_rx) = tokio::sync::mpsc::unbounded_channel::<()>();
+            let barrier = barrier.clone();
+            let start = start.clone();
+            let room_id = room_id.clone();
+            let messages = options.messages;
+            let burst = options.burst;
+
+            tasks.push(tokio::spawn(async move {
+                let reader = tokio::spawn({
+                    let start = start.clone();
+                    let user_id = user_id.clone();
+                    async move {
+                        let mut received = Received {
+                            messages: 0,
+                            latencies: Vec::with_capacity(expected),
+                            last_at: Duration::ZERO,
+                            dropped: false,
+                            gaps: 0,
+                        };
+                        while received.messages < expected {
+                            let frame = match tokio::time::timeout(IDLE_TIMEOUT, stream.next()).await {
+                                Ok(Some(Ok(Message::Text(text)))) => text,
+                                Ok(Some(Ok(_))) => continue,
+                                _ => {
+                                    received.dropped = true;
+                                    break;
+                                }
+                            };
+                            let Ok(msg) = serde_json::from_str::<Value>(&frame) else {
+                                continue;
+                            };
+                            if msg["type"] == "Error" {
+                                received.gaps += 1;
+                            }
+                            if msg["type"] != "MessageBroadcast" {
+                                continue;
+                            }
+                            received.messages += 1;
+                            if msg["user_id"] == user_id.as_str() {
+                                let _ = echo_tx.send(());
+                            }
+                            // every message carries when it was sent, in microseconds since the start
+                            let sent_at = msg["content"].as_str().and_then(|content| content.parse().ok());
+                            if let (Some(sent_at), Some(start)) = (sent_at, start.get()) {
+                                received.last_at = start.elapsed();
+                                received.latencies.push(received.last_at.saturating_sub(Duration::from_micros(sent_at)));
+                            }
+                        }
+                        received
+                    }
+                });
+
+                barrier.wait().await;
+                let start = *start.get().unwrap();
+                for sent in 0..messages {
+                    if sent >= burst && echo_rx.recv().await.is_none() {
+                        break;
+                    }
+                    let content = start.elapsed().as_micros().to_string();
+                    let msg = json!({"type": "SendMessage", "room_id": room_id, "content": content});
+                    if sink.send(Message::Text(msg.to_string())).await.is_err() {
+                        break;
+                    }
+                }
+                let received = reader.await.unwrap();
+                let _ = sink.close().await;
+                received
+            }));
+        }
+    }
+
+    // Everyone is connected, go
+    start.set(Instant::now()).unwrap();
+    barrier.wait().await;
+
+    let mut delivered = 0;
+    let mut dropped = 0;
+    let mut gaps = 0;
+    let mut busy = Duration::ZERO;
+    let mut latencies = Vec::new();
+    for task in tasks {
+        let received = task.await.unwrap();
+        delivered += received.messages;
+        dropped += received.dropped as usize;
+        gaps += received.gaps;
+        busy = busy.max(received.last_at);
+        latencies.extend(received.latencies);
+    }
+
+    latencies.sort();
+    let percentile = |p: f64| {
+        latencies
+            .get(((latencies.len() as f64 * p) as usize).min(latencies.len().saturating_sub(1)))
+            .copied()
+            .unwrap_or_default()
+    };
+    println!("delivered {} of {} broadcasts in {:.2?}", delivered, clients * expected, busy);
+    println!("throughput: {:.0} broadcasts/s", delivered as f64 / busy.as_secs_f64());
+    println!(
+        "latency: p50 {:.2?}, p99 {:.2?}, max {:.2?}",
+        percentile(0.5),
+        percentile(0.99),
+        percentile(1.0)
+    );
+    if dropped > 0 {
+        println!("{} of {} clients stopped receiving before the end", dropped, clients);
+    }
+    if gaps > 0 {
+        println!("{} times a client fell too far behind to be sent everything it missed", gaps);
+    }
+}
+
+// Signs a new user up and returns their session token
+async fn sign_up(http: &reqwest::Client, url: &str, user_id: &str) -> String {
+    let response = http
+        .post(format!("{}/create_user", url))
+        .json(&json!({"user_id": user_id, "password": "Load#Test1234"}))
+        .send()
+        .await
+        .expect("server isn't running");
+    if !response.status().is_success() {
+        eprintln!("Signing up {} failed: {}", user_id, response.text().await.unwrap_or_default());
+        std::process::exit(1);
+    }
+    let body: Value = response.json().await.unwrap_or_default();
+    body["token"].as_str().unwrap_or_default().to_string()
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        url: "http://127.0.0.1:3000".to_string(),
+        rooms: 10,
+        users: 10,
+        messages: 100,
+        burst: 1,
+    };
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    for pair in args.chunks(2) {
+        let value = pair.get(1).map(String::as_str).unwrap_or_default();
+        let number = || {
+            value.parse().unwrap_or_else(|_| {
+                eprintln!("{} needs a number", pair[0]);
+                std::process::exit(2);
+            })
+        };
+        match pair[0].as_str() {
+            "--url" => options.url = value.to_string(),
+            "--rooms" => options.rooms = number(),
+            "--users" => options.users = number(),
+            "--messages" => options.messages = number(),
+            "--burst" => options.burst = number().max(1),
+            other => {
+                eprintln!("Unknown option {} (try --rooms, --users, --messages, --burst or --url)", other);
+                std::process::exit(2);
+            }
+        }
+    }
+    options
+}
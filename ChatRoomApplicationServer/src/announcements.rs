@@ -0,0 +1,240 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+use crate::backplane::{Backplane, BackplaneError, BackplaneEvent, BackplaneResult};
+use crate::message::{ChatMessage, ScheduledAnnouncement};
+
+// What the server says as "system" (see validation::SYSTEM_USER):
+//
+//  - the message of the day, handed to everyone with their session when they log in. CHAT_MOTD
+//    sets it when the server starts, the admin API changes it afterwards.
+//  - announcements by the admin API, right away or at a time set in advance. Scheduled ones wait
+//    here until a tick in main.rs sends them.
+//
+// Both are kept in the backplane (see backplane.rs), so every instance sees the same message of
+// the day and any of them can send an announcement. Taking an announcement out of the backplane
+// is what makes an instance the one that sends it, so it goes out once however many are running.
+//
+// An announcement for named rooms is posted into them like any chat message. One for everyone is
+// published on the backplane instead, and every instance hands it to each of its connections
+// without going through the rooms, so it reaches every room somebody is in and nothing else.
+
+const MOTD_KEY: &str = "motd";
+// ids of the scheduled announcements
+const ANNOUNCEMENTS_KEY: &str = "announcements";
+
+fn announcement_key(announcement_id: &str) -> String {
+    format!("announcement:{}", announcement_id)
+}
+
+// Announcements for everyone that can pile up for a connection that isn't keeping up before it
+// misses some
+const EVERYONE_CAPACITY: usize = 16;
+
+// An announcement for everyone connected. Each connection gets it as a chat message from "system"
+// in its own room.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Announcement {
+    pub message_id: String,
+    pub content: String,
+    pub timestamp: String,
+}
+
+impl Announcement {
+    pub fn in_room(&self, room_id: &str) -> ChatMessage {
+        ChatMessage {
+            room_id: room_id.to_string(),
+            user_id: chat_room_shared::validation::SYSTEM_USER.to_string(),
+            message_id: self.message_id.clone(),
+            content: self.content.clone(),
+            timestamp: self.timestamp.clone(),
+        }
+    }
+}
+
+pub struct Announcements {
+    backplane: Arc<dyn Backplane>,
+    // announcements for everyone, as heard from the backplane, for this instance's connections
+    everyone: broadcast::Sender<Announcement>,
+}
+
+impl Announcements {
+    pub fn new(backplane: Arc<dyn Backplane>) -> Self {
+        Announcements {
+            backplane,
+            everyone: broadcast::channel(EVERYONE_CAPACITY).0,
+        }
+    }
+
+    // Sends an announcement to everyone connected to any instance. `content` has already been
+    // sanitized.
+    pub fn announce_to_everyone(&self, content: String) {
+        self.backplane.publish(BackplaneEvent::Announcement(Announcement {
+            message_id: uuid::Uuid::new_v4().to_string(),
+            content,
+            timestamp: Utc::now().to_rfc3339(),
+        }));
+    }
+
+    // Every announcement for everyone from now on, for a connection
+    pub fn subscribe(&self) -> broadcast::Receiver<Announcement> {
+        self.everyone.subscribe()
+    }
+
+    // Passes the announcements for everyone on to this instance's connections, for as long as the
+    // server runs
+    pub async fn relay(&self) {
+        let mut events = self.backplane.subscribe();
+        loop {
+            match events.recv().await {
+                // Nobody connected isn't an error
+                Ok(BackplaneEvent::Announcement(announcement)) => {
+                    let _ = self.everyone.send(announcement);
+                }
+                Ok(BackplaneEvent::Room { .. }) => {}
+                // an announcement missed then is lost, there is nothing to catch up from
+                Ok(BackplaneEvent::Missed) => {}
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Fell behind the backplane, {} events were missed", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+
+    // Sets the message of the day unless another instance already did, so restarting one
+    // instance doesn't undo what was changed through the admin API
+    pub async fn seed_motd(&self, motd: &str) -> BackplaneResult<()> {
+        self.backplane.set_new(MOTD_KEY, motd.trim(), None).await?;
+        Ok(())
+    }
+
+    pub async fn motd(&self) -> BackplaneResult<Option<String>> {
+        Ok(self.backplane.get(MOTD_KEY).await?.filter(|motd| !motd.is_empty()))
+    }
+
+    // None or an empty message clears it. Cleared is kept as "" rather than removing the key, so
+    // seed_motd doesn't bring CHAT_MOTD back.
+    pub async fn set_motd(&self, motd: Option<String>) -> BackplaneResult<Option<String>> {
+        let motd = motd
+            .map(|motd| motd.trim().to_string())
+            .filter(|motd| !motd.is_empty());
+        self.backplane.set(MOTD_KEY, motd.as_deref().unwrap_or_default(), None).await?;
+        Ok(motd)
+    }
+
+    pub async fn schedule(
+        &self,
+        content: String,
+        room_ids: Option<Vec<String>>,
+        send_at: DateTime<Utc>,
+    ) -> BackplaneResult<ScheduledAnnouncement> {
+        let announcement = ScheduledAnnouncement {
+            announcement_id: uuid::Uuid::new_v4().to_string(),
+            content,
+            room_ids,
+            send_at: send_at.to_rfc3339(),
+        };
+        let json = serde_json::to_string(&announcement).map_err(|e| BackplaneError(e.to_string()))?;
+        self.backplane.set(&announcement_key(&announcement.announcement_id), &json, None).await?;
+        self.backplane.add_member(ANNOUNCEMENTS_KEY, &announcement.announcement_id).await?;
+        Ok(announcement)
+    }
+
+    // Soonest first
+    pub async fn scheduled(&self) -> BackplaneResult<Vec<ScheduledAnnouncement>> {
+        let mut announcements = Vec::new();
+        for announcement_id in self.backplane.members(ANNOUNCEMENTS_KEY).await? {
+            // sent or cancelled in between
+            let Some(json) = self.backplane.get(&announcement_key(&announcement_id)).await? else {
+                continue;
+            };
+            let announcement: ScheduledAnnouncement =
+                serde_json::from_str(&json).map_err(|e| BackplaneError(e.to_string()))?;
+            announcements.push(announcement);
+        }
+        announcements.sort_by_key(send_time);
+        Ok(announcements)
+    }
+
+    // False if it was already sent or cancelled
+    pub async fn cancel(&self, announcement_id: &str) -> BackplaneResult<bool> {
+        let taken = self.backplane.take(&announcement_key(announcement_id)).await?;
+        self.backplane.remove_member(ANNOUNCEMENTS_KEY, announcement_id).await?;
+        Ok(taken.is_some())
+    }
+
+    // The announcements whose time has come, for this instance to send. Another instance may get
+    // to some of them first, those are left to it.
+    pub async fn take_due(&self, now: DateTime<Utc>) -> BackplaneResult<Vec<ScheduledAnnouncement>> {
+        let mut due = Vec::new();
+        for announcement in self.scheduled().await? {
+            if send_time(&announcement) > now {
+                break;
+            }
+            if self.cancel(&announcement.announcement_id).await? {
+                due.push(announcement);
+            }
+        }
+        Ok(due)
+    }
+}
+
+// Only ever written by schedule, so it always parses
+fn send_time(announcement: &ScheduledAnnouncement) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(&announcement.send_at)
+        .map(|send_at| send_at.with_timezone(&Utc))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backplane::LocalBackplane;
+    use chrono::Duration;
+
+    #[tokio::test]
+    async fn the_message_of_the_day_is_only_seeded_once() {
+        let announcements = Announcements::new(Arc::new(LocalBackplane::default()));
+        assert_eq!(announcements.motd().await.unwrap(), None);
+
+        announcements.seed_motd("  Welcome!  ").await.unwrap();
+        assert_eq!(announcements.motd().await.unwrap().as_deref(), Some("Welcome!"));
+
+        announcements.set_motd(Some("Be nice".to_string())).await.unwrap();
+        announcements.seed_motd("Welcome!").await.unwrap();
+        assert_eq!(announcements.motd().await.unwrap().as_deref(), Some("Be nice"));
+
+        // cleared stays cleared, even when the server starts again with CHAT_MOTD set
+        assert_eq!(announcements.set_motd(Some("   ".to_string())).await.unwrap(), None);
+        announcements.seed_motd("Welcome!").await.unwrap();
+        assert_eq!(announcements.motd().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn due_announcements_are_taken_once() {
+        let backplane: Arc<dyn Backplane> = Arc::new(LocalBackplane::default());
+        let here = Announcements::new(backplane.clone());
+        let there = Announcements::new(backplane);
+        let now = Utc::now();
+        let rooms = Some(vec!["lounge".to_string()]);
+
+        let later = here.schedule("later".to_string(), None, now + Duration::hours(1)).await.unwrap();
+        let due = here.schedule("now".to_string(), rooms.clone(), now - Duration::seconds(1)).await.unwrap();
+        let ids: Vec<String> = there.scheduled().await.unwrap().into_iter().map(|a| a.announcement_id).collect();
+        assert_eq!(ids, vec![due.announcement_id.clone(), later.announcement_id.clone()]);
+
+        let taken = here.take_due(now).await.unwrap();
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].content, "now");
+        assert_eq!(taken[0].room_ids, rooms);
+        assert!(there.take_due(now).await.unwrap().is_empty());
+        assert!(!there.cancel(&due.announcement_id).await.unwrap());
+
+        assert!(there.cancel(&later.announcement_id).await.unwrap());
+        assert!(here.take_due(now + Duration::hours(2)).await.unwrap().is_empty());
+        assert!(here.scheduled().await.unwrap().is_empty());
+    }
+}
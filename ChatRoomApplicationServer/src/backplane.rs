@@ -0,0 +1,511 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{broadcast, Mutex};
+
+use crate::announcements::Announcement;
+use crate::message::{ChatMessage, ServerWsMessage, StorageStats};
+use crate::redis_backplane::RedisBackplane;
+use crate::rooms::RoomSettings;
+
+// What lets several copies of the server run behind one load balancer as if they were one. Two
+// things go through it:
+//
+//  - events: every chat message and room broadcast is published, and each instance passes the
+//    ones published by the others on to its own members (see rooms.rs). So are announcements to
+//    everyone, which each instance passes on to all of its connections (see announcements.rs).
+//  - shared state, kept as string keys, counters, sets and lists of strings: accounts, login
+//    sessions, rooms and their settings, who is connected to each room, which room each user was
+//    last let into, each room's recent messages, invites, bots and webhooks, the moderation log,
+//    and the mutes, lockouts and slow mode timers of the rate limiter
+//
+// so any instance can answer any request and a websocket doesn't have to land on the instance
+// that let its user into the room.
+//
+// CHAT_BACKPLANE picks the implementation:
+//
+//  - "local" (the default): LocalBackplane below, which keeps everything in this process. For
+//    running a single server.
+//  - a redis:// URL: RedisBackplane (see redis_backplane.rs), shared by every server pointed at
+//    the same Redis. examples/backplane_broker.rs stands in for Redis when trying it out locally.
+//
+// Still kept by each instance on its own: the rate limiter's token buckets, so a client spreading
+// its requests over N instances can get up to N times the configured rates (see rate_limit.rs).
+
+// Events that can pile up for an instance that isn't keeping up before it misses some
+const EVENT_CAPACITY: usize = 1024;
+
+#[derive(Debug)]
+pub struct BackplaneError(pub String);
+
+impl fmt::Display for BackplaneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Backplane error: {}", self.0)
+    }
+}
+
+pub type BackplaneResult<T> = Result<T, BackplaneError>;
+
+// How long a key set to expire after `ttl` is really kept. Redis won't set a key to expire after no
+// time at all, so every backplane keeps it for at least a millisecond.
+pub fn kept_for(ttl: Duration) -> Duration {
+    ttl.max(Duration::from_millis(1))
+}
+
+// What is sent to the other instances
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum BackplaneEvent {
+    // Something that happened in a room
+    Room {
+        // the instance it happened on, so it can skip its own
+        origin: String,
+        room_id: String,
+        event: RoomEventKind,
+    },
+    // For everyone connected to any instance, whatever room they are in. The instance it was sent
+    // from gets it back like every other one.
+    Announcement(Announcement),
+    // Never published: handed to this instance's subscribers when events may have been missed,
+    // eg while the connection to Redis was down, so they can catch up from the shared state
+    Missed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum RoomEventKind {
+    // a chat message, kept in the room's history
+    Chat(ChatMessage),
+    // anything else the members should see
+    Broadcast(ServerWsMessage),
+    // a member was removed by an operator: everyone is told they were kicked and their
+    // connections are closed
+    Removed { user_id: String },
+    // the owner changed the room's settings: every instance's task for the room switches to them
+    // and tells the members
+    Updated { settings: Box<RoomSettings>, updated_by: String },
+    // the room is gone: everyone is told and every connection is closed
+    Deleted,
+}
+
+#[async_trait]
+pub trait Backplane: Send + Sync {
+    // Sends an event to every instance, this one included. Doesn't wait for it to go out, but
+    // events from one instance always arrive in the order they were published.
+    fn publish(&self, event: BackplaneEvent);
+
+    // Every event published from now on
+    fn subscribe(&self) -> broadcast::Receiver<BackplaneEvent>;
+
+    async fn get(&self, key: &str) -> BackplaneResult<Option<String>>;
+
+    // Keeps the value for `ttl` (see kept_for()), or until it is removed
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> BackplaneResult<()>;
+
+    // Only sets the key if it isn't there yet, keeping it for `ttl` like set(). False if it already
+    // was.
+    async fn set_new(&self, key: &str, value: &str, ttl: Option<Duration>) -> BackplaneResult<bool>;
+
+    // Only overwrites the key if it is already there. False if it wasn't.
+    async fn replace(&self, key: &str, value: &str) -> BackplaneResult<bool>;
+
+    // Removes a key and returns what it held, so only one caller can ever get it
+    async fn take(&self, key: &str) -> BackplaneResult<Option<String>>;
+
+    // Adds one to a counter and returns the new count. A counter that isn't there yet starts from
+    // 0 and is kept for `ttl`, counting on doesn't extend it.
+    async fn increment(&self, key: &str, ttl: Option<Duration>) -> BackplaneResult<u64>;
+
+    async fn add_member(&self, set: &str, member: &str) -> BackplaneResult<()>;
+
+    async fn remove_member(&self, set: &str, member: &str) -> BackplaneResult<()>;
+
+    async fn members(&self, set: &str) -> BackplaneResult<Vec<String>>;
+
+    async fn count_members(&self, set: &str) -> BackplaneResult<usize>;
+
+    // Adds a value to the front of a list and drops whatever is past the first `max_len`
+    async fn push(&self, list: &str, value: &str, max_len: usize) -> BackplaneResult<()>;
+
+    // The first `limit` values of a list, the newest first
+    async fn latest(&self, list: &str, limit: usize) -> BackplaneResult<Vec<String>>;
+
+    // Drops whatever is past the first `max_len` values of a list, all of them for 0
+    async fn trim(&self, list: &str, max_len: usize) -> BackplaneResult<()>;
+
+    // Forgets expired keys, for backplanes that don't do it on their own
+    async fn prune(&self) {}
+
+    // Waits until every event published so far has gone out, for shutting down
+    async fn flush(&self) {}
+
+    // Fails if it can't be reached, for /readyz
+    async fn ping(&self) -> BackplaneResult<()>;
+
+    // For /status
+    async fn stats(&self) -> BackplaneResult<StorageStats>;
+}
+
+// "local" or a redis:// URL, see the top of this file
+pub async fn from_config(backplane: &str) -> BackplaneResult<Arc<dyn Backplane>> {
+    if backplane == "local" {
+        return Ok(Arc::new(LocalBackplane::default()));
+    }
+    Ok(Arc::new(RedisBackplane::connect(backplane).await?))
+}
+
+struct Entry {
+    value: String,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn live(&self) -> bool {
+        self.expires_at.is_none_or(|at| at > Instant::now())
+    }
+}
+
+// Everything in this process. Several servers started in the same process with one
+// LocalBackplane share it just like separate servers share a Redis.
+pub struct LocalBackplane {
+    values: Mutex<HashMap<String, Entry>>,
+    sets: Mutex<HashMap<String, HashSet<String>>>,
+    // newest first
+    lists: Mutex<HashMap<String, VecDeque<String>>>,
+    events: broadcast::Sender<BackplaneEvent>,
+}
+
+impl Default for LocalBackplane {
+    fn default() -> Self {
+        LocalBackplane {
+            values: Mutex::new(HashMap::new()),
+            sets: Mutex::new(HashMap::new()),
+            lists: Mutex::new(HashMap::new()),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+}
+
+#[async_trait]
+impl Backplane for LocalBackplane {
+    fn publish(&self, event: BackplaneEvent) {
+        let _ = self.events.send(event);
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<BackplaneEvent> {
+        self.events.subscribe()
+    }
+
+    async fn get(&self, key: &str) -> BackplaneResult<Option<String>> {
+        let values = self.values.lock().await;
+        Ok(values.get(key).filter(|entry| entry.live()).map(|entry| entry.value.clone()))
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> BackplaneResult<()> {
+        let entry = Entry {
+            value: value.to_string(),
+            expires_at: ttl.map(|ttl| Instant::now() + kept_for(ttl)),
+        };
+        self.values.lock().await.insert(key.to_string(), entry);
+        Ok(())
+    }
+
+    async fn set_new(&self, key: &str, value: &str, ttl: Option<Duration>) -> BackplaneResult<bool> {
+        let mut values = self.values.lock().await;
+        if values.get(key).is_some_and(Entry::live) {
+            return Ok(false);
+        }
+        let entry = Entry {
+            value: value.to_string(),
+            expires_at: ttl.map(|ttl| Instant::now() + kept_for(ttl)),
+        };
+        values.insert(key.to_string(), entry);
+        Ok(true)
+    }
+
+    async fn replace(&self, key: &str, value: &str) -> BackplaneResult<bool> {
+        let mut values = self.values.lock().await;
+        match values.get_mut(key).filter(|entry| entry.live()) {
+            Some(entry) => {
+                entry.value = value.to_string();
+                entry.expires_at = None;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn take(&self, key: &str) -> BackplaneResult<Option<String>> {
+        let entry = self.values.lock().await.remove(key);
+        Ok(entry.filter(Entry::live).map(|entry| entry.value))
+    }
+
+    async fn increment(&self, key: &str, ttl: Option<Duration>) -> BackplaneResult<u64> {
+        let mut values = self.values.lock().await;
+        let entry = match values.get_mut(key).filter(|entry| entry.live()) {
+            Some(entry) => entry,
+            None => {
+                let entry = Entry {
+                    value: "0".to_string(),
+                    expires_at: ttl.map(|ttl| Instant::now() + kept_for(ttl)),
+                };
+                values.insert(key.to_string(), entry);
+                values.get_mut(key).expect("just inserted")
+            }
+        };
+        let count = entry
+            .value
+            .parse::<u64>()
+            .map_err(|_| BackplaneError(format!("{} doesn't hold a count", key)))?
+            + 1;
+        entry.value = count.to_string();
+        Ok(count)
+    }
+
+    async fn add_member(&self, set: &str, member: &str) -> BackplaneResult<()> {
+        self.sets
+            .lock()
+            .await
+            .entry(set.to_string())
+            .or_default()
+            .insert(member.to_string());
+        Ok(())
+    }
+
+    async fn remove_member(&self, set: &str, member: &str) -> BackplaneResult<()> {
+        let mut sets = self.sets.lock().await;
+        if let Some(members) = sets.get_mut(set) {
+            members.remove(member);
+            if members.is_empty() {
+                sets.remove(set);
+            }
+        }
+        Ok(())
+    }
+
+    async fn members(&self, set: &str) -> BackplaneResult<Vec<String>> {
+        let sets = self.sets.lock().await;
+        Ok(sets.get(set).map(|members| members.iter().cloned().collect()).unwrap_or_default())
+    }
+
+    async fn count_members(&self, set: &str) -> BackplaneResult<usize> {
+        Ok(self.sets.lock().await.get(set).map_or(0, HashSet::len))
+    }
+
+    async fn push(&self, list: &str, value: &str, max_len: usize) -> BackplaneResult<()> {
+        self.lists
+            .lock()
+            .await
+            .entry(list.to_string())
+            .or_default()
+            .push_front(value.to_string());
+        self.trim(list, max_len).await
+    }
+
+    async fn latest(&self, list: &str, limit: usize) -> BackplaneResult<Vec<String>> {
+        let lists = self.lists.lock().await;
+        Ok(lists
+            .get(list)
+            .map(|values| values.iter().take(limit).cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn trim(&self, list: &str, max_len: usize) -> BackplaneResult<()> {
+        let mut lists = self.lists.lock().await;
+        if let Some(values) = lists.get_mut(list) {
+            values.truncate(max_len);
+            if values.is_empty() {
+                lists.remove(list);
+            }
+        }
+        Ok(())
+    }
+
+    async fn prune(&self) {
+        self.values.lock().await.retain(|_, entry| entry.live());
+    }
+
+    async fn ping(&self) -> BackplaneResult<()> {
+        Ok(())
+    }
+
+    async fn stats(&self) -> BackplaneResult<StorageStats> {
+        let values = self.values.lock().await.len();
+        let sets = self.sets.lock().await.len();
+        let lists = self.lists.lock().await.len();
+        Ok(StorageStats {
+            backend: "local".to_string(),
+            keys: values + sets + lists,
+        })
+    }
+}
+
+// What every backplane has to do, run against each of them (see also redis_backplane.rs)
+#[cfg(test)]
+pub mod contract {
+    use super::*;
+    use crate::rooms::RoomSettings;
+    use crate::message::RoomVisibility;
+
+    // Long enough for a key kept for kept_for(Duration::ZERO) to be gone
+    async fn let_expire() {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    pub async fn replace_only_overwrites_existing_keys(backplane: &dyn Backplane) {
+        assert!(!backplane.replace("room:lounge", "new").await.unwrap());
+        assert_eq!(backplane.get("room:lounge").await.unwrap(), None);
+
+        backplane.set("room:lounge", "old", None).await.unwrap();
+        assert!(backplane.replace("room:lounge", "new").await.unwrap());
+        assert_eq!(backplane.get("room:lounge").await.unwrap().as_deref(), Some("new"));
+
+        backplane.take("room:lounge").await.unwrap();
+        assert!(!backplane.replace("room:lounge", "newer").await.unwrap());
+        assert_eq!(backplane.get("room:lounge").await.unwrap(), None);
+    }
+
+    pub async fn set_new_keeps_what_is_there(backplane: &dyn Backplane) {
+        assert!(backplane.set_new("user:alice", "first", None).await.unwrap());
+        assert!(!backplane.set_new("user:alice", "second", None).await.unwrap());
+        assert_eq!(backplane.get("user:alice").await.unwrap().as_deref(), Some("first"));
+
+        // an expired key is as good as gone
+        assert!(backplane.set_new("slow:alice", "first", Some(Duration::ZERO)).await.unwrap());
+        let_expire().await;
+        assert!(backplane.set_new("slow:alice", "second", None).await.unwrap());
+        assert_eq!(backplane.get("slow:alice").await.unwrap().as_deref(), Some("second"));
+    }
+
+    pub async fn take_gives_the_value_once(backplane: &dyn Backplane) {
+        backplane.set("invite:abc", "lounge", None).await.unwrap();
+        assert_eq!(backplane.take("invite:abc").await.unwrap().as_deref(), Some("lounge"));
+        assert_eq!(backplane.take("invite:abc").await.unwrap(), None);
+        assert_eq!(backplane.get("invite:abc").await.unwrap(), None);
+
+        backplane.set("session:abc", "alice", Some(Duration::ZERO)).await.unwrap();
+        let_expire().await;
+        assert_eq!(backplane.take("session:abc").await.unwrap(), None);
+    }
+
+    pub async fn increment_counts_from_zero(backplane: &dyn Backplane) {
+        assert_eq!(backplane.increment("uses:abc", None).await.unwrap(), 1);
+        assert_eq!(backplane.increment("uses:abc", None).await.unwrap(), 2);
+        assert_eq!(backplane.increment("uses:other", None).await.unwrap(), 1);
+
+        // counting on doesn't extend the expiry, and expired counters start over
+        let ttl = Some(Duration::from_millis(50));
+        assert_eq!(backplane.increment("strikes:alice", ttl).await.unwrap(), 1);
+        assert_eq!(backplane.increment("strikes:alice", ttl).await.unwrap(), 2);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(backplane.increment("strikes:alice", Some(Duration::ZERO)).await.unwrap(), 1);
+        let_expire().await;
+        assert_eq!(backplane.increment("strikes:alice", Some(Duration::ZERO)).await.unwrap(), 1);
+
+        backplane.set("user:alice", "hash", None).await.unwrap();
+        assert!(backplane.increment("user:alice", None).await.is_err());
+    }
+
+    pub async fn lists_keep_the_newest_values(backplane: &dyn Backplane) {
+        for value in ["one", "two", "three"] {
+            backplane.push("history:lounge", value, 2).await.unwrap();
+        }
+        assert_eq!(backplane.latest("history:lounge", 10).await.unwrap(), vec!["three", "two"]);
+        assert_eq!(backplane.latest("history:lounge", 1).await.unwrap(), vec!["three"]);
+
+        backplane.trim("history:lounge", 0).await.unwrap();
+        assert!(backplane.latest("history:lounge", 10).await.unwrap().is_empty());
+        assert!(backplane.latest("history:nowhere", 10).await.unwrap().is_empty());
+    }
+
+    pub async fn replace_skips_expired_keys(backplane: &dyn Backplane) {
+        backplane.set("session:abc", "old", Some(Duration::ZERO)).await.unwrap();
+        let_expire().await;
+        assert!(!backplane.replace("session:abc", "new").await.unwrap());
+    }
+
+    fn updated(room_id: &str) -> BackplaneEvent {
+        BackplaneEvent::Room {
+            origin: "elsewhere".to_string(),
+            room_id: room_id.to_string(),
+            event: RoomEventKind::Updated {
+                settings: Box::new(RoomSettings::new("alice", RoomVisibility::Public, None)),
+                updated_by: "alice".to_string(),
+            },
+        }
+    }
+
+    fn room_of(event: BackplaneEvent) -> String {
+        match event {
+            BackplaneEvent::Room { room_id, .. } => room_id,
+            _ => panic!("not a room event"),
+        }
+    }
+
+    pub async fn events_arrive_in_order(backplane: &dyn Backplane) {
+        let mut events = backplane.subscribe();
+        // the subscription can take a moment to be set up, so wait for one to come through first
+        loop {
+            backplane.publish(updated("ready"));
+            match tokio::time::timeout(Duration::from_millis(50), events.recv()).await {
+                Ok(event) => {
+                    assert_eq!(room_of(event.unwrap()), "ready");
+                    break;
+                }
+                Err(_) => continue,
+            }
+        }
+        while let Ok(Ok(_)) = tokio::time::timeout(Duration::from_millis(50), events.recv()).await {}
+
+        for n in 0..100 {
+            backplane.publish(updated(&format!("room-{}", n)));
+        }
+        backplane.flush().await;
+        for n in 0..100 {
+            assert_eq!(room_of(events.recv().await.unwrap()), format!("room-{}", n));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replace_only_overwrites_existing_keys() {
+        contract::replace_only_overwrites_existing_keys(&LocalBackplane::default()).await;
+    }
+
+    #[tokio::test]
+    async fn set_new_keeps_what_is_there() {
+        contract::set_new_keeps_what_is_there(&LocalBackplane::default()).await;
+    }
+
+    #[tokio::test]
+    async fn take_gives_the_value_once() {
+        contract::take_gives_the_value_once(&LocalBackplane::default()).await;
+    }
+
+    #[tokio::test]
+    async fn increment_counts_from_zero() {
+        contract::increment_counts_from_zero(&LocalBackplane::default()).await;
+    }
+
+    #[tokio::test]
+    async fn lists_keep_the_newest_values() {
+        contract::lists_keep_the_newest_values(&LocalBackplane::default()).await;
+    }
+
+    #[tokio::test]
+    async fn replace_skips_expired_keys() {
+        contract::replace_skips_expired_keys(&LocalBackplane::default()).await;
+    }
+
+    #[tokio::test]
+    async fn events_arrive_in_order() {
+        contract::events_arrive_in_order(&LocalBackplane::default()).await;
+    }
+}
@@ -0,0 +1,419 @@
+// Command line tool for the admin API, for the people running the server. It needs the token the
+// server was started with (CHAT_ADMIN_TOKEN), from the same environment variable or --token:
+//
+//      CHAT_ADMIN_TOKEN=... cargo run --bin chatadmin -- rooms
+//
+// --url points it at another server (default http://127.0.0.1:3000, or CHAT_ADMIN_URL), and
+// --json prints the server's answers as they came instead of as text.
+//
+// With several server instances, anything kept by each instance on its own (the moderation log,
+// invites, bots and webhooks, see backplane.rs) is what the instance that answered has.
+
+use reqwest::Client;
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::{BufRead, Write};
+
+// the server is a binary, so the protocol types are pulled in straight from its source
+#[path = "../message.rs"]
+mod message;
+use message::{
+    AdminListUsersResponse, AdminRoomRequest, AdminRoomResponse, AdminUserRequest,
+    AdminUserResponse, AnnouncementRequest, CancelAnnouncementRequest, ErrorResponse,
+    ListAnnouncementsResponse, ListRoomsResponse, MaintenanceRequest, ModerationLogRequest,
+    ModerationLogResponse, MotdRequest, ResetPasswordRequest, StatusResponse, SuccessResponse,
+};
+
+const USAGE: &str = "Usage: chatadmin [--url URL] [--token TOKEN] [--json] COMMAND
+
+Commands:
+    status                          what the server is up to
+    rooms                           every room, unlisted ones too
+    room ROOM                       a room's settings, members, invites, bots and webhooks
+    delete-room ROOM                tell everyone in the room it is gone and delete it
+    users                           every account
+    user USER                       where a user is and how many sessions they have
+    delete-user USER                log a user out everywhere and delete their account
+    disconnect USER                 log a user out everywhere and kick them out of their room
+    reset-password USER             set a new password, read from stdin, and log them out
+    log [LIMIT]                     the moderation log, newest first
+    announce [--rooms ROOM,...] [--at TIME] MESSAGE...
+                                    post a message as \"system\" into the rooms (every room when
+                                    left out), at TIME (RFC 3339, eg 2030-01-31T18:00:00Z) or now
+    announcements                   the announcements waiting to be sent, soonest first
+    cancel-announcement ID          don't send a scheduled announcement after all
+    motd set MESSAGE...             show MESSAGE to everyone when they log in
+    motd clear
+    maintenance on [MESSAGE...]     turn people away with MESSAGE until it is turned off
+    maintenance off";
+
+struct Admin {
+    http: Client,
+    url: String,
+    token: String,
+    json: bool,
+}
+
+#[tokio::main]
+async fn main() {
+    let mut url = std::env::var("CHAT_ADMIN_URL").unwrap_or_else(|_| "http://127.0.0.1:3000".to_string());
+    let mut token = std::env::var("CHAT_ADMIN_TOKEN").unwrap_or_default();
+    let mut json = false;
+
+    let mut args = std::env::args().skip(1).peekable();
+    while let Some(flag) = args.peek().filter(|arg| arg.starts_with("--")).cloned() {
+        args.next();
+        match flag.as_str() {
+            "--json" => json = true,
+            "--url" | "--token" => {
+                let Some(value) = args.next() else {
+                    usage(&format!("{} needs a value", flag));
+                };
+                if flag == "--url" {
+                    url = value.trim_end_matches('/').to_string();
+                } else {
+                    token = value;
+                }
+            }
+            "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => usage(&format!("Unknown option {}", flag)),
+        }
+    }
+    let args: Vec<String> = args.collect();
+    let Some((command, args)) = args.split_first() else {
+        usage("No command given");
+    };
+    if token.is_empty() {
+        usage("Set CHAT_ADMIN_TOKEN or pass --token");
+    }
+
+    let admin = Admin {
+        http: Client::new(),
+        url,
+        token,
+        json,
+    };
+    match (command.as_str(), args) {
+        ("status", []) => status(&admin).await,
+        ("rooms", []) => rooms(&admin).await,
+        ("room", [room_id]) => room(&admin, room_id).await,
+        ("delete-room", [room_id]) => {
+            let req = AdminRoomRequest { room_id: room_id.clone() };
+            done(&admin, admin.post("delete_room", &req).await);
+        }
+        ("users", []) => users(&admin).await,
+        ("user", [user_id]) => user(&admin, user_id).await,
+        ("delete-user", [user_id]) => {
+            let req = AdminUserRequest { user_id: user_id.clone() };
+            done(&admin, admin.post("delete_user", &req).await);
+        }
+        ("disconnect", [user_id]) => {
+            let req = AdminUserRequest { user_id: user_id.clone() };
+            done(&admin, admin.post("disconnect_user", &req).await);
+        }
+        ("reset-password", [user_id]) => {
+            let req = ResetPasswordRequest {
+                user_id: user_id.clone(),
+                new_password: read_password(user_id),
+            };
+            done(&admin, admin.post("reset_password", &req).await);
+        }
+        ("log", []) => log(&admin, None).await,
+        ("log", [limit]) => match limit.parse() {
+            Ok(limit) => log(&admin, Some(limit)).await,
+            Err(_) => usage("LIMIT needs to be a number"),
+        },
+        ("announce", words) => {
+            let req = announcement(words);
+            done(&admin, admin.post("announce", &req).await);
+        }
+        ("announcements", []) => announcements(&admin).await,
+        ("cancel-announcement", [announcement_id]) => {
+            let req = CancelAnnouncementRequest { announcement_id: announcement_id.clone() };
+            done(&admin, admin.post("cancel_announcement", &req).await);
+        }
+        ("motd", [set, words @ ..]) if set == "set" && !words.is_empty() => {
+            let req = MotdRequest { motd: Some(words.join(" ")) };
+            done(&admin, admin.post("motd", &req).await);
+        }
+        ("motd", [clear]) if clear == "clear" => {
+            done(&admin, admin.post("motd", &MotdRequest { motd: None }).await);
+        }
+        ("maintenance", [on, words @ ..]) if on == "on" => {
+            let req = MaintenanceRequest {
+                enabled: true,
+                message: (!words.is_empty()).then(|| words.join(" ")),
+            };
+            done(&admin, admin.post("maintenance", &req).await);
+        }
+        ("maintenance", [off]) if off == "off" => {
+            let req = MaintenanceRequest { enabled: false, message: None };
+            done(&admin, admin.post("maintenance", &req).await);
+        }
+        _ => usage(&format!("Unknown command or wrong arguments: {} {}", command, args.join(" "))),
+    }
+}
+
+// announce's arguments: its options, then the message
+fn announcement(mut words: &[String]) -> AnnouncementRequest {
+    let mut room_ids = None;
+    let mut send_at = None;
+    while let [option, value, rest @ ..] = words {
+        match option.as_str() {
+            "--rooms" => room_ids = Some(value.split(',').map(|room_id| room_id.trim().to_string()).collect()),
+            "--at" => send_at = Some(value.clone()),
+            _ => break,
+        }
+        words = rest;
+    }
+    if words.is_empty() {
+        usage("announce needs a message");
+    }
+    AnnouncementRequest {
+        content: words.join(" "),
+        room_ids,
+        send_at,
+    }
+}
+
+fn usage(problem: &str) -> ! {
+    eprintln!("{}\n\n{}", problem, USAGE);
+    std::process::exit(2);
+}
+
+impl Admin {
+    // POSTs to /admin/<path>
+    async fn post<T: DeserializeOwned>(&self, path: &str, body: &impl Serialize) -> T {
+        let url = format!("{}/admin/{}", self.url, path);
+        self.send(self.http.post(url).json(body)).await
+    }
+
+    // Answers as it came with --json, otherwise parsed into T. Exits when the request fails.
+    async fn send<T: DeserializeOwned>(&self, request: reqwest::RequestBuilder) -> T {
+        let response = match request.bearer_auth(&self.token).send().await {
+            Ok(response) => response,
+            Err(e) => fail(&format!("Can't reach the server: {}", e)),
+        };
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        if !status.is_success() {
+            match serde_json::from_str::<ErrorResponse>(&body) {
+                Ok(error) => fail(&describe(&error)),
+                Err(_) => fail(&format!("{}: {}", status, body)),
+            }
+        }
+        if self.json {
+            println!("{}", body);
+        }
+        serde_json::from_str(&body).unwrap_or_else(|e| fail(&format!("Unexpected answer from the server ({}): {}", e, body)))
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+fn describe(error: &ErrorResponse) -> String {
+    match error {
+        ErrorResponse::AuthenticationFailed { message } => format!("Authentication failed: {}", message),
+        ErrorResponse::UserAlreadyExists { user_id } => format!("User {} already exists", user_id),
+        ErrorResponse::UserNotFound { user_id } => format!("User {} not found", user_id),
+        ErrorResponse::RoomNotFound { room_id } => format!("Room {} not found", room_id),
+        ErrorResponse::RoomAlreadyExists { room_id } => format!("Room {} already exists", room_id),
+        ErrorResponse::RoomFull { room_id } => format!("Room {} is full", room_id),
+        ErrorResponse::CommandNotFound { command } => format!("Unknown command /{}", command),
+        ErrorResponse::NotInRoom { room_id } => format!("Not in room {}", room_id),
+        ErrorResponse::ServerError { message } => format!("Server error: {}", message),
+        ErrorResponse::RateLimited { message, retry_after_secs } => {
+            format!("{} (try again in {}s)", message, retry_after_secs)
+        }
+        ErrorResponse::ValidationFailed { errors } => {
+            let broken: Vec<String> = errors.iter().map(|err| format!("{}: {}", err.field, err.message)).collect();
+            format!("Does not meet the requirements: {}", broken.join("; "))
+        }
+        ErrorResponse::InvalidPassword { message }
+        | ErrorResponse::InvalidPermissions { message }
+        | ErrorResponse::InviteInvalid { message }
+        | ErrorResponse::Unavailable { message } => message.clone(),
+        ErrorResponse::AnnouncementNotFound { announcement_id } => {
+            format!("No announcement {} waiting to be sent", announcement_id)
+        }
+    }
+}
+
+// What the server said it did
+fn done(admin: &Admin, response: SuccessResponse) {
+    if !admin.json {
+        println!("{}", response.message);
+    }
+}
+
+// Read from stdin rather than taken as an argument, so it doesn't end up in the shell's history
+fn read_password(user_id: &str) -> String {
+    eprint!("New password for {}: ", user_id);
+    let _ = std::io::stderr().flush();
+    let mut password = String::new();
+    if std::io::stdin().lock().read_line(&mut password).is_err() {
+        fail("Couldn't read the password");
+    }
+    password.trim_end_matches(['\r', '\n']).to_string()
+}
+
+async fn status(admin: &Admin) {
+    let url = format!("{}/status", admin.url);
+    let status: StatusResponse = admin.send(admin.http.get(url)).await;
+    if admin.json {
+        return;
+    }
+    let unknown = || "unknown (storage unreachable)".to_string();
+    println!("version:          {}", status.version);
+    println!("instance:         {}", status.instance_id);
+    println!("uptime:           {}s", status.uptime_secs);
+    println!("shutting down:    {}", status.shutting_down);
+    println!("maintenance:      {}", status.maintenance.as_deref().unwrap_or("off"));
+    println!("motd:             {}", status.motd.as_deref().unwrap_or("none"));
+    println!("connected users:  {}", status.connected_users);
+    println!("rooms:            {}", status.rooms.map_or_else(unknown, |rooms| rooms.to_string()));
+    println!("rooms running:    {}", status.rooms_running);
+    match status.storage {
+        Some(storage) => println!("storage:          {} ({} keys)", storage.backend, storage.keys),
+        None => println!("storage:          {}", unknown()),
+    }
+}
+
+async fn rooms(admin: &Admin) {
+    let response: ListRoomsResponse = admin.post("list_rooms", &()).await;
+    if admin.json {
+        return;
+    }
+    if response.rooms.is_empty() {
+        println!("No rooms");
+    }
+    for room in response.rooms {
+        println!(
+            "{:<24} {:<10} owner {:<20} {} connected",
+            room.room_id,
+            format!("{:?}", room.visibility),
+            room.owner,
+            room.users_count
+        );
+    }
+}
+
+async fn room(admin: &Admin, room_id: &str) {
+    let req = AdminRoomRequest { room_id: room_id.to_string() };
+    let response: AdminRoomResponse = admin.post("room_info", &req).await;
+    if admin.json {
+        return;
+    }
+    let room = response.room;
+    let details = response.details;
+    let max_members = details.max_members.map_or_else(|| "no limit".to_string(), |max| max.to_string());
+    let slow_mode = match details.slow_mode_secs {
+        0 => "off".to_string(),
+        secs => format!("{}s", secs),
+    };
+    println!("room:        {}", room.room_id);
+    println!("owner:       {}", room.owner);
+    println!("created:     {}", details.created_at);
+    println!("topic:       {}", details.topic.as_deref().unwrap_or("none"));
+    println!("visibility:  {:?}", room.visibility);
+    println!("password:    {}", if response.password_protected { "yes" } else { "no" });
+    println!("max members: {}", max_members);
+    println!("slow mode:   {}", slow_mode);
+    println!("history:     {} messages", details.history_len);
+    println!("members:     {}", list(&response.members));
+    if let Some(description) = details.description {
+        println!("description:");
+        for line in description.lines() {
+            println!("    {}", line);
+        }
+    }
+    println!("invites:");
+    for invite in response.invites {
+        let uses = invite.max_uses.map_or_else(|| invite.uses.to_string(), |max| format!("{}/{}", invite.uses, max));
+        let expires = invite.expires_at.unwrap_or_else(|| "never".to_string());
+        println!("    {} by {}, used {}, expires {}", invite.code, invite.created_by, uses, expires);
+    }
+    println!("bot commands:");
+    for command in response.bot_commands {
+        println!("    /{} ({}) {}", command.command, command.bot_id, command.description);
+    }
+    println!("webhooks:");
+    for webhook in response.webhooks {
+        let what = match (webhook.name, webhook.url) {
+            (Some(name), _) => format!("incoming, posts as {}", name),
+            (None, Some(url)) => format!("outgoing to {}", url),
+            (None, None) => "unknown".to_string(),
+        };
+        println!("    {} {}, by {}", webhook.webhook_id, what, webhook.created_by);
+    }
+}
+
+async fn users(admin: &Admin) {
+    let response: AdminListUsersResponse = admin.post("list_users", &()).await;
+    if admin.json {
+        return;
+    }
+    if response.users.is_empty() {
+        println!("No users");
+    }
+    for user in response.users {
+        println!("{}", user);
+    }
+}
+
+async fn user(admin: &Admin, user_id: &str) {
+    let req = AdminUserRequest { user_id: user_id.to_string() };
+    let response: AdminUserResponse = admin.post("user_info", &req).await;
+    if admin.json {
+        return;
+    }
+    let room = match (response.room_id, response.connected) {
+        (Some(room_id), true) => format!("{} (connected)", room_id),
+        (Some(room_id), false) => format!("{} (not connected)", room_id),
+        (None, _) => "none".to_string(),
+    };
+    println!("user:      {}", response.user_id);
+    println!("room:      {}", room);
+    println!("sessions:  {}", response.sessions);
+}
+
+async fn log(admin: &Admin, limit: Option<usize>) {
+    let response: ModerationLogResponse = admin.post("moderation_log", &ModerationLogRequest { limit }).await;
+    if admin.json {
+        return;
+    }
+    if response.entries.is_empty() {
+        println!("Nothing in the moderation log");
+    }
+    for entry in response.entries {
+        let room = entry.room_id.map(|room_id| format!(" in {}", room_id)).unwrap_or_default();
+        let detail = entry.detail.map(|detail| format!(": {}", detail)).unwrap_or_default();
+        println!("{} {:?} by {} on {}{}{}", entry.timestamp, entry.action, entry.actor, entry.target, room, detail);
+    }
+}
+
+async fn announcements(admin: &Admin) {
+    let response: ListAnnouncementsResponse = admin.post("list_announcements", &()).await;
+    if admin.json {
+        return;
+    }
+    if response.announcements.is_empty() {
+        println!("No announcements waiting to be sent");
+    }
+    for announcement in response.announcements {
+        let rooms = announcement.room_ids.map_or_else(|| "every room".to_string(), |room_ids| list(&room_ids));
+        println!("{} {} to {}: {}", announcement.announcement_id, announcement.send_at, rooms, announcement.content);
+    }
+}
+
+fn list(items: &[String]) -> String {
+    if items.is_empty() {
+        return "none".to_string();
+    }
+    items.join(", ")
+}
@@ -0,0 +1,461 @@
+use chrono::{DateTime, Utc};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::{net::IpAddr, sync::Arc, time::Duration};
+use chat_room_shared::commands;
+
+use crate::backplane::{Backplane, BackplaneError, BackplaneResult};
+use crate::message::{BotCommandInfo, BotReply, WebhookInfo};
+
+// Bots and webhooks let other programs take part in rooms.
+//
+//  - A bot is an account owned by a user that authenticates with an API token instead of a
+//    password. It can post into rooms and register slash commands (eg /weather) in the rooms its
+//    owner owns. When someone in the room uses the command the server POSTs it to the bot's
+//    callback URL, and whatever the bot answers with is posted into the room as the bot.
+//  - An incoming webhook posts a message into its room for anyone that knows its secret, eg a
+//    CI job announcing a build.
+//  - An outgoing webhook POSTs every message in its room (or only the ones starting with its
+//    trigger word) to a URL.
+//
+// Callback and outgoing webhook URLs must point at this machine, so the server can't be used to
+// reach into the network it runs in.
+//
+// All of it is kept in the backplane (see backplane.rs), so a bot or webhook made through one
+// server instance works through all of them. Each room's commands and webhooks are listed
+// together.
+
+const COMMAND_MAX_LEN: usize = 32;
+const TOKEN_BYTES: usize = 32;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bot {
+    pub bot_id: String,
+    pub owner: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BotCommand {
+    pub room_id: String,
+    pub command: String,
+    pub bot_id: String,
+    pub description: String,
+    pub callback_url: String,
+}
+
+impl BotCommand {
+    pub fn info(&self) -> BotCommandInfo {
+        BotCommandInfo {
+            command: self.command.clone(),
+            bot_id: self.bot_id.clone(),
+            description: self.description.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WebhookKind {
+    // posts into the room as `name` when called with the secret
+    Incoming { name: String, secret: String },
+    // gets a copy of the room's messages, all of them when there is no trigger
+    Outgoing { url: String, trigger: Option<String> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub webhook_id: String,
+    pub room_id: String,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub kind: WebhookKind,
+}
+
+impl Webhook {
+    // Never includes the secret, that is only shown once when the webhook is created
+    pub fn info(&self) -> WebhookInfo {
+        let (name, url, trigger) = match &self.kind {
+            WebhookKind::Incoming { name, .. } => (Some(name.clone()), None, None),
+            WebhookKind::Outgoing { url, trigger } => (None, Some(url.clone()), trigger.clone()),
+        };
+        WebhookInfo {
+            webhook_id: self.webhook_id.clone(),
+            room_id: self.room_id.clone(),
+            created_by: self.created_by.clone(),
+            created_at: self.created_at.to_rfc3339(),
+            name,
+            url,
+            trigger,
+        }
+    }
+
+    // Whether an outgoing webhook wants this message
+    pub fn matches(&self, content: &str) -> bool {
+        match &self.kind {
+            WebhookKind::Outgoing { trigger: None, .. } => true,
+            WebhookKind::Outgoing { trigger: Some(trigger), .. } => {
+                content.split_whitespace().next() == Some(trigger.as_str())
+            }
+            WebhookKind::Incoming { .. } => false,
+        }
+    }
+}
+
+fn bot_key(bot_id: &str) -> String {
+    format!("bot:{}", bot_id)
+}
+
+// API token -> bot_id
+fn bot_token_key(token: &str) -> String {
+    format!("bot_token:{}", token)
+}
+
+// Command names can't have a ':' in them
+fn command_key(room_id: &str, command: &str) -> String {
+    format!("bot_command:{}:{}", room_id, command)
+}
+
+// The names of the room's commands
+fn room_commands_key(room_id: &str) -> String {
+    format!("bot_commands:{}", room_id)
+}
+
+fn webhook_key(webhook_id: &str) -> String {
+    format!("webhook:{}", webhook_id)
+}
+
+// The ids of the room's webhooks
+fn room_webhooks_key(room_id: &str) -> String {
+    format!("webhooks:{}", room_id)
+}
+
+// Incoming webhook name -> webhook_id
+fn webhook_name_key(name: &str) -> String {
+    format!("webhook_name:{}", name)
+}
+
+pub struct BotStore {
+    backplane: Arc<dyn Backplane>,
+    // for callbacks and outgoing webhooks
+    http: reqwest::Client,
+}
+
+impl BotStore {
+    pub fn new(backplane: Arc<dyn Backplane>, callback_timeout: Duration) -> Self {
+        BotStore {
+            backplane,
+            http: reqwest::Client::builder()
+                .timeout(callback_timeout)
+                // a local URL redirecting somewhere else would get around the check
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    pub async fn exists(&self, bot_id: &str) -> BackplaneResult<bool> {
+        Ok(self.backplane.get(&bot_key(bot_id)).await?.is_some())
+    }
+
+    // Returns the bot's API token, which is only ever shown this once. None if the name is taken.
+    pub async fn create(&self, bot_id: &str, owner: &str) -> BackplaneResult<Option<String>> {
+        let bot = Bot {
+            bot_id: bot_id.to_string(),
+            owner: owner.to_string(),
+        };
+        if !self.backplane.set_new(&bot_key(bot_id), &to_json(&bot)?, None).await? {
+            return Ok(None);
+        }
+
+        let token = generate_secret();
+        self.backplane.set(&bot_token_key(&token), bot_id, None).await?;
+        Ok(Some(token))
+    }
+
+    // The bot an API token belongs to
+    pub async fn authenticate(&self, token: &str) -> BackplaneResult<Option<Bot>> {
+        let Some(bot_id) = self.backplane.get(&bot_token_key(token)).await? else {
+            return Ok(None);
+        };
+        self.backplane.get(&bot_key(&bot_id)).await?.map(|json| from_json(&json)).transpose()
+    }
+
+    // Replaces the command if the same bot already registered it in the room. Returns false if
+    // another bot has it.
+    pub async fn register_command(&self, command: BotCommand) -> BackplaneResult<bool> {
+        let key = command_key(&command.room_id, &command.command);
+        let json = to_json(&command)?;
+        if !self.backplane.set_new(&key, &json, None).await? {
+            match self.command(&command.room_id, &command.command).await? {
+                Some(existing) if existing.bot_id != command.bot_id => return Ok(false),
+                _ => self.backplane.set(&key, &json, None).await?,
+            }
+        }
+        self.backplane
+            .add_member(&room_commands_key(&command.room_id), &command.command)
+            .await?;
+        Ok(true)
+    }
+
+    pub async fn unregister_command(&self, room_id: &str, command: &str, bot_id: &str) -> BackplaneResult<bool> {
+        match self.command(room_id, command).await? {
+            Some(existing) if existing.bot_id == bot_id => {
+                self.backplane.take(&command_key(room_id, command)).await?;
+                self.backplane.remove_member(&room_commands_key(room_id), command).await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    pub async fn command(&self, room_id: &str, command: &str) -> BackplaneResult<Option<BotCommand>> {
+        let json = self.backplane.get(&command_key(room_id, command)).await?;
+        json.map(|json| from_json(&json)).transpose()
+    }
+
+    // Commands available in a room, sorted by name
+    pub async fn commands_in(&self, room_id: &str) -> BackplaneResult<Vec<BotCommand>> {
+        let mut commands = Vec::new();
+        for command in self.backplane.members(&room_commands_key(room_id)).await? {
+            if let Some(command) = self.command(room_id, &command).await? {
+                commands.push(command);
+            }
+        }
+        commands.sort_by(|a, b| a.command.cmp(&b.command));
+        Ok(commands)
+    }
+
+    pub async fn create_webhook(&self, room_id: &str, created_by: &str, kind: WebhookKind) -> BackplaneResult<Webhook> {
+        let webhook = Webhook {
+            webhook_id: uuid::Uuid::new_v4().to_string(),
+            room_id: room_id.to_string(),
+            created_by: created_by.to_string(),
+            created_at: Utc::now(),
+            kind,
+        };
+        if let WebhookKind::Incoming { name, .. } = &webhook.kind {
+            self.backplane.set(&webhook_name_key(name), &webhook.webhook_id, None).await?;
+        }
+        self.backplane.set(&webhook_key(&webhook.webhook_id), &to_json(&webhook)?, None).await?;
+        self.backplane.add_member(&room_webhooks_key(room_id), &webhook.webhook_id).await?;
+        Ok(webhook)
+    }
+
+    pub async fn webhook(&self, webhook_id: &str) -> BackplaneResult<Option<Webhook>> {
+        let json = self.backplane.get(&webhook_key(webhook_id)).await?;
+        json.map(|json| from_json(&json)).transpose()
+    }
+
+    pub async fn delete_webhook(&self, webhook_id: &str) -> BackplaneResult<Option<Webhook>> {
+        let Some(json) = self.backplane.take(&webhook_key(webhook_id)).await? else {
+            return Ok(None);
+        };
+        let webhook: Webhook = from_json(&json)?;
+        if let WebhookKind::Incoming { name, .. } = &webhook.kind {
+            self.backplane.take(&webhook_name_key(name)).await?;
+        }
+        self.backplane.remove_member(&room_webhooks_key(&webhook.room_id), webhook_id).await?;
+        Ok(Some(webhook))
+    }
+
+    // Oldest first
+    pub async fn webhooks_in(&self, room_id: &str) -> BackplaneResult<Vec<Webhook>> {
+        let mut webhooks = Vec::new();
+        for webhook_id in self.backplane.members(&room_webhooks_key(room_id)).await? {
+            if let Some(webhook) = self.webhook(&webhook_id).await? {
+                webhooks.push(webhook);
+            }
+        }
+        webhooks.sort_by_key(|webhook| webhook.created_at);
+        Ok(webhooks)
+    }
+
+    // Drops the commands and webhooks of a room that was deleted. The bots themselves belong to
+    // their owner and stay.
+    pub async fn forget_room(&self, room_id: &str) -> BackplaneResult<()> {
+        for command in self.backplane.members(&room_commands_key(room_id)).await? {
+            self.backplane.take(&command_key(room_id, &command)).await?;
+            self.backplane.remove_member(&room_commands_key(room_id), &command).await?;
+        }
+        for webhook_id in self.backplane.members(&room_webhooks_key(room_id)).await? {
+            self.delete_webhook(&webhook_id).await?;
+            self.backplane.remove_member(&room_webhooks_key(room_id), &webhook_id).await?;
+        }
+        Ok(())
+    }
+
+    // Incoming webhook names share the user namespace, so nobody can post as someone else
+    pub async fn webhook_name_taken(&self, name: &str) -> BackplaneResult<bool> {
+        Ok(self.backplane.get(&webhook_name_key(name)).await?.is_some())
+    }
+
+    // POSTs the payload and reads the answer as a BotReply. Bots that have nothing to say can
+    // answer with an empty body.
+    pub async fn deliver<T: serde::Serialize>(&self, url: &str, payload: &T) -> Result<BotReply, String> {
+        let response = self
+            .http
+            .post(url)
+            .json(payload)
+            .send()
+            .await
+            .map_err(|e| format!("{} did not answer: {}", url, e))?;
+        if !response.status().is_success() {
+            return Err(format!("{} answered with {}", url, response.status()));
+        }
+        let body = response.text().await.map_err(|e| e.to_string())?;
+        if body.trim().is_empty() {
+            return Ok(BotReply::default());
+        }
+        serde_json::from_str(&body).map_err(|e| format!("{} sent an invalid reply: {}", url, e))
+    }
+}
+
+// eg "weather" from "/weather paris", None if the message isn't a command
+pub fn parse_command(content: &str) -> Option<(&str, &str)> {
+    let rest = content.strip_prefix('/')?;
+    let (command, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    (!command.is_empty()).then_some((command, args.trim()))
+}
+
+pub fn validate_command(command: &str) -> Option<String> {
+    if command.is_empty() || command.chars().count() > COMMAND_MAX_LEN {
+        return Some(format!("Must be between 1 and {} characters", COMMAND_MAX_LEN));
+    }
+    if !command.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-') {
+        return Some("Can only contain lowercase letters, digits, '_' and '-'".to_string());
+    }
+    // The client handles its own commands (names and aliases) itself, a bot registering one of
+    // these would never see it used
+    if commands::find(command).is_some() {
+        return Some(format!("/{} is one of the client's own commands", command));
+    }
+    None
+}
+
+// Only http URLs on a loopback address or localhost, there is no need for TLS to reach this machine
+pub fn validate_local_url(url: &str) -> Option<String> {
+    let Some(rest) = url.strip_prefix("http://") else {
+        return Some("Must be an http:// URL".to_string());
+    };
+
+    // host[:port][/path], with IPv6 hosts in brackets
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    if authority.contains('@') {
+        return Some("Must not contain credentials".to_string());
+    }
+    let host = match authority.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+        None => authority.split(':').next().unwrap_or_default(),
+    };
+
+    let local = host.eq_ignore_ascii_case("localhost")
+        || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback());
+    if local {
+        None
+    } else {
+        Some("Must point at this machine (localhost or a loopback address)".to_string())
+    }
+}
+
+// Compares secrets without giving away how much of a guess was right through timing
+pub fn secrets_match(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn to_json<T: Serialize>(value: &T) -> BackplaneResult<String> {
+    serde_json::to_string(value).map_err(|e| BackplaneError(e.to_string()))
+}
+
+fn from_json<T: for<'de> Deserialize<'de>>(json: &str) -> BackplaneResult<T> {
+    serde_json::from_str(json).map_err(|e| BackplaneError(e.to_string()))
+}
+
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backplane::LocalBackplane;
+
+    fn store() -> BotStore {
+        BotStore::new(Arc::new(LocalBackplane::default()), Duration::from_secs(1))
+    }
+
+    fn weather(bot_id: &str) -> BotCommand {
+        BotCommand {
+            room_id: "room".to_string(),
+            command: "weather".to_string(),
+            bot_id: bot_id.to_string(),
+            description: "Today's weather".to_string(),
+            callback_url: "http://localhost:9000/weather".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn tokens_authenticate_their_bot() {
+        let store = store();
+        let token = store.create("helper", "alice").await.unwrap().unwrap();
+        assert!(store.create("helper", "bob").await.unwrap().is_none());
+
+        let bot = store.authenticate(&token).await.unwrap().unwrap();
+        assert_eq!((bot.bot_id.as_str(), bot.owner.as_str()), ("helper", "alice"));
+        assert!(store.authenticate("not-a-token").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn commands_belong_to_the_bot_that_registered_them() {
+        let store = store();
+        assert!(store.register_command(weather("helper")).await.unwrap());
+        assert!(!store.register_command(weather("other")).await.unwrap());
+        // the same bot can update its own
+        assert!(store.register_command(weather("helper")).await.unwrap());
+        assert_eq!(store.commands_in("room").await.unwrap().len(), 1);
+
+        assert!(!store.unregister_command("room", "weather", "other").await.unwrap());
+        assert!(store.unregister_command("room", "weather", "helper").await.unwrap());
+        assert!(store.command("room", "weather").await.unwrap().is_none());
+        assert!(store.commands_in("room").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn deleted_rooms_lose_their_commands_and_webhooks() {
+        let store = store();
+        store.register_command(weather("helper")).await.unwrap();
+        let kind = WebhookKind::Incoming {
+            name: "ci".to_string(),
+            secret: generate_secret(),
+        };
+        let webhook = store.create_webhook("room", "alice", kind).await.unwrap();
+        assert!(store.webhook_name_taken("ci").await.unwrap());
+
+        store.forget_room("room").await.unwrap();
+        assert!(store.commands_in("room").await.unwrap().is_empty());
+        assert!(store.webhook(&webhook.webhook_id).await.unwrap().is_none());
+        assert!(store.webhooks_in("room").await.unwrap().is_empty());
+        assert!(!store.webhook_name_taken("ci").await.unwrap());
+    }
+
+    #[test]
+    fn client_commands_are_reserved() {
+        for spec in commands::COMMANDS {
+            let name = spec.name.trim_start_matches('/');
+            assert!(validate_command(name).is_some(), "{} isn't reserved", name);
+            for alias in spec.aliases {
+                assert!(validate_command(alias.trim_start_matches('/')).is_some(), "{} isn't reserved", alias);
+            }
+        }
+        assert!(validate_command("markdown").is_some());
+        assert!(validate_command("topic").is_some());
+    }
+
+    #[test]
+    fn other_commands_are_allowed() {
+        assert_eq!(validate_command("weather"), None);
+        assert_eq!(validate_command("roll-2"), None);
+        assert!(validate_command("Weather").is_some());
+        assert!(validate_command("").is_some());
+    }
+}
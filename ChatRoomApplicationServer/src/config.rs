@@ -0,0 +1,200 @@
+use std::{str::FromStr, time::Duration};
+
+// Server settings. Everything has a sensible default and can be overridden with an
+// environment variable so we don't need a config file to run the server locally.
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub bind_addr: String,
+    // "local", or a redis:// URL shared by every instance of the server (see backplane.rs)
+    pub backplane: String,
+    // what operators authenticate with for /status, None turns it off
+    pub admin_token: Option<String>,
+    // the message of the day until it is changed through the admin API
+    pub motd: Option<String>,
+    // longest chat message accepted, counted in characters after sanitizing
+    pub max_message_chars: usize,
+    // largest websocket message/frame the server will read, anything bigger closes the connection
+    pub max_ws_message_bytes: usize,
+    // how long a bot's callback or an outgoing webhook gets to answer
+    pub bot_callback_timeout: Duration,
+    // how long a client can stay logged in without using its refresh token
+    pub refresh_token_lifetime: Duration,
+    // chat messages each room keeps in memory and sends to people who join, unless its owner set it
+    // to keep fewer
+    pub room_history_len: usize,
+    // frames that can wait to be written to one websocket
+    pub ws_outbound_queue: usize,
+    // what to do when that queue is full
+    pub ws_overflow_policy: OverflowPolicy,
+    // longest a shutdown waits for clients to be sent what they have queued before exiting anyway
+    pub shutdown_deadline: Duration,
+    // how long clients are told to wait before reconnecting after a shutdown
+    pub shutdown_reconnect_after: Duration,
+    pub rate_limits: RateLimitConfig,
+}
+
+// What happens to a room's broadcasts when a client reads them slower than they arrive and its
+// outbound queue fills up. Set with CHAT_WS_OVERFLOW_POLICY.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    // "resync": wait for the client. If it falls behind the room's broadcasts as well, it is sent
+    // the chat messages it skipped from the room's history.
+    Resync,
+    // "drop_newest": throw away whatever doesn't fit, the client never sees it
+    DropNewest,
+    // "disconnect": close the connection, the client can reconnect and start over
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "resync" => Ok(OverflowPolicy::Resync),
+            "drop_newest" => Ok(OverflowPolicy::DropNewest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(()),
+        }
+    }
+}
+
+// How log lines are written. Set with CHAT_LOG_FORMAT.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    // "text": one readable line per event, the default
+    Text,
+    // "json": one JSON object per event, with the span it happened in (eg the websocket's user
+    // and room), for log collectors
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+// Read before logging is set up, so unlike the rest of the settings a bad value can only be
+// reported on stderr
+pub fn log_format_from_env() -> LogFormat {
+    match std::env::var("CHAT_LOG_FORMAT") {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            eprintln!("Ignoring invalid value {:?} for CHAT_LOG_FORMAT", value);
+            LogFormat::Text
+        }),
+        Err(_) => LogFormat::Text,
+    }
+}
+
+// Shape of a single token bucket: it holds at most `capacity` tokens (the burst size)
+// and regains `refill_per_sec` tokens every second. A refill rate of 0 disables the limit.
+#[derive(Debug, Clone, Copy)]
+pub struct BucketConfig {
+    pub capacity: f64,
+    pub refill_per_sec: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    // chat messages sent by one user
+    pub user_messages: BucketConfig,
+    // chat messages sent from one IP address (covers several accounts on one machine)
+    pub ip_messages: BucketConfig,
+    // chat messages broadcast into one room, regardless of who sent them
+    pub room_messages: BucketConfig,
+    // HTTP requests and websocket upgrades from one IP address
+    pub ip_requests: BucketConfig,
+
+    // Flood protection: this many rate limit violations inside the window gets a user muted
+    pub mute_after_violations: u32,
+    pub violation_window: Duration,
+    pub mute_duration: Duration,
+
+    // Brute force protection on room passwords: this many wrong guesses inside the window
+    // locks the user (and the IP they came from) out of that room for a while
+    pub join_max_failures: u32,
+    pub join_failure_window: Duration,
+    pub join_lockout: Duration,
+
+    // Brute force protection on account passwords: this many failed logins from one IP inside the
+    // window locks that IP out of the account for a while. The account is only locked for everyone
+    // after login_max_account_failures from any IPs, set much higher so a stranger can't easily
+    // keep someone else locked out but guesses spread over many addresses still run out.
+    pub login_max_failures: u32,
+    pub login_max_account_failures: u32,
+    pub login_failure_window: Duration,
+    pub login_lockout: Duration,
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Self {
+        ServerConfig {
+            bind_addr: env_or("CHAT_BIND_ADDR", "127.0.0.1:3000".to_string()),
+            backplane: env_or("CHAT_BACKPLANE", "local".to_string()),
+            admin_token: std::env::var("CHAT_ADMIN_TOKEN").ok().filter(|token| !token.is_empty()),
+            motd: std::env::var("CHAT_MOTD").ok().filter(|motd| !motd.trim().is_empty()),
+            max_message_chars: env_or("CHAT_MAX_MESSAGE_CHARS", 2000),
+            max_ws_message_bytes: env_or("CHAT_MAX_WS_MESSAGE_BYTES", 16 * 1024),
+            bot_callback_timeout: secs_from_env("CHAT_BOT_CALLBACK_TIMEOUT_SECS", 5),
+            refresh_token_lifetime: Duration::from_secs(env_or("CHAT_REFRESH_TOKEN_DAYS", 30) * 24 * 60 * 60),
+            room_history_len: env_or("CHAT_ROOM_HISTORY", 50),
+            ws_outbound_queue: env_or("CHAT_WS_OUTBOUND_QUEUE", 64).max(1),
+            ws_overflow_policy: env_or("CHAT_WS_OVERFLOW_POLICY", OverflowPolicy::Resync),
+            shutdown_deadline: secs_from_env("CHAT_SHUTDOWN_DEADLINE_SECS", 10),
+            shutdown_reconnect_after: secs_from_env("CHAT_SHUTDOWN_RECONNECT_AFTER_SECS", 5),
+            rate_limits: RateLimitConfig::from_env(),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn from_env() -> Self {
+        RateLimitConfig {
+            user_messages: bucket_from_env("CHAT_USER_MSG", 10.0, 2.0),
+            ip_messages: bucket_from_env("CHAT_IP_MSG", 30.0, 6.0),
+            room_messages: bucket_from_env("CHAT_ROOM_MSG", 60.0, 20.0),
+            ip_requests: bucket_from_env("CHAT_IP_REQ", 20.0, 1.0),
+            mute_after_violations: env_or("CHAT_MUTE_AFTER_VIOLATIONS", 5),
+            violation_window: secs_from_env("CHAT_VIOLATION_WINDOW_SECS", 30),
+            mute_duration: secs_from_env("CHAT_MUTE_SECS", 60),
+            join_max_failures: env_or("CHAT_JOIN_MAX_FAILURES", 5),
+            join_failure_window: secs_from_env("CHAT_JOIN_FAILURE_WINDOW_SECS", 300),
+            join_lockout: secs_from_env("CHAT_JOIN_LOCKOUT_SECS", 300),
+            login_max_failures: env_or("CHAT_LOGIN_MAX_FAILURES", 10),
+            login_max_account_failures: env_or("CHAT_LOGIN_MAX_ACCOUNT_FAILURES", 100),
+            login_failure_window: secs_from_env("CHAT_LOGIN_FAILURE_WINDOW_SECS", 900),
+            login_lockout: secs_from_env("CHAT_LOGIN_LOCKOUT_SECS", 300),
+        }
+    }
+}
+
+// Reads `{prefix}_BURST` and `{prefix}_PER_SEC`
+fn bucket_from_env(prefix: &str, capacity: f64, refill_per_sec: f64) -> BucketConfig {
+    BucketConfig {
+        capacity: env_or(&format!("{}_BURST", prefix), capacity),
+        refill_per_sec: env_or(&format!("{}_PER_SEC", prefix), refill_per_sec),
+    }
+}
+
+fn secs_from_env(key: &str, default: u64) -> Duration {
+    Duration::from_secs(env_or(key, default))
+}
+
+// Falls back to the default (with a warning) when the variable is missing or doesn't parse
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match std::env::var(key) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            tracing::warn!("Ignoring invalid value {:?} for {}", value, key);
+            default
+        }),
+        Err(_) => default,
+    }
+}
@@ -0,0 +1,301 @@
+use chrono::{DateTime, Utc};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+
+use crate::backplane::{Backplane, BackplaneError, BackplaneResult};
+use crate::message::InviteInfo;
+
+// Invite codes let a room owner hand out access without sharing the room password.
+// A code can be limited to a number of uses and/or expire, and the owner can revoke it at any time.
+//
+// Invites are kept in the backplane (see backplane.rs), so a code made on one server instance can
+// be redeemed on any other. Each one is a key that expires along with the invite, its uses are
+// counted separately so two instances redeeming the last use at once can't both get it, and each
+// room's codes are listed together.
+
+// No 0/O or 1/I so codes can be read out loud or retyped without mistakes. 32 symbols keeps
+// the mapping from random bytes unbiased.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LEN: usize = 12;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Invite {
+    pub code: String,
+    pub room_id: String,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    // None means unlimited
+    pub max_uses: Option<u32>,
+    // read from its own counter, see uses_key()
+    #[serde(skip)]
+    pub uses: u32,
+}
+
+impl Invite {
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    fn is_used_up(&self) -> bool {
+        self.max_uses.is_some_and(|max_uses| self.uses >= max_uses)
+    }
+
+    // How much longer the invite is kept, None for as long as it isn't used up or revoked
+    fn ttl(&self) -> Option<Duration> {
+        // never 0, which Redis won't take
+        self.expires_at.map(|expires_at| {
+            (expires_at - Utc::now())
+                .to_std()
+                .unwrap_or_default()
+                .max(Duration::from_millis(1))
+        })
+    }
+
+    pub fn info(&self) -> InviteInfo {
+        InviteInfo {
+            code: self.code.clone(),
+            room_id: self.room_id.clone(),
+            created_by: self.created_by.clone(),
+            created_at: self.created_at.to_rfc3339(),
+            expires_at: self.expires_at.map(|t| t.to_rfc3339()),
+            max_uses: self.max_uses,
+            uses: self.uses,
+        }
+    }
+}
+
+fn invite_key(code: &str) -> String {
+    format!("invite:{}", code)
+}
+
+fn uses_key(code: &str) -> String {
+    format!("invite_uses:{}", code)
+}
+
+// The room's codes. Ones that expired are only taken out the next time it is listed.
+fn room_invites_key(room_id: &str) -> String {
+    format!("invites:{}", room_id)
+}
+
+// Codes are case insensitive
+fn normalize(code: &str) -> String {
+    code.trim().to_ascii_uppercase()
+}
+
+pub struct InviteStore {
+    backplane: Arc<dyn Backplane>,
+}
+
+impl InviteStore {
+    pub fn new(backplane: Arc<dyn Backplane>) -> Self {
+        InviteStore { backplane }
+    }
+
+    pub async fn create(
+        &self,
+        room_id: &str,
+        created_by: &str,
+        max_uses: Option<u32>,
+        expires_in: Option<Duration>,
+    ) -> BackplaneResult<Invite> {
+        let now = Utc::now();
+        let mut invite = Invite {
+            code: String::new(),
+            room_id: room_id.to_string(),
+            created_by: created_by.to_string(),
+            created_at: now,
+            expires_at: expires_in
+                .and_then(|d| chrono::Duration::from_std(d).ok())
+                .map(|d| now + d),
+            max_uses,
+            uses: 0,
+        };
+
+        loop {
+            invite.code = generate_code();
+            let json = serde_json::to_string(&invite).map_err(|e| BackplaneError(e.to_string()))?;
+            if self.backplane.set_new(&invite_key(&invite.code), &json, expires_in).await? {
+                break;
+            }
+        }
+        self.backplane.add_member(&room_invites_key(room_id), &invite.code).await?;
+        Ok(invite)
+    }
+
+    // The invite behind a code if it can still be redeemed, without using it up
+    pub async fn usable(&self, code: &str) -> BackplaneResult<Option<Invite>> {
+        let invite = self.get(code).await?;
+        Ok(invite.filter(|invite| !invite.is_used_up()))
+    }
+
+    // Uses up one use of the code and returns the room it is for
+    pub async fn redeem(&self, code: &str) -> BackplaneResult<Option<String>> {
+        let Some(invite) = self.get(code).await? else {
+            return Ok(None);
+        };
+        // Every redeem counts, so whoever counts past max_uses knows the code was already used up
+        let uses = self.backplane.increment(&uses_key(&invite.code), invite.ttl()).await?;
+        match invite.max_uses {
+            Some(max_uses) if uses > max_uses as u64 => Ok(None),
+            Some(max_uses) if uses == max_uses as u64 => {
+                self.remove(&invite).await?;
+                Ok(Some(invite.room_id))
+            }
+            _ => Ok(Some(invite.room_id)),
+        }
+    }
+
+    // None if there is no such code or it expired
+    pub async fn get(&self, code: &str) -> BackplaneResult<Option<Invite>> {
+        let code = normalize(code);
+        let Some(json) = self.backplane.get(&invite_key(&code)).await? else {
+            return Ok(None);
+        };
+        let mut invite: Invite = serde_json::from_str(&json).map_err(|e| BackplaneError(e.to_string()))?;
+        if invite.is_expired(Utc::now()) {
+            return Ok(None);
+        }
+        if let Some(uses) = self.backplane.get(&uses_key(&code)).await? {
+            invite.uses = uses.parse().unwrap_or(u32::MAX);
+        }
+        Ok(Some(invite))
+    }
+
+    // Active invites for a room, oldest first
+    pub async fn list(&self, room_id: &str) -> BackplaneResult<Vec<Invite>> {
+        let key = room_invites_key(room_id);
+        let mut invites = Vec::new();
+        for code in self.backplane.members(&key).await? {
+            match self.get(&code).await? {
+                Some(invite) => invites.push(invite),
+                None => self.backplane.remove_member(&key, &code).await?,
+            }
+        }
+        invites.sort_by_key(|invite| invite.created_at);
+        Ok(invites)
+    }
+
+    pub async fn revoke(&self, code: &str) -> BackplaneResult<Option<Invite>> {
+        let Some(invite) = self.get(code).await? else {
+            return Ok(None);
+        };
+        // whoever takes the invite is the one revoking it
+        if !self.remove(&invite).await? {
+            return Ok(None);
+        }
+        Ok(Some(invite))
+    }
+
+    // Drops every invite to a room that was deleted
+    pub async fn forget_room(&self, room_id: &str) -> BackplaneResult<()> {
+        for code in self.backplane.members(&room_invites_key(room_id)).await? {
+            self.backplane.take(&invite_key(&code)).await?;
+            self.backplane.take(&uses_key(&code)).await?;
+            self.backplane.remove_member(&room_invites_key(room_id), &code).await?;
+        }
+        Ok(())
+    }
+
+    // False if it was already gone
+    async fn remove(&self, invite: &Invite) -> BackplaneResult<bool> {
+        let removed = self.backplane.take(&invite_key(&invite.code)).await?.is_some();
+        self.backplane.take(&uses_key(&invite.code)).await?;
+        self.backplane
+            .remove_member(&room_invites_key(&invite.room_id), &invite.code)
+            .await?;
+        Ok(removed)
+    }
+}
+
+fn generate_code() -> String {
+    let mut bytes = [0u8; CODE_LEN];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+        .iter()
+        .map(|b| CODE_ALPHABET[(*b as usize) % CODE_ALPHABET.len()] as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backplane::LocalBackplane;
+
+    fn store() -> InviteStore {
+        InviteStore::new(Arc::new(LocalBackplane::default()))
+    }
+
+    #[tokio::test]
+    async fn codes_are_case_insensitive() {
+        let store = store();
+        let invite = store.create("room", "alice", None, None).await.unwrap();
+        let code = format!("  {}  ", invite.code.to_ascii_lowercase());
+        assert_eq!(store.redeem(&code).await.unwrap().as_deref(), Some("room"));
+    }
+
+    #[tokio::test]
+    async fn expired_codes_are_refused() {
+        let store = store();
+        let expired = store.create("room", "alice", None, Some(Duration::ZERO)).await.unwrap();
+        let valid = store.create("room", "alice", None, Some(Duration::from_secs(3600))).await.unwrap();
+
+        assert!(store.usable(&expired.code).await.unwrap().is_none());
+        assert_eq!(store.redeem(&expired.code).await.unwrap(), None);
+        assert!(store.get(&expired.code).await.unwrap().is_none());
+        assert_eq!(store.redeem(&valid.code).await.unwrap().as_deref(), Some("room"));
+
+        let listed: Vec<String> = store.list("room").await.unwrap().into_iter().map(|i| i.code).collect();
+        assert_eq!(listed, vec![valid.code]);
+    }
+
+    #[tokio::test]
+    async fn codes_run_out_after_max_uses() {
+        let store = store();
+        let invite = store.create("room", "alice", Some(2), None).await.unwrap();
+
+        assert_eq!(store.redeem(&invite.code).await.unwrap().as_deref(), Some("room"));
+        assert_eq!(store.get(&invite.code).await.unwrap().map(|i| i.uses), Some(1));
+        assert_eq!(store.redeem(&invite.code).await.unwrap().as_deref(), Some("room"));
+        assert_eq!(store.redeem(&invite.code).await.unwrap(), None);
+        assert!(store.usable(&invite.code).await.unwrap().is_none());
+        assert!(store.list("room").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn checking_a_code_does_not_use_it() {
+        let store = store();
+        let invite = store.create("room", "alice", Some(1), None).await.unwrap();
+
+        assert!(store.usable(&invite.code).await.unwrap().is_some());
+        assert!(store.usable(&invite.code).await.unwrap().is_some());
+        assert_eq!(store.redeem(&invite.code).await.unwrap().as_deref(), Some("room"));
+    }
+
+    #[tokio::test]
+    async fn revoked_and_forgotten_codes_are_refused() {
+        let store = store();
+        let revoked = store.create("room", "alice", None, None).await.unwrap();
+        let other_room = store.create("other", "alice", None, None).await.unwrap();
+
+        let revoked_code = store.revoke(&revoked.code).await.unwrap().map(|i| i.code);
+        assert_eq!(revoked_code, Some(revoked.code.clone()));
+        assert_eq!(store.redeem(&revoked.code).await.unwrap(), None);
+        assert!(store.revoke(&revoked.code).await.unwrap().is_none());
+
+        store.forget_room("other").await.unwrap();
+        assert_eq!(store.redeem(&other_room.code).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn codes_work_on_every_instance() {
+        let backplane: Arc<dyn Backplane> = Arc::new(LocalBackplane::default());
+        let here = InviteStore::new(backplane.clone());
+        let there = InviteStore::new(backplane);
+        let invite = here.create("room", "alice", Some(1), None).await.unwrap();
+
+        assert_eq!(there.redeem(&invite.code).await.unwrap().as_deref(), Some("room"));
+        assert_eq!(here.redeem(&invite.code).await.unwrap(), None);
+    }
+}
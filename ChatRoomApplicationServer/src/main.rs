@@ -1,325 +1,2676 @@
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        Query, State,
+        ws::{close_code, CloseFrame, Message, Utf8Bytes, WebSocket, WebSocketUpgrade},
+        ConnectInfo, MatchedPath, Path, Request, State,
     },
-    http::StatusCode,
-    response::IntoResponse,
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
+use chat_room_shared::sanitize::sanitize_message;
+use chat_room_shared::validation;
 use futures_util::{sink::SinkExt, stream::StreamExt};
-use serde::Deserialize;
 use std::{
-    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
     sync::Arc,
+    time::{Duration, Instant},
 };
-use tokio::sync::{broadcast, Mutex};
+use tokio::sync::{broadcast, mpsc, mpsc::error::TrySendError};
+use tower_http::trace::TraceLayer;
+use tracing::Instrument;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod announcements;
+mod backplane;
+mod bots;
+mod config;
+mod invites;
+mod maintenance;
+mod metrics;
+mod moderation;
+mod passwords;
+mod rate_limit;
+mod redis_backplane;
+mod rooms;
+mod sessions;
+mod shutdown;
+mod users;
+// stands in for Redis in redis_backplane.rs's tests
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "../examples/backplane_broker.rs"]
+mod backplane_broker;
+use announcements::Announcements;
+use backplane::{Backplane, BackplaneError};
+use bots::{Bot, BotCommand, BotStore, WebhookKind};
+use config::{LogFormat, OverflowPolicy, ServerConfig};
+use invites::InviteStore;
+use maintenance::Maintenance;
+use metrics::{AuthFailure, Metrics};
+use moderation::ModerationLog;
+use rate_limit::{retry_after_secs, RateLimitError, RateLimiter};
+use rooms::{RoomEvent, RoomHandle, RoomRegistry, RoomSettings};
+use sessions::SessionStore;
+use shutdown::Shutdown;
+use users::UserStore;
+
 // Import your message protocol types
 mod message;
 use message::{
-    ChatMessage, ClientWsMessage, CreateRoomResponse, JoinRoomResponse,
-    ServerWsMessage, ErrorResponse,
+    AdminListUsersResponse, AdminRoomRequest, AdminRoomResponse, AdminUserRequest,
+    AdminUserResponse, AnnouncementRequest, AuthSuccessResponse, BotCommandInvocation, BotReply,
+    BotSendMessageRequest, CancelAnnouncementRequest, ChatMessage, ClientWsMessage,
+    CreateBotRequest, CreateBotResponse, CreateInviteRequest, CreateInviteResponse,
+    CreateOutgoingWebhookRequest, CreateOutgoingWebhookResponse, CreateRoomRequest,
+    CreateRoomResponse, CreateWebhookRequest, CreateWebhookResponse, DeleteRoomRequest, DeleteWebhookRequest,
+    JoinInviteRequest, JoinRoomRequest, JoinRoomResponse, ListAnnouncementsResponse,
+    ListBotCommandsRequest, ListBotCommandsResponse, ListInvitesRequest, ListInvitesResponse,
+    ListRoomUsersRequest, ListRoomUsersResponse, ListRoomsRequest, ListRoomsResponse, LoginRequest,
+    ListWebhooksRequest, ListWebhooksResponse, MaintenanceRequest, ModerationAction,
+    ModerationLogRequest, ModerationLogResponse, MotdRequest, ReadinessCheck, ReadinessResponse,
+    RegisterBotCommandRequest, RefreshSessionRequest, RegisterRequest, ResetPasswordRequest,
+    RevokeInviteRequest, RoomDetails, RoomInfo, RoomSettingsChange, RoomVisibility, ServerWsMessage,
+    StatusResponse, SuccessResponse, UnregisterBotCommandRequest, UpdateRoomSettingsRequest,
+    UpdateRoomSettingsResponse, WebhookMessageRequest, ErrorResponse, ValidationError,
 };
 
-#[derive(Clone)]
-struct Room {
-    room_id: String,
-    room_password: String,
-    owner: String,
-    // Set of user_ids currently in this room
-    members: HashSet<String>,
+struct AppState {
+    config: ServerConfig,
+    // what is shared with the other server instances, if there are any (see backplane.rs)
+    backplane: Arc<dyn Backplane>,
+    // every room, and which room each user is in (see rooms.rs)
+    rooms: RoomRegistry,
+    users: UserStore,
+    sessions: SessionStore,
+    invites: InviteStore,
+    bots: BotStore,
+    rate_limiter: RateLimiter,
+    metrics: Metrics,
+    moderation: ModerationLog,
+    maintenance: Maintenance,
+    // the message of the day and scheduled announcements
+    announcements: Announcements,
+    shutdown: Shutdown,
+    started_at: Instant,
 }
 
-struct AppState {
-    // room_id -> Room
-    rooms: Mutex<HashMap<String, Room>>,
-    // user_id -> broadcast sender for that user's room
-    // Each room has its own broadcast channel
-    room_channels: Mutex<HashMap<String, broadcast::Sender<String>>>,
-    // user_id -> room_id (tracks which room each user is in)
-    user_rooms: Mutex<HashMap<String, String>>,
+impl AppState {
+    fn new(config: ServerConfig, backplane: Arc<dyn Backplane>) -> Self {
+        AppState {
+            rooms: RoomRegistry::new(backplane.clone(), config.room_history_len),
+            users: UserStore::new(backplane.clone()),
+            sessions: SessionStore::new(backplane.clone(), config.refresh_token_lifetime),
+            maintenance: Maintenance::new(backplane.clone()),
+            announcements: Announcements::new(backplane.clone()),
+            invites: InviteStore::new(backplane.clone()),
+            bots: BotStore::new(backplane.clone(), config.bot_callback_timeout),
+            moderation: ModerationLog::new(backplane.clone()),
+            rate_limiter: RateLimiter::new(backplane.clone(), &config.rate_limits),
+            backplane,
+            metrics: Metrics::default(),
+            shutdown: Shutdown::new(),
+            started_at: Instant::now(),
+            config,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    // RUST_LOG overrides what is logged, tower_http's lines are the HTTP requests (see TraceLayer below)
+    let logs = tracing_subscriber::registry().with(
+        tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| format!("{}=debug,tower_http=debug", env!("CARGO_CRATE_NAME")).into()),
+    );
+    match config::log_format_from_env() {
+        LogFormat::Text => logs.with(tracing_subscriber::fmt::layer()).init(),
+        LogFormat::Json => logs.with(tracing_subscriber::fmt::layer().json()).init(),
+    }
+
+    let config = ServerConfig::from_env();
+
+    let backplane = match backplane::from_config(&config.backplane).await {
+        Ok(backplane) => backplane,
+        Err(e) => {
+            tracing::error!("Can't start without the backplane: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let app_state = Arc::new(AppState::new(config.clone(), backplane));
+
+    if let Some(motd) = &config.motd {
+        if let Err(e) = app_state.announcements.seed_motd(motd).await {
+            tracing::warn!("Failed to set the message of the day from CHAT_MOTD: {}", e);
+        }
+    }
+
+    // Pass on what the other server instances publish to the rooms here
+    let relay_state = app_state.clone();
+    tokio::spawn(async move { relay_state.rooms.relay_events().await });
+    // Say this instance is running, and clear up after the ones that stopped without saying goodbye
+    let alive_state = app_state.clone();
+    tokio::spawn(async move { alive_state.rooms.keep_alive().await });
+    // and the announcements for everyone to every connection here
+    let everyone_state = app_state.clone();
+    tokio::spawn(async move { everyone_state.announcements.relay().await });
+
+    // Send scheduled announcements once their time comes
+    let announce_state = app_state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            send_due_announcements(&announce_state).await;
+        }
+    });
+
+    // Periodically forget rate limit state for clients that have gone quiet and expired sessions
+    let prune_state = app_state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            prune_state.rate_limiter.prune().await;
+            prune_state.backplane.prune().await;
+        }
+    });
+
+    let app = Router::new()
+        .route("/create_user", post(create_user_handler))
+        .route("/login", post(login_handler))
+        .route("/refresh_session", post(refresh_session_handler))
+        .route("/logout", post(logout_handler))
+        .route("/create_room", post(create_room_handler))
+        .route("/join_room", post(join_room_handler))
+        .route("/all_rooms", post(all_rooms_handler))
+        .route("/list_room_users", post(list_room_users_handler))
+        .route("/create_invite", post(create_invite_handler))
+        .route("/join_invite", post(join_invite_handler))
+        .route("/list_invites", post(list_invites_handler))
+        .route("/revoke_invite", post(revoke_invite_handler))
+        .route("/update_room_settings", post(update_room_settings_handler))
+        .route("/delete_room", post(delete_room_handler))
+        .route("/create_bot", post(create_bot_handler))
+        .route("/list_bot_commands", post(list_bot_commands_handler))
+        .route("/bot/register_command", post(register_bot_command_handler))
+        .route("/bot/unregister_command", post(unregister_bot_command_handler))
+        .route("/bot/send_message", post(bot_send_message_handler))
+        .route("/create_webhook", post(create_webhook_handler))
+        .route("/create_outgoing_webhook", post(create_outgoing_webhook_handler))
+        .route("/list_webhooks", post(list_webhooks_handler))
+        .route("/delete_webhook", post(delete_webhook_handler))
+        .route("/webhooks/{webhook_id}", post(webhook_message_handler))
+        .route("/ws", get(websocket_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler))
+        .route("/status", get(status_handler))
+        .route("/admin/list_rooms", post(admin_list_rooms_handler))
+        .route("/admin/room_info", post(admin_room_info_handler))
+        .route("/admin/delete_room", post(admin_delete_room_handler))
+        .route("/admin/list_users", post(admin_list_users_handler))
+        .route("/admin/user_info", post(admin_user_info_handler))
+        .route("/admin/delete_user", post(admin_delete_user_handler))
+        .route("/admin/disconnect_user", post(admin_disconnect_user_handler))
+        .route("/admin/reset_password", post(admin_reset_password_handler))
+        .route("/admin/moderation_log", post(admin_moderation_log_handler))
+        .route("/admin/announce", post(admin_announce_handler))
+        .route("/admin/list_announcements", post(admin_list_announcements_handler))
+        .route("/admin/cancel_announcement", post(admin_cancel_announcement_handler))
+        .route("/admin/motd", post(admin_motd_handler))
+        .route("/admin/maintenance", post(admin_maintenance_handler))
+        .route_layer(middleware::from_fn_with_state(app_state.clone(), track_latency))
+        // a span for every request, and a log line when it is answered
+        .layer(TraceLayer::new_for_http())
+        .with_state(app_state.clone());
+
+    let listener = tokio::net::TcpListener::bind(&config.bind_addr)
+        .await
+        .unwrap();
+    tracing::info!("Server listening on {}", listener.local_addr().unwrap());
+    // Connect info is needed so handlers can rate limit by the client's IP address
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal(app_state.clone()))
+        .await
+        .unwrap();
+
+    // Nothing new is coming in, wait for the websockets to be done (see shutdown.rs)
+    app_state.shutdown.connections_closed().await;
+    app_state.backplane.flush().await;
+    tracing::info!("Shut down cleanly");
+}
+
+// Resolves on SIGINT or SIGTERM, which stops the server taking new connections and tells the
+// websockets to wrap up. From then on the server has the configured deadline to finish.
+async fn shutdown_signal(state: Arc<AppState>) {
+    shutdown::signal().await;
+    let deadline = state.config.shutdown_deadline;
+    tracing::info!("Shutting down, giving clients up to {:?} to be sent what they have queued", deadline);
+    state.shutdown.start();
+
+    tokio::spawn(async move {
+        tokio::time::sleep(deadline).await;
+        tracing::warn!(
+            "Shutdown deadline passed with {} connections still open, exiting anyway",
+            state.shutdown.open_connections()
+        );
+        std::process::exit(1);
+    });
+}
+
+// 429 response with both a Retry-After header and the hint in the body for our client
+fn rate_limited_response(message: &str, retry_after: Duration) -> Response {
+    let secs = retry_after_secs(retry_after);
+    let error = ErrorResponse::RateLimited {
+        message: message.to_string(),
+        retry_after_secs: secs,
+    };
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, secs.to_string())],
+        Json(error),
+    )
+        .into_response()
+}
+
+fn validation_failed_response(errors: Vec<ValidationError>) -> Response {
+    let error = ErrorResponse::ValidationFailed { errors };
+    (StatusCode::BAD_REQUEST, Json(error)).into_response()
+}
+
+fn server_error_response(message: String) -> Response {
+    tracing::error!("{}", message);
+    let error = ErrorResponse::ServerError { message };
+    (StatusCode::INTERNAL_SERVER_ERROR, Json(error)).into_response()
+}
+
+fn unavailable_response(message: String) -> Response {
+    let error = ErrorResponse::Unavailable { message };
+    (StatusCode::SERVICE_UNAVAILABLE, Json(error)).into_response()
+}
+
+// The message of the day for a response that just logged someone in. Logging in still works if
+// it can't be read.
+async fn motd(state: &AppState) -> Option<String> {
+    state.announcements.motd().await.unwrap_or_else(|e| {
+        tracing::warn!("Failed to read the message of the day: {}", e);
+        None
+    })
+}
+
+// Turns away requests that would let someone new in while maintenance mode is on (see maintenance.rs)
+async fn refuse_during_maintenance(state: &AppState) -> Result<(), Response> {
+    match state.maintenance.message().await {
+        Ok(None) => Ok(()),
+        Ok(Some(message)) => Err(unavailable_response(message)),
+        Err(e) => Err(server_error_response(e.to_string())),
+    }
+}
+
+async fn create_user_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(req): Json<RegisterRequest>,
+) -> impl IntoResponse {
+    // Don't log the request itself, it contains the plaintext password
+    tracing::info!("Create user request: {}", req.user_id);
+
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = refuse_during_maintenance(&state).await {
+        return response;
+    }
+
+    let mut errors = validation::validate_user_id(&req.user_id);
+    errors.extend(validation::validate_password("password", &req.password));
+    if !errors.is_empty() {
+        return validation_failed_response(errors);
+    }
+
+    match name_taken(&state, &req.user_id).await {
+        Ok(false) => {}
+        Ok(true) => {
+            let error = ErrorResponse::UserAlreadyExists { user_id: req.user_id };
+            return (StatusCode::CONFLICT, Json(error)).into_response();
+        }
+        Err(e) => return server_error_response(e.to_string()),
+    }
+
+    // Hashing takes a while on purpose
+    let password_hash = match passwords::hash_password(&req.password).await {
+        Ok(hash) => hash,
+        Err(e) => return server_error_response(e),
+    };
+
+    // Someone may have registered the same name while we were hashing
+    match state.users.create(&req.user_id, &password_hash).await {
+        Ok(true) => {}
+        Ok(false) => {
+            let error = ErrorResponse::UserAlreadyExists { user_id: req.user_id };
+            return (StatusCode::CONFLICT, Json(error)).into_response();
+        }
+        Err(e) => return server_error_response(e.to_string()),
+    }
+
+    // TODO: Save user to database
+    // db::save_user(&req.user_id, &password_hash).await;
+
+    let tokens = match state.sessions.issue(&req.user_id).await {
+        Ok(tokens) => tokens,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let response = AuthSuccessResponse {
+        token: tokens.token,
+        user_id: req.user_id,
+        refresh_token: tokens.refresh_token,
+        motd: motd(&state).await,
+    };
+    (StatusCode::CREATED, Json(response)).into_response()
+}
+
+async fn login_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(req): Json<LoginRequest>,
+) -> impl IntoResponse {
+    // Don't log the request itself, it contains the plaintext password
+    tracing::info!("Login request: {}", req.user_id);
+
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = refuse_during_maintenance(&state).await {
+        return response;
+    }
+
+    if let Some(retry_after) = state.rate_limiter.login_lockout(&req.user_id, addr.ip()).await {
+        return rate_limited_response("Too many failed logins for this account", retry_after);
+    }
+
+    // The same answer, after the same amount of work, for an unknown user and a wrong password, so
+    // accounts can't be probed
+    let password_hash = match state.users.password_hash(&req.user_id).await {
+        Ok(hash) => hash,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    if !passwords::verify_password_or_dummy(&req.password, password_hash.as_deref()).await {
+        state.metrics.auth_failed(AuthFailure::Login);
+        if state.rate_limiter.record_login_failure(&req.user_id, addr.ip()).await {
+            let detail = format!("too many failed logins, the last from {}", addr.ip());
+            state
+                .moderation
+                .record(ModerationAction::LoginLockout, moderation::SERVER, &req.user_id, None, Some(detail))
+                .await;
+        }
+        let error = ErrorResponse::AuthenticationFailed {
+            message: "Invalid username or password".to_string(),
+        };
+        return (StatusCode::UNAUTHORIZED, Json(error)).into_response();
+    }
+    state.rate_limiter.record_login_success(&req.user_id, addr.ip()).await;
+
+    let tokens = match state.sessions.issue(&req.user_id).await {
+        Ok(tokens) => tokens,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let response = AuthSuccessResponse {
+        token: tokens.token,
+        user_id: req.user_id,
+        refresh_token: tokens.refresh_token,
+        motd: motd(&state).await,
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+async fn refresh_session_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(req): Json<RefreshSessionRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+
+    let refreshed = match state.sessions.refresh(&req.refresh_token).await {
+        Ok(refreshed) => refreshed,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let Some((user_id, tokens)) = refreshed else {
+        state.metrics.auth_failed(AuthFailure::Session);
+        let error = ErrorResponse::AuthenticationFailed {
+            message: "Session expired, please log in again".to_string(),
+        };
+        return (StatusCode::UNAUTHORIZED, Json(error)).into_response();
+    };
+    tracing::info!("Session refreshed for {}", user_id);
+
+    let response = AuthSuccessResponse {
+        token: tokens.token,
+        user_id,
+        refresh_token: tokens.refresh_token,
+        motd: motd(&state).await,
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+async fn logout_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+
+    let user_id = match bearer_token(&headers) {
+        Some(token) => match state.sessions.revoke(token).await {
+            Ok(user_id) => user_id,
+            Err(e) => return server_error_response(e.to_string()),
+        },
+        None => None,
+    };
+    let Some(user_id) = user_id else {
+        state.metrics.auth_failed(AuthFailure::Session);
+        let error = ErrorResponse::AuthenticationFailed {
+            message: "Not logged in".to_string(),
+        };
+        return (StatusCode::UNAUTHORIZED, Json(error)).into_response();
+    };
+    tracing::info!("User {} logged out", user_id);
+
+    let response = SuccessResponse {
+        message: format!("{} logged out", user_id),
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+// The token from an "Authorization: Bearer <token>" header
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+
+// The user whose session token is in the Authorization header
+async fn authenticate_user(state: &AppState, headers: &HeaderMap) -> Result<String, Response> {
+    let user_id = match bearer_token(headers) {
+        Some(token) => match state.sessions.user(token).await {
+            Ok(user_id) => user_id,
+            Err(e) => return Err(server_error_response(e.to_string())),
+        },
+        None => None,
+    };
+    user_id.ok_or_else(|| {
+        state.metrics.auth_failed(AuthFailure::Session);
+        let error = ErrorResponse::AuthenticationFailed {
+            message: "Not logged in".to_string(),
+        };
+        (StatusCode::UNAUTHORIZED, Json(error)).into_response()
+    })
+}
+
+// The room belongs to whoever the session token is for
+async fn create_room_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<CreateRoomRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = refuse_during_maintenance(&state).await {
+        return response;
+    }
+    let user_id = match authenticate_user(&state, &headers).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+    tracing::info!("Create room request: {} by {}", req.room_id, user_id);
+
+    let mut errors = validation::validate_room_id(&req.room_id);
+    errors.extend(validation::validate_room_password(req.visibility, req.room_password.as_deref()));
+    if !errors.is_empty() {
+        return validation_failed_response(errors);
+    }
+
+    // Check if room already exists before spending time on hashing the password
+    match state.rooms.exists(&req.room_id).await {
+        Ok(false) => {}
+        Ok(true) => {
+            let error = ErrorResponse::RoomAlreadyExists {
+                room_id: req.room_id.clone(),
+            };
+            return (StatusCode::CONFLICT, Json(error)).into_response();
+        }
+        Err(e) => return server_error_response(e.to_string()),
+    }
+
+    let password_hash = match &req.room_password {
+        Some(password) => match passwords::hash_password(password).await {
+            Ok(hash) => Some(hash),
+            Err(e) => return server_error_response(e),
+        },
+        None => None,
+    };
+
+    let settings = RoomSettings::new(&user_id, req.visibility, password_hash);
+    // Someone may have created the same room while we were hashing
+    let room = match state.rooms.create(&req.room_id, settings).await {
+        Ok(Some(room)) => room,
+        Ok(None) => {
+            let error = ErrorResponse::RoomAlreadyExists {
+                room_id: req.room_id.clone(),
+            };
+            return (StatusCode::CONFLICT, Json(error)).into_response();
+        }
+        Err(e) => return server_error_response(e.to_string()),
+    };
+
+    // The creator automatically joins their new room
+    if let Err(e) = state.rooms.admit(&user_id, &req.room_id).await {
+        return server_error_response(e.to_string());
+    }
+
+    // TODO: Save room to database
+    // db::save_room(&room).await;
+
+    let response = CreateRoomResponse {
+        room_id: req.room_id,
+        created_at: room.settings().created_at.clone(),
+    };
+
+    (StatusCode::CREATED, Json(response)).into_response()
+}
+
+// Lets the user whose session token it is into the room, for their websocket to connect to. A
+// revoked session can't be used to get back in.
+async fn join_room_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<JoinRoomRequest>,
+) -> impl IntoResponse {
+    let ip = addr.ip();
+    if let Err(retry_after) = state.rate_limiter.check_request(ip).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = refuse_during_maintenance(&state).await {
+        return response;
+    }
+    let user_id = match authenticate_user(&state, &headers).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+    tracing::info!("Join room request: {} by {}", req.room_id, user_id);
+    if let Some(retry_after) = state.rate_limiter.join_lockout(&user_id, ip, &req.room_id).await {
+        return rate_limited_response("Too many incorrect passwords for this room", retry_after);
+    }
+
+    let room = match state.rooms.get(&req.room_id).await {
+        Ok(Some(room)) => room,
+        Ok(None) => {
+            let error = ErrorResponse::RoomNotFound {
+                room_id: req.room_id.clone(),
+            };
+            return (StatusCode::NOT_FOUND, Json(error)).into_response();
+        }
+        Err(e) => return server_error_response(e.to_string()),
+    };
+
+    // Verify password
+    let settings = room.settings();
+    match (settings.visibility, &settings.password_hash) {
+        (RoomVisibility::Public, _) => {}
+        (_, Some(hash)) => {
+            let password = req.room_password.as_deref().unwrap_or_default();
+            if !passwords::verify_password(password, hash).await {
+                if state.rate_limiter.record_join_failure(&user_id, ip, &req.room_id).await {
+                    let detail = format!("too many wrong passwords from {}", ip);
+                    state
+                        .moderation
+                        .record(ModerationAction::JoinLockout, moderation::SERVER, &user_id, Some(&req.room_id), Some(detail))
+                        .await;
+                }
+                let error = ErrorResponse::InvalidPassword {
+                    message: "Incorrect room password".to_string(),
+                };
+                return (StatusCode::UNAUTHORIZED, Json(error)).into_response();
+            }
+            state.rate_limiter.record_join_success(&user_id, ip, &req.room_id).await;
+        }
+        (_, None) => {
+            let error = ErrorResponse::InvalidPermissions {
+                message: "This room is invite only".to_string(),
+            };
+            return (StatusCode::FORBIDDEN, Json(error)).into_response();
+        }
+    }
+
+    enter_room(&state, &user_id, &room).await
+}
+
+// Last step of joining a room, once the user has proven they are allowed in
+async fn enter_room(state: &AppState, user_id: &str, room: &RoomHandle) -> Response {
+    if let Some(max_members) = room.settings().max_members {
+        let members = match room.members().await {
+            Ok(members) => members,
+            Err(e) => return server_error_response(e.to_string()),
+        };
+        // someone already connected (eg from another device) doesn't take another place
+        if members.len() >= max_members as usize && !members.iter().any(|member| member == user_id) {
+            let error = ErrorResponse::RoomFull {
+                room_id: room.room_id.clone(),
+            };
+            return (StatusCode::FORBIDDEN, Json(error)).into_response();
+        }
+    }
+
+    if let Err(e) = state.rooms.admit(user_id, &room.room_id).await {
+        return server_error_response(e.to_string());
+    }
+
+    // The room's recent messages until there is a database to load them from
+    // TODO: Load chat history from database
+    // let chat_history = db::get_chat_history(&room_id, 50).await;
+    let chat_history = match room.history().await {
+        Ok(chat_history) => chat_history,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+
+    // TODO: Save user room membership to database
+    // db::add_user_to_room(&user_id, &room_id).await;
+
+    let response = JoinRoomResponse {
+        room_id: room.room_id.clone(),
+        chat_history,
+        details: room.details(),
+    };
+
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+// Invites, bots and webhooks can only be managed by the owner of the room. `what` is what they
+// were trying to manage, for the error message.
+async fn require_room_owner(state: &AppState, room_id: &str, user_id: &str, what: &str) -> Result<(), Response> {
+    match state.rooms.get(room_id).await {
+        Ok(Some(room)) if room.settings().owner == user_id => Ok(()),
+        Ok(Some(_)) => {
+            let error = ErrorResponse::InvalidPermissions {
+                message: format!("Only the room owner can manage {}", what),
+            };
+            Err((StatusCode::FORBIDDEN, Json(error)).into_response())
+        }
+        Ok(None) => {
+            let error = ErrorResponse::RoomNotFound {
+                room_id: room_id.to_string(),
+            };
+            Err((StatusCode::NOT_FOUND, Json(error)).into_response())
+        }
+        Err(e) => Err(server_error_response(e.to_string())),
+    }
+}
+
+fn invite_invalid_response() -> Response {
+    let error = ErrorResponse::InviteInvalid {
+        message: "Invite code is invalid or has expired".to_string(),
+    };
+    (StatusCode::NOT_FOUND, Json(error)).into_response()
+}
+
+// Longest an invite can stay valid for
+const MAX_INVITE_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+async fn create_invite_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<CreateInviteRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    let user_id = match authenticate_user(&state, &headers).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+    tracing::info!("Create invite request: {:?} by {}", req, user_id);
+    if let Err(response) = require_room_owner(&state, &req.room_id, &user_id, "invites").await {
+        return response;
+    }
+
+    let mut errors = Vec::new();
+    if req.max_uses == Some(0) {
+        errors.push(ValidationError {
+            field: "max_uses".to_string(),
+            rule: "min_value".to_string(),
+            message: "Must allow at least one use".to_string(),
+        });
+    }
+    let expires_in = req.expires_in_secs.map(Duration::from_secs);
+    if expires_in.is_some_and(|d| d.is_zero() || d > MAX_INVITE_LIFETIME) {
+        errors.push(ValidationError {
+            field: "expires_in_secs".to_string(),
+            rule: "range".to_string(),
+            message: format!("Must be between 1 second and {} days", MAX_INVITE_LIFETIME.as_secs() / 86400),
+        });
+    }
+    if !errors.is_empty() {
+        return validation_failed_response(errors);
+    }
+
+    let invite = match state
+        .invites
+        .create(&req.room_id, &user_id, req.max_uses, expires_in)
+        .await
+    {
+        Ok(invite) => invite,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let response = CreateInviteResponse { invite: invite.info() };
+    (StatusCode::CREATED, Json(response)).into_response()
+}
+
+async fn join_invite_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<JoinInviteRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = refuse_during_maintenance(&state).await {
+        return response;
+    }
+    let user_id = match authenticate_user(&state, &headers).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+    // The code is as good as a password, so it isn't logged
+    tracing::info!("Join invite request by {}", user_id);
+
+    let invite = match state.invites.usable(&req.code).await {
+        Ok(Some(invite)) => invite,
+        Ok(None) => return invite_invalid_response(),
+        Err(e) => return server_error_response(e.to_string()),
+    };
+
+    // The room may have been deleted since the invite was made, which must not cost the code a use
+    let room = match state.rooms.get(&invite.room_id).await {
+        Ok(Some(room)) => room,
+        Ok(None) => return invite_invalid_response(),
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    match state.invites.redeem(&invite.code).await {
+        Ok(Some(_)) => {}
+        // Someone else used the last use up in the meantime
+        Ok(None) => return invite_invalid_response(),
+        Err(e) => return server_error_response(e.to_string()),
+    }
+
+    enter_room(&state, &user_id, &room).await
+}
+
+async fn list_invites_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<ListInvitesRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    let user_id = match authenticate_user(&state, &headers).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+    if let Err(response) = require_room_owner(&state, &req.room_id, &user_id, "invites").await {
+        return response;
+    }
+
+    let invites = match state.invites.list(&req.room_id).await {
+        Ok(invites) => invites.iter().map(|invite| invite.info()).collect(),
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let response = ListInvitesResponse {
+        room_id: req.room_id,
+        invites,
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+async fn revoke_invite_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<RevokeInviteRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    let user_id = match authenticate_user(&state, &headers).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+
+    let invite = match state.invites.get(&req.code).await {
+        Ok(Some(invite)) => invite,
+        Ok(None) => return invite_invalid_response(),
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    if let Err(response) = require_room_owner(&state, &invite.room_id, &user_id, "invites").await {
+        return response;
+    }
+
+    match state.invites.revoke(&invite.code).await {
+        Ok(Some(_)) => {}
+        // revoked or used up in the meantime
+        Ok(None) => return invite_invalid_response(),
+        Err(e) => return server_error_response(e.to_string()),
+    }
+    let response = SuccessResponse {
+        message: format!("Invite {} for {} revoked", invite.code, invite.room_id),
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+async fn update_room_settings_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<UpdateRoomSettingsRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    let user_id = match authenticate_user(&state, &headers).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+    tracing::info!("Update room settings request: {} by {}", req.room_id, user_id);
+
+    let room = match state.rooms.get(&req.room_id).await {
+        Ok(Some(room)) => room,
+        Ok(None) => {
+            let error = ErrorResponse::RoomNotFound {
+                room_id: req.room_id.clone(),
+            };
+            return (StatusCode::NOT_FOUND, Json(error)).into_response();
+        }
+        Err(e) => return server_error_response(e.to_string()),
+    };
+
+    match update_room_settings(&state, &room, &user_id, req.settings).await {
+        Ok(details) => {
+            let response = UpdateRoomSettingsResponse {
+                room_id: req.room_id,
+                details,
+            };
+            (StatusCode::OK, Json(response)).into_response()
+        }
+        Err((status, error)) => (status, Json(error)).into_response(),
+    }
+}
+
+// Only the owner can delete their own room, operators can delete any through /admin/delete_room
+async fn delete_room_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<DeleteRoomRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    let user_id = match authenticate_user(&state, &headers).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+    tracing::info!("Delete room request: {} by {}", req.room_id, user_id);
+    if let Err(response) = require_room_owner(&state, &req.room_id, &user_id, "the room").await {
+        return response;
+    }
+
+    delete_room(&state, &req.room_id, &user_id).await
+}
+
+// Checks and saves a change to a room's settings, for both /update_room_settings and the websocket's
+// UpdateRoomSettings. Everyone in the room is sent RoomUpdated.
+async fn update_room_settings(
+    state: &AppState,
+    room: &RoomHandle,
+    user_id: &str,
+    change: RoomSettingsChange,
+) -> Result<RoomDetails, (StatusCode, ErrorResponse)> {
+    let mut settings = RoomSettings::clone(&room.settings());
+    if settings.owner != user_id {
+        let error = ErrorResponse::InvalidPermissions {
+            message: "Only the room owner can change its settings".to_string(),
+        };
+        return Err((StatusCode::FORBIDDEN, error));
+    }
+
+    let mut errors = validation::validate_room_limits(change.slow_mode_secs, change.history_len, state.config.room_history_len);
+    let topic = change.topic.as_deref().map(|topic| room_text("topic", topic, validation::TOPIC_MAX_LEN, &mut errors));
+    // the topic is shown on one line
+    let topic = topic.map(|topic| topic.map(|topic| topic.split_whitespace().collect::<Vec<_>>().join(" ")));
+    let description = change
+        .description
+        .as_deref()
+        .map(|description| room_text("description", description, validation::DESCRIPTION_MAX_LEN, &mut errors));
+    let visibility = change.visibility.unwrap_or(settings.visibility);
+    // a room that already has a password keeps it unless it is given a new one
+    if change.room_password.is_some() || settings.password_hash.is_none() {
+        errors.extend(validation::validate_room_password(visibility, change.room_password.as_deref()));
+    }
+    if !errors.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, ErrorResponse::ValidationFailed { errors }));
+    }
+
+    let mut changed = Vec::new();
+    if let Some(topic) = topic {
+        settings.topic = topic;
+        changed.push("topic");
+    }
+    if let Some(description) = description {
+        settings.description = description;
+        changed.push("description");
+    }
+    if let Some(password) = &change.room_password {
+        let hash = passwords::hash_password(password).await.map_err(|e| {
+            (StatusCode::INTERNAL_SERVER_ERROR, ErrorResponse::ServerError { message: e })
+        })?;
+        settings.password_hash = Some(hash);
+        changed.push("password");
+    }
+    if change.visibility.is_some() {
+        settings.visibility = visibility;
+        changed.push("visibility");
+    }
+    if visibility == RoomVisibility::Public {
+        settings.password_hash = None;
+    }
+    if let Some(max_members) = change.max_members {
+        settings.max_members = (max_members > 0).then_some(max_members);
+        changed.push("max_members");
+    }
+    if let Some(slow_mode_secs) = change.slow_mode_secs {
+        settings.slow_mode_secs = slow_mode_secs;
+        changed.push("slow_mode_secs");
+    }
+    if let Some(history_len) = change.history_len {
+        settings.history_len = Some(history_len);
+        changed.push("history_len");
+    }
+
+    let details = settings.details(state.config.room_history_len);
+    match state.rooms.update(room, settings, user_id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            let error = ErrorResponse::RoomNotFound {
+                room_id: room.room_id.clone(),
+            };
+            return Err((StatusCode::NOT_FOUND, error));
+        }
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, ErrorResponse::ServerError { message: e.to_string() })),
+    }
+    tracing::info!("{} changed the settings of room {}: {}", user_id, room.room_id, changed.join(", "));
+    state
+        .moderation
+        .record(ModerationAction::RoomSettingsChanged, user_id, &room.room_id, Some(&room.room_id), Some(changed.join(", ")))
+        .await;
+    Ok(details)
+}
+
+// A topic or description cleaned up like a chat message, None for "" which clears it. Problems are
+// added to `errors`.
+fn room_text(field: &str, text: &str, max_chars: usize, errors: &mut Vec<ValidationError>) -> Option<String> {
+    if text.trim().is_empty() {
+        return None;
+    }
+    match sanitize_message(text, max_chars) {
+        Ok(text) => Some(text),
+        Err(e) => {
+            errors.push(ValidationError {
+                field: field.to_string(),
+                rule: "content".to_string(),
+                message: e.message(),
+            });
+            None
+        }
+    }
+}
+
+// User ids, bot ids and incoming webhook names share one namespace, so nothing can post as someone
+// else. That includes deleted users, whose names stay on what they made (see users.rs).
+async fn name_taken(state: &AppState, name: &str) -> Result<bool, BackplaneError> {
+    Ok(state.users.exists(name).await?
+        || state.users.retired(name).await?
+        || state.bots.exists(name).await?
+        || state.bots.webhook_name_taken(name).await?)
+}
+
+// The bot whose API token is in the Authorization header
+async fn authenticate_bot(state: &AppState, headers: &HeaderMap) -> Result<Bot, Response> {
+    let bot = match bearer_token(headers) {
+        Some(token) => state.bots.authenticate(token).await.map_err(|e| server_error_response(e.to_string()))?,
+        None => None,
+    };
+    bot.ok_or_else(|| {
+        state.metrics.auth_failed(AuthFailure::BotToken);
+        let error = ErrorResponse::AuthenticationFailed {
+            message: "Missing or invalid bot API token".to_string(),
+        };
+        (StatusCode::UNAUTHORIZED, Json(error)).into_response()
+    })
+}
+
+fn single_error(field: &str, rule: &str, message: String) -> Response {
+    validation_failed_response(vec![ValidationError {
+        field: field.to_string(),
+        rule: rule.to_string(),
+        message,
+    }])
+}
+
+// Rate limits a chat message from a person, bot or webhook, and notes it in the moderation log
+// when that gets them muted
+async fn check_message(state: &AppState, sender: &str, ip: IpAddr, room_id: &str) -> Result<(), RateLimitError> {
+    let result = state.rate_limiter.check_message(sender, ip, room_id).await;
+    if let Err(RateLimitError::Muted { retry_after, started: true }) = result {
+        let detail = format!("muted for {}s", retry_after_secs(retry_after));
+        state
+            .moderation
+            .record(ModerationAction::Mute, moderation::SERVER, sender, Some(room_id), Some(detail))
+            .await;
+    }
+    result
+}
+
+// Posts a message into a room on behalf of a bot or webhook
+async fn post_to_room(state: &Arc<AppState>, room_id: &str, user_id: &str, content: &str) -> Result<(), String> {
+    let content = sanitize_message(content, state.config.max_message_chars).map_err(|e| e.message())?;
+    post_sanitized(state, room_id, user_id, content).await
+}
+
+// post_to_room() for content that was sanitized already
+async fn post_sanitized(state: &Arc<AppState>, room_id: &str, user_id: &str, content: String) -> Result<(), String> {
+    let chat_msg = ChatMessage {
+        room_id: room_id.to_string(),
+        user_id: user_id.to_string(),
+        message_id: uuid::Uuid::new_v4().to_string(),
+        content,
+        timestamp: chrono::Utc::now().to_rfc3339(),
+    };
+
+    // TODO: Save message to database
+    // db::save_message(&chat_msg).await;
+
+    if let Some(room) = state.rooms.get(room_id).await.map_err(|e| e.to_string())? {
+        room.post(chat_msg).await;
+        Metrics::add(&state.metrics.messages, 1);
+    }
+    Ok(())
+}
+
+async fn create_bot_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<CreateBotRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    let user_id = match authenticate_user(&state, &headers).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+    tracing::info!("Create bot request: {} by {}", req.bot_id, user_id);
+
+    let errors = validation::validate_bot_id(&req.bot_id);
+    if !errors.is_empty() {
+        return validation_failed_response(errors);
+    }
+
+    // The account may have been deleted while the session was still around
+    match state.users.exists(&user_id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            let error = ErrorResponse::UserNotFound { user_id };
+            return (StatusCode::NOT_FOUND, Json(error)).into_response();
+        }
+        Err(e) => return server_error_response(e.to_string()),
+    }
+    match name_taken(&state, &req.bot_id).await {
+        Ok(false) => {}
+        Ok(true) => {
+            let error = ErrorResponse::UserAlreadyExists { user_id: req.bot_id };
+            return (StatusCode::CONFLICT, Json(error)).into_response();
+        }
+        Err(e) => return server_error_response(e.to_string()),
+    }
+
+    let api_token = match state.bots.create(&req.bot_id, &user_id).await {
+        Ok(Some(token)) => token,
+        Ok(None) => {
+            let error = ErrorResponse::UserAlreadyExists { user_id: req.bot_id };
+            return (StatusCode::CONFLICT, Json(error)).into_response();
+        }
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let response = CreateBotResponse {
+        bot_id: req.bot_id,
+        api_token,
+    };
+    (StatusCode::CREATED, Json(response)).into_response()
+}
+
+async fn register_bot_command_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<RegisterBotCommandRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    let bot = match authenticate_bot(&state, &headers).await {
+        Ok(bot) => bot,
+        Err(response) => return response,
+    };
+    tracing::info!("Register command /{} in {} by bot {}", req.command, req.room_id, bot.bot_id);
+
+    if let Err(response) = require_room_owner(&state, &req.room_id, &bot.owner, "bots").await {
+        return response;
+    }
+
+    let mut errors = Vec::new();
+    if let Some(message) = bots::validate_command(&req.command) {
+        errors.push(ValidationError {
+            field: "command".to_string(),
+            rule: "command".to_string(),
+            message,
+        });
+    }
+    if let Some(message) = bots::validate_local_url(&req.callback_url) {
+        errors.push(ValidationError {
+            field: "callback_url".to_string(),
+            rule: "local_url".to_string(),
+            message,
+        });
+    }
+    if !errors.is_empty() {
+        return validation_failed_response(errors);
+    }
+
+    let command = BotCommand {
+        room_id: req.room_id.clone(),
+        command: req.command.clone(),
+        bot_id: bot.bot_id,
+        description: req.description,
+        callback_url: req.callback_url,
+    };
+    match state.bots.register_command(command).await {
+        Ok(true) => {}
+        Ok(false) => {
+            let error = ErrorResponse::InvalidPermissions {
+                message: format!("/{} is already registered by another bot in {}", req.command, req.room_id),
+            };
+            return (StatusCode::CONFLICT, Json(error)).into_response();
+        }
+        Err(e) => return server_error_response(e.to_string()),
+    }
+
+    let response = SuccessResponse {
+        message: format!("/{} registered in {}", req.command, req.room_id),
+    };
+    (StatusCode::CREATED, Json(response)).into_response()
+}
+
+async fn unregister_bot_command_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<UnregisterBotCommandRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    let bot = match authenticate_bot(&state, &headers).await {
+        Ok(bot) => bot,
+        Err(response) => return response,
+    };
+
+    match state.bots.unregister_command(&req.room_id, &req.command, &bot.bot_id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            let error = ErrorResponse::CommandNotFound { command: req.command };
+            return (StatusCode::NOT_FOUND, Json(error)).into_response();
+        }
+        Err(e) => return server_error_response(e.to_string()),
+    }
+    let response = SuccessResponse {
+        message: format!("/{} unregistered from {}", req.command, req.room_id),
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+// Bot commands anyone in the room can use
+async fn list_bot_commands_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(req): Json<ListBotCommandsRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    match state.rooms.exists(&req.room_id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            let error = ErrorResponse::RoomNotFound { room_id: req.room_id };
+            return (StatusCode::NOT_FOUND, Json(error)).into_response();
+        }
+        Err(e) => return server_error_response(e.to_string()),
+    }
+
+    let commands = match state.bots.commands_in(&req.room_id).await {
+        Ok(commands) => commands.iter().map(|command| command.info()).collect(),
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let response = ListBotCommandsResponse {
+        room_id: req.room_id,
+        commands,
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+// Lets a bot post on its own, eg an answer that took too long to send back from its callback
+async fn bot_send_message_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<BotSendMessageRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    let bot = match authenticate_bot(&state, &headers).await {
+        Ok(bot) => bot,
+        Err(response) => return response,
+    };
+    if let Err(response) = require_room_owner(&state, &req.room_id, &bot.owner, "bots").await {
+        return response;
+    }
+    if let Err(limit) = check_message(&state, &bot.bot_id, addr.ip(), &req.room_id).await {
+        return rate_limited_response(&limit.message(), limit.retry_after());
+    }
+
+    if let Err(message) = post_to_room(&state, &req.room_id, &bot.bot_id, &req.content).await {
+        return single_error("content", "content", message);
+    }
+    let response = SuccessResponse {
+        message: format!("Message posted in {}", req.room_id),
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+async fn create_webhook_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<CreateWebhookRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    let user_id = match authenticate_user(&state, &headers).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+    tracing::info!("Create webhook request: {:?} by {}", req, user_id);
+    if let Err(response) = require_room_owner(&state, &req.room_id, &user_id, "webhooks").await {
+        return response;
+    }
+
+    let errors = validation::validate_webhook_name(&req.name);
+    if !errors.is_empty() {
+        return validation_failed_response(errors);
+    }
+    match name_taken(&state, &req.name).await {
+        Ok(false) => {}
+        Ok(true) => {
+            let error = ErrorResponse::UserAlreadyExists { user_id: req.name };
+            return (StatusCode::CONFLICT, Json(error)).into_response();
+        }
+        Err(e) => return server_error_response(e.to_string()),
+    }
+
+    let secret = bots::generate_secret();
+    let kind = WebhookKind::Incoming {
+        name: req.name,
+        secret: secret.clone(),
+    };
+    let webhook = match state.bots.create_webhook(&req.room_id, &user_id, kind).await {
+        Ok(webhook) => webhook,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let response = CreateWebhookResponse {
+        webhook: webhook.info(),
+        secret,
+    };
+    (StatusCode::CREATED, Json(response)).into_response()
+}
+
+async fn create_outgoing_webhook_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<CreateOutgoingWebhookRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    let user_id = match authenticate_user(&state, &headers).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+    tracing::info!("Create outgoing webhook request: {:?} by {}", req, user_id);
+    if let Err(response) = require_room_owner(&state, &req.room_id, &user_id, "webhooks").await {
+        return response;
+    }
+
+    if let Some(message) = bots::validate_local_url(&req.url) {
+        return single_error("url", "local_url", message);
+    }
+    let trigger = req.trigger.map(|trigger| trigger.trim().to_string()).filter(|trigger| !trigger.is_empty());
+    if trigger.as_ref().is_some_and(|trigger| trigger.contains(char::is_whitespace)) {
+        return single_error("trigger", "single_word", "Must be a single word".to_string());
+    }
+
+    let kind = WebhookKind::Outgoing { url: req.url, trigger };
+    let webhook = match state.bots.create_webhook(&req.room_id, &user_id, kind).await {
+        Ok(webhook) => webhook,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let response = CreateOutgoingWebhookResponse { webhook: webhook.info() };
+    (StatusCode::CREATED, Json(response)).into_response()
+}
+
+async fn list_webhooks_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<ListWebhooksRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    let user_id = match authenticate_user(&state, &headers).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+    if let Err(response) = require_room_owner(&state, &req.room_id, &user_id, "webhooks").await {
+        return response;
+    }
+
+    let webhooks = match state.bots.webhooks_in(&req.room_id).await {
+        Ok(webhooks) => webhooks.iter().map(|webhook| webhook.info()).collect(),
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let response = ListWebhooksResponse {
+        room_id: req.room_id,
+        webhooks,
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+async fn delete_webhook_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<DeleteWebhookRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    let user_id = match authenticate_user(&state, &headers).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+
+    let webhook = match state.bots.webhook(&req.webhook_id).await {
+        Ok(Some(webhook)) => webhook,
+        Ok(None) => return webhook_not_found_response(),
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    if let Err(response) = require_room_owner(&state, &webhook.room_id, &user_id, "webhooks").await {
+        return response;
+    }
+
+    if let Err(e) = state.bots.delete_webhook(&webhook.webhook_id).await {
+        return server_error_response(e.to_string());
+    }
+    let response = SuccessResponse {
+        message: format!("Webhook {} for {} deleted", webhook.webhook_id, webhook.room_id),
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+// Same answer for a webhook that doesn't exist and a wrong secret, so ids can't be probed
+fn webhook_not_found_response() -> Response {
+    let error = ErrorResponse::AuthenticationFailed {
+        message: "Unknown webhook or wrong secret".to_string(),
+    };
+    (StatusCode::UNAUTHORIZED, Json(error)).into_response()
+}
+
+// POST /webhooks/<webhook_id> with the secret in the X-Webhook-Secret header
+async fn webhook_message_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(webhook_id): Path<String>,
+    headers: HeaderMap,
+    Json(req): Json<WebhookMessageRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+
+    let given_secret = headers
+        .get("x-webhook-secret")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let webhook = match state.bots.webhook(&webhook_id).await {
+        Ok(webhook) => webhook,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let incoming = webhook.and_then(|webhook| match webhook.kind {
+        WebhookKind::Incoming { name, secret } if bots::secrets_match(given_secret, &secret) => {
+            Some((webhook.room_id, name))
+        }
+        _ => None,
+    });
+    let Some((room_id, name)) = incoming else {
+        state.metrics.auth_failed(AuthFailure::WebhookSecret);
+        return webhook_not_found_response();
+    };
+
+    if let Err(limit) = check_message(&state, &name, addr.ip(), &room_id).await {
+        return rate_limited_response(&limit.message(), limit.retry_after());
+    }
+    if let Err(message) = post_to_room(&state, &room_id, &name, &req.content).await {
+        return single_error("content", "content", message);
+    }
+    let response = SuccessResponse {
+        message: format!("Message posted in {}", room_id),
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+// Lists public and private rooms, unlisted rooms are never shown
+async fn all_rooms_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(req): Json<ListRoomsRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+
+    let all = match state.rooms.all().await {
+        Ok(all) => all,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let mut rooms: Vec<RoomInfo> = Vec::new();
+    for room in all.iter().filter(|room| room.settings().visibility != RoomVisibility::Unlisted) {
+        match room.info().await {
+            Ok(info) if req.only_active && info.users_count == 0 => {}
+            Ok(info) => rooms.push(info),
+            Err(e) => return server_error_response(e.to_string()),
+        }
+    }
+    rooms.sort_by(|a, b| a.room_id.cmp(&b.room_id));
+
+    (StatusCode::OK, Json(ListRoomsResponse { rooms })).into_response()
+}
+
+// Members currently connected to a room, used by the client's member list
+// Who is in a public room is open to anyone, for other rooms only to the people in it and its owner
+async fn list_room_users_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<ListRoomUsersRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    let user_id = match authenticate_user(&state, &headers).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+
+    let room = match state.rooms.get(&req.room_id).await {
+        Ok(Some(room)) => room,
+        Ok(None) => {
+            let error = ErrorResponse::RoomNotFound {
+                room_id: req.room_id,
+            };
+            return (StatusCode::NOT_FOUND, Json(error)).into_response();
+        }
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let active_users = match room.members().await {
+        Ok(members) => members,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+
+    let settings = room.settings();
+    let admitted = match state.rooms.admitted_room(&user_id).await {
+        Ok(admitted) => admitted.is_some_and(|room_id| room_id == req.room_id),
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let allowed = settings.visibility == RoomVisibility::Public
+        || settings.owner == user_id
+        || admitted
+        || active_users.contains(&user_id);
+    if !allowed {
+        // an unlisted room's name is as good as a secret, so don't confirm it exists
+        if settings.visibility == RoomVisibility::Unlisted {
+            let error = ErrorResponse::RoomNotFound {
+                room_id: req.room_id,
+            };
+            return (StatusCode::NOT_FOUND, Json(error)).into_response();
+        }
+        let error = ErrorResponse::InvalidPermissions {
+            message: "Only members of the room can see who is in it".to_string(),
+        };
+        return (StatusCode::FORBIDDEN, Json(error)).into_response();
+    }
+
+    let response = ListRoomUsersResponse {
+        room_id: req.room_id,
+        active_users,
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+// The connection is for whoever the session token in the Authorization header belongs to, so an
+// operator ending their sessions also keeps them from connecting again
+async fn websocket_handler(
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many connection attempts", retry_after);
+    }
+
+    if state.shutdown.is_started() {
+        return unavailable_response("Server is shutting down".to_string());
+    }
+    if let Err(response) = refuse_during_maintenance(&state).await {
+        return response;
+    }
+    let user_id = match authenticate_user(&state, &headers).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+    tracing::info!("WebSocket connection request from user: {}", user_id);
+
+    let max_bytes = state.config.max_ws_message_bytes;
+    ws.max_message_size(max_bytes)
+        .max_frame_size(max_bytes)
+        .on_upgrade(move |socket| {
+            // everything logged about the connection carries who it is, and the room once it is known
+            let span = tracing::info_span!(
+                "ws",
+                user_id = %user_id,
+                room_id = tracing::field::Empty,
+                ip = %addr.ip()
+            );
+            handle_websocket(socket, user_id, addr.ip(), state).instrument(span)
+        })
+}
+
+// Prometheus text format, see metrics.rs. Meant for a scraper on the same network, so it isn't
+// rate limited.
+async fn metrics_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let mut gauges = vec![
+        ("chat_ws_connections", "Open websocket connections", state.shutdown.open_connections() as u64),
+        ("chat_rooms_running", "Rooms with a task on this instance", state.rooms.running().await as u64),
+    ];
+    // left out rather than failing the whole scrape when the backplane can't be reached
+    if let Ok(rooms) = state.rooms.count().await {
+        gauges.push(("chat_rooms", "Rooms, on every instance", rooms as u64));
+    }
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(&gauges),
+    )
+}
+
+// Liveness: answering at all is the whole check. Like /readyz it is for the orchestrator running
+// the server and isn't rate limited.
+async fn healthz_handler() -> impl IntoResponse {
+    (StatusCode::OK, "ok")
+}
+
+// How long /readyz waits on the backplane before calling it unreachable
+const READINESS_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Readiness: 200 while the server can take traffic, 503 with the checks that failed otherwise.
+// There is no database yet (see the db:: TODOs), so no migrations to check either; the backplane
+// is where everything is kept.
+async fn readyz_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let storage = match tokio::time::timeout(READINESS_TIMEOUT, state.backplane.ping()).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some("Backplane didn't answer in time".to_string()),
+    };
+    let shutting_down = state.shutdown.is_started().then(|| "Server is shutting down".to_string());
+
+    let checks: Vec<ReadinessCheck> = [("storage", storage), ("accepting_connections", shutting_down)]
+        .into_iter()
+        .map(|(name, error)| ReadinessCheck {
+            name: name.to_string(),
+            ok: error.is_none(),
+            error,
+        })
+        .collect();
+    let ready = checks.iter().all(|check| check.ok);
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(ReadinessResponse { ready, checks }))
+}
+
+async fn status_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = require_admin(&state, &headers).await {
+        return response;
+    }
+
+    let storage = state.backplane.stats().await;
+    if let Err(e) = &storage {
+        tracing::warn!("Failed to get storage stats for /status: {}", e);
+    }
+    let response = StatusResponse {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        instance_id: state.rooms.instance_id().to_string(),
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        shutting_down: state.shutdown.is_started(),
+        connected_users: state.shutdown.open_connections(),
+        rooms: state.rooms.count().await.ok(),
+        rooms_running: state.rooms.running().await,
+        storage: storage.ok(),
+        maintenance: state.maintenance.message().await.unwrap_or_else(|e| {
+            tracing::warn!("Failed to check maintenance mode for /status: {}", e);
+            None
+        }),
+        motd: motd(&state).await,
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+// Operator endpoints take CHAT_ADMIN_TOKEN as "Authorization: Bearer <token>", and are turned off
+// while it isn't set
+async fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), Response> {
+    let Some(admin_token) = &state.config.admin_token else {
+        let error = ErrorResponse::InvalidPermissions {
+            message: "The admin API is turned off, set CHAT_ADMIN_TOKEN to use it".to_string(),
+        };
+        return Err((StatusCode::FORBIDDEN, Json(error)).into_response());
+    };
+    match bearer_token(headers) {
+        Some(token) if bots::secrets_match(token, admin_token) => Ok(()),
+        _ => {
+            state.metrics.auth_failed(AuthFailure::AdminToken);
+            let error = ErrorResponse::AuthenticationFailed {
+                message: "Missing or invalid admin token".to_string(),
+            };
+            Err((StatusCode::UNAUTHORIZED, Json(error)).into_response())
+        }
+    }
+}
+
+// The following are the admin API, for the people running the server and for chatadmin (see
+// src/bin/chatadmin.rs). Every request needs the admin token, see require_admin. What is done
+// to people and rooms is noted in the moderation log.
+
+// Every room, unlisted ones too
+async fn admin_list_rooms_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = require_admin(&state, &headers).await {
+        return response;
+    }
+
+    let all = match state.rooms.all().await {
+        Ok(all) => all,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let mut rooms: Vec<RoomInfo> = Vec::new();
+    for room in all {
+        match room.info().await {
+            Ok(info) => rooms.push(info),
+            Err(e) => return server_error_response(e.to_string()),
+        }
+    }
+    rooms.sort_by(|a, b| a.room_id.cmp(&b.room_id));
+
+    (StatusCode::OK, Json(ListRoomsResponse { rooms })).into_response()
+}
+
+async fn admin_room_info_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<AdminRoomRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = require_admin(&state, &headers).await {
+        return response;
+    }
+
+    let room = match state.rooms.get(&req.room_id).await {
+        Ok(Some(room)) => room,
+        Ok(None) => {
+            let error = ErrorResponse::RoomNotFound {
+                room_id: req.room_id,
+            };
+            return (StatusCode::NOT_FOUND, Json(error)).into_response();
+        }
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let info = match room.info().await {
+        Ok(info) => info,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let members = match room.members().await {
+        Ok(members) => members,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let invites = match state.invites.list(&req.room_id).await {
+        Ok(invites) => invites,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let bot_commands = match state.bots.commands_in(&req.room_id).await {
+        Ok(bot_commands) => bot_commands,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let webhooks = match state.bots.webhooks_in(&req.room_id).await {
+        Ok(webhooks) => webhooks,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+
+    let response = AdminRoomResponse {
+        room: info,
+        details: room.details(),
+        password_protected: room.settings().password_hash.is_some(),
+        members,
+        invites: invites.iter().map(|invite| invite.info()).collect(),
+        bot_commands: bot_commands.iter().map(|command| command.info()).collect(),
+        webhooks: webhooks.iter().map(|webhook| webhook.info()).collect(),
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+// Everyone in the room is told it was deleted and disconnected
+async fn admin_delete_room_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<AdminRoomRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = require_admin(&state, &headers).await {
+        return response;
+    }
+
+    delete_room(&state, &req.room_id, moderation::ADMIN).await
+}
+
+// Deletes a room on every instance along with its invites, bot commands and webhooks, for both
+// /delete_room and /admin/delete_room
+async fn delete_room(state: &AppState, room_id: &str, deleted_by: &str) -> Response {
+    match state.rooms.delete(room_id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            let error = ErrorResponse::RoomNotFound {
+                room_id: room_id.to_string(),
+            };
+            return (StatusCode::NOT_FOUND, Json(error)).into_response();
+        }
+        Err(e) => return server_error_response(e.to_string()),
+    }
+    tracing::info!("Room {} deleted by {}", room_id, deleted_by);
+    // Otherwise they would carry over to a new room with the same name
+    if let Err(e) = state.invites.forget_room(room_id).await {
+        tracing::warn!("Failed to drop the invites to deleted room {}: {}", room_id, e);
+    }
+    if let Err(e) = state.bots.forget_room(room_id).await {
+        tracing::warn!("Failed to drop the bot commands and webhooks of deleted room {}: {}", room_id, e);
+    }
+    state
+        .moderation
+        .record(ModerationAction::DeleteRoom, deleted_by, room_id, Some(room_id), None)
+        .await;
+
+    let response = SuccessResponse {
+        message: format!("Room {} deleted", room_id),
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+async fn admin_list_users_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = require_admin(&state, &headers).await {
+        return response;
+    }
+
+    match state.users.all().await {
+        Ok(users) => (StatusCode::OK, Json(AdminListUsersResponse { users })).into_response(),
+        Err(e) => server_error_response(e.to_string()),
+    }
+}
+
+async fn admin_user_info_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<AdminUserRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = require_admin(&state, &headers).await {
+        return response;
+    }
+
+    match state.users.exists(&req.user_id).await {
+        Ok(true) => {}
+        Ok(false) => return user_not_found_response(req.user_id),
+        Err(e) => return server_error_response(e.to_string()),
+    }
+    let (room, connected) = match connected_room(&state, &req.user_id).await {
+        Ok(found) => found,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    let sessions = match state.sessions.count(&req.user_id).await {
+        Ok(sessions) => sessions,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+
+    let response = AdminUserResponse {
+        user_id: req.user_id,
+        room_id: room.map(|room| room.room_id),
+        connected,
+        sessions,
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+// Logs them out everywhere and kicks them out of their room, then removes the account. Rooms, bots
+// and webhooks they own are left as they are, and the name can't be signed up again so nobody
+// else ends up owning them.
+async fn admin_delete_user_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<AdminUserRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = require_admin(&state, &headers).await {
+        return response;
+    }
+
+    match state.users.delete(&req.user_id).await {
+        Ok(true) => {}
+        Ok(false) => return user_not_found_response(req.user_id),
+        Err(e) => return server_error_response(e.to_string()),
+    }
+    if let Err(e) = disconnect_user(&state, &req.user_id).await {
+        return server_error_response(e.to_string());
+    }
+    tracing::info!("User {} deleted through the admin API", req.user_id);
+    state
+        .moderation
+        .record(ModerationAction::DeleteUser, moderation::ADMIN, &req.user_id, None, None)
+        .await;
+
+    let response = SuccessResponse {
+        message: format!("User {} deleted", req.user_id),
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+// Ends every session the user has and kicks them out of their room. They can log back in.
+async fn admin_disconnect_user_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<AdminUserRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = require_admin(&state, &headers).await {
+        return response;
+    }
+
+    match state.users.exists(&req.user_id).await {
+        Ok(true) => {}
+        Ok(false) => return user_not_found_response(req.user_id),
+        Err(e) => return server_error_response(e.to_string()),
+    }
+    let sessions = match disconnect_user(&state, &req.user_id).await {
+        Ok(sessions) => sessions,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    tracing::info!("User {} disconnected through the admin API", req.user_id);
+    let detail = format!("{} sessions ended", sessions);
+    state
+        .moderation
+        .record(ModerationAction::Disconnect, moderation::ADMIN, &req.user_id, None, Some(detail))
+        .await;
+
+    let response = SuccessResponse {
+        message: format!("Disconnected {} and ended {} sessions", req.user_id, sessions),
+    };
+    (StatusCode::OK, Json(response)).into_response()
 }
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| format!("{}=debug", env!("CARGO_CRATE_NAME")).into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
-    let app_state = Arc::new(AppState {
-        rooms: Mutex::new(HashMap::new()),
-        room_channels: Mutex::new(HashMap::new()),
-        user_rooms: Mutex::new(HashMap::new()),
-    });
+// Logs them out everywhere too, in case someone else had their password
+async fn admin_reset_password_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<ResetPasswordRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = require_admin(&state, &headers).await {
+        return response;
+    }
 
-    let app = Router::new()
-        .route("/create_room", post(create_room_handler))
-        .route("/join_room", post(join_room_handler))
-        .route("/ws", get(websocket_handler))
-        .with_state(app_state);
+    let errors = validation::validate_password("new_password", &req.new_password);
+    if !errors.is_empty() {
+        return validation_failed_response(errors);
+    }
+    // Checked before spending time on hashing
+    match state.users.exists(&req.user_id).await {
+        Ok(true) => {}
+        Ok(false) => return user_not_found_response(req.user_id),
+        Err(e) => return server_error_response(e.to_string()),
+    }
+    let password_hash = match passwords::hash_password(&req.new_password).await {
+        Ok(hash) => hash,
+        Err(e) => return server_error_response(e),
+    };
+    // The user may have been deleted while we were hashing
+    match state.users.set_password_hash(&req.user_id, &password_hash).await {
+        Ok(true) => {}
+        Ok(false) => return user_not_found_response(req.user_id),
+        Err(e) => return server_error_response(e.to_string()),
+    }
+    if let Err(e) = disconnect_user(&state, &req.user_id).await {
+        return server_error_response(e.to_string());
+    }
+    // the guesses were against the old password, so the account is open again right away. Addresses
+    // locked out of it stay locked out until that runs out.
+    state.rate_limiter.clear_account_login_failures(&req.user_id).await;
+    tracing::info!("Password of {} reset through the admin API", req.user_id);
+    state
+        .moderation
+        .record(ModerationAction::ResetPassword, moderation::ADMIN, &req.user_id, None, None)
+        .await;
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
-        .await
-        .unwrap();
-    tracing::info!("Server listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+    let response = SuccessResponse {
+        message: format!("Password of {} reset", req.user_id),
+    };
+    (StatusCode::OK, Json(response)).into_response()
 }
 
+async fn admin_moderation_log_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<ModerationLogRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = require_admin(&state, &headers).await {
+        return response;
+    }
 
-// TEMPORARY: For demo purposes, we'll accept user_id in the request body later should use JWT
-#[derive(Deserialize,Debug)]
-struct CreateRoomRequestDemo {
-    room_id: String,
-    room_password: String,
-    user_id: String, // TEMPORARY: Remove when JWT auth is implemented
+    let entries = match state.moderation.recent(req.limit.unwrap_or(usize::MAX)).await {
+        Ok(entries) => entries,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    (StatusCode::OK, Json(ModerationLogResponse { entries })).into_response()
 }
 
-async fn create_room_handler(
+// Posts an announcement as "system", which the client shows apart from what people say, into the
+// rooms it names or into every room. With send_at it is kept until then instead (see
+// announcements.rs).
+async fn admin_announce_handler(
     State(state): State<Arc<AppState>>,
-    Json(req): Json<CreateRoomRequestDemo>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<AnnouncementRequest>,
 ) -> impl IntoResponse {
-    tracing::info!("Create room request: {:?}", req);
-
-    let mut rooms = state.rooms.lock().await;
-    
-    // Check if room already exists
-    if rooms.contains_key(&req.room_id) {
-        let error = ErrorResponse::RoomAlreadyExists {
-            room_id: req.room_id.clone(),
-        };
-        return (StatusCode::CONFLICT, Json(error)).into_response();
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = require_admin(&state, &headers).await {
+        return response;
     }
 
-    // TODO: Validate room_id format and password policy
-    // TODO: Extract user_id from JWT token in Authorization header (remove user_id from body)
-
-    // Create room
-    let room = Room {
-        room_id: req.room_id.clone(),
-        room_password: req.room_password.clone(),
-        owner: req.user_id.clone(),
-        members: HashSet::new(),
+    let content = match sanitize_message(&req.content, state.config.max_message_chars) {
+        Ok(content) => content,
+        Err(e) => return single_error("content", "content", e.message()),
     };
+    if let Some(room_ids) = &req.room_ids {
+        if room_ids.is_empty() {
+            return single_error("room_ids", "required", "Name at least one room, or leave room_ids out for every room".to_string());
+        }
+        for room_id in room_ids {
+            match state.rooms.exists(room_id).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    let error = ErrorResponse::RoomNotFound {
+                        room_id: room_id.clone(),
+                    };
+                    return (StatusCode::NOT_FOUND, Json(error)).into_response();
+                }
+                Err(e) => return server_error_response(e.to_string()),
+            }
+        }
+    }
 
-    // Create broadcast channel for this room
-    let (tx, _rx) = broadcast::channel(100);
-    state.room_channels.lock().await.insert(req.room_id.clone(), tx);
-
-    rooms.insert(req.room_id.clone(), room);
+    let Some(send_at) = &req.send_at else {
+        let sent_to = match send_announcement(&state, &content, req.room_ids.as_deref()).await {
+            Ok(sent_to) => sent_to,
+            Err(e) => return server_error_response(e),
+        };
+        tracing::info!("Announcement sent to {} through the admin API", sent_to);
+        let response = SuccessResponse {
+            message: format!("Announced to {}", sent_to),
+        };
+        return (StatusCode::OK, Json(response)).into_response();
+    };
 
-    // Add creator to user_rooms mapping (they automatically join their created room)
-    state.user_rooms.lock().await.insert(req.user_id.clone(), req.room_id.clone());
+    let send_at = match chrono::DateTime::parse_from_rfc3339(send_at) {
+        Ok(send_at) => send_at.with_timezone(&chrono::Utc),
+        Err(_) => {
+            return single_error("send_at", "format", "Must be an RFC 3339 time, eg 2030-01-31T18:00:00Z".to_string());
+        }
+    };
+    if send_at <= chrono::Utc::now() {
+        return single_error("send_at", "future", "Must be in the future".to_string());
+    }
+    let announcement = match state.announcements.schedule(content, req.room_ids, send_at).await {
+        Ok(announcement) => announcement,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    tracing::info!(
+        "Announcement {} scheduled for {} through the admin API",
+        announcement.announcement_id,
+        announcement.send_at
+    );
 
-    // TODO: Save room to database
-    // db::save_room(&req.room_id, &req.room_password, &req.user_id).await;
+    let response = SuccessResponse {
+        message: format!("Announcement {} scheduled for {}", announcement.announcement_id, announcement.send_at),
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
 
-    let response = CreateRoomResponse {
-        room_id: req.room_id,
-        created_at: chrono::Utc::now().to_rfc3339(),
+// Posts an announcement into `room_ids`, or sends it to everyone connected when None, and notes it
+// in the moderation log. Returns who it went to. `content` was sanitized when the announcement was
+// made (see admin_announce_handler).
+async fn send_announcement(state: &Arc<AppState>, content: &str, room_ids: Option<&[String]>) -> Result<String, String> {
+    let target = match room_ids {
+        Some(room_ids) => {
+            // rooms deleted since it was scheduled are skipped by post_to_room
+            for room_id in room_ids {
+                post_sanitized(state, room_id, validation::SYSTEM_USER, content.to_string()).await?;
+            }
+            format!("rooms {}", room_ids.join(", "))
+        }
+        None => {
+            state.announcements.announce_to_everyone(content.to_string());
+            Metrics::add(&state.metrics.messages, 1);
+            "everyone".to_string()
+        }
     };
+    state
+        .moderation
+        .record(ModerationAction::Announcement, moderation::ADMIN, &target, None, Some(content.to_string()))
+        .await;
+    Ok(target)
+}
 
-    (StatusCode::CREATED, Json(response)).into_response()
+// Sends the scheduled announcements whose time has come, called every second
+async fn send_due_announcements(state: &Arc<AppState>) {
+    let due = match state.announcements.take_due(chrono::Utc::now()).await {
+        Ok(due) => due,
+        Err(e) => {
+            tracing::warn!("Failed to check for scheduled announcements: {}", e);
+            return;
+        }
+    };
+    for announcement in due {
+        match send_announcement(state, &announcement.content, announcement.room_ids.as_deref()).await {
+            Ok(sent_to) => tracing::info!(
+                "Scheduled announcement {} sent to {}",
+                announcement.announcement_id,
+                sent_to
+            ),
+            Err(e) => tracing::warn!("Failed to send scheduled announcement {}: {}", announcement.announcement_id, e),
+        }
+    }
 }
 
-// TEMPORARY: For demo purposes, we'll accept user_id in the request body
-// In production, this should be extracted from JWT token
-#[derive(Deserialize,Debug)]
-struct JoinRoomRequestDemo {
-    room_id: String,
-    room_password: String,
-    user_id: String, // TEMPORARY: Remove when JWT auth is implemented
+async fn admin_list_announcements_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = require_admin(&state, &headers).await {
+        return response;
+    }
+
+    match state.announcements.scheduled().await {
+        Ok(announcements) => (StatusCode::OK, Json(ListAnnouncementsResponse { announcements })).into_response(),
+        Err(e) => server_error_response(e.to_string()),
+    }
 }
 
-async fn join_room_handler(
+async fn admin_cancel_announcement_handler(
     State(state): State<Arc<AppState>>,
-    Json(req): Json<JoinRoomRequestDemo>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<CancelAnnouncementRequest>,
 ) -> impl IntoResponse {
-    tracing::info!("Join room request: {:?}", req);
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = require_admin(&state, &headers).await {
+        return response;
+    }
 
-    let rooms = state.rooms.lock().await;
-    
-    // Check if room exists
-    let room = match rooms.get(&req.room_id) {
-        Some(r) => r,
-        None => {
-            let error = ErrorResponse::RoomNotFound {
-                room_id: req.room_id.clone(),
+    match state.announcements.cancel(&req.announcement_id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            let error = ErrorResponse::AnnouncementNotFound {
+                announcement_id: req.announcement_id,
             };
             return (StatusCode::NOT_FOUND, Json(error)).into_response();
         }
+        Err(e) => return server_error_response(e.to_string()),
+    }
+    tracing::info!("Announcement {} cancelled through the admin API", req.announcement_id);
+
+    let response = SuccessResponse {
+        message: format!("Announcement {} cancelled", req.announcement_id),
     };
+    (StatusCode::OK, Json(response)).into_response()
+}
 
-    // Verify password
-    if room.room_password != req.room_password {
-        let error = ErrorResponse::InvalidPassword {
-            message: "Incorrect room password".to_string(),
-        };
-        return (StatusCode::UNAUTHORIZED, Json(error)).into_response();
+// Sets or clears the message of the day. Whoever is already logged in sees the new one the next
+// time they log in.
+async fn admin_motd_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<MotdRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = require_admin(&state, &headers).await {
+        return response;
     }
 
-    // Add user to user_rooms mapping
-    state.user_rooms.lock().await.insert(req.user_id.clone(), req.room_id.clone());
+    let motd = match req.motd.filter(|motd| !motd.trim().is_empty()) {
+        Some(motd) => match sanitize_message(&motd, state.config.max_message_chars) {
+            Ok(motd) => Some(motd),
+            Err(e) => return single_error("motd", "content", e.message()),
+        },
+        None => None,
+    };
+    let motd = match state.announcements.set_motd(motd).await {
+        Ok(motd) => motd,
+        Err(e) => return server_error_response(e.to_string()),
+    };
+    tracing::info!("Message of the day changed through the admin API");
+    state
+        .moderation
+        .record(ModerationAction::MotdChanged, moderation::ADMIN, "server", None, motd.clone())
+        .await;
 
-    // TODO: Load chat history from database
-    // let chat_history = db::get_chat_history(&req.room_id, 50).await;
-    let chat_history = Vec::new(); // Empty for now
+    let message = match motd {
+        Some(_) => "Message of the day set",
+        None => "Message of the day cleared",
+    };
+    let response = SuccessResponse {
+        message: message.to_string(),
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
 
-    // TODO: Save user room membership to database
-    // db::add_user_to_room(&req.user_id, &req.room_id).await;
+async fn admin_maintenance_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<MaintenanceRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = state.rate_limiter.check_request(addr.ip()).await {
+        return rate_limited_response("Too many requests", retry_after);
+    }
+    if let Err(response) = require_admin(&state, &headers).await {
+        return response;
+    }
 
-    let response = JoinRoomResponse {
-        room_id: req.room_id,
-        chat_history,
+    let message = if req.enabled {
+        let message = match state.maintenance.start(req.message).await {
+            Ok(message) => message,
+            Err(e) => return server_error_response(e.to_string()),
+        };
+        tracing::warn!("Maintenance mode turned on through the admin API");
+        state
+            .moderation
+            .record(ModerationAction::MaintenanceStarted, moderation::ADMIN, "server", None, Some(message.clone()))
+            .await;
+        format!("Maintenance mode on: {}", message)
+    } else {
+        match state.maintenance.end().await {
+            Ok(true) => {}
+            Ok(false) => {
+                let response = SuccessResponse {
+                    message: "Maintenance mode wasn't on".to_string(),
+                };
+                return (StatusCode::OK, Json(response)).into_response();
+            }
+            Err(e) => return server_error_response(e.to_string()),
+        }
+        tracing::warn!("Maintenance mode turned off through the admin API");
+        state
+            .moderation
+            .record(ModerationAction::MaintenanceEnded, moderation::ADMIN, "server", None, None)
+            .await;
+        "Maintenance mode off".to_string()
     };
+    (StatusCode::OK, Json(SuccessResponse { message })).into_response()
+}
 
-    (StatusCode::OK, Json(response)).into_response()
+fn user_not_found_response(user_id: String) -> Response {
+    let error = ErrorResponse::UserNotFound { user_id };
+    (StatusCode::NOT_FOUND, Json(error)).into_response()
 }
 
-#[derive(Deserialize)]
-struct WsQuery {
-    user_id: String,
+// The room the user was last let into, and whether they are connected to it
+async fn connected_room(state: &AppState, user_id: &str) -> Result<(Option<RoomHandle>, bool), BackplaneError> {
+    let room = match state.rooms.admitted_room(user_id).await? {
+        Some(room_id) => state.rooms.get(&room_id).await?,
+        None => None,
+    };
+    let connected = match &room {
+        Some(room) => room.members().await?.iter().any(|member| member == user_id),
+        None => false,
+    };
+    Ok((room, connected))
 }
 
-async fn websocket_handler(
-    ws: WebSocketUpgrade,
-    Query(query): Query<WsQuery>,
-    State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    tracing::info!("WebSocket connection request from user: {}", query.user_id);
-    
-    // TODO: Validate JWT token from query params or headers
-    // For now, we just accept the user_id
+// Ends every session the user has and kicks them out of their room, closing their websocket on
+// whichever instance it is connected to. Returns how many sessions were ended.
+async fn disconnect_user(state: &AppState, user_id: &str) -> Result<usize, BackplaneError> {
+    let sessions = state.sessions.revoke_all(user_id).await?;
+    if let (Some(room), true) = connected_room(state, user_id).await? {
+        room.remove(user_id).await;
+    }
+    Ok(sessions)
+}
+
+// Times every request for the chat_http_request_duration_seconds histogram, by route
+async fn track_latency(State(state): State<Arc<AppState>>, path: MatchedPath, request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let response = next.run(request).await;
+    state.metrics.observe_request(path.as_str(), started.elapsed());
+    response
+}
+
+// Everything the receive loop needs to know about the client it is serving
+#[derive(Clone)]
+struct ClientConn {
+    user_id: String,
+    room_id: String,
+    room: RoomHandle,
+    ip: IpAddr,
+    // Messages meant only for this client (errors, pongs) rather than the whole room
+    direct_tx: mpsc::UnboundedSender<String>,
+}
 
-    ws.on_upgrade(move |socket| handle_websocket(socket, query.user_id, state))
+impl ClientConn {
+    fn send(&self, msg: &ServerWsMessage) -> Result<(), String> {
+        let json = serde_json::to_string(msg)
+            .map_err(|e| format!("Failed to serialize message: {}", e))?;
+        // Only fails if the send task is already gone, in which case the client is disconnecting
+        let _ = self.direct_tx.send(json);
+        Ok(())
+    }
 }
 
-async fn handle_websocket(socket: WebSocket, user_id: String, state: Arc<AppState>) {
+async fn handle_websocket(socket: WebSocket, user_id: String, ip: IpAddr, state: Arc<AppState>) {
+    // open until the end of the cleanup below, which shutting down waits for
+    let _connection = state.shutdown.track_connection();
     let (mut sender, mut receiver) = socket.split();
 
     // Determine which room this user is in
-    let room_id = {
-        let user_rooms = state.user_rooms.lock().await;
-        user_rooms.get(&user_id).cloned()
+    let room = match state.rooms.admitted_room(&user_id).await {
+        Ok(Some(room_id)) => state.rooms.get(&room_id).await,
+        other => other.map(|_| None),
     };
+    let room = room.unwrap_or_else(|e| {
+        tracing::error!("Failed to find the room for {}: {}", user_id, e);
+        None
+    });
 
-    let room_id = match room_id {
-        Some(id) => id,
+    let room = match room {
+        Some(room) => room,
         None => {
             // User hasn't joined a room yet
             tracing::warn!("User {} connected without joining a room", user_id);
             let error = ServerWsMessage::Error {
                 error_msg: "You must join a room before connecting to WebSocket".to_string(),
+                retry_after_ms: None,
             };
             let _ = sender.send(Message::Text(serde_json::to_string(&error).unwrap().into())).await;
             return;
         }
     };
 
-    // Add user to room members
-    {
-        let mut rooms = state.rooms.lock().await;
-        if let Some(room) = rooms.get_mut(&room_id) {
-            room.members.insert(user_id.clone());
-        }
-    }
+    let room_id = room.room_id.clone();
+    tracing::Span::current().record("room_id", room_id.as_str());
 
-    // Get broadcast receiver for this room
-    let mut rx = {
-        let channels = state.room_channels.lock().await;
-        match channels.get(&room_id) {
-            Some(tx) => tx.subscribe(),
-            None => {
-                tracing::error!("No broadcast channel for room {}", room_id);
-                return;
-            }
-        }
+    // Add user to room members, which also tells the room they joined
+    let Some(subscription) = room.connect(&user_id).await else {
+        tracing::error!("Room {} isn't running", room_id);
+        return;
     };
 
-    // Notify room that user joined
-    let join_msg = ServerWsMessage::UserJoined {
-        room_id: room_id.clone(),
-        user_id: user_id.clone(),
+    let connection_id = subscription.connection_id;
+
+    let (direct_tx, direct_rx) = mpsc::unbounded_channel::<String>();
+    let (queue, mut outbound_rx) = mpsc::channel::<Message>(state.config.ws_outbound_queue);
+    let outbound = Outbound {
+        queue,
+        policy: state.config.ws_overflow_policy,
     };
-    broadcast_to_room(&state, &room_id, &join_msg).await;
 
-    // Spawn task to send broadcast messages to this user
+    // Spawn task to queue broadcast messages (and messages addressed only to this user) for this
+    // user, and another to write them out as fast as the client reads them
+    let mut forward_task = tokio::spawn(forward_to_client(
+        subscription.events,
+        subscription.seen_through,
+        direct_rx,
+        outbound,
+        room.clone(),
+        user_id.clone(),
+        state.clone(),
+    ).in_current_span());
     let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            if sender.send(Message::Text(msg.into())).await.is_err() {
+        while let Some(msg) = outbound_rx.recv().await {
+            let close = matches!(msg, Message::Close(_));
+            if sender.send(msg).await.is_err() || close {
                 break;
             }
         }
-    });
+    }.in_current_span());
 
-    // Clone for recv task
-    let tx = {
-        let channels = state.room_channels.lock().await;
-        channels.get(&room_id).unwrap().clone()
+    let conn = ClientConn {
+        user_id: user_id.clone(),
+        room_id: room_id.clone(),
+        room: room.clone(),
+        ip,
+        direct_tx,
     };
-    let recv_user_id = user_id.clone();
-    let recv_room_id = room_id.clone();
     let recv_state = state.clone();
 
     // Spawn task to receive messages from this user
     let mut recv_task = tokio::spawn(async move {
         while let Some(Ok(Message::Text(text))) = receiver.next().await {
-            if let Err(e) = handle_client_message(
-                &text,
-                &recv_user_id,
-                &recv_room_id,
-                &tx,
-                &recv_state,
-            )
-            .await
-            {
+            if let Err(e) = handle_client_message(&text, &conn, &recv_state).await {
                 tracing::error!("Error handling message: {}", e);
             }
         }
-    });
+    }.in_current_span());
 
-    // Wait for either task to complete
+    // Wait for any task to complete
     tokio::select! {
-        _ = &mut send_task => recv_task.abort(),
-        _ = &mut recv_task => send_task.abort(),
+        drain = &mut forward_task => {
+            // the connection is being closed on purpose, let the client have what is still queued for it
+            if drain.unwrap_or(false) {
+                recv_task.abort();
+                let _ = (&mut send_task).await;
+            }
+        }
+        _ = &mut send_task => {}
+        _ = &mut recv_task => {}
     }
+    forward_task.abort();
+    send_task.abort();
+    recv_task.abort();
 
-    // Cleanup: remove user from room
-    {
-        let mut rooms = state.rooms.lock().await;
-        if let Some(room) = rooms.get_mut(&room_id) {
-            room.members.remove(&user_id);
+    // Cleanup: remove the connection from the room, which tells the room they left if it was
+    // their last
+    if room.disconnect(&user_id, &connection_id).await {
+        if let Err(e) = state.rooms.release(&user_id, &room_id).await {
+            tracing::warn!("Failed to release {} from room {}: {}", user_id, room_id, e);
         }
     }
 
-    // Remove from user_rooms mapping
-    state.user_rooms.lock().await.remove(&user_id);
+    tracing::info!("User {} disconnected from room {}", user_id, room_id);
+}
 
-    // Notify room that user left
-    let leave_msg = ServerWsMessage::UserLeft {
-        room_id: room_id.clone(),
-        user_id: user_id.clone(),
-    };
-    broadcast_to_room(&state, &room_id, &leave_msg).await;
+// A connection's frames on their way to the socket
+struct Outbound {
+    queue: mpsc::Sender<Message>,
+    policy: OverflowPolicy,
+}
 
-    tracing::info!("User {} disconnected from room {}", user_id, room_id);
+impl Outbound {
+    // Queues a room broadcast, following the overflow policy if the queue is full. False once the
+    // connection should be closed.
+    async fn push(&self, frame: Utf8Bytes, metrics: &Metrics) -> bool {
+        let frame = Message::Text(frame);
+        if self.policy == OverflowPolicy::Resync {
+            return self.queue.send(frame).await.is_ok();
+        }
+        match self.queue.try_send(frame) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) if self.policy == OverflowPolicy::DropNewest => {
+                Metrics::add(&metrics.ws_dropped_frames, 1);
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                Metrics::add(&metrics.ws_slow_disconnects, 1);
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+// Moves a room's broadcasts and the messages meant only for this user into the outbound queue.
+//
+// A client that reads slower than the room talks first fills its outbound queue and then falls
+// behind the room's broadcast channel, which skips it past the broadcasts it had no room for
+// (RecvError::Lagged). Instead of dropping the connection, the chat messages it skipped are
+// fetched from the room's history and sent in order, and copies of them still coming through the
+// channel are passed over. Joins and leaves in the gap aren't kept anywhere and are lost.
+//
+// When the server shuts down, ServerShutdown and a close frame are queued behind everything else
+// and true is returned, for the caller to let the send task get through the queue. The same goes
+// for the room being deleted or an operator removing this user from it, after the broadcast
+// saying so. Otherwise the connection is finished with straight away.
+async fn forward_to_client(
+    mut events: broadcast::Receiver<RoomEvent>,
+    seen_through: u64,
+    mut direct_rx: mpsc::UnboundedReceiver<String>,
+    outbound: Outbound,
+    room: RoomHandle,
+    user_id: String,
+    state: Arc<AppState>,
+) -> bool {
+    let metrics = &state.metrics;
+    let mut announcements = state.announcements.subscribe();
+    // the last chat message queued for this client
+    let mut last_seq = seen_through;
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    if let Some(seq) = event.seq {
+                        if seq <= last_seq {
+                            continue;
+                        }
+                        last_seq = seq;
+                    }
+                    let closes = event.closes(&user_id);
+                    if !outbound.push(event.frame, metrics).await {
+                        break;
+                    }
+                    metrics.broadcast_lag.observe(event.sent_at.elapsed());
+                    if closes {
+                        let close = CloseFrame {
+                            code: close_code::NORMAL,
+                            reason: "Removed from the room".into(),
+                        };
+                        let _ = outbound.queue.send(Message::Close(Some(close))).await;
+                        return true;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    Metrics::add(&metrics.ws_lag_events, 1);
+                    let resync = room.history_after(last_seq).await;
+                    tracing::warn!(
+                        "User {} fell {} broadcasts behind in room {}, resending {} messages",
+                        user_id,
+                        skipped,
+                        room.room_id,
+                        resync.messages.len()
+                    );
+                    Metrics::add(&metrics.ws_resynced_messages, resync.messages.len() as u64);
+                    let mut frames = Vec::new();
+                    if resync.incomplete {
+                        frames.push(ServerWsMessage::Error {
+                            error_msg: "Your connection fell behind and some messages were missed".to_string(),
+                            retry_after_ms: None,
+                        });
+                    }
+                    for (seq, message) in resync.messages {
+                        last_seq = seq;
+                        frames.push(ServerWsMessage::MessageBroadcast(message));
+                    }
+                    for frame in frames {
+                        let Ok(json) = serde_json::to_string(&frame) else {
+                            continue;
+                        };
+                        if !outbound.push(json.into(), metrics).await {
+                            return false;
+                        }
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            // Direct messages are few and always wait for room in the queue
+            Some(msg) = direct_rx.recv() => {
+                if outbound.queue.send(Message::Text(msg.into())).await.is_err() {
+                    break;
+                }
+            }
+            // So are announcements to everyone, which come as a message from "system" in this room
+            announcement = announcements.recv() => match announcement {
+                Ok(announcement) => {
+                    let message = ServerWsMessage::MessageBroadcast(announcement.in_room(&room.room_id));
+                    let Ok(json) = serde_json::to_string(&message) else {
+                        continue;
+                    };
+                    if outbound.queue.send(Message::Text(json.into())).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("User {} missed {} announcements", user_id, skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = state.shutdown.started() => {
+                let notice = ServerWsMessage::ServerShutdown {
+                    reconnect_after_ms: state.config.shutdown_reconnect_after.as_millis() as u64,
+                };
+                if let Ok(json) = serde_json::to_string(&notice) {
+                    let _ = outbound.queue.send(Message::Text(json.into())).await;
+                }
+                let close = CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "Server shutting down".into(),
+                };
+                let _ = outbound.queue.send(Message::Close(Some(close))).await;
+                return true;
+            }
+        }
+    }
+    false
 }
 
 async fn handle_client_message(
     text: &str,
-    user_id: &str,
-    room_id: &str,
-    tx: &broadcast::Sender<String>,
+    conn: &ClientConn,
     state: &Arc<AppState>,
 ) -> Result<(), String> {
     let msg: ClientWsMessage = serde_json::from_str(text)
         .map_err(|e| format!("Failed to parse message: {}", e))?;
+    let user_id = conn.user_id.as_str();
+    let room_id = conn.room_id.as_str();
 
     match msg {
         ClientWsMessage::SendMessage { room_id: msg_room_id, content } => {
@@ -328,6 +2679,41 @@ async fn handle_client_message(
                 return Err("Cannot send to a room you're not in".to_string());
             }
 
+            // The owner isn't held back by their own room's slow mode
+            let settings = conn.room.settings();
+            if settings.slow_mode_secs > 0 && settings.owner != user_id {
+                let interval = Duration::from_secs(settings.slow_mode_secs);
+                if let Err(limit) = state.rate_limiter.check_slow_mode(user_id, room_id, interval).await {
+                    return conn.send(&ServerWsMessage::Error {
+                        error_msg: limit.message(),
+                        retry_after_ms: Some(limit.retry_after().as_millis() as u64),
+                    });
+                }
+            }
+
+            // Drop the message and tell the sender when to retry rather than letting them flood the room
+            if let Err(limit) = check_message(state, user_id, conn.ip, room_id).await {
+                return conn.send(&ServerWsMessage::Error {
+                    error_msg: limit.message(),
+                    retry_after_ms: Some(limit.retry_after().as_millis() as u64),
+                });
+            }
+
+            let content = match sanitize_message(&content, state.config.max_message_chars) {
+                Ok(content) => content,
+                Err(e) => {
+                    return conn.send(&ServerWsMessage::Error {
+                        error_msg: e.message(),
+                        retry_after_ms: None,
+                    });
+                }
+            };
+
+            // Slash commands go to the bot that registered them instead of the room
+            if let Some((command, args)) = bots::parse_command(&content) {
+                return run_bot_command(state, conn, command, args).await;
+            }
+
             let chat_msg = ChatMessage {
                 room_id: room_id.to_string(),
                 user_id: user_id.to_string(),
@@ -339,11 +2725,11 @@ async fn handle_client_message(
             // TODO: Save message to database
             // db::save_message(&chat_msg).await;
 
-            let broadcast_msg = ServerWsMessage::MessageBroadcast(chat_msg);
-            let json = serde_json::to_string(&broadcast_msg)
-                .map_err(|e| format!("Failed to serialize message: {}", e))?;
-            
-            let _ = tx.send(json);
+            conn.room.post(chat_msg.clone()).await;
+            Metrics::add(&state.metrics.messages, 1);
+
+            // Only messages from users are sent out, so two webhooks can't keep answering each other
+            notify_outgoing_webhooks(state, chat_msg).await;
         }
 
         ClientWsMessage::LeaveRoom { room_id: leave_room_id } => {
@@ -355,40 +2741,384 @@ async fn handle_client_message(
         }
 
         ClientWsMessage::KickUser { room_id: kick_room_id, user_id: kick_user_id } => {
-            // TODO: Verify that the requesting user is the room owner
-            // For now, we'll allow anyone to kick (not secure!)
-            
-            let kicked_msg = ServerWsMessage::UserKicked {
-                room_id: kick_room_id.clone(),
-                user_id: kick_user_id.clone(),
+            if kick_room_id != room_id {
+                return Err("Cannot kick from a room you're not in".to_string());
+            }
+            // Only the owner can kick, and only an attempt that was allowed ends up in the moderation log
+            let refusal = if conn.room.settings().owner != user_id {
+                Some("Only the room owner can kick".to_string())
+            } else if kick_user_id == user_id {
+                Some("You can't kick yourself".to_string())
+            } else {
+                match conn.room.members().await {
+                    Ok(members) if members.contains(&kick_user_id) => None,
+                    Ok(_) => Some(format!("{} is not in the room", kick_user_id)),
+                    Err(e) => return Err(e.to_string()),
+                }
             };
-            broadcast_to_room(state, &kick_room_id, &kicked_msg).await;
-            
-            // TODO: Actually disconnect the kicked user
+            if let Some(error_msg) = refusal {
+                return conn.send(&ServerWsMessage::Error {
+                    error_msg,
+                    retry_after_ms: None,
+                });
+            }
+
+            // Everyone in the room is told, and the kicked member's connection is closed after that
+            conn.room.remove(&kick_user_id).await;
+            tracing::info!("{} kicked {} from room {}", user_id, kick_user_id, room_id);
+            state
+                .moderation
+                .record(ModerationAction::Kick, user_id, &kick_user_id, Some(room_id), None)
+                .await;
+        }
+
+        ClientWsMessage::UpdateRoomSettings { room_id: update_room_id, settings } => {
+            if update_room_id != room_id {
+                return Err("Cannot change the settings of a room you're not in".to_string());
+            }
+            // Everyone, the sender included, is sent RoomUpdated once it is saved
+            if let Err((_, error)) = update_room_settings(state, &conn.room, user_id, settings).await {
+                return conn.send(&ServerWsMessage::Error {
+                    error_msg: refusal_message(error),
+                    retry_after_ms: None,
+                });
+            }
         }
 
         ClientWsMessage::Ping { timestamp } => {
-            let pong = ServerWsMessage::Pong { timestamp };
-            let json = serde_json::to_string(&pong)
-                .map_err(|e| format!("Failed to serialize pong: {}", e))?;
-            let _ = tx.send(json);
+            conn.send(&ServerWsMessage::Pong { timestamp })?;
+        }
+    }
+
+    Ok(())
+}
+
+// What a websocket client is told when update_room_settings turns it down
+fn refusal_message(error: ErrorResponse) -> String {
+    match error {
+        ErrorResponse::ValidationFailed { errors } => {
+            let broken: Vec<String> = errors.iter().map(|err| format!("{}: {}", err.field, err.message)).collect();
+            broken.join("; ")
         }
+        ErrorResponse::RoomNotFound { room_id } => format!("Room {} no longer exists", room_id),
+        ErrorResponse::InvalidPermissions { message } | ErrorResponse::ServerError { message } => message,
+        error => format!("{:?}", error),
     }
+}
 
+// Hands a slash command to its bot. The callback runs in its own task so a slow bot doesn't hold
+// up the sender's other messages, and whatever the bot answers is posted into the room as the bot.
+async fn run_bot_command(state: &Arc<AppState>, conn: &ClientConn, command: &str, args: &str) -> Result<(), String> {
+    let Some(bot_command) = state.bots.command(&conn.room_id, command).await.map_err(|e| e.to_string())? else {
+        return conn.send(&ServerWsMessage::Error {
+            error_msg: format!("Unknown command /{}", command),
+            retry_after_ms: None,
+        });
+    };
+
+    let invocation = BotCommandInvocation {
+        room_id: conn.room_id.clone(),
+        user_id: conn.user_id.clone(),
+        command: command.to_string(),
+        args: args.to_string(),
+        timestamp: chrono::Utc::now().to_rfc3339(),
+    };
+    let state = state.clone();
+    let conn = conn.clone();
+    tokio::spawn(async move {
+        let reply: Result<BotReply, String> = state.bots.deliver(&bot_command.callback_url, &invocation).await;
+        let posted = match reply {
+            Ok(BotReply { content: Some(content) }) => {
+                post_to_room(&state, &conn.room_id, &bot_command.bot_id, &content).await
+            }
+            Ok(BotReply { content: None }) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = posted {
+            tracing::warn!("Bot {} failed to handle /{}: {}", bot_command.bot_id, bot_command.command, e);
+            let _ = conn.send(&ServerWsMessage::Error {
+                error_msg: format!("/{} isn't working right now", bot_command.command),
+                retry_after_ms: None,
+            });
+        }
+    });
     Ok(())
 }
 
-async fn broadcast_to_room(state: &Arc<AppState>, room_id: &str, msg: &ServerWsMessage) {
-    let json = match serde_json::to_string(msg) {
-        Ok(j) => j,
+// Sends a copy of the message to every outgoing webhook in the room that wants it. Nobody is
+// waiting on these, so failures are only logged.
+async fn notify_outgoing_webhooks(state: &Arc<AppState>, chat_msg: ChatMessage) {
+    let webhooks: Vec<_> = match state.bots.webhooks_in(&chat_msg.room_id).await {
+        Ok(webhooks) => webhooks.into_iter().filter(|webhook| webhook.matches(&chat_msg.content)).collect(),
         Err(e) => {
-            tracing::error!("Failed to serialize broadcast message: {}", e);
+            tracing::warn!("Failed to look up the webhooks of {}: {}", chat_msg.room_id, e);
             return;
         }
     };
+    if webhooks.is_empty() {
+        return;
+    }
 
-    let channels = state.room_channels.lock().await;
-    if let Some(tx) = channels.get(room_id) {
-        let _ = tx.send(json);
+    let state = state.clone();
+    tokio::spawn(async move {
+        for webhook in webhooks {
+            let WebhookKind::Outgoing { url, .. } = &webhook.kind else {
+                continue;
+            };
+            if let Err(e) = state.bots.deliver(url, &chat_msg).await {
+                tracing::warn!("Outgoing webhook {} failed: {}", webhook.webhook_id, e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backplane::LocalBackplane;
+
+    // The server as it is started, on a backplane of its own
+    fn test_state(room_history_len: usize) -> Arc<AppState> {
+        let mut config = ServerConfig::from_env();
+        config.room_history_len = room_history_len;
+        Arc::new(AppState::new(config, Arc::new(LocalBackplane::default())))
+    }
+
+    async fn public_room(state: &AppState, room_id: &str) -> RoomHandle {
+        let settings = RoomSettings::new("alice", RoomVisibility::Public, None);
+        state.rooms.create(room_id, settings).await.unwrap().unwrap()
+    }
+
+    fn chat_message(room_id: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            room_id: room_id.to_string(),
+            user_id: "alice".to_string(),
+            message_id: uuid::Uuid::new_v4().to_string(),
+            content: content.to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+
+    // Everything queued for a connection until it has been quiet for a moment
+    async fn queued(queue: &mut mpsc::Receiver<Message>) -> Vec<ServerWsMessage> {
+        let mut frames = Vec::new();
+        while let Ok(Some(frame)) = tokio::time::timeout(Duration::from_millis(200), queue.recv()).await {
+            if let Message::Text(text) = frame {
+                frames.push(serde_json::from_str(text.as_str()).unwrap());
+            }
+        }
+        frames
+    }
+
+    // Has the room send `count` chat messages before bob's connection reads any, more than fit
+    // in the broadcast channel, and returns what was queued for him once he does
+    async fn fall_behind(state: Arc<AppState>, count: usize) -> Vec<ServerWsMessage> {
+        let room = public_room(&state, "lounge").await;
+        let subscription = room.connect("bob").await.unwrap();
+        for n in 0..count {
+            room.post(chat_message("lounge", &n.to_string())).await;
+        }
+        // only answered once the room has sent them all
+        room.history_after(0).await;
+
+        let (queue, mut queued_rx) = mpsc::channel(1000);
+        let outbound = Outbound {
+            queue,
+            policy: OverflowPolicy::Resync,
+        };
+        let (_direct, direct_rx) = mpsc::unbounded_channel();
+        let forward = tokio::spawn(forward_to_client(
+            subscription.events,
+            subscription.seen_through,
+            direct_rx,
+            outbound,
+            room,
+            "bob".to_string(),
+            state.clone(),
+        ));
+        let frames = queued(&mut queued_rx).await;
+        forward.abort();
+        assert_eq!(state.metrics.ws_lag_events.load(std::sync::atomic::Ordering::Relaxed), 1);
+        frames
+    }
+
+    fn contents(frames: &[ServerWsMessage]) -> Vec<String> {
+        frames
+            .iter()
+            .filter_map(|frame| match frame {
+                ServerWsMessage::MessageBroadcast(message) => Some(message.content.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // The history is written behind the room's back, so give it a moment
+    async fn history_of(room: &RoomHandle, len: usize) -> Vec<ChatMessage> {
+        for _ in 0..100 {
+            let history = room.history().await.unwrap();
+            if history.len() >= len {
+                return history;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the history never reached {} messages", len);
+    }
+
+    #[tokio::test]
+    async fn announcements_to_rooms_are_kept_for_whoever_joins_later() {
+        let state = test_state(50);
+        let lounge = public_room(&state, "lounge").await;
+        let kitchen = public_room(&state, "kitchen").await;
+
+        let rooms = vec!["lounge".to_string()];
+        assert_eq!(send_announcement(&state, "Back in 5", Some(&rooms)).await.unwrap(), "rooms lounge");
+        let history = history_of(&lounge, 1).await;
+        assert_eq!(history[0].user_id, validation::SYSTEM_USER);
+        assert_eq!(history[0].content, "Back in 5");
+        assert!(kitchen.history().await.unwrap().is_empty());
+
+        let log = state.moderation.recent(10).await.unwrap();
+        assert!(matches!(log[0].action, ModerationAction::Announcement));
+    }
+
+    #[tokio::test]
+    async fn announcements_to_everyone_reach_every_connection() {
+        let state = test_state(50);
+        let relay = state.clone();
+        tokio::spawn(async move { relay.announcements.relay().await });
+        let mut announcements = state.announcements.subscribe();
+        // let the relay subscribe to the backplane first
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(send_announcement(&state, "Hello all", None).await.unwrap(), "everyone");
+        let announcement = announcements.recv().await.unwrap();
+        let message = announcement.in_room("lounge");
+        assert_eq!(message.user_id, validation::SYSTEM_USER);
+        assert_eq!(message.content, "Hello all");
+    }
+
+    #[tokio::test]
+    async fn scheduled_announcements_go_out_once_due() {
+        let state = test_state(50);
+        let lounge = public_room(&state, "lounge").await;
+        let rooms = Some(vec!["lounge".to_string()]);
+        let now = chrono::Utc::now();
+        state
+            .announcements
+            .schedule("Due".to_string(), rooms.clone(), now - chrono::Duration::seconds(1))
+            .await
+            .unwrap();
+        state
+            .announcements
+            .schedule("Not yet".to_string(), rooms, now + chrono::Duration::hours(1))
+            .await
+            .unwrap();
+
+        send_due_announcements(&state).await;
+        send_due_announcements(&state).await;
+        assert_eq!(history_of(&lounge, 1).await[0].content, "Due");
+        // and only once
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(lounge.history().await.unwrap().len(), 1);
+        let scheduled = state.announcements.scheduled().await.unwrap();
+        assert_eq!(scheduled.len(), 1);
+        assert_eq!(scheduled[0].content, "Not yet");
+    }
+
+    async fn room_with(state: &AppState, visibility: RoomVisibility, password: Option<&str>) -> RoomHandle {
+        let password_hash = match password {
+            Some(password) => Some(passwords::hash_password(password).await.unwrap()),
+            None => None,
+        };
+        let settings = RoomSettings::new("alice", visibility, password_hash);
+        state.rooms.create("lounge", settings).await.unwrap().unwrap()
+    }
+
+    fn refused_rule(result: Result<RoomDetails, (StatusCode, ErrorResponse)>) -> String {
+        match result {
+            Err((StatusCode::BAD_REQUEST, ErrorResponse::ValidationFailed { errors })) => errors[0].rule.clone(),
+            other => panic!("expected a validation error, got {:?}", other.map_err(|(status, _)| status)),
+        }
+    }
+
+    #[tokio::test]
+    async fn making_a_private_room_public_drops_its_password() {
+        let state = test_state(50);
+        let room = room_with(&state, RoomVisibility::Private, Some("Secret#1234")).await;
+        let change = RoomSettingsChange {
+            visibility: Some(RoomVisibility::Public),
+            ..Default::default()
+        };
+        let details = update_room_settings(&state, &room, "alice", change).await.unwrap();
+        assert_eq!(details.visibility, RoomVisibility::Public);
+        assert_eq!(room.settings().visibility, RoomVisibility::Public);
+        assert_eq!(room.settings().password_hash, None);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn public_rooms_cant_have_a_password() {
+        let state = test_state(50);
+        let room = room_with(&state, RoomVisibility::Public, None).await;
+        let change = RoomSettingsChange {
+            room_password: Some("Secret#1234".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(refused_rule(update_room_settings(&state, &room, "alice", change).await), "not_allowed");
+        assert_eq!(room.settings().password_hash, None);
+    }
+
+    #[tokio::test]
+    async fn making_an_unlisted_room_private_needs_a_password() {
+        let state = test_state(50);
+        let room = room_with(&state, RoomVisibility::Unlisted, None).await;
+        let change = RoomSettingsChange {
+            visibility: Some(RoomVisibility::Private),
+            ..Default::default()
+        };
+        assert_eq!(refused_rule(update_room_settings(&state, &room, "alice", change).await), "required");
+        assert_eq!(room.settings().visibility, RoomVisibility::Unlisted);
+
+        let change = RoomSettingsChange {
+            visibility: Some(RoomVisibility::Private),
+            room_password: Some("Secret#1234".to_string()),
+            ..Default::default()
+        };
+        update_room_settings(&state, &room, "alice", change).await.unwrap();
+        let settings = room.settings();
+        assert_eq!(settings.visibility, RoomVisibility::Private);
+        assert!(passwords::verify_password("Secret#1234", settings.password_hash.as_deref().unwrap()).await);
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_can_change_the_settings() {
+        let state = test_state(50);
+        let room = room_with(&state, RoomVisibility::Private, Some("Secret#1234")).await;
+        let change = RoomSettingsChange {
+            visibility: Some(RoomVisibility::Public),
+            ..Default::default()
+        };
+        let refused = update_room_settings(&state, &room, "mallory", change).await;
+        assert!(matches!(refused, Err((StatusCode::FORBIDDEN, ErrorResponse::InvalidPermissions { .. }))));
+        assert_eq!(room.settings().visibility, RoomVisibility::Private);
+        assert!(room.settings().password_hash.is_some());
+    }
+
+    #[tokio::test]
+    async fn a_lagging_connection_is_resent_what_it_skipped() {
+        let frames = fall_behind(test_state(500), 300).await;
+        let expected: Vec<String> = (0..300).map(|n| n.to_string()).collect();
+        assert_eq!(contents(&frames), expected);
+        assert!(!frames.iter().any(|frame| matches!(frame, ServerWsMessage::Error { .. })));
+    }
+
+    #[tokio::test]
+    async fn a_gap_the_history_cant_fill_is_reported() {
+        let frames = fall_behind(test_state(20), 300).await;
+        assert!(matches!(
+            &frames[0],
+            ServerWsMessage::Error { error_msg, .. } if error_msg.contains("fell behind")
+        ));
+        let expected: Vec<String> = (280..300).map(|n| n.to_string()).collect();
+        assert_eq!(contents(&frames), expected);
+    }
+}
@@ -0,0 +1,42 @@
+use std::sync::Arc;
+
+use crate::backplane::{Backplane, BackplaneResult};
+
+// Maintenance mode, turned on and off through the admin API. While it is on nobody new gets in:
+// signing up, logging in, creating or joining rooms and connecting websockets are answered with
+// 503 and the operator's message. Whoever is already connected stays connected. Kept in the
+// backplane (see backplane.rs), so it is on for every instance at once.
+
+const MAINTENANCE_KEY: &str = "maintenance";
+
+// What people are told when the operator didn't say
+const DEFAULT_MESSAGE: &str = "The server is down for maintenance, please try again later";
+
+pub struct Maintenance {
+    backplane: Arc<dyn Backplane>,
+}
+
+impl Maintenance {
+    pub fn new(backplane: Arc<dyn Backplane>) -> Self {
+        Maintenance { backplane }
+    }
+
+    // What people are told, None while maintenance mode is off
+    pub async fn message(&self) -> BackplaneResult<Option<String>> {
+        self.backplane.get(MAINTENANCE_KEY).await
+    }
+
+    pub async fn start(&self, message: Option<String>) -> BackplaneResult<String> {
+        let message = message
+            .map(|message| message.trim().to_string())
+            .filter(|message| !message.is_empty())
+            .unwrap_or_else(|| DEFAULT_MESSAGE.to_string());
+        self.backplane.set(MAINTENANCE_KEY, &message, None).await?;
+        Ok(message)
+    }
+
+    // False if it wasn't on
+    pub async fn end(&self) -> BackplaneResult<bool> {
+        Ok(self.backplane.take(MAINTENANCE_KEY).await?.is_some())
+    }
+}
@@ -1,6 +1,12 @@
-use serde::{Serialize, Deserialize};
 // This file has all the messages and asscoiated datastructure to be sent between the server and client
 // for both HTTPS and Websocket requests/responses.
+// Both sides keep a copy of the full protocol, so not every type is used by each of them.
+#![allow(dead_code)]
+
+use serde::{Serialize, Deserialize};
+
+// Who can see and join a room, and the rules a request broke; they live with the shared validation
+pub use chat_room_shared::validation::{RoomVisibility, ValidationError};
 
 
 // The following are associated with the HTTPS Account/Authentication requests
@@ -34,6 +40,17 @@ pub struct DeleteAccountRequest{}
 pub struct AuthSuccessResponse{
     pub token: String,
     pub user_id:String,
+    // single use, trade it in at /refresh_session for a new session once this one is gone
+    pub refresh_token: String,
+    // the message of the day, to show once logged in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motd: Option<String>,
+}
+
+// Starts a new session without the password, see sessions.rs
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct RefreshSessionRequest{
+    pub refresh_token: String,
 }
 
 // The following are associated with the HTTPS room management requests
@@ -42,13 +59,19 @@ pub struct CreateRoomRequest{
 // MUST IMPLEMENT NO CONFLICT VALIDATION(even if client already has validation)
     pub room_id: String,
 // MUST IMPLEMENT POLICY VALIDATION(even if client already has validation)
-    pub room_password: String,
+    // public rooms don't have a password, unlisted rooms may leave it out to be invite only
+    #[serde(default)]
+    pub room_password: Option<String>,
+    #[serde(default)]
+    pub visibility: RoomVisibility,
 }
 
 #[derive(Serialize,Deserialize,Debug,Clone)]
 pub struct JoinRoomRequest{
     pub room_id: String,
-    pub room_password: String,
+    // not needed for public rooms
+    #[serde(default)]
+    pub room_password: Option<String>,
 }
 
 #[derive(Serialize,Deserialize,Debug,Clone)]
@@ -56,6 +79,39 @@ pub struct DeleteRoomRequest{
     pub room_id: String,
 }
 
+// Only the room owner can create, list and revoke invites
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct CreateInviteRequest{
+    pub room_id: String,
+    // None for unlimited uses, Some(1) for a single use code
+    pub max_uses: Option<u32>,
+    // None for an invite that never expires
+    pub expires_in_secs: Option<u64>,
+}
+
+// Joins the room the code is for without needing its password
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct JoinInviteRequest{
+    pub code: String,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct ListInvitesRequest{
+    pub room_id: String,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct RevokeInviteRequest{
+    pub code: String,
+}
+
+// Only the room owner can change its settings
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct UpdateRoomSettingsRequest{
+    pub room_id: String,
+    pub settings: RoomSettingsChange,
+}
+
 // even though JoinRoomRequest should get a deafault amount of chat history this request is necessary
 // if a client wants to load in even more history
 #[derive(Serialize,Deserialize,Debug,Clone)]
@@ -91,6 +147,14 @@ pub struct CreateRoomResponse{
 pub struct JoinRoomResponse{
     pub room_id: String,
     pub chat_history: Vec<ChatMessage>,
+    pub details: RoomDetails,
+}
+
+// The room's settings once they were changed
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct UpdateRoomSettingsResponse{
+    pub room_id: String,
+    pub details: RoomDetails,
 }
 
 #[derive(Serialize,Deserialize,Debug,Clone)]
@@ -107,12 +171,298 @@ pub struct ListRoomsResponse{
     pub rooms: Vec<RoomInfo>,
 }
 
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct CreateInviteResponse{
+    pub invite: InviteInfo,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct ListInvitesResponse{
+    pub room_id: String,
+    pub invites: Vec<InviteInfo>,
+}
+
 #[derive(Serialize,Deserialize,Debug,Clone)]
 pub struct ListRoomUsersResponse{
     pub room_id: String,
     pub active_users: Vec<String>,
 }
 
+// The following are associated with bots and webhooks (see bots.rs). Bots authenticate with
+// "Authorization: Bearer <api_token>" instead of a user_id/password.
+// The logged in user becomes the bot's owner, bots can only act in rooms their owner owns
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct CreateBotRequest{
+    pub bot_id: String,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct CreateBotResponse{
+    pub bot_id: String,
+    // only ever sent this once
+    pub api_token: String,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct RegisterBotCommandRequest{
+    pub room_id: String,
+    // without the slash, eg "weather" for /weather
+    pub command: String,
+    #[serde(default)]
+    pub description: String,
+    // gets a BotCommandInvocation POSTed to it every time the command is used
+    pub callback_url: String,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct UnregisterBotCommandRequest{
+    pub room_id: String,
+    pub command: String,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct ListBotCommandsRequest{
+    pub room_id: String,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct ListBotCommandsResponse{
+    pub room_id: String,
+    pub commands: Vec<BotCommandInfo>,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct BotSendMessageRequest{
+    pub room_id: String,
+    pub content: String,
+}
+
+// What a bot's callback URL receives when its command is used
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct BotCommandInvocation{
+    pub room_id: String,
+    pub user_id: String,
+    pub command: String,
+    // everything after the command, eg "paris" for "/weather paris"
+    pub args: String,
+    pub timestamp: String,
+}
+
+// What a callback answers with, the content (if any) is posted into the room as the bot
+#[derive(Serialize,Deserialize,Debug,Clone,Default)]
+pub struct BotReply{
+    #[serde(default)]
+    pub content: Option<String>,
+}
+
+// Only the room owner can create, list and delete webhooks
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct CreateWebhookRequest{
+    pub room_id: String,
+    // who the messages appear to be from
+    pub name: String,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct CreateWebhookResponse{
+    pub webhook: WebhookInfo,
+    // sent in the X-Webhook-Secret header when posting to /webhooks/<webhook_id>, only ever sent this once
+    pub secret: String,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct CreateOutgoingWebhookRequest{
+    pub room_id: String,
+    // gets every matching ChatMessage POSTed to it
+    pub url: String,
+    // only messages starting with this word, every message when left out
+    #[serde(default)]
+    pub trigger: Option<String>,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct CreateOutgoingWebhookResponse{
+    pub webhook: WebhookInfo,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct ListWebhooksRequest{
+    pub room_id: String,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct ListWebhooksResponse{
+    pub room_id: String,
+    pub webhooks: Vec<WebhookInfo>,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct DeleteWebhookRequest{
+    pub webhook_id: String,
+}
+
+// Body of a POST to an incoming webhook
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct WebhookMessageRequest{
+    pub content: String,
+}
+
+// The following are for the people and tools running the server. /healthz and /readyz are open to
+// anyone, /status and everything under /admin/ need "Authorization: Bearer <CHAT_ADMIN_TOKEN>".
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct ReadinessResponse{
+    pub ready: bool,
+    pub checks: Vec<ReadinessCheck>,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct ReadinessCheck{
+    pub name: String,
+    pub ok: bool,
+    // why it failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct StatusResponse{
+    pub version: String,
+    // tells the instances apart when several share a backplane
+    pub instance_id: String,
+    pub uptime_secs: u64,
+    pub shutting_down: bool,
+    // websocket connections to this instance
+    pub connected_users: usize,
+    // every room, None if the storage couldn't be reached
+    pub rooms: Option<usize>,
+    // the rooms with a task on this instance
+    pub rooms_running: usize,
+    // None if the storage couldn't be reached
+    pub storage: Option<StorageStats>,
+    // what people are told while maintenance mode is on, None when it is off
+    pub maintenance: Option<String>,
+    // the message of the day, None when there isn't one
+    pub motd: Option<String>,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct StorageStats{
+    // "local" or "redis"
+    pub backend: String,
+    // keys and sets kept in it, by every instance
+    pub keys: usize,
+}
+
+// The room or user an admin request is about
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct AdminRoomRequest{
+    pub room_id: String,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct AdminUserRequest{
+    pub user_id: String,
+}
+
+// Everything the server knows about a room. Invites, bot commands and webhooks are the ones kept by
+// the instance that answered (see backplane.rs).
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct AdminRoomResponse{
+    pub room: RoomInfo,
+    pub details: RoomDetails,
+    pub password_protected: bool,
+    pub members: Vec<String>,
+    pub invites: Vec<InviteInfo>,
+    pub bot_commands: Vec<BotCommandInfo>,
+    pub webhooks: Vec<WebhookInfo>,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct AdminListUsersResponse{
+    pub users: Vec<String>,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct AdminUserResponse{
+    pub user_id: String,
+    // the room they were last let into, if they haven't left it since
+    pub room_id: Option<String>,
+    // connected to that room right now
+    pub connected: bool,
+    // login sessions that haven't expired or been revoked
+    pub sessions: usize,
+}
+
+// Sets a new password for someone who lost theirs, which also logs them out everywhere
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct ResetPasswordRequest{
+    pub user_id: String,
+// MUST IMPLEMENT POLICY VALIDATION(even if client already has validation)
+    pub new_password: String,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct ModerationLogRequest{
+    // the newest this many entries, all of them when left out
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct ModerationLogResponse{
+    // newest first
+    pub entries: Vec<ModerationLogEntry>,
+}
+
+// Posted as user_id "system" into the given rooms, or into every room when room_ids is left out
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct AnnouncementRequest{
+    pub content: String,
+    #[serde(default)]
+    pub room_ids: Option<Vec<String>>,
+    // RFC 3339, sends it then instead of right away
+    #[serde(default)]
+    pub send_at: Option<String>,
+}
+
+// An announcement waiting for its send_at
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct ScheduledAnnouncement{
+    pub announcement_id: String,
+    pub content: String,
+    // every room when None
+    pub room_ids: Option<Vec<String>>,
+    pub send_at: String,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct ListAnnouncementsResponse{
+    // soonest first
+    pub announcements: Vec<ScheduledAnnouncement>,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct CancelAnnouncementRequest{
+    pub announcement_id: String,
+}
+
+// Shown to everyone when they log in. Left out or empty clears it.
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct MotdRequest{
+    #[serde(default)]
+    pub motd: Option<String>,
+}
+
+// While maintenance mode is on, nobody can sign up, log in, create or join rooms or connect a
+// websocket. Whoever is already connected stays connected.
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct MaintenanceRequest{
+    pub enabled: bool,
+    // what people are told when they are turned away, a generic message when left out
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
 // this is a generic response used for LogoutRequest, DeleteAccountRequest, and DeleteRoomRequest
 #[derive(Serialize,Deserialize,Debug,Clone)]
 pub struct SuccessResponse{
@@ -127,6 +477,8 @@ pub enum ClientWsMessage{
     LeaveRoom{room_id: String},
     KickUser{room_id: String, user_id: String},
     SendMessage{room_id: String, content: String},
+    // only the room owner can, see UpdateRoomSettingsRequest
+    UpdateRoomSettings{room_id: String, settings: RoomSettingsChange},
     // to be used for health checks
     Ping{timestamp: String},
 }
@@ -139,10 +491,20 @@ pub enum ServerWsMessage{
     UserJoined{room_id: String, user_id: String},
     UserLeft{room_id: String, user_id: String},
     UserKicked{room_id: String, user_id: String},
+    // the owner changed the room's settings, these are the new ones
+    RoomUpdated{room_id: String, updated_by: String, details: RoomDetails},
     MessageBroadcast(ChatMessage),
     // to be used for health checks
     Pong{timestamp: String},
-    Error{error_msg:String},
+    Error{
+        error_msg:String,
+        // set when the request was rate limited, how long to wait before trying again
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>,
+    },
+    // the server is going away (eg restarting) and is about to close the connection. Clients
+    // should wait this long and then log back in and rejoin.
+    ServerShutdown{reconnect_after_ms: u64},
 }
 
 // The following are the data structures used in the messages
@@ -162,6 +524,116 @@ pub struct RoomInfo{
     pub room_id: String,
     pub owner: String,
     pub users_count: usize,
+    pub visibility: RoomVisibility,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+}
+
+// What the owner can change about a room (see RoomSettingsChange), as everyone may see it
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct RoomDetails{
+    pub visibility: RoomVisibility,
+    // one line, shown when joining and in the room list
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    pub created_at: String,
+    // members connected at once, None for no limit
+    pub max_members: Option<u32>,
+    // how long each member has to wait between two messages, 0 when slow mode is off
+    pub slow_mode_secs: u64,
+    // chat messages kept for people who join later
+    pub history_len: usize,
+}
+
+// Anything left out stays as it is
+#[derive(Serialize,Deserialize,Debug,Clone,Default)]
+pub struct RoomSettingsChange{
+    // "" clears it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    // "" clears it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    // making a room public takes its password away, making it private needs one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub visibility: Option<RoomVisibility>,
+// MUST IMPLEMENT POLICY VALIDATION(even if client already has validation)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room_password: Option<String>,
+    // 0 for no limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_members: Option<u32>,
+    // 0 turns slow mode off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slow_mode_secs: Option<u64>,
+    // up to the server's CHAT_ROOM_HISTORY
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_len: Option<usize>,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct InviteInfo{
+    pub code: String,
+    pub room_id: String,
+    pub created_by: String,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct BotCommandInfo{
+    pub command: String,
+    pub bot_id: String,
+    pub description: String,
+}
+
+// Incoming webhooks have a name, outgoing ones a url and maybe a trigger
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct WebhookInfo{
+    pub webhook_id: String,
+    pub room_id: String,
+    pub created_by: String,
+    pub created_at: String,
+    pub name: Option<String>,
+    pub url: Option<String>,
+    pub trigger: Option<String>,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct ModerationLogEntry{
+    pub timestamp: String,
+    pub action: ModerationAction,
+    // the user who did it, "admin" for the admin API or "server" for what the server does on its own
+    pub actor: String,
+    // the user or room it was done to
+    pub target: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ModerationAction{
+    Kick,
+    // for flooding a room
+    Mute,
+    // for guessing a room password wrong too often
+    JoinLockout,
+    // for failing to log in to an account too often
+    LoginLockout,
+    Disconnect,
+    DeleteUser,
+    DeleteRoom,
+    ResetPassword,
+    // a room's settings were changed, by its owner
+    RoomSettingsChanged,
+    Announcement,
+    MotdChanged,
+    MaintenanceStarted,
+    MaintenanceEnded,
 }
 
 #[derive(Serialize,Deserialize,Debug,Clone)]
@@ -174,8 +646,21 @@ pub enum ErrorResponse{
     InvalidPermissions{message: String},
     RoomNotFound{room_id: String},
     RoomAlreadyExists{room_id: String},
+    // it has as many members connected as its max_members allows
+    RoomFull{room_id: String},
+    // the code doesn't exist, expired, ran out of uses or was revoked
+    InviteInvalid{message: String},
+    // no bot has registered the command in that room
+    CommandNotFound{command: String},
+    // it was already sent or cancelled
+    AnnouncementNotFound{announcement_id: String},
     NotInRoom{room_id: String},
     ServerError{message: String},
+    // the server isn't taking new people right now (maintenance mode, shutting down), try again later
+    Unavailable{message: String},
+    RateLimited{message: String, retry_after_secs: u64},
+    // every policy rule the request broke, so they can all be shown at once
+    ValidationFailed{errors: Vec<ValidationError>},
 }
 
 
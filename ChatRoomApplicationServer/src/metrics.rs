@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Numbers worth watching on a running server, served at GET /metrics in the Prometheus text
+// format. Counters only ever go up and histograms count what they saw since the server started;
+// rates (eg messages per second) and alerts are left to whatever scrapes them. Gauges for what is
+// open right now (connections, rooms) are read when the page is rendered, see metrics_handler.
+
+// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+#[derive(Default)]
+pub struct Metrics {
+    // a websocket fell so far behind its room that it missed broadcasts
+    pub ws_lag_events: AtomicU64,
+    // chat messages sent again from the room's history to connections that lagged
+    pub ws_resynced_messages: AtomicU64,
+    // frames thrown away because a connection's outbound queue was full (drop_newest)
+    pub ws_dropped_frames: AtomicU64,
+    // connections closed because their outbound queue was full (disconnect)
+    pub ws_slow_disconnects: AtomicU64,
+    // chat messages posted to rooms through this instance, by people, bots and webhooks
+    pub messages: AtomicU64,
+    // from a room sending out a broadcast to it being queued for one of its members
+    pub broadcast_lag: Histogram,
+    auth_failures: [AtomicU64; AuthFailure::ALL.len()],
+    // how long each route took to answer, by the route's path
+    http_latency: Mutex<BTreeMap<String, Histogram>>,
+}
+
+// What was being checked when a request was turned away as unauthenticated
+#[derive(Clone, Copy)]
+pub enum AuthFailure {
+    // wrong username or password
+    Login,
+    // an expired or unknown session or refresh token
+    Session,
+    BotToken,
+    WebhookSecret,
+    AdminToken,
+}
+
+impl AuthFailure {
+    const ALL: [AuthFailure; 5] = [
+        AuthFailure::Login,
+        AuthFailure::Session,
+        AuthFailure::BotToken,
+        AuthFailure::WebhookSecret,
+        AuthFailure::AdminToken,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            AuthFailure::Login => "login",
+            AuthFailure::Session => "session",
+            AuthFailure::BotToken => "bot_token",
+            AuthFailure::WebhookSecret => "webhook_secret",
+            AuthFailure::AdminToken => "admin_token",
+        }
+    }
+}
+
+// Counts observations into LATENCY_BUCKETS. Each bucket only counts what landed in it, they are
+// added up into Prometheus' cumulative buckets when rendered.
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        // slower than the last bucket only shows up in +Inf, which is the count
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    // The _bucket, _sum and _count lines, with `labels` (eg `path="/login"`) on each
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = format!("le=\"{}\"", bound);
+            let _ = writeln!(out, "{}_bucket{} {}", name, label_set(labels, &le), cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_bucket{} {}", name, label_set(labels, "le=\"+Inf\""), count);
+        let _ = writeln!(out, "{}_sum{} {}", name, label_set(labels, ""), sum);
+        let _ = writeln!(out, "{}_count{} {}", name, label_set(labels, ""), count);
+    }
+}
+
+// `{a,b}` from whichever of the two are there, nothing at all if neither is
+fn label_set(labels: &str, more: &str) -> String {
+    let labels: Vec<&str> = [labels, more].into_iter().filter(|l| !l.is_empty()).collect();
+    if labels.is_empty() {
+        return String::new();
+    }
+    format!("{{{}}}", labels.join(","))
+}
+
+impl Metrics {
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn auth_failed(&self, failure: AuthFailure) {
+        Metrics::add(&self.auth_failures[failure as usize], 1);
+    }
+
+    pub fn observe_request(&self, path: &str, elapsed: Duration) {
+        let mut latency = self.http_latency.lock().unwrap();
+        match latency.get(path) {
+            Some(histogram) => histogram.observe(elapsed),
+            None => latency.entry(path.to_string()).or_default().observe(elapsed),
+        }
+    }
+
+    // `gauges` are (name, help, value) for what is open right now
+    pub fn render(&self, gauges: &[(&str, &str, u64)]) -> String {
+        let counters = [
+            ("chat_ws_lag_events_total", "Times a websocket connection fell behind its room's broadcasts", &self.ws_lag_events),
+            ("chat_ws_resynced_messages_total", "Chat messages resent from history to lagging connections", &self.ws_resynced_messages),
+            ("chat_ws_dropped_frames_total", "Frames dropped because a connection's outbound queue was full", &self.ws_dropped_frames),
+            ("chat_ws_slow_disconnects_total", "Connections closed because their outbound queue was full", &self.ws_slow_disconnects),
+            ("chat_messages_total", "Chat messages posted to rooms through this instance", &self.messages),
+        ];
+        let mut out = String::new();
+        for (name, help, value) in gauges {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+
+        let name = "chat_auth_failures_total";
+        let _ = writeln!(out, "# HELP {} Requests turned away for failing authentication, by what was checked", name);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for failure in AuthFailure::ALL {
+            let count = self.auth_failures[failure as usize].load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}{{kind=\"{}\"}} {}", name, failure.label(), count);
+        }
+
+        let name = "chat_broadcast_lag_seconds";
+        let _ = writeln!(out, "# HELP {} Time from a room sending out a broadcast to it being queued for a member", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        self.broadcast_lag.render(&mut out, name, "");
+
+        let name = "chat_http_request_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Time taken to answer HTTP requests, by route", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (path, histogram) in self.http_latency.lock().unwrap().iter() {
+            histogram.render(&mut out, name, &format!("path=\"{}\"", path));
+        }
+        out
+    }
+}
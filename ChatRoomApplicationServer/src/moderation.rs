@@ -0,0 +1,64 @@
+use std::sync::Arc;
+
+use crate::backplane::{Backplane, BackplaneError, BackplaneResult};
+use crate::message::{ModerationAction, ModerationLogEntry};
+
+// What was done to people and rooms, for operators to look through (POST /admin/moderation_log):
+// kicks, flood mutes, password lockouts and everything done through the admin API. Kept in the
+// backplane (see backplane.rs) so every instance adds to and reads the same log, which only keeps
+// the newest LOG_LEN entries.
+
+const LOG_LEN: usize = 1000;
+
+// Newest first
+const LOG_KEY: &str = "moderation_log";
+
+// The actor of what the server does on its own
+pub const SERVER: &str = "server";
+// The actor of what is done through the admin API
+pub const ADMIN: &str = "admin";
+
+pub struct ModerationLog {
+    backplane: Arc<dyn Backplane>,
+}
+
+impl ModerationLog {
+    pub fn new(backplane: Arc<dyn Backplane>) -> Self {
+        ModerationLog { backplane }
+    }
+
+    // Whatever was done has already happened, so failing to note it down is only logged
+    pub async fn record(
+        &self,
+        action: ModerationAction,
+        actor: &str,
+        target: &str,
+        room_id: Option<&str>,
+        detail: Option<String>,
+    ) {
+        let entry = ModerationLogEntry {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            action,
+            actor: actor.to_string(),
+            target: target.to_string(),
+            room_id: room_id.map(str::to_string),
+            detail,
+        };
+        let pushed = match serde_json::to_string(&entry) {
+            Ok(json) => self.backplane.push(LOG_KEY, &json, LOG_LEN).await,
+            Err(e) => Err(BackplaneError(e.to_string())),
+        };
+        if let Err(e) = pushed {
+            tracing::error!("Failed to record {:?} of {} in the moderation log: {}", entry.action, entry.target, e);
+        }
+    }
+
+    // The newest `limit` entries, newest first
+    pub async fn recent(&self, limit: usize) -> BackplaneResult<Vec<ModerationLogEntry>> {
+        let entries = self.backplane.latest(LOG_KEY, limit.min(LOG_LEN)).await?;
+        entries
+            .iter()
+            .map(|json| serde_json::from_str(json).map_err(|e| BackplaneError(e.to_string())))
+            .collect()
+    }
+}
@@ -0,0 +1,82 @@
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use rand_core::OsRng;
+
+// Password hashing with Argon2id, used for both account and room passwords. Hashing is
+// deliberately slow, so it runs on the blocking thread pool instead of stalling the async workers.
+
+pub async fn hash_password(password: &str) -> Result<String, String> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| format!("Failed to hash password: {}", e))
+    })
+    .await
+    .map_err(|e| format!("Password hashing task failed: {}", e))?
+}
+
+// Checked instead when a login names a user that doesn't exist, so it takes as long as a wrong
+// password does and accounts can't be found by timing the answer. Made with `hash_password`, so it
+// has the same parameters as every real hash.
+const DUMMY_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$QaxE7WneKyOkr/A5fdSNjg$I8OevQN1zQf6HJXvLsUVuV0Of4tZphOyRR3RksAdWKU";
+
+// Like `verify_password`, but does the same work when there is no hash to check against
+pub async fn verify_password_or_dummy(password: &str, hash: Option<&str>) -> bool {
+    match hash {
+        Some(hash) => verify_password(password, hash).await,
+        None => {
+            verify_password(password, DUMMY_HASH).await;
+            false
+        }
+    }
+}
+
+// `verify_password` compares the hashes in constant time
+pub async fn verify_password(password: &str, hash: &str) -> bool {
+    let password = password.to_string();
+    let hash = hash.to_string();
+    tokio::task::spawn_blocking(move || match PasswordHash::new(&hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(e) => {
+            tracing::error!("Stored password hash is invalid: {}", e);
+            false
+        }
+    })
+    .await
+    .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dummy_hash_costs_as_much_as_a_real_one() {
+        let dummy = PasswordHash::new(DUMMY_HASH).unwrap();
+        let salt = SaltString::generate(&mut OsRng);
+        let real = Argon2::default().hash_password(b"password", &salt).unwrap();
+        assert_eq!(dummy.algorithm, real.algorithm);
+        assert_eq!(dummy.version, real.version);
+        assert_eq!(dummy.params, real.params);
+    }
+
+    #[tokio::test]
+    async fn nothing_matches_a_missing_hash() {
+        assert!(!verify_password_or_dummy("", None).await);
+        assert!(!verify_password_or_dummy("not the password of any account", None).await);
+    }
+
+    #[tokio::test]
+    async fn real_hashes_are_still_checked() {
+        let hash = hash_password("Passw0rd!x").await.unwrap();
+        assert!(verify_password_or_dummy("Passw0rd!x", Some(&hash)).await);
+        assert!(!verify_password_or_dummy("Passw0rd!y", Some(&hash)).await);
+    }
+}
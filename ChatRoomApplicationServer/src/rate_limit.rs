@@ -0,0 +1,770 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::Mutex;
+
+use crate::backplane::Backplane;
+use crate::config::{BucketConfig, RateLimitConfig};
+
+// Token buckets keyed by user, IP and room, plus the bookkeeping for muting flooders, locking
+// out room and account password guessers and rooms in slow mode. Every check returns how long the
+// caller has to wait so it can be passed back to the client as a retry-after hint.
+//
+// Mutes, lockouts and slow mode are kept in the backplane (see backplane.rs), so strikes add up
+// across server instances and a mute or lockout holds on all of them. The token buckets are the
+// exception: every instance fills and empties its own, since they are checked for every message
+// and request and only smooth out bursts. With N instances behind the load balancer a client that
+// spreads its requests over all of them can get up to N times the configured rates. A chat
+// connection only ever goes through one instance.
+//
+// If the backplane can't be reached the checks it is needed for let everything through, and say so
+// in the logs, rather than lock everyone out.
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(config: &BucketConfig, now: Instant) -> Self {
+        TokenBucket {
+            tokens: config.capacity,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, config: &BucketConfig, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.refill_per_sec).min(config.capacity);
+        self.last_refill = now;
+    }
+
+    // Checks for a token without taking it, or returns how long until the next one is available
+    fn available(&mut self, config: &BucketConfig, now: Instant) -> Result<(), Duration> {
+        self.refill(config, now);
+        if self.tokens >= 1.0 {
+            Ok(())
+        } else {
+            let missing = 1.0 - self.tokens;
+            Err(Duration::from_secs_f64(missing / config.refill_per_sec))
+        }
+    }
+
+    // Takes a token, or returns how long until the next one is available
+    fn try_take(&mut self, config: &BucketConfig, now: Instant) -> Result<(), Duration> {
+        self.available(config, now)?;
+        self.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+struct BucketMap<K> {
+    config: BucketConfig,
+    buckets: Mutex<HashMap<K, TokenBucket>>,
+}
+
+impl<K: Eq + Hash> BucketMap<K> {
+    fn new(config: BucketConfig) -> Self {
+        BucketMap {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.config.refill_per_sec > 0.0
+    }
+
+    async fn check(&self, key: K, now: Instant) -> Result<(), Duration> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let mut buckets = self.buckets.lock().await;
+        buckets
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(&self.config, now))
+            .try_take(&self.config, now)
+    }
+
+    // Takes a token from this map and one from `other`, or from neither if either of them is
+    // empty, so a message refused by one limit doesn't still use up the other
+    async fn check_with<L: Eq + Hash>(
+        &self,
+        key: K,
+        other: &BucketMap<L>,
+        other_key: L,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().await;
+        let mut other_buckets = other.buckets.lock().await;
+        let mut bucket = self
+            .enabled()
+            .then(|| buckets.entry(key).or_insert_with(|| TokenBucket::new(&self.config, now)));
+        let mut other_bucket = other
+            .enabled()
+            .then(|| other_buckets.entry(other_key).or_insert_with(|| TokenBucket::new(&other.config, now)));
+
+        if let Some(bucket) = bucket.as_deref_mut() {
+            bucket.available(&self.config, now)?;
+        }
+        if let Some(other_bucket) = other_bucket.as_deref_mut() {
+            other_bucket.available(&other.config, now)?;
+        }
+        if let Some(bucket) = bucket {
+            bucket.tokens -= 1.0;
+        }
+        if let Some(other_bucket) = other_bucket {
+            other_bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+
+    // A bucket that has refilled completely behaves exactly like a fresh one, so drop it
+    async fn prune(&self, now: Instant) {
+        let mut buckets = self.buckets.lock().await;
+        buckets.retain(|_, bucket| {
+            bucket.refill(&self.config, now);
+            bucket.tokens < self.config.capacity
+        });
+    }
+}
+
+// Counts strikes against a key in fixed windows and blocks the key for a while once one window
+// has max_strikes of them. Used for rate limit violations (-> mute) and wrong room or account
+// passwords (-> lockout). The counts and blocks are backplane keys that expire on their own.
+struct StrikeMap {
+    // keeps the keys of the different maps apart
+    name: &'static str,
+    max_strikes: u32,
+    window: Duration,
+    block_for: Duration,
+    backplane: Arc<dyn Backplane>,
+}
+
+impl StrikeMap {
+    fn new(
+        backplane: Arc<dyn Backplane>,
+        name: &'static str,
+        max_strikes: u32,
+        window: Duration,
+        block_for: Duration,
+    ) -> Self {
+        StrikeMap {
+            name,
+            max_strikes,
+            window,
+            block_for,
+            backplane,
+        }
+    }
+
+    // The strikes of the window `now_ms` falls in
+    fn strikes_key(&self, key: &str, now_ms: u64) -> String {
+        let window = now_ms / (self.window.as_millis() as u64).max(1);
+        format!("strikes:{}:{}:{}", self.name, key, window)
+    }
+
+    // Holds when the block ends, in milliseconds since the epoch
+    fn blocked_key(&self, key: &str) -> String {
+        format!("blocked:{}:{}", self.name, key)
+    }
+
+    // Returns the remaining block time if the key is currently blocked
+    async fn blocked(&self, key: &str, now_ms: u64) -> Option<Duration> {
+        let until = match self.backplane.get(&self.blocked_key(key)).await {
+            Ok(until) => until?.parse::<u64>().ok()?,
+            Err(e) => {
+                tracing::warn!("Failed to check whether {} is blocked ({}): {}", key, self.name, e);
+                return None;
+            }
+        };
+        Some(Duration::from_millis(until.checked_sub(now_ms)?)).filter(|d| !d.is_zero())
+    }
+
+    // Records a strike and returns the block duration if this strike tipped the key over the limit
+    async fn strike(&self, key: &str, now_ms: u64) -> Option<Duration> {
+        if self.max_strikes == 0 {
+            return None;
+        }
+        match self.try_strike(key, now_ms).await {
+            Ok(blocked) => blocked,
+            Err(e) => {
+                tracing::warn!("Failed to record a strike against {} ({}): {}", key, self.name, e);
+                None
+            }
+        }
+    }
+
+    async fn try_strike(&self, key: &str, now_ms: u64) -> crate::backplane::BackplaneResult<Option<Duration>> {
+        let strikes_key = self.strikes_key(key, now_ms);
+        let strikes = self.backplane.increment(&strikes_key, Some(self.window)).await?;
+        if strikes < self.max_strikes as u64 {
+            return Ok(None);
+        }
+        // starts counting over, like the block was a clean slate
+        self.backplane.take(&strikes_key).await?;
+        let until = now_ms + self.block_for.as_millis() as u64;
+        self.backplane
+            .set(&self.blocked_key(key), &until.to_string(), Some(self.block_for))
+            .await?;
+        Ok(Some(self.block_for))
+    }
+
+    async fn clear(&self, key: &str, now_ms: u64) {
+        for cleared in [self.strikes_key(key, now_ms), self.blocked_key(key)] {
+            if let Err(e) = self.backplane.take(&cleared).await {
+                tracing::warn!("Failed to clear the strikes against {} ({}): {}", key, self.name, e);
+            }
+        }
+    }
+}
+
+// Turns the Instants the limits are checked at into milliseconds since the epoch, which every
+// instance agrees on
+struct WallClock {
+    at: Instant,
+    unix_ms: u64,
+}
+
+impl WallClock {
+    fn new() -> Self {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        WallClock {
+            at: Instant::now(),
+            unix_ms: since_epoch.as_millis() as u64,
+        }
+    }
+
+    fn unix_ms(&self, now: Instant) -> u64 {
+        match now.checked_duration_since(self.at) {
+            Some(since) => self.unix_ms + since.as_millis() as u64,
+            None => self.unix_ms.saturating_sub((self.at - now).as_millis() as u64),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RateLimitError {
+    // Too many messages, try again after the given delay
+    Throttled { retry_after: Duration },
+    // The user kept flooding and has been muted for a while. `started` is set for the message
+    // that got them muted.
+    Muted { retry_after: Duration, started: bool },
+    // The room is in slow mode and the user already sent a message not long ago
+    SlowMode { retry_after: Duration },
+}
+
+impl RateLimitError {
+    pub fn retry_after(&self) -> Duration {
+        match self {
+            RateLimitError::Throttled { retry_after }
+            | RateLimitError::Muted { retry_after, .. }
+            | RateLimitError::SlowMode { retry_after } => *retry_after,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            RateLimitError::Throttled { .. } => "You are sending messages too quickly".to_string(),
+            RateLimitError::Muted { retry_after, .. } => format!(
+                "You have been muted for flooding the room ({}s remaining)",
+                retry_after_secs(*retry_after)
+            ),
+            RateLimitError::SlowMode { retry_after } => format!(
+                "Slow mode is on, you can send another message in {}s",
+                retry_after_secs(*retry_after)
+            ),
+        }
+    }
+}
+
+// Retry-after hints are given in whole seconds and never as 0 so clients don't retry immediately
+pub fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after.as_secs_f64().ceil().max(1.0) as u64
+}
+
+pub struct RateLimiter {
+    user_messages: BucketMap<String>,
+    ip_messages: BucketMap<IpAddr>,
+    room_messages: BucketMap<String>,
+    ip_requests: BucketMap<IpAddr>,
+    // user_id -> rate limit violations / mute
+    mutes: StrikeMap,
+    // "user_id:room_id" and "ip:room_id" -> wrong room passwords / lockout.
+    // Both are tracked since the user_id is picked by the client and could just be changed.
+    user_join_failures: StrikeMap,
+    ip_join_failures: StrikeMap,
+    // "user_id:ip" and user_id -> failed logins / lockout. Someone guessing only locks their own
+    // address out of the account, the account as a whole takes many more failures to lock (see
+    // RateLimitConfig) so spreading the guesses over many addresses doesn't help either.
+    login_failures: StrikeMap,
+    account_login_failures: StrikeMap,
+    backplane: Arc<dyn Backplane>,
+    clock: WallClock,
+}
+
+// Names can't have a ':' in them, so these can't run into each other
+fn join_keys(user_id: &str, ip: IpAddr, room_id: &str) -> (String, String) {
+    (format!("{}:{}", user_id, room_id), format!("{}:{}", ip, room_id))
+}
+
+fn login_key(user_id: &str, ip: IpAddr) -> String {
+    format!("{}:{}", user_id, ip)
+}
+
+// Holds when the user can next send a message into a room in slow mode
+fn slow_mode_key(user_id: &str, room_id: &str) -> String {
+    format!("slow_mode:{}:{}", user_id, room_id)
+}
+
+impl RateLimiter {
+    pub fn new(backplane: Arc<dyn Backplane>, config: &RateLimitConfig) -> Self {
+        RateLimiter {
+            user_messages: BucketMap::new(config.user_messages),
+            ip_messages: BucketMap::new(config.ip_messages),
+            room_messages: BucketMap::new(config.room_messages),
+            ip_requests: BucketMap::new(config.ip_requests),
+            mutes: StrikeMap::new(
+                backplane.clone(),
+                "mute",
+                config.mute_after_violations,
+                config.violation_window,
+                config.mute_duration,
+            ),
+            user_join_failures: StrikeMap::new(
+                backplane.clone(),
+                "join_user",
+                config.join_max_failures,
+                config.join_failure_window,
+                config.join_lockout,
+            ),
+            ip_join_failures: StrikeMap::new(
+                backplane.clone(),
+                "join_ip",
+                config.join_max_failures,
+                config.join_failure_window,
+                config.join_lockout,
+            ),
+            login_failures: StrikeMap::new(
+                backplane.clone(),
+                "login",
+                config.login_max_failures,
+                config.login_failure_window,
+                config.login_lockout,
+            ),
+            account_login_failures: StrikeMap::new(
+                backplane.clone(),
+                "login_account",
+                config.login_max_account_failures,
+                config.login_failure_window,
+                config.login_lockout,
+            ),
+            backplane,
+            clock: WallClock::new(),
+        }
+    }
+
+    // Called for every chat message before it is broadcast
+    pub async fn check_message(
+        &self,
+        user_id: &str,
+        ip: IpAddr,
+        room_id: &str,
+    ) -> Result<(), RateLimitError> {
+        self.check_message_at(user_id, ip, room_id, Instant::now()).await
+    }
+
+    async fn check_message_at(
+        &self,
+        user_id: &str,
+        ip: IpAddr,
+        room_id: &str,
+        now: Instant,
+    ) -> Result<(), RateLimitError> {
+        let now_ms = self.clock.unix_ms(now);
+        if let Some(retry_after) = self.mutes.blocked(user_id, now_ms).await {
+            return Err(RateLimitError::Muted { retry_after, started: false });
+        }
+
+        let sender_limit = self
+            .user_messages
+            .check_with(user_id.to_string(), &self.ip_messages, ip, now)
+            .await;
+        if let Err(retry_after) = sender_limit {
+            // Only limits caused by the sender count towards a mute, a busy room is not their fault
+            if let Some(mute) = self.mutes.strike(user_id, now_ms).await {
+                tracing::warn!("Muting user {} for {:?} after repeated flooding", user_id, mute);
+                return Err(RateLimitError::Muted { retry_after: mute, started: true });
+            }
+            return Err(RateLimitError::Throttled { retry_after });
+        }
+
+        self.room_messages
+            .check(room_id.to_string(), now)
+            .await
+            .map_err(|retry_after| RateLimitError::Throttled { retry_after })
+    }
+
+    // Called for chat messages into a room in slow mode, where each member can send one message per
+    // `interval`
+    pub async fn check_slow_mode(&self, user_id: &str, room_id: &str, interval: Duration) -> Result<(), RateLimitError> {
+        let now_ms = self.clock.unix_ms(Instant::now());
+        let key = slow_mode_key(user_id, room_id);
+        let until = now_ms + interval.as_millis() as u64;
+        // Whoever sets the key gets to send, until it expires
+        let until = match self.backplane.set_new(&key, &until.to_string(), Some(interval)).await {
+            Ok(true) => return Ok(()),
+            Ok(false) => self.backplane.get(&key).await,
+            Err(e) => Err(e),
+        };
+        let until = match until {
+            Ok(until) => until.and_then(|until| until.parse::<u64>().ok()),
+            Err(e) => {
+                tracing::warn!("Failed to check slow mode for {} in {}: {}", user_id, room_id, e);
+                return Ok(());
+            }
+        };
+        // None if it expired in the meantime
+        match until.and_then(|until| until.checked_sub(now_ms)).filter(|ms| *ms > 0) {
+            Some(ms) => Err(RateLimitError::SlowMode {
+                retry_after: Duration::from_millis(ms),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // Called for HTTP requests and websocket upgrades
+    pub async fn check_request(&self, ip: IpAddr) -> Result<(), Duration> {
+        self.ip_requests.check(ip, Instant::now()).await
+    }
+
+    // Returns the remaining lockout if this user or IP guessed the room password wrong too often
+    pub async fn join_lockout(&self, user_id: &str, ip: IpAddr, room_id: &str) -> Option<Duration> {
+        self.join_lockout_at(user_id, ip, room_id, Instant::now()).await
+    }
+
+    async fn join_lockout_at(&self, user_id: &str, ip: IpAddr, room_id: &str, now: Instant) -> Option<Duration> {
+        let now_ms = self.clock.unix_ms(now);
+        let (user_key, ip_key) = join_keys(user_id, ip, room_id);
+        let user_lockout = self.user_join_failures.blocked(&user_key, now_ms).await;
+        let ip_lockout = self.ip_join_failures.blocked(&ip_key, now_ms).await;
+        user_lockout.max(ip_lockout)
+    }
+
+    // True if this locked the user or their IP out of the room
+    pub async fn record_join_failure(&self, user_id: &str, ip: IpAddr, room_id: &str) -> bool {
+        self.record_join_failure_at(user_id, ip, room_id, Instant::now()).await
+    }
+
+    async fn record_join_failure_at(&self, user_id: &str, ip: IpAddr, room_id: &str, now: Instant) -> bool {
+        let now_ms = self.clock.unix_ms(now);
+        let (user_key, ip_key) = join_keys(user_id, ip, room_id);
+        let user_locked = self.user_join_failures.strike(&user_key, now_ms).await.is_some();
+        let ip_locked = self.ip_join_failures.strike(&ip_key, now_ms).await.is_some();
+        if user_locked || ip_locked {
+            tracing::warn!(
+                "Locking {} ({}) out of room {} after repeated wrong passwords",
+                user_id, ip, room_id
+            );
+        }
+        user_locked || ip_locked
+    }
+
+    pub async fn record_join_success(&self, user_id: &str, ip: IpAddr, room_id: &str) {
+        let now_ms = self.clock.unix_ms(Instant::now());
+        let (user_key, ip_key) = join_keys(user_id, ip, room_id);
+        self.user_join_failures.clear(&user_key, now_ms).await;
+        self.ip_join_failures.clear(&ip_key, now_ms).await;
+    }
+
+    // Returns the remaining lockout if there were too many failed logins to the account from this
+    // IP, or from everywhere
+    pub async fn login_lockout(&self, user_id: &str, ip: IpAddr) -> Option<Duration> {
+        self.login_lockout_at(user_id, ip, Instant::now()).await
+    }
+
+    async fn login_lockout_at(&self, user_id: &str, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let now_ms = self.clock.unix_ms(now);
+        let from_ip = self.login_failures.blocked(&login_key(user_id, ip), now_ms).await;
+        let account = self.account_login_failures.blocked(user_id, now_ms).await;
+        from_ip.max(account)
+    }
+
+    // True if this locked the IP or the whole account out. Also counted for names that aren't
+    // accounts, so the answer doesn't give away which ones are.
+    pub async fn record_login_failure(&self, user_id: &str, ip: IpAddr) -> bool {
+        self.record_login_failure_at(user_id, ip, Instant::now()).await
+    }
+
+    async fn record_login_failure_at(&self, user_id: &str, ip: IpAddr, now: Instant) -> bool {
+        let now_ms = self.clock.unix_ms(now);
+        let from_ip = self.login_failures.strike(&login_key(user_id, ip), now_ms).await.is_some();
+        let account = self.account_login_failures.strike(user_id, now_ms).await.is_some();
+        if account {
+            tracing::warn!("Locking account {} after repeated failed logins", user_id);
+        } else if from_ip {
+            tracing::warn!("Locking {} out of account {} after repeated failed logins", ip, user_id);
+        }
+        from_ip || account
+    }
+
+    pub async fn record_login_success(&self, user_id: &str, ip: IpAddr) {
+        let now_ms = self.clock.unix_ms(Instant::now());
+        self.login_failures.clear(&login_key(user_id, ip), now_ms).await;
+        self.account_login_failures.clear(user_id, now_ms).await;
+    }
+
+    // Lifts the lockout of the account as a whole. The addresses locked out of it stay locked until
+    // their lockout runs out, there is no telling which ones they are.
+    pub async fn clear_account_login_failures(&self, user_id: &str) {
+        self.account_login_failures.clear(user_id, self.clock.unix_ms(Instant::now())).await;
+    }
+
+    // Drops state for keys that have been quiet long enough to not matter anymore
+    pub async fn prune(&self) {
+        let now = Instant::now();
+        self.user_messages.prune(now).await;
+        self.ip_messages.prune(now).await;
+        self.room_messages.prune(now).await;
+        self.ip_requests.prune(now).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backplane::LocalBackplane;
+
+    fn bucket(capacity: f64, refill_per_sec: f64) -> BucketConfig {
+        BucketConfig { capacity, refill_per_sec }
+    }
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            user_messages: bucket(2.0, 1.0),
+            ip_messages: bucket(3.0, 1.0),
+            room_messages: bucket(100.0, 100.0),
+            ip_requests: bucket(100.0, 100.0),
+            mute_after_violations: 3,
+            violation_window: Duration::from_secs(30),
+            mute_duration: Duration::from_secs(60),
+            join_max_failures: 3,
+            join_failure_window: Duration::from_secs(300),
+            join_lockout: Duration::from_secs(300),
+            login_max_failures: 3,
+            login_max_account_failures: 5,
+            login_failure_window: Duration::from_secs(900),
+            login_lockout: Duration::from_secs(300),
+        }
+    }
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(Arc::new(LocalBackplane::default()), &config())
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn bucket_empties_and_refills() {
+        let config = bucket(2.0, 0.5);
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(&config, start);
+
+        assert!(bucket.try_take(&config, start).is_ok());
+        assert!(bucket.try_take(&config, start).is_ok());
+        assert_eq!(bucket.try_take(&config, start), Err(Duration::from_secs(2)));
+
+        // Half a token back after a second, so one more second to go
+        let later = start + Duration::from_secs(1);
+        assert_eq!(bucket.try_take(&config, later), Err(Duration::from_secs(1)));
+        assert!(bucket.try_take(&config, start + Duration::from_secs(2)).is_ok());
+    }
+
+    #[test]
+    fn bucket_never_holds_more_than_capacity() {
+        let config = bucket(2.0, 1.0);
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(&config, start);
+
+        let much_later = start + Duration::from_secs(3600);
+        assert!(bucket.try_take(&config, much_later).is_ok());
+        assert!(bucket.try_take(&config, much_later).is_ok());
+        assert!(bucket.try_take(&config, much_later).is_err());
+    }
+
+    #[tokio::test]
+    async fn disabled_bucket_never_limits() {
+        let map = BucketMap::new(bucket(1.0, 0.0));
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert!(map.check("key", now).await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn throttled_message_takes_no_token_from_the_other_bucket() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        // Alice empties her own bucket, which leaves the IP one token
+        assert!(limiter.check_message_at("alice", ip(1), "room", now).await.is_ok());
+        assert!(limiter.check_message_at("alice", ip(1), "room", now).await.is_ok());
+        let throttled = limiter.check_message_at("alice", ip(1), "room", now).await;
+        assert!(matches!(throttled, Err(RateLimitError::Throttled { .. })));
+
+        // Her refused message didn't spend it, so bob on the same IP still gets through
+        assert!(limiter.check_message_at("bob", ip(1), "room", now).await.is_ok());
+        let throttled = limiter.check_message_at("bob", ip(1), "room", now).await;
+        assert!(matches!(throttled, Err(RateLimitError::Throttled { .. })));
+
+        // And the IP being empty didn't spend bob's second token either
+        assert!(limiter.check_message_at("bob", ip(2), "room", now).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn repeated_flooding_mutes_then_expires() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        assert!(limiter.check_message_at("alice", ip(1), "room", now).await.is_ok());
+        assert!(limiter.check_message_at("alice", ip(1), "room", now).await.is_ok());
+        for _ in 0..2 {
+            let result = limiter.check_message_at("alice", ip(1), "room", now).await;
+            assert!(matches!(result, Err(RateLimitError::Throttled { .. })));
+        }
+        let result = limiter.check_message_at("alice", ip(1), "room", now).await;
+        assert!(matches!(result, Err(RateLimitError::Muted { started: true, .. })));
+
+        // Still muted even though the bucket has refilled, and only alice is
+        let later = now + Duration::from_secs(30);
+        let result = limiter.check_message_at("alice", ip(1), "room", later).await;
+        assert!(matches!(
+            result,
+            Err(RateLimitError::Muted { retry_after, started: false }) if retry_after == Duration::from_secs(30)
+        ));
+        assert!(limiter.check_message_at("bob", ip(2), "room", later).await.is_ok());
+
+        let after_mute = now + Duration::from_secs(61);
+        assert!(limiter.check_message_at("alice", ip(1), "room", after_mute).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn violations_outside_the_window_dont_add_up() {
+        let limiter = limiter();
+        let mut now = Instant::now();
+
+        // Two violations every 40s never reach three inside one 30s window
+        for _ in 0..5 {
+            while limiter.check_message_at("alice", ip(1), "room", now).await.is_ok() {}
+            let result = limiter.check_message_at("alice", ip(1), "room", now).await;
+            assert!(matches!(result, Err(RateLimitError::Throttled { .. })));
+            now += Duration::from_secs(40);
+        }
+    }
+
+    #[tokio::test]
+    async fn wrong_passwords_lock_out_user_and_ip() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        assert!(!limiter.record_join_failure_at("alice", ip(1), "room", now).await);
+        assert!(!limiter.record_join_failure_at("alice", ip(1), "room", now).await);
+        assert_eq!(limiter.join_lockout_at("alice", ip(1), "room", now).await, None);
+        assert!(limiter.record_join_failure_at("alice", ip(1), "room", now).await);
+
+        let later = now + Duration::from_secs(100);
+        let remaining = Some(Duration::from_secs(200));
+        assert_eq!(limiter.join_lockout_at("alice", ip(1), "room", later).await, remaining);
+        // Switching user_id doesn't help from the same IP, switching IP doesn't help as the same user
+        assert_eq!(limiter.join_lockout_at("mallory", ip(1), "room", later).await, remaining);
+        assert_eq!(limiter.join_lockout_at("alice", ip(2), "room", later).await, remaining);
+        // Other rooms aren't affected
+        assert_eq!(limiter.join_lockout_at("alice", ip(1), "other", later).await, None);
+
+        let after_lockout = now + Duration::from_secs(300);
+        assert_eq!(limiter.join_lockout_at("alice", ip(1), "room", after_lockout).await, None);
+    }
+
+    #[tokio::test]
+    async fn successful_join_clears_failures() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        limiter.record_join_failure_at("alice", ip(1), "room", now).await;
+        limiter.record_join_failure_at("alice", ip(1), "room", now).await;
+        limiter.record_join_success("alice", ip(1), "room").await;
+        assert!(!limiter.record_join_failure_at("alice", ip(1), "room", now).await);
+        assert_eq!(limiter.join_lockout_at("alice", ip(1), "room", now).await, None);
+    }
+
+    #[tokio::test]
+    async fn failed_logins_lock_the_address_out_of_the_account() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        assert!(!limiter.record_login_failure_at("alice", ip(1), now).await);
+        assert!(!limiter.record_login_failure_at("alice", ip(1), now).await);
+        assert!(limiter.record_login_failure_at("alice", ip(1), now).await);
+        assert_eq!(limiter.login_lockout_at("alice", ip(1), now).await, Some(Duration::from_secs(300)));
+        assert_eq!(limiter.login_lockout_at("bob", ip(1), now).await, None);
+        // alice herself can still log in from somewhere else
+        assert_eq!(limiter.login_lockout_at("alice", ip(2), now).await, None);
+
+        let after_lockout = now + Duration::from_secs(300);
+        assert_eq!(limiter.login_lockout_at("alice", ip(1), after_lockout).await, None);
+    }
+
+    #[tokio::test]
+    async fn failed_logins_from_many_addresses_lock_the_account() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        for last in 1..5 {
+            assert!(!limiter.record_login_failure_at("alice", ip(last), now).await);
+        }
+        assert!(limiter.record_login_failure_at("alice", ip(5), now).await);
+        assert_eq!(limiter.login_lockout_at("alice", ip(6), now).await, Some(Duration::from_secs(300)));
+
+        limiter.clear_account_login_failures("alice").await;
+        assert_eq!(limiter.login_lockout_at("alice", ip(6), now).await, None);
+    }
+
+    #[tokio::test]
+    async fn successful_login_clears_failures() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        limiter.record_login_failure_at("alice", ip(1), now).await;
+        limiter.record_login_failure_at("alice", ip(1), now).await;
+        limiter.record_login_success("alice", ip(1)).await;
+        assert!(!limiter.record_login_failure_at("alice", ip(1), now).await);
+        assert!(!limiter.record_login_failure_at("alice", ip(1), now).await);
+    }
+
+    #[tokio::test]
+    async fn lockouts_and_slow_mode_hold_on_every_instance() {
+        let backplane: Arc<dyn Backplane> = Arc::new(LocalBackplane::default());
+        let here = RateLimiter::new(backplane.clone(), &config());
+        let there = RateLimiter::new(backplane, &config());
+        let now = Instant::now();
+
+        assert!(!here.record_login_failure_at("alice", ip(1), now).await);
+        assert!(!there.record_login_failure_at("alice", ip(1), now).await);
+        assert!(here.record_login_failure_at("alice", ip(1), now).await);
+        assert!(there.login_lockout("alice", ip(1)).await.is_some());
+
+        let interval = Duration::from_secs(10);
+        assert!(here.check_slow_mode("alice", "lobby", interval).await.is_ok());
+        assert!(matches!(
+            there.check_slow_mode("alice", "lobby", interval).await,
+            Err(RateLimitError::SlowMode { .. })
+        ));
+        assert!(there.check_slow_mode("bob", "lobby", interval).await.is_ok());
+    }
+}
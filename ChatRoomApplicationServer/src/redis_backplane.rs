@@ -0,0 +1,317 @@
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use redis::{aio::MultiplexedConnection, AsyncCommands};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use crate::backplane::{kept_for, Backplane, BackplaneError, BackplaneEvent, BackplaneResult};
+use crate::message::StorageStats;
+
+// The backplane shared through Redis (see backplane.rs). Keys and sets are plain Redis strings
+// and sets, expiry is left to Redis, and room events go out on one pub/sub channel that every
+// instance subscribes to.
+//
+// Publishing doesn't wait on Redis: events are queued and a task sends them out one at a time, so
+// a room never waits on the network and an instance's events keep their order. flush() queues a
+// marker behind them and waits for the task to reach it. If the connection
+// drops, the subscription is opened again after RECONNECT_DELAY; events published in between are
+// missed by this instance, so subscribers are handed BackplaneEvent::Missed once it is back.
+//
+// Needs Redis 6.2 or later, for GETDEL.
+
+const EVENTS_CHANNEL: &str = "chat:room_events";
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const EVENT_CAPACITY: usize = 1024;
+
+pub struct RedisBackplane {
+    // commands from any number of tasks share this one connection
+    connection: MultiplexedConnection,
+    outgoing: mpsc::UnboundedSender<Outgoing>,
+    incoming: broadcast::Sender<BackplaneEvent>,
+}
+
+enum Outgoing {
+    Event(BackplaneEvent),
+    // everything queued before this has been sent
+    Flushed(oneshot::Sender<()>),
+}
+
+impl From<redis::RedisError> for BackplaneError {
+    fn from(e: redis::RedisError) -> Self {
+        BackplaneError(e.to_string())
+    }
+}
+
+impl RedisBackplane {
+    pub async fn connect(url: &str) -> BackplaneResult<Self> {
+        let client = redis::Client::open(url)?;
+        let connection = client.get_multiplexed_tokio_connection().await?;
+
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        tokio::spawn(publish_events(connection.clone(), outgoing_rx));
+
+        let (incoming, _) = broadcast::channel(EVENT_CAPACITY);
+        tokio::spawn(receive_events(client, incoming.clone()));
+
+        tracing::info!("Using the Redis backplane at {}", url);
+        Ok(RedisBackplane {
+            connection,
+            outgoing,
+            incoming,
+        })
+    }
+}
+
+async fn publish_events(mut connection: MultiplexedConnection, mut events: mpsc::UnboundedReceiver<Outgoing>) {
+    while let Some(outgoing) = events.recv().await {
+        let event = match outgoing {
+            Outgoing::Event(event) => event,
+            Outgoing::Flushed(done) => {
+                let _ = done.send(());
+                continue;
+            }
+        };
+        let json = match serde_json::to_string(&event) {
+            Ok(json) => json,
+            Err(e) => {
+                tracing::error!("Failed to serialize backplane event: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = connection.publish::<_, _, ()>(EVENTS_CHANNEL, json).await {
+            match &event {
+                BackplaneEvent::Room { room_id, .. } => {
+                    tracing::warn!("Failed to publish an event for room {}: {}", room_id, e)
+                }
+                BackplaneEvent::Announcement(_) => tracing::warn!("Failed to publish an announcement: {}", e),
+                BackplaneEvent::Missed => {}
+            }
+        }
+    }
+}
+
+// Stays subscribed for as long as the server runs, reconnecting whenever the connection drops
+async fn receive_events(client: redis::Client, incoming: broadcast::Sender<BackplaneEvent>) {
+    let mut reconnecting = false;
+    loop {
+        match client.get_async_pubsub().await {
+            Ok(mut pubsub) => match pubsub.subscribe(EVENTS_CHANNEL).await {
+                Ok(()) => {
+                    if reconnecting {
+                        let _ = incoming.send(BackplaneEvent::Missed);
+                    }
+                    reconnecting = true;
+                    let mut messages = pubsub.on_message();
+                    while let Some(message) = messages.next().await {
+                        let event = message
+                            .get_payload::<String>()
+                            .ok()
+                            .and_then(|json| serde_json::from_str::<BackplaneEvent>(&json).ok());
+                        match event {
+                            Some(event) => {
+                                let _ = incoming.send(event);
+                            }
+                            None => tracing::warn!("Ignoring a malformed backplane event"),
+                        }
+                    }
+                    tracing::warn!("Lost the backplane subscription, reconnecting");
+                }
+                Err(e) => tracing::warn!("Failed to subscribe to backplane events: {}", e),
+            },
+            Err(e) => tracing::warn!("Failed to connect to the backplane for events: {}", e),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+// The index of the last of the first `len` items of a list, which Redis counts as -1 when that is
+// all of them. `len` can't be 0.
+fn last_index(len: usize) -> isize {
+    isize::try_from(len).map_or(-1, |len| len - 1)
+}
+
+#[async_trait]
+impl Backplane for RedisBackplane {
+    fn publish(&self, event: BackplaneEvent) {
+        let _ = self.outgoing.send(Outgoing::Event(event));
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<BackplaneEvent> {
+        self.incoming.subscribe()
+    }
+
+    async fn get(&self, key: &str) -> BackplaneResult<Option<String>> {
+        Ok(self.connection.clone().get(key).await?)
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> BackplaneResult<()> {
+        let mut connection = self.connection.clone();
+        match ttl {
+            Some(ttl) => connection.pset_ex(key, value, kept_for(ttl).as_millis() as u64).await?,
+            None => connection.set(key, value).await?,
+        }
+        Ok(())
+    }
+
+    async fn set_new(&self, key: &str, value: &str, ttl: Option<Duration>) -> BackplaneResult<bool> {
+        // SET ... NX answers OK when it set the key and nil when it was already there
+        let mut command = redis::cmd("SET");
+        command.arg(key).arg(value).arg("NX");
+        if let Some(ttl) = ttl {
+            command.arg("PX").arg(kept_for(ttl).as_millis() as u64);
+        }
+        let reply: Option<String> = command.query_async(&mut self.connection.clone()).await?;
+        Ok(reply.is_some())
+    }
+
+    async fn replace(&self, key: &str, value: &str) -> BackplaneResult<bool> {
+        // SET ... XX answers OK when it overwrote the key and nil when there was nothing to overwrite
+        let reply: Option<String> = redis::cmd("SET")
+            .arg(key)
+            .arg(value)
+            .arg("XX")
+            .query_async(&mut self.connection.clone())
+            .await?;
+        Ok(reply.is_some())
+    }
+
+    async fn take(&self, key: &str) -> BackplaneResult<Option<String>> {
+        Ok(self.connection.clone().get_del(key).await?)
+    }
+
+    async fn increment(&self, key: &str, ttl: Option<Duration>) -> BackplaneResult<u64> {
+        // Starting the counter with its expiry and counting are one transaction, so a counter can't
+        // be left without one
+        let mut transaction = redis::pipe();
+        transaction.atomic();
+        if let Some(ttl) = ttl {
+            transaction
+                .cmd("SET")
+                .arg(key)
+                .arg(0)
+                .arg("NX")
+                .arg("PX")
+                .arg(kept_for(ttl).as_millis() as u64)
+                .ignore();
+        }
+        let (count,): (u64,) = transaction.cmd("INCR").arg(key).query_async(&mut self.connection.clone()).await?;
+        Ok(count)
+    }
+
+    async fn add_member(&self, set: &str, member: &str) -> BackplaneResult<()> {
+        Ok(self.connection.clone().sadd(set, member).await?)
+    }
+
+    async fn remove_member(&self, set: &str, member: &str) -> BackplaneResult<()> {
+        Ok(self.connection.clone().srem(set, member).await?)
+    }
+
+    async fn members(&self, set: &str) -> BackplaneResult<Vec<String>> {
+        Ok(self.connection.clone().smembers(set).await?)
+    }
+
+    async fn count_members(&self, set: &str) -> BackplaneResult<usize> {
+        Ok(self.connection.clone().scard(set).await?)
+    }
+
+    async fn push(&self, list: &str, value: &str, max_len: usize) -> BackplaneResult<()> {
+        if max_len == 0 {
+            return self.trim(list, 0).await;
+        }
+        redis::pipe()
+            .atomic()
+            .lpush(list, value)
+            .ignore()
+            .ltrim(list, 0, last_index(max_len))
+            .ignore()
+            .query_async::<()>(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn latest(&self, list: &str, limit: usize) -> BackplaneResult<Vec<String>> {
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        Ok(self.connection.clone().lrange(list, 0, last_index(limit)).await?)
+    }
+
+    async fn trim(&self, list: &str, max_len: usize) -> BackplaneResult<()> {
+        // LTRIM 0 -1 would keep everything, and Redis drops a list once it is empty
+        if max_len == 0 {
+            return Ok(self.connection.clone().del(list).await?);
+        }
+        Ok(self.connection.clone().ltrim(list, 0, last_index(max_len)).await?)
+    }
+
+    async fn ping(&self) -> BackplaneResult<()> {
+        redis::cmd("PING").query_async::<()>(&mut self.connection.clone()).await?;
+        Ok(())
+    }
+
+    async fn stats(&self) -> BackplaneResult<StorageStats> {
+        let keys = redis::cmd("DBSIZE").query_async(&mut self.connection.clone()).await?;
+        Ok(StorageStats {
+            backend: "redis".to_string(),
+            keys,
+        })
+    }
+
+    async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.outgoing.send(Outgoing::Flushed(done)).is_ok() {
+            let _ = flushed.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backplane::contract;
+    use crate::backplane_broker;
+    use tokio::net::TcpListener;
+
+    // Against the stand-in for Redis in examples/backplane_broker.rs, one each so they don't share keys
+    async fn backplane() -> RedisBackplane {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        tokio::spawn(backplane_broker::accept_clients(listener));
+        RedisBackplane::connect(&url).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn replace_only_overwrites_existing_keys() {
+        contract::replace_only_overwrites_existing_keys(&backplane().await).await;
+    }
+
+    #[tokio::test]
+    async fn set_new_keeps_what_is_there() {
+        contract::set_new_keeps_what_is_there(&backplane().await).await;
+    }
+
+    #[tokio::test]
+    async fn take_gives_the_value_once() {
+        contract::take_gives_the_value_once(&backplane().await).await;
+    }
+
+    #[tokio::test]
+    async fn increment_counts_from_zero() {
+        contract::increment_counts_from_zero(&backplane().await).await;
+    }
+
+    #[tokio::test]
+    async fn lists_keep_the_newest_values() {
+        contract::lists_keep_the_newest_values(&backplane().await).await;
+    }
+
+    #[tokio::test]
+    async fn replace_skips_expired_keys() {
+        contract::replace_skips_expired_keys(&backplane().await).await;
+    }
+
+    #[tokio::test]
+    async fn events_arrive_in_order() {
+        contract::events_arrive_in_order(&backplane().await).await;
+    }
+}
@@ -0,0 +1,1013 @@
+use axum::extract::ws::Utf8Bytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Mutex};
+
+use crate::backplane::{Backplane, BackplaneError, BackplaneEvent, BackplaneResult, RoomEventKind};
+use crate::message::{ChatMessage, RoomDetails, RoomInfo, RoomVisibility, ServerWsMessage};
+
+// Every room runs as its own task (an actor) that owns the room's members, its recent history and
+// its broadcast channel. Handlers never touch that state themselves: they look the room up in the
+// RoomRegistry and send the task a RoomCommand through its RoomHandle, and the task carries them
+// out one at a time. Rooms don't share a lock, so a busy room never holds up the others, and since
+// only the room's task changes it, a message can't be broadcast without making it into the history
+// (or the other way around) and a member can't be half added.
+//
+// The registry's own lock is only held to find a room, never while waiting on a room.
+//
+// With several server instances (see backplane.rs) every instance runs its own task for each room
+// it has been asked about, started the first time it is. The room's settings, who is connected to
+// it, who was let into it and its recent chat messages are kept in the backplane, so every instance
+// sees the same. A room's task publishes everything it sends out, and RoomRegistry::relay_events()
+// hands what the other instances published to the same room's task here, to send to the members
+// connected to this one. Only the task on the instance a message was sent through adds it to the
+// history in the backplane, in the order it sent them out.
+//
+// The owner changing a room's settings is published like everything else the room sends out, and
+// handles always read the newest settings this instance has heard of. Those are switched to by
+// update() and relay_events() themselves, not by the room's task, so the change holds as soon as
+// update() returns and can't be lost to a task that is falling behind (only the members being told
+// about it can).
+//
+// Deleting a room stops it on every instance: its task closes every member's connection and drops
+// whatever it is sent from then on, and the registries forget it. Telling the task it was deleted
+// waits for room in its queue rather than being dropped.
+//
+// Whenever this instance may have missed events, because it fell behind the backplane or lost its
+// connection to it, every room running here reloads its settings from the backplane, and the ones
+// that are gone are stopped like a deleted room (see resync()).
+//
+// Who is connected is kept per connection and tagged with the instance it is to. Every instance
+// keeps a heartbeat key in the backplane while it runs, and keep_alive() forgets the connections of
+// the instances whose heartbeat has expired, since one that crashed or gave up at the shutdown
+// deadline never removed them.
+//
+// Chat messages are numbered as the room sends them out. A connection that falls too far behind
+// the broadcasts to get them all can then ask for exactly the ones it skipped (see
+// history_after()), as long as they are still in the history the task keeps for that. Those
+// numbers are only known to this instance, which is all a connection to it needs.
+
+// Commands that can queue up for one room before the senders have to wait their turn
+const COMMAND_QUEUE: usize = 256;
+// Broadcasts a connection can fall behind by before it starts missing them
+const BROADCAST_CAPACITY: usize = 100;
+// How often an instance says it is still running, and how long after the last time it counts as
+// gone
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+const INSTANCE_TTL: Duration = Duration::from_secs(30);
+
+// Everything about a room but its members and messages. Only the owner is fixed when the room is
+// created, the rest can be changed later (see RoomRegistry::update).
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomSettings {
+    // argon2 hash, None for public rooms and invite only unlisted rooms
+    pub password_hash: Option<String>,
+    pub visibility: RoomVisibility,
+    pub owner: String,
+    // RFC 3339
+    #[serde(default)]
+    pub created_at: String,
+    #[serde(default)]
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    // members connected at once, None for no limit
+    #[serde(default)]
+    pub max_members: Option<u32>,
+    // 0 when slow mode is off
+    #[serde(default)]
+    pub slow_mode_secs: u64,
+    // chat messages kept for people who join later, None for the server's CHAT_ROOM_HISTORY
+    #[serde(default)]
+    pub history_len: Option<usize>,
+}
+
+impl RoomSettings {
+    pub fn new(owner: &str, visibility: RoomVisibility, password_hash: Option<String>) -> Self {
+        RoomSettings {
+            password_hash,
+            visibility,
+            owner: owner.to_string(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            topic: None,
+            description: None,
+            max_members: None,
+            slow_mode_secs: 0,
+            history_len: None,
+        }
+    }
+
+    // As members see them. `default_history_len` is the server's CHAT_ROOM_HISTORY.
+    pub fn details(&self, default_history_len: usize) -> RoomDetails {
+        RoomDetails {
+            visibility: self.visibility,
+            topic: self.topic.clone(),
+            description: self.description.clone(),
+            created_at: self.created_at.clone(),
+            max_members: self.max_members,
+            slow_mode_secs: self.slow_mode_secs,
+            history_len: self.history_len.unwrap_or(default_history_len),
+        }
+    }
+}
+
+// One broadcast as every member receives it. The message is serialized once, by the room, and
+// every member's connection sends the same frame: cloning it only bumps a reference count.
+#[derive(Clone)]
+pub struct RoomEvent {
+    // set for chat messages, which are kept in the history
+    pub seq: Option<u64>,
+    pub frame: Utf8Bytes,
+    // when the room sent it out, for measuring how far behind the connections are
+    pub sent_at: Instant,
+    closes: Closes,
+}
+
+// Whose connections are closed once they have been sent an event
+#[derive(Clone)]
+enum Closes {
+    Nobody,
+    Member(String),
+    Everyone,
+}
+
+impl RoomEvent {
+    pub fn closes(&self, user_id: &str) -> bool {
+        match &self.closes {
+            Closes::Nobody => false,
+            Closes::Member(member) => member == user_id,
+            Closes::Everyone => true,
+        }
+    }
+}
+
+// What a member gets when they connect
+pub struct Subscription {
+    pub events: broadcast::Receiver<RoomEvent>,
+    // the last chat message sent before they arrived, 0 if there was none
+    pub seen_through: u64,
+    // tells this connection apart from the member's others, for disconnect()
+    pub connection_id: String,
+}
+
+// The chat messages a lagging connection missed
+pub struct Resync {
+    pub messages: Vec<(u64, ChatMessage)>,
+    // some of them had already dropped out of the history
+    pub incomplete: bool,
+}
+
+enum RoomCommand {
+    // Adds a connection and hands back its subscription to the room's broadcasts. The others are
+    // only told the member joined when it is their first.
+    Connect {
+        user_id: String,
+        connection_id: String,
+        joined: bool,
+        reply: oneshot::Sender<Subscription>,
+    },
+    // A member's last connection closed
+    Disconnect {
+        user_id: String,
+    },
+    // A chat message: kept in the history and sent to everyone
+    Post(ChatMessage),
+    // Kicks a member out, closing their connections on every instance
+    Remove {
+        user_id: String,
+    },
+    // New settings, already saved in the backplane
+    Update {
+        settings: RoomSettings,
+        updated_by: String,
+    },
+    Delete,
+    // Something that happened in this room on another instance, sent out here but not published
+    Relayed(RoomEventKind),
+    HistoryAfter {
+        seq: u64,
+        reply: oneshot::Sender<Resync>,
+    },
+}
+
+// How the rest of the server talks to a room. Cheap to clone, and the room keeps running for as
+// long as the registry (or anyone else) holds one.
+#[derive(Clone)]
+pub struct RoomHandle {
+    pub room_id: String,
+    settings: watch::Receiver<Arc<RoomSettings>>,
+    // shared with the room's task, which switches to settings changed through this instance, and
+    // with relay_events(), which switches to the ones changed through the others
+    settings_writer: Arc<watch::Sender<Arc<RoomSettings>>>,
+    // the server's CHAT_ROOM_HISTORY, for rooms that haven't set their own
+    default_history_len: usize,
+    commands: mpsc::Sender<RoomCommand>,
+    backplane: Arc<dyn Backplane>,
+    instance_id: String,
+}
+
+// The room's recent chat messages on every instance, newest first
+fn history_key(room_id: &str) -> String {
+    format!("history:{}", room_id)
+}
+
+// Every connection to the room on any instance, as "user_id:instance_id:connection_id" so a member
+// connected twice stays a member until both are closed. Names can't have a ':' in them.
+fn members_key(room_id: &str) -> String {
+    format!("members:{}", room_id)
+}
+
+fn connection_entry(user_id: &str, instance_id: &str, connection_id: &str) -> String {
+    format!("{}:{}:{}", user_id, instance_id, connection_id)
+}
+
+fn member_of(entry: &str) -> &str {
+    entry.split_once(':').map_or(entry, |(user_id, _)| user_id)
+}
+
+fn instance_of(entry: &str) -> &str {
+    entry.split(':').nth(1).unwrap_or_default()
+}
+
+// There for as long as the instance is running (see RoomRegistry::keep_alive)
+fn instance_key(instance_id: &str) -> String {
+    format!("instance:{}", instance_id)
+}
+
+impl RoomHandle {
+    pub fn settings(&self) -> Arc<RoomSettings> {
+        self.settings.borrow().clone()
+    }
+
+    pub fn details(&self) -> RoomDetails {
+        self.settings().details(self.default_history_len)
+    }
+
+    // None if the room's task is gone
+    pub async fn connect(&self, user_id: &str) -> Option<Subscription> {
+        let connection_id = uuid::Uuid::new_v4().to_string();
+        // Members are listed from the backplane, so a failure here only hides them from the list
+        let joined = !self.is_connected(user_id).await;
+        if let Err(e) = self
+            .backplane
+            .add_member(&members_key(&self.room_id), &connection_entry(user_id, &self.instance_id, &connection_id))
+            .await
+        {
+            tracing::warn!("Failed to add {} to the members of {}: {}", user_id, self.room_id, e);
+        }
+        let (reply, receiver) = oneshot::channel();
+        self.send(RoomCommand::Connect {
+            user_id: user_id.to_string(),
+            connection_id,
+            joined,
+            reply,
+        })
+        .await;
+        receiver.await.ok()
+    }
+
+    // True if it was the member's last connection to the room, on any instance
+    pub async fn disconnect(&self, user_id: &str, connection_id: &str) -> bool {
+        if let Err(e) = self
+            .backplane
+            .remove_member(&members_key(&self.room_id), &connection_entry(user_id, &self.instance_id, connection_id))
+            .await
+        {
+            tracing::warn!("Failed to remove {} from the members of {}: {}", user_id, self.room_id, e);
+        }
+        if self.is_connected(user_id).await {
+            return false;
+        }
+        self.send(RoomCommand::Disconnect {
+            user_id: user_id.to_string(),
+        })
+        .await;
+        true
+    }
+
+    // Whether the user has a connection to the room on any instance. Taken as no if the backplane
+    // can't say, which at worst tells the others about a join or leave twice.
+    async fn is_connected(&self, user_id: &str) -> bool {
+        match self.members().await {
+            Ok(members) => members.iter().any(|member| member == user_id),
+            Err(e) => {
+                tracing::warn!("Failed to list the members of {}: {}", self.room_id, e);
+                false
+            }
+        }
+    }
+
+    pub async fn post(&self, message: ChatMessage) {
+        self.send(RoomCommand::Post(message)).await;
+    }
+
+    pub async fn remove(&self, user_id: &str) {
+        self.send(RoomCommand::Remove {
+            user_id: user_id.to_string(),
+        })
+        .await;
+    }
+
+    fn relay(&self, event: RoomEventKind) -> bool {
+        // Never waits: a room that can't keep up loses relayed events rather than holding up
+        // every other room on this instance
+        self.commands.try_send(RoomCommand::Relayed(event)).is_ok()
+    }
+
+    // Has the room's task close every connection and stop, waiting for room in its queue in the
+    // background so the caller doesn't have to
+    fn stop(&self) {
+        let room = self.clone();
+        tokio::spawn(async move { room.send(RoomCommand::Relayed(RoomEventKind::Deleted)).await });
+    }
+
+    // Everyone connected to the room on any instance, sorted by name, once however many
+    // connections they have
+    pub async fn members(&self) -> BackplaneResult<Vec<String>> {
+        let entries = self.backplane.members(&members_key(&self.room_id)).await?;
+        let mut members: Vec<String> = entries.iter().map(|entry| member_of(entry).to_string()).collect();
+        members.sort();
+        members.dedup();
+        Ok(members)
+    }
+
+    // The last history_len chat messages sent through any instance (see RoomSettings), oldest first
+    pub async fn history(&self) -> BackplaneResult<Vec<ChatMessage>> {
+        let history_len = self.details().history_len;
+        let mut history = self.backplane.latest(&history_key(&self.room_id), history_len).await?;
+        history.reverse();
+        history
+            .iter()
+            .map(|json| serde_json::from_str(json).map_err(|e| BackplaneError(e.to_string())))
+            .collect()
+    }
+
+    // Chat messages numbered after `seq`, oldest first
+    pub async fn history_after(&self, seq: u64) -> Resync {
+        let (reply, receiver) = oneshot::channel();
+        self.send(RoomCommand::HistoryAfter { seq, reply }).await;
+        receiver.await.unwrap_or(Resync {
+            messages: Vec::new(),
+            incomplete: true,
+        })
+    }
+
+    pub async fn members_count(&self) -> BackplaneResult<usize> {
+        Ok(self.members().await?.len())
+    }
+
+    pub async fn info(&self) -> BackplaneResult<RoomInfo> {
+        let settings = self.settings();
+        Ok(RoomInfo {
+            room_id: self.room_id.clone(),
+            owner: settings.owner.clone(),
+            users_count: self.members_count().await?,
+            visibility: settings.visibility,
+            topic: settings.topic.clone(),
+        })
+    }
+
+    // Only fails once the room's task has stopped, and then there is nobody left to tell
+    async fn send(&self, command: RoomCommand) {
+        if self.commands.send(command).await.is_err() {
+            tracing::warn!("Room {} is no longer running", self.room_id);
+        }
+    }
+}
+
+struct RoomActor {
+    room_id: String,
+    settings: Arc<watch::Sender<Arc<RoomSettings>>>,
+    // the last history_len chat messages (see RoomSettings) sent out here and their numbers, oldest
+    // first, for history_after()
+    history: VecDeque<(u64, ChatMessage)>,
+    // the chat messages published here and the history_len they were sent with, on their way to the
+    // backplane (see write_history())
+    history_writer: mpsc::UnboundedSender<(ChatMessage, usize)>,
+    default_history_len: usize,
+    // the number of the last chat message sent
+    last_seq: u64,
+    broadcast: broadcast::Sender<RoomEvent>,
+    backplane: Arc<dyn Backplane>,
+    instance_id: String,
+    // once set, commands are dropped until the last handle is
+    deleted: bool,
+}
+
+impl RoomActor {
+    async fn run(mut self, mut commands: mpsc::Receiver<RoomCommand>) {
+        while let Some(command) = commands.recv().await {
+            if !self.deleted {
+                self.handle(command);
+            }
+        }
+        tracing::debug!("Room {} stopped", self.room_id);
+    }
+
+    fn handle(&mut self, command: RoomCommand) {
+        match command {
+            RoomCommand::Connect {
+                user_id,
+                connection_id,
+                joined,
+                reply,
+            } => {
+                // Subscribed before the join goes out, so the new member sees their own
+                let _ = reply.send(Subscription {
+                    events: self.broadcast.subscribe(),
+                    seen_through: self.last_seq,
+                    connection_id,
+                });
+                if joined {
+                    self.publish(RoomEventKind::Broadcast(ServerWsMessage::UserJoined {
+                        room_id: self.room_id.clone(),
+                        user_id,
+                    }));
+                }
+            }
+            RoomCommand::Disconnect { user_id } => {
+                self.publish(RoomEventKind::Broadcast(ServerWsMessage::UserLeft {
+                    room_id: self.room_id.clone(),
+                    user_id,
+                }));
+            }
+            RoomCommand::Post(message) => self.publish(RoomEventKind::Chat(message)),
+            RoomCommand::Remove { user_id } => self.publish(RoomEventKind::Removed { user_id }),
+            RoomCommand::Update { settings, updated_by } => {
+                self.publish(RoomEventKind::Updated {
+                    settings: Box::new(settings),
+                    updated_by,
+                })
+            }
+            RoomCommand::Delete => self.publish(RoomEventKind::Deleted),
+            RoomCommand::Relayed(event) => self.deliver(event),
+            RoomCommand::HistoryAfter { seq, reply } => {
+                let messages: Vec<(u64, ChatMessage)> =
+                    self.history.iter().filter(|(n, _)| *n > seq).cloned().collect();
+                let oldest_kept = self.history.front().map_or(self.last_seq + 1, |(n, _)| *n);
+                let _ = reply.send(Resync {
+                    messages,
+                    incomplete: oldest_kept > seq + 1,
+                });
+            }
+        }
+    }
+
+    // Sends it to the members here and to the other instances
+    fn publish(&mut self, event: RoomEventKind) {
+        if let RoomEventKind::Chat(message) = &event {
+            let history_len = self.settings.borrow().history_len.unwrap_or(self.default_history_len);
+            let _ = self.history_writer.send((message.clone(), history_len));
+        }
+        self.backplane.publish(BackplaneEvent::Room {
+            origin: self.instance_id.clone(),
+            room_id: self.room_id.clone(),
+            event: event.clone(),
+        });
+        self.deliver(event);
+    }
+
+    // Sends it to the members connected to this instance
+    fn deliver(&mut self, event: RoomEventKind) {
+        match event {
+            RoomEventKind::Chat(message) => {
+                self.last_seq += 1;
+                self.history.push_back((self.last_seq, message.clone()));
+                self.trim_history();
+                self.send(Some(self.last_seq), &ServerWsMessage::MessageBroadcast(message), Closes::Nobody);
+            }
+            RoomEventKind::Broadcast(message) => self.send(None, &message, Closes::Nobody),
+            RoomEventKind::Removed { user_id } => {
+                let message = ServerWsMessage::UserKicked {
+                    room_id: self.room_id.clone(),
+                    user_id: user_id.clone(),
+                };
+                self.send(None, &message, Closes::Member(user_id));
+            }
+            // the settings were already switched to, by whoever passed the event on
+            RoomEventKind::Updated { settings, updated_by } => {
+                let message = ServerWsMessage::RoomUpdated {
+                    room_id: self.room_id.clone(),
+                    updated_by,
+                    details: settings.details(self.default_history_len),
+                };
+                self.trim_history();
+                self.send(None, &message, Closes::Nobody);
+            }
+            RoomEventKind::Deleted => {
+                let message = ServerWsMessage::RoomDeleted {
+                    room_id: self.room_id.clone(),
+                };
+                self.send(None, &message, Closes::Everyone);
+                self.deleted = true;
+            }
+        }
+    }
+
+    fn trim_history(&mut self) {
+        let history_len = self.settings.borrow().history_len.unwrap_or(self.default_history_len);
+        while self.history.len() > history_len {
+            self.history.pop_front();
+        }
+    }
+
+    fn send(&self, seq: Option<u64>, message: &ServerWsMessage, closes: Closes) {
+        match serde_json::to_string(message) {
+            // Nobody listening isn't an error, the room is just empty
+            Ok(json) => {
+                let _ = self.broadcast.send(RoomEvent {
+                    seq,
+                    frame: Utf8Bytes::from(json),
+                    sent_at: Instant::now(),
+                    closes,
+                });
+            }
+            Err(e) => tracing::error!("Failed to serialize broadcast message: {}", e),
+        }
+    }
+}
+
+// Adds the chat messages a room publishes to its history in the backplane one at a time, so they
+// stay in the order the room sent them out without the room waiting on the backplane. Stops once
+// the room's task is gone.
+async fn write_history(
+    backplane: Arc<dyn Backplane>,
+    room_id: String,
+    mut messages: mpsc::UnboundedReceiver<(ChatMessage, usize)>,
+) {
+    let key = history_key(&room_id);
+    while let Some((message, history_len)) = messages.recv().await {
+        let pushed = match serde_json::to_string(&message) {
+            Ok(json) => backplane.push(&key, &json, history_len).await,
+            Err(e) => Err(BackplaneError(e.to_string())),
+        };
+        if let Err(e) = pushed {
+            tracing::warn!("Failed to add a message to the history of {}: {}", room_id, e);
+        }
+    }
+}
+
+pub struct RoomRegistry {
+    backplane: Arc<dyn Backplane>,
+    // tells this instance's events apart from the ones the others publish
+    instance_id: String,
+    // room_id -> the handle of every room this instance has a task for
+    rooms: Mutex<HashMap<String, RoomHandle>>,
+    // chat messages each room keeps for people who join later
+    history_len: usize,
+}
+
+// Every room ever created, so any instance can list them
+const ROOMS_KEY: &str = "rooms";
+
+fn room_key(room_id: &str) -> String {
+    format!("room:{}", room_id)
+}
+
+// The room a user was last let into over HTTP, looked up when their websocket connects
+fn admitted_key(user_id: &str) -> String {
+    format!("admitted:{}", user_id)
+}
+
+impl RoomRegistry {
+    pub fn new(backplane: Arc<dyn Backplane>, history_len: usize) -> Self {
+        RoomRegistry {
+            backplane,
+            instance_id: uuid::Uuid::new_v4().to_string(),
+            rooms: Mutex::new(HashMap::new()),
+            history_len,
+        }
+    }
+
+    // None if there already is a room with that name, on any instance
+    pub async fn create(&self, room_id: &str, settings: RoomSettings) -> BackplaneResult<Option<RoomHandle>> {
+        let json = serde_json::to_string(&settings).map_err(|e| BackplaneError(e.to_string()))?;
+        if !self.backplane.set_new(&room_key(room_id), &json, None).await? {
+            return Ok(None);
+        }
+        self.backplane.add_member(ROOMS_KEY, room_id).await?;
+        Ok(Some(self.start(room_id, settings).await))
+    }
+
+    // Starts the room's task here if it isn't running yet
+    pub async fn get(&self, room_id: &str) -> BackplaneResult<Option<RoomHandle>> {
+        if let Some(room) = self.rooms.lock().await.get(room_id) {
+            return Ok(Some(room.clone()));
+        }
+        let Some(json) = self.backplane.get(&room_key(room_id)).await? else {
+            return Ok(None);
+        };
+        let settings = serde_json::from_str(&json).map_err(|e| BackplaneError(e.to_string()))?;
+        Ok(Some(self.start(room_id, settings).await))
+    }
+
+    pub async fn exists(&self, room_id: &str) -> BackplaneResult<bool> {
+        if self.rooms.lock().await.contains_key(room_id) {
+            return Ok(true);
+        }
+        Ok(self.backplane.get(&room_key(room_id)).await?.is_some())
+    }
+
+    pub async fn all(&self) -> BackplaneResult<Vec<RoomHandle>> {
+        let mut rooms = Vec::new();
+        for room_id in self.backplane.members(ROOMS_KEY).await? {
+            if let Some(room) = self.get(&room_id).await? {
+                rooms.push(room);
+            }
+        }
+        Ok(rooms)
+    }
+
+    // Saves new settings for a room and has its task on every instance use them and tell the
+    // members. False if the room was deleted in the meantime.
+    pub async fn update(&self, room: &RoomHandle, settings: RoomSettings, updated_by: &str) -> BackplaneResult<bool> {
+        let json = serde_json::to_string(&settings).map_err(|e| BackplaneError(e.to_string()))?;
+        // Only overwritten if it is still there, so a room deleted in between stays deleted
+        if !self.backplane.replace(&room_key(&room.room_id), &json).await? {
+            return Ok(false);
+        }
+        // in use here before this returns, the room's task only tells everyone
+        room.settings_writer.send_replace(Arc::new(settings.clone()));
+        room.send(RoomCommand::Update {
+            settings,
+            updated_by: updated_by.to_string(),
+        })
+        .await;
+        Ok(true)
+    }
+
+    // Tells the members the room is gone and stops it on every instance. False if there was no such
+    // room.
+    pub async fn delete(&self, room_id: &str) -> BackplaneResult<bool> {
+        let Some(room) = self.get(room_id).await? else {
+            return Ok(false);
+        };
+        // whoever takes the settings is the one deleting it
+        if self.backplane.take(&room_key(room_id)).await?.is_none() {
+            return Ok(false);
+        }
+        self.backplane.remove_member(ROOMS_KEY, room_id).await?;
+        // so a new room with the same name starts out empty
+        self.backplane.trim(&history_key(room_id), 0).await?;
+        self.rooms.lock().await.remove(room_id);
+        room.send(RoomCommand::Delete).await;
+        Ok(true)
+    }
+
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    // Every room, on any instance
+    pub async fn count(&self) -> BackplaneResult<usize> {
+        self.backplane.count_members(ROOMS_KEY).await
+    }
+
+    // The rooms this instance has a task for
+    pub async fn running(&self) -> usize {
+        self.rooms.lock().await.len()
+    }
+
+    // The handle of the room's task on this instance. If another request started one while the
+    // settings were being fetched, that one is used.
+    async fn start(&self, room_id: &str, settings: RoomSettings) -> RoomHandle {
+        let mut rooms = self.rooms.lock().await;
+        if let Some(room) = rooms.get(room_id) {
+            return room.clone();
+        }
+
+        let (commands, receiver) = mpsc::channel(COMMAND_QUEUE);
+        let (broadcast, _) = broadcast::channel(BROADCAST_CAPACITY);
+        let (settings, settings_receiver) = watch::channel(Arc::new(settings));
+        let settings = Arc::new(settings);
+        let (history_writer, history_messages) = mpsc::unbounded_channel();
+        tokio::spawn(write_history(self.backplane.clone(), room_id.to_string(), history_messages));
+        let actor = RoomActor {
+            room_id: room_id.to_string(),
+            settings: settings.clone(),
+            history: VecDeque::with_capacity(self.history_len),
+            history_writer,
+            default_history_len: self.history_len,
+            last_seq: 0,
+            broadcast,
+            backplane: self.backplane.clone(),
+            instance_id: self.instance_id.clone(),
+            deleted: false,
+        };
+        tokio::spawn(actor.run(receiver));
+
+        let handle = RoomHandle {
+            room_id: room_id.to_string(),
+            settings: settings_receiver,
+            settings_writer: settings,
+            default_history_len: self.history_len,
+            commands,
+            backplane: self.backplane.clone(),
+            instance_id: self.instance_id.clone(),
+        };
+        rooms.insert(room_id.to_string(), handle.clone());
+        handle
+    }
+
+    // Lets a user's websocket into a room they were just let into over HTTP, on any instance
+    pub async fn admit(&self, user_id: &str, room_id: &str) -> BackplaneResult<()> {
+        self.backplane.set(&admitted_key(user_id), room_id, None).await
+    }
+
+    pub async fn admitted_room(&self, user_id: &str) -> BackplaneResult<Option<String>> {
+        self.backplane.get(&admitted_key(user_id)).await
+    }
+
+    // Forgets where the user was, unless they have since joined somewhere else. Another instance
+    // could let them in somewhere in between the two steps, which would only mean they have to
+    // join again.
+    pub async fn release(&self, user_id: &str, room_id: &str) -> BackplaneResult<()> {
+        let key = admitted_key(user_id);
+        if self.backplane.get(&key).await?.is_some_and(|room| room == room_id) {
+            self.backplane.take(&key).await?;
+        }
+        Ok(())
+    }
+
+    // Keeps this instance's heartbeat going and forgets the connections of instances that stopped
+    // without closing theirs, for as long as the server runs
+    pub async fn keep_alive(&self) {
+        let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.heartbeat().await {
+                tracing::warn!("Failed to renew this instance's heartbeat: {}", e);
+            }
+            if let Err(e) = self.sweep().await {
+                tracing::warn!("Failed to sweep the connections of stopped instances: {}", e);
+            }
+        }
+    }
+
+    async fn heartbeat(&self) -> BackplaneResult<()> {
+        self.backplane.set(&instance_key(&self.instance_id), "", Some(INSTANCE_TTL)).await
+    }
+
+    // Removes every connection to an instance without a heartbeat, and tells the room about the
+    // members that left with them. Instances sweeping at the same time could tell it twice.
+    async fn sweep(&self) -> BackplaneResult<()> {
+        // instance_id -> whether it is still running
+        let mut running: HashMap<String, bool> = HashMap::new();
+        for room_id in self.backplane.members(ROOMS_KEY).await? {
+            let key = members_key(&room_id);
+            let mut gone = Vec::new();
+            for entry in self.backplane.members(&key).await? {
+                let instance_id = instance_of(&entry);
+                let alive = match running.get(instance_id) {
+                    Some(alive) => *alive,
+                    None => {
+                        let alive = self.backplane.get(&instance_key(instance_id)).await?.is_some();
+                        running.insert(instance_id.to_string(), alive);
+                        alive
+                    }
+                };
+                if !alive {
+                    self.backplane.remove_member(&key, &entry).await?;
+                    gone.push(member_of(&entry).to_string());
+                }
+            }
+            if gone.is_empty() {
+                continue;
+            }
+            let Some(room) = self.get(&room_id).await? else {
+                continue;
+            };
+            gone.sort();
+            gone.dedup();
+            for user_id in gone {
+                if room.is_connected(&user_id).await {
+                    continue;
+                }
+                tracing::info!("{} left room {} with the instance they were connected to", user_id, room_id);
+                self.release(&user_id, &room_id).await?;
+                room.send(RoomCommand::Disconnect { user_id }).await;
+            }
+        }
+        Ok(())
+    }
+
+    // Passes what the other instances publish on to the rooms here, for as long as the server runs
+    pub async fn relay_events(&self) {
+        let mut events = self.backplane.subscribe();
+        loop {
+            let (origin, room_id, event) = match events.recv().await {
+                Ok(BackplaneEvent::Room { origin, room_id, event }) => (origin, room_id, event),
+                // not for any one room
+                Ok(BackplaneEvent::Announcement(_)) => continue,
+                Ok(BackplaneEvent::Missed) => {
+                    tracing::warn!("Room events may have been missed while the backplane was unreachable");
+                    self.resync().await;
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Fell behind the backplane, {} room events were missed", skipped);
+                    self.resync().await;
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            if origin == self.instance_id {
+                continue;
+            }
+            // A deleted room only needs stopping where it is running, its settings are gone
+            if let RoomEventKind::Deleted = event {
+                if let Some(room) = self.rooms.lock().await.remove(&room_id) {
+                    room.stop();
+                }
+                continue;
+            }
+            let room = match self.get(&room_id).await {
+                Ok(Some(room)) => room,
+                Ok(None) => {
+                    tracing::warn!("Got an event for unknown room {}", room_id);
+                    continue;
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to look up room {}: {}", room_id, e);
+                    continue;
+                }
+            };
+            if let RoomEventKind::Updated { settings, .. } = &event {
+                room.settings_writer.send_replace(Arc::new(RoomSettings::clone(settings)));
+            }
+            if !room.relay(event) {
+                tracing::warn!("Room {} is falling behind, dropped an event from another instance", room.room_id);
+            }
+        }
+    }
+
+    // Brings every room running here up to date with the backplane after events may have been
+    // missed: new settings are switched to, and a room that is gone is stopped
+    async fn resync(&self) {
+        let rooms: Vec<RoomHandle> = self.rooms.lock().await.values().cloned().collect();
+        for room in rooms {
+            match self.backplane.get(&room_key(&room.room_id)).await {
+                Ok(Some(json)) => match serde_json::from_str::<RoomSettings>(&json) {
+                    Ok(settings) => {
+                        room.settings_writer.send_replace(Arc::new(settings));
+                    }
+                    Err(e) => tracing::warn!("Failed to read the settings of room {}: {}", room.room_id, e),
+                },
+                Ok(None) => {
+                    tracing::info!("Room {} was deleted while events were being missed", room.room_id);
+                    self.rooms.lock().await.remove(&room.room_id);
+                    room.stop();
+                }
+                Err(e) => tracing::warn!("Failed to reload room {}: {}", room.room_id, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backplane::LocalBackplane;
+
+    #[tokio::test]
+    async fn members_stay_until_their_last_connection_closes() {
+        let rooms = RoomRegistry::new(Arc::new(LocalBackplane::default()), 10);
+        let settings = RoomSettings::new("alice", RoomVisibility::Public, None);
+        let room = rooms.create("lounge", settings).await.unwrap().unwrap();
+
+        let first = room.connect("bob").await.unwrap();
+        let second = room.connect("bob").await.unwrap();
+        assert_eq!(room.members().await.unwrap(), vec!["bob"]);
+        assert_eq!(room.members_count().await.unwrap(), 1);
+
+        assert!(!room.disconnect("bob", &first.connection_id).await);
+        assert_eq!(room.members().await.unwrap(), vec!["bob"]);
+
+        assert!(room.disconnect("bob", &second.connection_id).await);
+        assert!(room.members().await.unwrap().is_empty());
+    }
+
+    fn message(content: &str) -> ChatMessage {
+        ChatMessage {
+            room_id: "lounge".to_string(),
+            user_id: "alice".to_string(),
+            message_id: uuid::Uuid::new_v4().to_string(),
+            content: content.to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+
+    // The history is written behind the room's back, so give it a moment
+    async fn history_of(room: &RoomHandle, len: usize) -> Vec<String> {
+        for _ in 0..100 {
+            let history = room.history().await.unwrap();
+            if history.len() == len {
+                return history.into_iter().map(|message| message.content).collect();
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("the history never reached {} messages", len);
+    }
+
+    #[tokio::test]
+    async fn history_is_shared_by_every_instance() {
+        let backplane: Arc<dyn Backplane> = Arc::new(LocalBackplane::default());
+        let here = RoomRegistry::new(backplane.clone(), 2);
+        let there = RoomRegistry::new(backplane, 2);
+        let settings = RoomSettings::new("alice", RoomVisibility::Public, None);
+        let room = here.create("lounge", settings).await.unwrap().unwrap();
+
+        for content in ["one", "two", "three"] {
+            room.post(message(content)).await;
+        }
+        // started after the messages were sent, and still sees the newest
+        let elsewhere = there.get("lounge").await.unwrap().unwrap();
+        assert_eq!(history_of(&elsewhere, 2).await, vec!["two", "three"]);
+
+        here.delete("lounge").await.unwrap();
+        let settings = RoomSettings::new("bob", RoomVisibility::Public, None);
+        let new_room = here.create("lounge", settings).await.unwrap().unwrap();
+        assert!(new_room.history().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn connections_to_stopped_instances_are_swept() {
+        let backplane: Arc<dyn Backplane> = Arc::new(LocalBackplane::default());
+        let here = RoomRegistry::new(backplane.clone(), 10);
+        // never sends a heartbeat, like an instance that crashed
+        let there = RoomRegistry::new(backplane, 10);
+        let settings = RoomSettings::new("alice", RoomVisibility::Public, None);
+        let room = here.create("lounge", settings).await.unwrap().unwrap();
+        let elsewhere = there.get("lounge").await.unwrap().unwrap();
+
+        let mut alice = room.connect("alice").await.unwrap();
+        elsewhere.connect("bob").await.unwrap();
+        there.admit("bob", "lounge").await.unwrap();
+        assert_eq!(room.members().await.unwrap(), vec!["alice", "bob"]);
+
+        here.heartbeat().await.unwrap();
+        here.sweep().await.unwrap();
+        assert_eq!(room.members().await.unwrap(), vec!["alice"]);
+        assert_eq!(here.admitted_room("bob").await.unwrap(), None);
+        loop {
+            let event = alice.events.recv().await.unwrap();
+            if event.frame.as_str().contains("UserLeft") {
+                assert!(event.frame.as_str().contains("bob"));
+                break;
+            }
+        }
+    }
+
+    // relay_events() runs in the background, so give it a moment
+    async fn eventually(mut done: impl FnMut() -> bool) {
+        for _ in 0..100 {
+            if done() {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("never happened");
+    }
+
+    #[tokio::test]
+    async fn settings_catch_up_after_missed_events() {
+        let backplane: Arc<dyn Backplane> = Arc::new(LocalBackplane::default());
+        let here = Arc::new(RoomRegistry::new(backplane.clone(), 10));
+        let there = Arc::new(RoomRegistry::new(backplane.clone(), 10));
+        tokio::spawn({
+            let there = there.clone();
+            async move { there.relay_events().await }
+        });
+        let settings = RoomSettings::new("alice", RoomVisibility::Public, None);
+        let room = here.create("lounge", settings).await.unwrap().unwrap();
+        let elsewhere = there.get("lounge").await.unwrap().unwrap();
+
+        let mut settings = RoomSettings::clone(&room.settings());
+        settings.topic = Some("relayed".to_string());
+        here.update(&room, settings.clone(), "alice").await.unwrap();
+        eventually(|| elsewhere.settings().topic.as_deref() == Some("relayed")).await;
+
+        // changed without the other instance hearing about it
+        settings.visibility = RoomVisibility::Private;
+        settings.password_hash = Some("hash".to_string());
+        let json = serde_json::to_string(&settings).unwrap();
+        backplane.set(&room_key("lounge"), &json, None).await.unwrap();
+        backplane.publish(BackplaneEvent::Missed);
+        eventually(|| elsewhere.settings().password_hash.is_some()).await;
+
+        // and deleted the same way
+        backplane.take(&room_key("lounge")).await.unwrap();
+        backplane.publish(BackplaneEvent::Missed);
+        for _ in 0..100 {
+            if there.running().await == 0 {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("the deleted room kept running");
+    }
+}
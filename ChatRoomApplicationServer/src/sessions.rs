@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+
+use crate::backplane::{Backplane, BackplaneError, BackplaneResult};
+
+// Login sessions. Logging in (or signing up) hands out two tokens:
+//
+//  - a session token, sent as "Authorization: Bearer <token>" with every request
+//  - a refresh token, which the client keeps on disk so it can start a new session on its next
+//    launch without asking for the password again
+//
+// A refresh token can only be used once: every refresh replaces it with a new one, so a copied
+// token stops working as soon as the real client uses its own. Logging out revokes both.
+//
+// Both are kept in the backplane (see backplane.rs), so a session started on one server instance
+// works on all of them, and expire on their own once the refresh token would have. Each user's
+// session tokens are also listed together, so an operator can end all of them at once.
+
+pub struct Tokens {
+    pub token: String,
+    pub refresh_token: String,
+}
+
+// What a session token is kept with
+#[derive(Serialize, Deserialize)]
+struct Session {
+    user_id: String,
+    // revoked along with the session
+    refresh_token: String,
+}
+
+// What a refresh token is kept with
+#[derive(Serialize, Deserialize)]
+struct RefreshToken {
+    user_id: String,
+    // the session it was handed out with, ended when the token is used or revoked
+    session_token: String,
+}
+
+fn session_key(token: &str) -> String {
+    format!("session:{}", token)
+}
+
+fn refresh_key(refresh_token: &str) -> String {
+    format!("refresh:{}", refresh_token)
+}
+
+// The user's session tokens. Expired ones are only taken out the next time it is looked at.
+fn user_sessions_key(user_id: &str) -> String {
+    format!("sessions:{}", user_id)
+}
+
+pub struct SessionStore {
+    backplane: Arc<dyn Backplane>,
+    refresh_token_lifetime: Duration,
+}
+
+impl SessionStore {
+    pub fn new(backplane: Arc<dyn Backplane>, refresh_token_lifetime: Duration) -> Self {
+        SessionStore {
+            backplane,
+            refresh_token_lifetime,
+        }
+    }
+
+    // Starts a new session for a user who just proved who they are
+    pub async fn issue(&self, user_id: &str) -> BackplaneResult<Tokens> {
+        let tokens = Tokens {
+            token: uuid::Uuid::new_v4().to_string(),
+            refresh_token: uuid::Uuid::new_v4().to_string(),
+        };
+        let session = Session {
+            user_id: user_id.to_string(),
+            refresh_token: tokens.refresh_token.clone(),
+        };
+        let refresh = RefreshToken {
+            user_id: user_id.to_string(),
+            session_token: tokens.token.clone(),
+        };
+
+        let lifetime = Some(self.refresh_token_lifetime);
+        self.backplane.set(&session_key(&tokens.token), &to_json(&session)?, lifetime).await?;
+        self.backplane.set(&refresh_key(&tokens.refresh_token), &to_json(&refresh)?, lifetime).await?;
+        self.backplane.add_member(&user_sessions_key(user_id), &tokens.token).await?;
+        // keeps the list from growing with every login of someone who never logs out
+        self.count(user_id).await?;
+        Ok(tokens)
+    }
+
+    // Who a session token belongs to. None if it is unknown, expired or was revoked.
+    pub async fn user(&self, token: &str) -> BackplaneResult<Option<String>> {
+        let Some(session) = self.backplane.get(&session_key(token)).await? else {
+            return Ok(None);
+        };
+        let session: Session = from_json(&session)?;
+        Ok(Some(session.user_id))
+    }
+
+    // Swaps a refresh token for a new session (and a new refresh token). None if the token is
+    // unknown, expired or was already used.
+    pub async fn refresh(&self, refresh_token: &str) -> BackplaneResult<Option<(String, Tokens)>> {
+        let Some(old) = self.backplane.take(&refresh_key(refresh_token)).await? else {
+            return Ok(None);
+        };
+        let old: RefreshToken = from_json(&old)?;
+        self.backplane.take(&session_key(&old.session_token)).await?;
+        self.backplane.remove_member(&user_sessions_key(&old.user_id), &old.session_token).await?;
+        let tokens = self.issue(&old.user_id).await?;
+        Ok(Some((old.user_id, tokens)))
+    }
+
+    // Ends a session along with the refresh token handed out with it. Returns who was logged in.
+    pub async fn revoke(&self, token: &str) -> BackplaneResult<Option<String>> {
+        let Some(session) = self.backplane.take(&session_key(token)).await? else {
+            return Ok(None);
+        };
+        let session: Session = from_json(&session)?;
+        self.backplane.take(&refresh_key(&session.refresh_token)).await?;
+        self.backplane.remove_member(&user_sessions_key(&session.user_id), token).await?;
+        Ok(Some(session.user_id))
+    }
+
+    // Ends every session the user has, eg after their password was reset. Returns how many there were.
+    pub async fn revoke_all(&self, user_id: &str) -> BackplaneResult<usize> {
+        let mut revoked = 0;
+        for token in self.backplane.members(&user_sessions_key(user_id)).await? {
+            if self.revoke(&token).await?.is_some() {
+                revoked += 1;
+            } else {
+                self.backplane.remove_member(&user_sessions_key(user_id), &token).await?;
+            }
+        }
+        Ok(revoked)
+    }
+
+    // Sessions of the user that haven't expired or been revoked, forgetting the ones that have
+    pub async fn count(&self, user_id: &str) -> BackplaneResult<usize> {
+        let key = user_sessions_key(user_id);
+        let mut live = 0;
+        for token in self.backplane.members(&key).await? {
+            if self.backplane.get(&session_key(&token)).await?.is_some() {
+                live += 1;
+            } else {
+                self.backplane.remove_member(&key, &token).await?;
+            }
+        }
+        Ok(live)
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> BackplaneResult<String> {
+    serde_json::to_string(value).map_err(|e| BackplaneError(e.to_string()))
+}
+
+fn from_json<T: for<'de> Deserialize<'de>>(json: &str) -> BackplaneResult<T> {
+    serde_json::from_str(json).map_err(|e| BackplaneError(e.to_string()))
+}
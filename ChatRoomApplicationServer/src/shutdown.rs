@@ -0,0 +1,109 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::{watch, Notify};
+
+// Graceful shutdown. On SIGINT or SIGTERM:
+//
+//  1. the listener is closed and the HTTP requests in progress are answered (axum's graceful
+//     shutdown), and new websockets are turned away
+//  2. every websocket is sent ServerShutdown, telling the client when to reconnect, after
+//     whatever was already queued for it, and is then closed
+//  3. once every connection has left its room, the backplane sends out what it still has queued
+//     (see Backplane::flush), so the other instances hear about the leaves
+//
+// If that takes longer than CHAT_SHUTDOWN_DEADLINE_SECS, eg because a client stopped reading, the
+// server exits anyway.
+//
+// axum stops following a connection once it has been upgraded to a websocket, so the websockets
+// are counted here instead.
+pub struct Shutdown {
+    started: watch::Sender<bool>,
+    connections: AtomicUsize,
+    connection_closed: Notify,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown {
+            started: watch::channel(false).0,
+            connections: AtomicUsize::new(0),
+            connection_closed: Notify::new(),
+        }
+    }
+
+    pub fn start(&self) {
+        self.started.send_replace(true);
+    }
+
+    pub fn is_started(&self) -> bool {
+        *self.started.borrow()
+    }
+
+    // Resolves once the server starts shutting down, straight away if it already has
+    pub async fn started(&self) {
+        let mut started = self.started.subscribe();
+        let _ = started.wait_for(|started| *started).await;
+    }
+
+    pub fn open_connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    // Counts a websocket as open until the guard is dropped
+    pub fn track_connection(&self) -> ConnectionGuard<'_> {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard(self)
+    }
+
+    // Resolves once every websocket has been closed and cleaned up after
+    pub async fn connections_closed(&self) {
+        loop {
+            // registered before checking, so a connection closing in between isn't missed
+            let closed = self.connection_closed.notified();
+            tokio::pin!(closed);
+            closed.as_mut().enable();
+            if self.open_connections() == 0 {
+                return;
+            }
+            closed.await;
+        }
+    }
+}
+
+pub struct ConnectionGuard<'a>(&'a Shutdown);
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::SeqCst);
+        self.0.connection_closed.notify_waiters();
+    }
+}
+
+// Resolves on the first SIGINT (Ctrl-C) or SIGTERM
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
@@ -0,0 +1,104 @@
+use std::sync::Arc;
+
+use crate::backplane::{Backplane, BackplaneResult};
+
+// Accounts: user_id -> argon2 password hash. Kept in the backplane (see backplane.rs), so an
+// account made on one server instance can log in on any of them.
+//
+// The name of a deleted account can't be signed up again. Rooms, bots, webhooks and invites keep
+// the name of whoever made them, and a newcomer with the same name would otherwise own them.
+
+pub struct UserStore {
+    backplane: Arc<dyn Backplane>,
+}
+
+// Every account, so operators can list them
+const USERS_KEY: &str = "users";
+
+fn user_key(user_id: &str) -> String {
+    format!("user:{}", user_id)
+}
+
+// Set for good once the account is deleted
+fn retired_key(user_id: &str) -> String {
+    format!("retired_user:{}", user_id)
+}
+
+impl UserStore {
+    pub fn new(backplane: Arc<dyn Backplane>) -> Self {
+        UserStore { backplane }
+    }
+
+    // False if the name was taken first, or belonged to a deleted account
+    pub async fn create(&self, user_id: &str, password_hash: &str) -> BackplaneResult<bool> {
+        if self.retired(user_id).await? {
+            return Ok(false);
+        }
+        if !self.backplane.set_new(&user_key(user_id), password_hash, None).await? {
+            return Ok(false);
+        }
+        self.backplane.add_member(USERS_KEY, user_id).await?;
+        Ok(true)
+    }
+
+    pub async fn password_hash(&self, user_id: &str) -> BackplaneResult<Option<String>> {
+        self.backplane.get(&user_key(user_id)).await
+    }
+
+    // False if there is no such user
+    pub async fn set_password_hash(&self, user_id: &str, password_hash: &str) -> BackplaneResult<bool> {
+        if !self.exists(user_id).await? {
+            return Ok(false);
+        }
+        self.backplane.set(&user_key(user_id), password_hash, None).await?;
+        Ok(true)
+    }
+
+    pub async fn exists(&self, user_id: &str) -> BackplaneResult<bool> {
+        Ok(self.password_hash(user_id).await?.is_some())
+    }
+
+    // Whether the name belonged to an account that was deleted
+    pub async fn retired(&self, user_id: &str) -> BackplaneResult<bool> {
+        Ok(self.backplane.get(&retired_key(user_id)).await?.is_some())
+    }
+
+    // Sorted by name
+    pub async fn all(&self) -> BackplaneResult<Vec<String>> {
+        let mut users = self.backplane.members(USERS_KEY).await?;
+        users.sort();
+        Ok(users)
+    }
+
+    // False if there was no such user
+    pub async fn delete(&self, user_id: &str) -> BackplaneResult<bool> {
+        let existed = self.backplane.take(&user_key(user_id)).await?.is_some();
+        if existed {
+            self.backplane.set(&retired_key(user_id), "", None).await?;
+        }
+        self.backplane.remove_member(USERS_KEY, user_id).await?;
+        Ok(existed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backplane::LocalBackplane;
+
+    #[tokio::test]
+    async fn deleted_names_cant_be_signed_up_again() {
+        let users = UserStore::new(Arc::new(LocalBackplane::default()));
+        assert!(users.create("alice", "hash").await.unwrap());
+        assert!(!users.create("alice", "other hash").await.unwrap());
+
+        assert!(users.delete("alice").await.unwrap());
+        assert!(!users.exists("alice").await.unwrap());
+        assert!(users.retired("alice").await.unwrap());
+        assert!(!users.create("alice", "other hash").await.unwrap());
+
+        // only names that were ever used are kept from being signed up
+        assert!(!users.delete("bob").await.unwrap());
+        assert!(users.create("bob", "hash").await.unwrap());
+    }
+}
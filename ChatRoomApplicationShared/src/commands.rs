@@ -0,0 +1,805 @@
+/*
+ * commands.rs
+ *
+ * Every slash command the client understands, described in one table (COMMANDS) instead of being
+ * spread over match arms. Each entry says where the command can be used (Context), what arguments
+ * and flags it takes and how to describe it, and everything else is generated from that:
+ *
+ *  - parse():       turns a line of input into a Command with its arguments checked, or an error
+ *                   with the usage line to show
+ *  - help_lines():  the /help menu for the current context, or the details of one command
+ *  - names():       command names for tab completion, arg_kind_at() for completing arguments
+ *
+ * Arguments are split on whitespace like a shell does, so they can be quoted to contain spaces:
+ *      /create "book club" 'Pass word!'
+ * Backslash escapes the next character (\" or \ followed by a space).
+ */
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Context {
+    // not logged in yet
+    Auth,
+    Lobby,
+    Room,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandId {
+    Help,
+    Quit,
+    SignUp,
+    Login,
+    Logout,
+    AllRooms,
+    ActiveRooms,
+    Create,
+    Join,
+    Delete,
+    JoinInvite,
+    ActiveUsers,
+    Kick,
+    Invite,
+    Invites,
+    RevokeInvite,
+    Topic,
+    Leave,
+    Markdown,
+}
+
+// What an argument is, so completion knows what to offer and parse() knows what to check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    RoomId,
+    Username,
+    Password,
+    InviteCode,
+    CommandName,
+    // a whole number
+    Number,
+    // "on" or "off"
+    Switch,
+    // anything, nothing to complete
+    Text,
+}
+
+pub struct ArgSpec {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    pub required: bool,
+}
+
+pub struct FlagSpec {
+    pub name: &'static str,
+    // flags like --uses take a value, switches like --public don't
+    pub value: Option<ArgSpec>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    General,
+    Authentication,
+    Navigation,
+    RoomManagement,
+}
+
+impl Section {
+    const ALL: [Section; 4] = [Section::General, Section::Authentication, Section::Navigation, Section::RoomManagement];
+
+    fn title(self) -> &'static str {
+        match self {
+            Section::General => "General Commands:",
+            Section::Authentication => "Authentication Commands:",
+            Section::Navigation => "Navigation Commands:",
+            Section::RoomManagement => "Room Management Commands:",
+        }
+    }
+}
+
+pub struct CommandSpec {
+    pub id: CommandId,
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub contexts: &'static [Context],
+    pub args: &'static [ArgSpec],
+    pub flags: &'static [FlagSpec],
+    pub summary: &'static str,
+    // extra help lines, shown under the usage
+    pub details: &'static [&'static str],
+    section: Section,
+}
+
+const ANYWHERE: &[Context] = &[Context::Auth, Context::Lobby, Context::Room];
+const AUTH: &[Context] = &[Context::Auth];
+const LOBBY: &[Context] = &[Context::Lobby];
+const ROOM: &[Context] = &[Context::Room];
+const LOGGED_IN: &[Context] = &[Context::Lobby, Context::Room];
+
+const fn required(name: &'static str, kind: ArgKind) -> ArgSpec {
+    ArgSpec { name, kind, required: true }
+}
+
+const fn optional(name: &'static str, kind: ArgKind) -> ArgSpec {
+    ArgSpec { name, kind, required: false }
+}
+
+const fn switch(name: &'static str) -> FlagSpec {
+    FlagSpec { name, value: None }
+}
+
+pub static COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        id: CommandId::Help,
+        name: "/help",
+        aliases: &["/?"],
+        contexts: ANYWHERE,
+        args: &[optional("command", ArgKind::CommandName)],
+        flags: &[],
+        summary: "Show this help menu, or the details of one command",
+        details: &[],
+        section: Section::General,
+    },
+    CommandSpec {
+        id: CommandId::Quit,
+        name: "/quit",
+        aliases: &["/exit"],
+        contexts: ANYWHERE,
+        args: &[],
+        flags: &[],
+        summary: "Quit the chat room application",
+        details: &[],
+        section: Section::General,
+    },
+    CommandSpec {
+        id: CommandId::Markdown,
+        name: "/markdown",
+        aliases: &[],
+        contexts: ANYWHERE,
+        args: &[optional("on|off", ArgKind::Switch)],
+        flags: &[],
+        summary: "Turn markdown in messages (bold, code, links...) on or off",
+        details: &["without on or off it switches, set markdown = false under [format] in the config file to keep it off"],
+        section: Section::General,
+    },
+    CommandSpec {
+        id: CommandId::SignUp,
+        name: "/sign_up",
+        aliases: &["/signup"],
+        contexts: AUTH,
+        args: &[],
+        flags: &[],
+        summary: "Create a new username and password",
+        details: &[],
+        section: Section::Authentication,
+    },
+    CommandSpec {
+        id: CommandId::Login,
+        name: "/login",
+        aliases: &[],
+        contexts: AUTH,
+        args: &[],
+        flags: &[],
+        summary: "Login with your username and password",
+        details: &[],
+        section: Section::Authentication,
+    },
+    CommandSpec {
+        id: CommandId::Logout,
+        name: "/logout",
+        aliases: &[],
+        contexts: LOBBY,
+        args: &[],
+        flags: &[],
+        summary: "Logout of the chatroom application",
+        details: &[],
+        section: Section::Authentication,
+    },
+    CommandSpec {
+        id: CommandId::AllRooms,
+        name: "/all_rooms",
+        aliases: &["/rooms"],
+        contexts: LOBBY,
+        args: &[],
+        flags: &[],
+        summary: "Show all available chat rooms",
+        details: &[],
+        section: Section::Navigation,
+    },
+    CommandSpec {
+        id: CommandId::ActiveRooms,
+        name: "/active_rooms",
+        aliases: &[],
+        contexts: LOBBY,
+        args: &[],
+        flags: &[],
+        summary: "Show all active chat rooms",
+        details: &[],
+        section: Section::Navigation,
+    },
+    CommandSpec {
+        id: CommandId::Create,
+        name: "/create",
+        aliases: &[],
+        contexts: LOBBY,
+        args: &[required("room_id", ArgKind::RoomId), optional("password", ArgKind::Password)],
+        flags: &[switch("--public"), switch("--unlisted")],
+        summary: "Create a new chat room",
+        details: &["private by default, public rooms have no password, unlisted rooms aren't shown in /all_rooms"],
+        section: Section::Navigation,
+    },
+    CommandSpec {
+        id: CommandId::Join,
+        name: "/join",
+        aliases: &[],
+        contexts: LOBBY,
+        args: &[required("room_id", ArgKind::RoomId), optional("password", ArgKind::Password)],
+        flags: &[],
+        summary: "Join an existing chat room",
+        details: &["public rooms don't need a password"],
+        section: Section::Navigation,
+    },
+    CommandSpec {
+        id: CommandId::Delete,
+        name: "/delete",
+        aliases: &[],
+        contexts: LOBBY,
+        args: &[required("room_id", ArgKind::RoomId)],
+        flags: &[],
+        summary: "Delete your chat room (owner only)",
+        details: &[],
+        section: Section::Navigation,
+    },
+    CommandSpec {
+        id: CommandId::JoinInvite,
+        name: "/join_invite",
+        aliases: &[],
+        contexts: LOBBY,
+        args: &[required("code", ArgKind::InviteCode)],
+        flags: &[],
+        summary: "Join a chat room with an invite code",
+        details: &[],
+        section: Section::Navigation,
+    },
+    CommandSpec {
+        id: CommandId::ActiveUsers,
+        name: "/active_users",
+        aliases: &["/who"],
+        contexts: ROOM,
+        args: &[],
+        flags: &[],
+        summary: "Show all active users in the current room",
+        details: &[],
+        section: Section::RoomManagement,
+    },
+    CommandSpec {
+        id: CommandId::Kick,
+        name: "/kick",
+        aliases: &[],
+        contexts: ROOM,
+        args: &[required("username", ArgKind::Username)],
+        flags: &[],
+        summary: "Remove a user from your room. Need to own chat room",
+        details: &[],
+        section: Section::RoomManagement,
+    },
+    CommandSpec {
+        id: CommandId::Invite,
+        name: "/invite",
+        aliases: &[],
+        contexts: LOGGED_IN,
+        args: &[optional("room_id", ArgKind::RoomId)],
+        flags: &[
+            switch("--single-use"),
+            FlagSpec { name: "--uses", value: Some(required("n", ArgKind::Number)) },
+            FlagSpec { name: "--expires", value: Some(required("minutes", ArgKind::Number)) },
+        ],
+        summary: "Create an invite code for your room (owner only)",
+        details: &["room_id is only needed from the lobby"],
+        section: Section::RoomManagement,
+    },
+    CommandSpec {
+        id: CommandId::Invites,
+        name: "/invites",
+        aliases: &[],
+        contexts: LOGGED_IN,
+        args: &[optional("room_id", ArgKind::RoomId)],
+        flags: &[],
+        summary: "List the active invite codes for your room",
+        details: &["room_id is only needed from the lobby"],
+        section: Section::RoomManagement,
+    },
+    CommandSpec {
+        id: CommandId::RevokeInvite,
+        name: "/revoke_invite",
+        aliases: &[],
+        contexts: LOGGED_IN,
+        args: &[required("code", ArgKind::InviteCode)],
+        flags: &[],
+        summary: "Revoke an invite code",
+        details: &[],
+        section: Section::RoomManagement,
+    },
+    CommandSpec {
+        id: CommandId::Topic,
+        name: "/topic",
+        aliases: &[],
+        contexts: ROOM,
+        args: &[required("topic", ArgKind::Text)],
+        flags: &[],
+        summary: "Change the topic of your room (owner only)",
+        details: &["quote it if it has spaces, /topic \"\" clears it"],
+        section: Section::RoomManagement,
+    },
+    CommandSpec {
+        id: CommandId::Leave,
+        name: "/leave",
+        aliases: &[],
+        contexts: ROOM,
+        args: &[],
+        flags: &[],
+        summary: "Leave the current chat room",
+        details: &[],
+        section: Section::RoomManagement,
+    },
+];
+
+impl CommandSpec {
+    pub fn available_in(&self, context: Context) -> bool {
+        self.contexts.contains(&context)
+    }
+
+    // eg "/invite [room_id] [--single-use] [--uses <n>] [--expires <minutes>]"
+    pub fn usage(&self) -> String {
+        let mut usage = self.name.to_string();
+        for arg in self.args {
+            if arg.required {
+                usage.push_str(&format!(" <{}>", arg.name));
+            } else {
+                usage.push_str(&format!(" [{}]", arg.name));
+            }
+        }
+        for flag in self.flags {
+            match &flag.value {
+                Some(value) => usage.push_str(&format!(" [{} <{}>]", flag.name, value.name)),
+                None => usage.push_str(&format!(" [{}]", flag.name)),
+            }
+        }
+        usage
+    }
+
+    fn flag(&self, name: &str) -> Option<&FlagSpec> {
+        self.flags.iter().find(|flag| flag.name == name)
+    }
+}
+
+// Looks a command up by name or alias, with or without the leading slash
+pub fn find(name: &str) -> Option<&'static CommandSpec> {
+    let name = if name.starts_with('/') { name.to_string() } else { format!("/{}", name) };
+    COMMANDS
+        .iter()
+        .find(|spec| spec.name == name || spec.aliases.contains(&name.as_str()))
+}
+
+// What the user typed: a command, or a chat message for the current room
+pub enum Input<'a> {
+    Command(Command),
+    Message(&'a str),
+}
+
+pub struct Command {
+    pub spec: &'static CommandSpec,
+    args: Vec<String>,
+    flags: Vec<(&'static str, Option<String>)>,
+}
+
+impl Command {
+    pub fn id(&self) -> CommandId {
+        self.spec.id
+    }
+
+    // Positional arguments, in the order of spec.args. Required ones are always there.
+    pub fn arg(&self, index: usize) -> Option<&str> {
+        self.args.get(index).map(String::as_str)
+    }
+
+    pub fn has_flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|(flag, _)| *flag == name)
+    }
+
+    // The value of a flag like --uses; Number values were already checked by parse()
+    pub fn flag_value<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.flags
+            .iter()
+            .rev()
+            .find(|(flag, _)| *flag == name)
+            .and_then(|(_, value)| value.as_deref())
+            .and_then(|value| value.parse().ok())
+    }
+}
+
+pub enum CommandError {
+    Unknown(String),
+    // exists, but not here (eg /leave from the lobby)
+    NotAvailable(&'static CommandSpec),
+    // wrong arguments, with what was wrong
+    Usage(&'static CommandSpec, String),
+    UnclosedQuote,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Unknown(name) => write!(f, "Unknown Command {} - try /help", name),
+            CommandError::NotAvailable(spec) => {
+                let places: Vec<&str> = spec
+                    .contexts
+                    .iter()
+                    .map(|context| match context {
+                        Context::Auth => "before logging in",
+                        Context::Lobby => "in the lobby",
+                        Context::Room => "in a room",
+                    })
+                    .collect();
+                write!(f, "{} can only be used {}", spec.name, places.join(" or "))
+            }
+            CommandError::Usage(spec, problem) => write!(f, "{} - Usage: {}", problem, spec.usage()),
+            CommandError::UnclosedQuote => write!(f, "Missing closing quote"),
+        }
+    }
+}
+
+pub fn parse(input: &str, context: Context) -> Result<Input<'_>, CommandError> {
+    let input = input.trim();
+    if !input.starts_with('/') {
+        return Ok(Input::Message(input));
+    }
+
+    // Bots can add their own commands to a room, and only the server knows which ones there are
+    let first_word = input.split_whitespace().next().unwrap_or_default();
+    if context == Context::Room && find(first_word).is_none() {
+        return Ok(Input::Message(input));
+    }
+
+    let mut words = tokenize(input)?.into_iter();
+    let name = words.next().unwrap_or_default();
+    let spec = find(&name).ok_or(CommandError::Unknown(name))?;
+    if !spec.available_in(context) {
+        return Err(CommandError::NotAvailable(spec));
+    }
+
+    let usage = |problem: String| CommandError::Usage(spec, problem);
+    let mut args = Vec::new();
+    let mut flags = Vec::new();
+
+    while let Some(word) = words.next() {
+        if let Some(flag) = spec.flag(&word) {
+            let value = match &flag.value {
+                Some(value_spec) => {
+                    let value = words
+                        .next()
+                        .ok_or_else(|| usage(format!("{} needs a value", flag.name)))?;
+                    check_value(value_spec, &value).map_err(usage)?;
+                    Some(value)
+                }
+                None => None,
+            };
+            flags.push((flag.name, value));
+        } else if word.starts_with("--") {
+            return Err(usage(format!("Unknown option {}", word)));
+        } else {
+            let arg_spec = spec
+                .args
+                .get(args.len())
+                .ok_or_else(|| usage("Too many arguments".to_string()))?;
+            check_value(arg_spec, &word).map_err(usage)?;
+            args.push(word);
+        }
+    }
+
+    if let Some(missing) = spec.args.iter().skip(args.len()).find(|arg| arg.required) {
+        return Err(usage(format!("Missing {}", missing.name)));
+    }
+
+    Ok(Input::Command(Command { spec, args, flags }))
+}
+
+fn check_value(spec: &ArgSpec, value: &str) -> Result<(), String> {
+    match spec.kind {
+        ArgKind::Number if value.parse::<u64>().is_err() => Err(format!("{} must be a whole number", spec.name)),
+        ArgKind::Switch if value != "on" && value != "off" => Err(format!("{} isn't on or off", value)),
+        _ => Ok(()),
+    }
+}
+
+// Splits on whitespace, keeping anything in single or double quotes together
+pub fn tokenize(input: &str) -> Result<Vec<String>, CommandError> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // a quoted empty string ("") is still an argument
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            // single quotes are literal, like in a shell
+            (Some('\''), c) => word.push(c),
+            (_, '\\') => {
+                if let Some(escaped) = chars.next() {
+                    word.push(escaped);
+                }
+                in_word = true;
+            }
+            (Some(_), c) => word.push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+
+    if quote.is_some() {
+        return Err(CommandError::UnclosedQuote);
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+// Command names available in a context, for completion
+pub fn names(context: Context) -> Vec<&'static str> {
+    COMMANDS
+        .iter()
+        .filter(|spec| spec.available_in(context))
+        .map(|spec| spec.name)
+        .collect()
+}
+
+// What kind of argument comes after `words` (the command and the arguments typed so far), or
+// None if nothing more is expected there. Flag values count as well.
+pub fn arg_kind_at(words: &[String], context: Context) -> Option<ArgKind> {
+    let spec = find(words.first()?).filter(|spec| spec.available_in(context))?;
+
+    let mut positional = 0;
+    let mut rest = words[1..].iter();
+    while let Some(word) = rest.next() {
+        match spec.flag(word) {
+            Some(FlagSpec { value: Some(value), .. }) => {
+                if rest.next().is_none() {
+                    return Some(value.kind);
+                }
+            }
+            Some(_) => {}
+            None => positional += 1,
+        }
+    }
+    spec.args.get(positional).map(|arg| arg.kind)
+}
+
+// Flags of the command at the start of `words`, for completion
+pub fn flag_names(words: &[String]) -> Vec<&'static str> {
+    words
+        .first()
+        .and_then(|name| find(name))
+        .map(|spec| spec.flags.iter().map(|flag| flag.name).collect())
+        .unwrap_or_default()
+}
+
+// The /help menu: every command available in `context` grouped by section, or with a command
+// name just that command
+pub fn help_lines(context: Context, command: Option<&str>) -> Vec<String> {
+    if let Some(name) = command {
+        return match find(name) {
+            Some(spec) => command_help(spec),
+            None => vec![format!("Unknown command {}", name)],
+        };
+    }
+
+    let mut lines = vec![
+        String::new(),
+        "==============================".to_string(),
+        "           HELP MENU          ".to_string(),
+        "==============================".to_string(),
+    ];
+
+    for section in Section::ALL {
+        let specs: Vec<&CommandSpec> = COMMANDS
+            .iter()
+            .filter(|spec| spec.section == section && spec.available_in(context))
+            .collect();
+        if specs.is_empty() {
+            continue;
+        }
+
+        lines.push(String::new());
+        lines.push(section.title().to_string());
+        for spec in specs {
+            lines.push(format!("  {:<18} {}", spec.name, spec.summary));
+            if !spec.args.is_empty() || !spec.flags.is_empty() {
+                lines.push(format!("{:<21}(usage: {})", "", spec.usage()));
+            }
+        }
+    }
+
+    if context == Context::Room {
+        lines.push(String::new());
+        lines.push("Messaging Commands:".to_string());
+        lines.push(format!("  {:<18} {}", "<message>", "Type and send a message to your current room"));
+        lines.push(format!("  {:<18} {}", "/<command>", "Any other command goes to the room's bots"));
+    }
+
+    lines.push(String::new());
+    lines.push("Type /help <command> for more about a command".to_string());
+    lines.push("==============================".to_string());
+    lines
+}
+
+fn command_help(spec: &CommandSpec) -> Vec<String> {
+    let mut lines = vec![
+        format!("{} - {}", spec.name, spec.summary),
+        format!("  usage: {}", spec.usage()),
+    ];
+    for detail in spec.details {
+        lines.push(format!("  {}", detail));
+    }
+    if !spec.aliases.is_empty() {
+        lines.push(format!("  also: {}", spec.aliases.join(", ")));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(input: &str, context: Context) -> Command {
+        match parse(input, context) {
+            Ok(Input::Command(cmd)) => cmd,
+            Ok(Input::Message(_)) => panic!("{} parsed as a message", input),
+            Err(e) => panic!("{} didn't parse: {}", input, e),
+        }
+    }
+
+    fn error(input: &str, context: Context) -> CommandError {
+        match parse(input, context) {
+            Err(e) => e,
+            Ok(_) => panic!("{} parsed", input),
+        }
+    }
+
+    #[test]
+    fn splits_arguments_on_whitespace() {
+        let cmd = command("  /join   lounge   Passw0rd!  ", Context::Lobby);
+        assert_eq!(cmd.id(), CommandId::Join);
+        assert_eq!(cmd.arg(0), Some("lounge"));
+        assert_eq!(cmd.arg(1), Some("Passw0rd!"));
+        assert_eq!(cmd.arg(2), None);
+    }
+
+    #[test]
+    fn quoted_arguments_keep_their_spaces() {
+        let cmd = command(r#"/create "book club" 'Pass word!'"#, Context::Lobby);
+        assert_eq!(cmd.arg(0), Some("book club"));
+        assert_eq!(cmd.arg(1), Some("Pass word!"));
+
+        let cmd = command(r#"/topic "Rust, mostly""#, Context::Room);
+        assert_eq!(cmd.arg(0), Some("Rust, mostly"));
+    }
+
+    #[test]
+    fn an_empty_quoted_string_is_an_argument() {
+        let cmd = command(r#"/topic """#, Context::Room);
+        assert_eq!(cmd.arg(0), Some(""));
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(tokenize(r#"/join a\"b"#).ok(), Some(vec!["/join".to_string(), "a\"b".to_string()]));
+        assert_eq!(tokenize(r"/join book\ club").ok(), Some(vec!["/join".to_string(), "book club".to_string()]));
+        assert_eq!(tokenize(r#""say \"hi\"""#).ok(), Some(vec!["say \"hi\"".to_string()]));
+        // single quotes are literal, backslashes included
+        assert_eq!(tokenize(r"'a\b'").ok(), Some(vec![r"a\b".to_string()]));
+        assert_eq!(tokenize(r#"'it"s'"#).ok(), Some(vec!["it\"s".to_string()]));
+    }
+
+    #[test]
+    fn unclosed_quotes_are_an_error() {
+        assert!(matches!(error(r#"/join "book club"#, Context::Lobby), CommandError::UnclosedQuote));
+        assert!(matches!(error("/join 'lounge", Context::Lobby), CommandError::UnclosedQuote));
+    }
+
+    #[test]
+    fn unknown_commands() {
+        assert!(matches!(error("/frobnicate", Context::Lobby), CommandError::Unknown(name) if name == "/frobnicate"));
+        assert!(matches!(error("/frobnicate", Context::Auth), CommandError::Unknown(_)));
+        // in a room they may be bot commands, which only the server knows about
+        assert!(matches!(parse("/roll 2d6", Context::Room), Ok(Input::Message("/roll 2d6"))));
+    }
+
+    #[test]
+    fn plain_text_is_a_message() {
+        assert!(matches!(parse("  hello there ", Context::Room), Ok(Input::Message("hello there"))));
+    }
+
+    #[test]
+    fn wrong_number_of_arguments() {
+        assert!(matches!(error("/join", Context::Lobby), CommandError::Usage(_, problem) if problem == "Missing room_id"));
+        assert!(matches!(error("/kick alice bob", Context::Room), CommandError::Usage(_, problem) if problem == "Too many arguments"));
+        assert!(matches!(error("/logout now", Context::Lobby), CommandError::Usage(..)));
+    }
+
+    #[test]
+    fn flags() {
+        let cmd = command("/invite lounge --uses 5 --expires 60", Context::Lobby);
+        assert_eq!(cmd.arg(0), Some("lounge"));
+        assert_eq!(cmd.flag_value::<u32>("--uses"), Some(5));
+        assert_eq!(cmd.flag_value::<u64>("--expires"), Some(60));
+        assert!(!cmd.has_flag("--single-use"));
+
+        let cmd = command("/create lounge --public", Context::Lobby);
+        assert!(cmd.has_flag("--public"));
+        assert_eq!(cmd.arg(1), None);
+
+        assert!(matches!(error("/invite --uses", Context::Room), CommandError::Usage(_, problem) if problem == "--uses needs a value"));
+        assert!(matches!(error("/invite --uses many", Context::Room), CommandError::Usage(..)));
+        assert!(matches!(error("/create lounge --secret", Context::Lobby), CommandError::Usage(_, problem) if problem == "Unknown option --secret"));
+        assert!(matches!(error("/markdown maybe", Context::Lobby), CommandError::Usage(..)));
+    }
+
+    #[test]
+    fn commands_are_only_available_in_their_context() {
+        assert!(matches!(error("/leave", Context::Lobby), CommandError::NotAvailable(spec) if spec.id == CommandId::Leave));
+        assert!(matches!(error("/join lounge", Context::Room), CommandError::NotAvailable(_)));
+        assert!(matches!(error("/login", Context::Lobby), CommandError::NotAvailable(_)));
+        assert!(matches!(error("/all_rooms", Context::Auth), CommandError::NotAvailable(_)));
+        // some work everywhere
+        for context in [Context::Auth, Context::Lobby, Context::Room] {
+            assert_eq!(command("/help", context).id(), CommandId::Help);
+        }
+    }
+
+    #[test]
+    fn aliases() {
+        assert_eq!(command("/exit", Context::Lobby).id(), CommandId::Quit);
+        assert_eq!(command("/rooms", Context::Lobby).id(), CommandId::AllRooms);
+        assert_eq!(command("/signup", Context::Auth).id(), CommandId::SignUp);
+        assert_eq!(command("/who", Context::Room).id(), CommandId::ActiveUsers);
+        assert_eq!(command("/? join", Context::Lobby).arg(0), Some("join"));
+        assert_eq!(find("rooms").map(|spec| spec.id), Some(CommandId::AllRooms));
+    }
+
+    #[test]
+    fn names_are_unique() {
+        let mut names: Vec<&str> = COMMANDS
+            .iter()
+            .flat_map(|spec| std::iter::once(spec.name).chain(spec.aliases.iter().copied()))
+            .collect();
+        let count = names.len();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), count);
+    }
+
+    #[test]
+    fn usage_lines() {
+        assert_eq!(find("/join").map(CommandSpec::usage).as_deref(), Some("/join <room_id> [password]"));
+        assert_eq!(
+            find("/invite").map(CommandSpec::usage).as_deref(),
+            Some("/invite [room_id] [--single-use] [--uses <n>] [--expires <minutes>]")
+        );
+    }
+}
@@ -0,0 +1,7 @@
+// What the server and the client have in common besides the protocol. Both depend on this crate
+// through a path dependency, so a rule changed here changes on both sides at once.
+
+// the client's slash commands, which the server also needs so bots can't take their names
+pub mod commands;
+pub mod sanitize;
+pub mod validation;
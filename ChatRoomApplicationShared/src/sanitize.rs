@@ -0,0 +1,215 @@
+use unicode_normalization::UnicodeNormalization;
+
+// Cleans up chat message content before it is broadcast. Clients print messages straight into
+// other users' terminals, so anything that a terminal would interpret (ANSI/VT escape sequences,
+// carriage returns, C1 controls, bidi overrides) is stripped out here.
+//
+// The client runs strip_control_sequences again on everything it prints, as defense in depth:
+// usernames and room names end up in the terminal too, and a server could be older or hostile.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ContentError {
+    // nothing printable left after stripping
+    Empty,
+    TooLong { max_chars: usize },
+}
+
+impl ContentError {
+    pub fn message(&self) -> String {
+        match self {
+            ContentError::Empty => "Message is empty".to_string(),
+            ContentError::TooLong { max_chars } => {
+                format!("Message is too long (max {} characters)", max_chars)
+            }
+        }
+    }
+}
+
+// Strips control and escape sequences, normalizes to NFC, and enforces the length limit
+pub fn sanitize_message(content: &str, max_chars: usize) -> Result<String, ContentError> {
+    let stripped = strip_control_sequences(content);
+    let normalized: String = stripped.nfc().collect();
+    let trimmed = normalized.trim();
+
+    if trimmed.is_empty() {
+        return Err(ContentError::Empty);
+    }
+    if trimmed.chars().count() > max_chars {
+        return Err(ContentError::TooLong { max_chars });
+    }
+    Ok(trimmed.to_string())
+}
+
+const ESC: char = '\u{1b}';
+const BEL: char = '\u{07}';
+
+// Removes terminal escape sequences and every control character except newlines and tabs
+pub fn strip_control_sequences(text: &str) -> String {
+    // fast path, nearly every message is plain text
+    if !text.chars().any(needs_stripping) {
+        return text.to_string();
+    }
+
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' | '\t' => out.push(c),
+            ESC => match chars.next() {
+                // CSI: ESC [ params... final byte
+                Some('[') => skip_csi(&mut chars),
+                // OSC: ESC ] ... terminated by BEL or ST (ESC \)
+                Some(']') => skip_string(&mut chars),
+                // DCS, SOS, PM, APC: ESC P/X/^/_ ... terminated by ST
+                Some('P' | 'X' | '^' | '_') => skip_string(&mut chars),
+                // nF sequences: ESC, any number of intermediate bytes, then a final byte
+                Some(' '..='/') => {
+                    while chars.next_if(|c| (' '..='/').contains(c)).is_some() {}
+                    chars.next();
+                }
+                // two character sequences like ESC c (full reset), or a lone ESC at the end
+                _ => {}
+            },
+            // 8-bit (C1) forms of CSI, OSC and the string introducers
+            '\u{9b}' => skip_csi(&mut chars),
+            '\u{9d}' | '\u{90}' | '\u{98}' | '\u{9e}' | '\u{9f}' => skip_string(&mut chars),
+            c if needs_stripping(c) => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn needs_stripping(c: char) -> bool {
+    (c.is_control() && c != '\n' && c != '\t') || is_bidi_control(c)
+}
+
+fn skip_csi(chars: &mut std::iter::Peekable<std::str::Chars>) {
+    for c in chars.by_ref() {
+        if ('\u{40}'..='\u{7e}').contains(&c) {
+            break;
+        }
+    }
+}
+
+fn skip_string(chars: &mut std::iter::Peekable<std::str::Chars>) {
+    while let Some(c) = chars.next() {
+        match c {
+            BEL | '\u{9c}' => break,
+            ESC if chars.peek() == Some(&'\\') => {
+                chars.next();
+                break;
+            }
+            _ => {}
+        }
+    }
+}
+
+// Bidirectional overrides/isolates can make text render in a different order than it was written
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_text_is_untouched() {
+        assert_eq!(strip_control_sequences("hello, world"), "hello, world");
+        assert_eq!(strip_control_sequences("line one\n\tline two"), "line one\n\tline two");
+    }
+
+    #[test]
+    fn strips_csi_colours_and_cursor_moves() {
+        assert_eq!(strip_control_sequences("\x1b[31mred\x1b[0m text"), "red text");
+        assert_eq!(strip_control_sequences("\x1b[1;38;5;196mbold\x1b[m"), "bold");
+        // clear the screen, move home, move up and erase the line
+        assert_eq!(strip_control_sequences("a\x1b[2J\x1b[1;1Hb\x1b[3A\x1b[2Kc"), "abc");
+    }
+
+    #[test]
+    fn strips_osc_title_changes() {
+        assert_eq!(strip_control_sequences("\x1b]0;pwned\x07hello"), "hello");
+        assert_eq!(strip_control_sequences("\x1b]2;pwned\x1b\\hello"), "hello");
+    }
+
+    #[test]
+    fn strips_osc_8_hyperlinks_but_keeps_their_text() {
+        let link = "\x1b]8;;https://evil.example\x1b\\click me\x1b]8;;\x1b\\";
+        assert_eq!(strip_control_sequences(link), "click me");
+    }
+
+    #[test]
+    fn strips_dcs_and_other_string_sequences() {
+        assert_eq!(strip_control_sequences("\x1bPq#0;2;0;0;0#0~~\x1b\\after"), "after");
+        assert_eq!(strip_control_sequences("\x1b_apc payload\x1b\\after"), "after");
+        assert_eq!(strip_control_sequences("\x1b^pm payload\x07after"), "after");
+    }
+
+    #[test]
+    fn strips_8_bit_c1_sequences() {
+        assert_eq!(strip_control_sequences("\u{9b}31mred"), "red");
+        assert_eq!(strip_control_sequences("\u{9d}0;title\u{9c}after"), "after");
+        assert_eq!(strip_control_sequences("\u{90}dcs\u{9c}after"), "after");
+    }
+
+    #[test]
+    fn unterminated_escapes_swallow_the_rest() {
+        assert_eq!(strip_control_sequences("hi\x1b[31"), "hi");
+        assert_eq!(strip_control_sequences("hi\x1b]0;title that never ends"), "hi");
+        assert_eq!(strip_control_sequences("hi\x1bPno terminator"), "hi");
+        assert_eq!(strip_control_sequences("hi\x1b"), "hi");
+        assert_eq!(strip_control_sequences("hi\u{9b}"), "hi");
+    }
+
+    #[test]
+    fn strips_two_character_and_nf_escapes() {
+        // full reset, and selecting a character set
+        assert_eq!(strip_control_sequences("a\x1bcb\x1b(0c"), "abc");
+    }
+
+    #[test]
+    fn strips_bidi_overrides() {
+        assert_eq!(strip_control_sequences("abc\u{202e}fed"), "abcfed");
+        assert_eq!(strip_control_sequences("\u{2066}x\u{2069}\u{200f}"), "x");
+    }
+
+    #[test]
+    fn strips_bare_carriage_returns_and_other_controls() {
+        assert_eq!(strip_control_sequences("safe\rEVIL"), "safeEVIL");
+        assert_eq!(strip_control_sequences("bell\x07 back\x08space\x7f"), "bell backspace");
+    }
+
+    #[test]
+    fn empty_messages_are_refused() {
+        assert_eq!(sanitize_message("", 10), Err(ContentError::Empty));
+        assert_eq!(sanitize_message(" \n\t ", 10), Err(ContentError::Empty));
+        // nothing left once the escapes are gone
+        assert_eq!(sanitize_message("\x1b[2J\x1b[H", 10), Err(ContentError::Empty));
+    }
+
+    #[test]
+    fn over_length_messages_are_refused() {
+        assert_eq!(sanitize_message(&"a".repeat(10), 10), Ok("a".repeat(10)));
+        assert_eq!(sanitize_message(&"a".repeat(11), 10), Err(ContentError::TooLong { max_chars: 10 }));
+        // counted in characters, not bytes
+        assert_eq!(sanitize_message(&"é".repeat(10), 10), Ok("é".repeat(10)));
+        // and without the escapes that were stripped
+        assert!(sanitize_message(&format!("\x1b[31m{}\x1b[0m", "a".repeat(10)), 10).is_ok());
+    }
+
+    #[test]
+    fn normalizes_to_nfc() {
+        // e followed by a combining acute accent becomes the single character é
+        assert_eq!(sanitize_message("caf\u{65}\u{301}", 10), Ok("caf\u{e9}".to_string()));
+        // so it is counted as one character
+        assert!(sanitize_message(&"e\u{301}".repeat(10), 10).is_ok());
+    }
+
+    #[test]
+    fn trims_surrounding_whitespace() {
+        assert_eq!(sanitize_message("  hello \n", 10), Ok("hello".to_string()));
+    }
+}
@@ -0,0 +1,267 @@
+use serde::{Deserialize, Serialize};
+
+// Naming and password policy for users and rooms. The client runs the same checks before sending
+// a request, but the server is the one that has to enforce them.
+//
+// Every rule that fails is reported (not just the first one) so the client can show the user
+// everything they need to fix in one go.
+
+// Who can see and join a room. Part of the protocol (both message.rs and messages.rs re-export it),
+// kept here because the room password rules depend on it.
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub enum RoomVisibility{
+    // listed, anyone can join without a password
+    Public,
+    // listed, joining needs the room password
+    #[default]
+    Private,
+    // not listed, joined with the room password, or invite only if it has none
+    Unlisted,
+}
+
+// One broken rule, as sent back in ErrorResponse::ValidationFailed
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+pub struct ValidationError{
+    // which request field broke the rule (eg room_id, password)
+    pub field: String,
+    // short machine readable name of the rule (eg min_length, uppercase)
+    pub rule: String,
+    pub message: String,
+}
+
+pub const NAME_MIN_LEN: usize = 3;
+pub const NAME_MAX_LEN: usize = 32;
+pub const PASSWORD_MIN_LEN: usize = 8;
+// Keeps password hashing cost bounded
+pub const PASSWORD_MAX_LEN: usize = 128;
+
+// Room topics are a single line, shown when joining and in the room list
+pub const TOPIC_MAX_LEN: usize = 120;
+pub const DESCRIPTION_MAX_LEN: usize = 1000;
+pub const SLOW_MODE_MAX_SECS: u64 = 24 * 60 * 60;
+
+// Announcements and the message of the day come from this user, so nobody else may go by it
+pub const SYSTEM_USER: &str = "system";
+
+pub fn validate_room_id(room_id: &str) -> Vec<ValidationError> {
+    validate_name("room_id", room_id)
+}
+
+pub fn validate_user_id(user_id: &str) -> Vec<ValidationError> {
+    validate_sender("user_id", user_id)
+}
+
+// Bots and incoming webhooks post as if they were users, so they follow the same rules
+pub fn validate_bot_id(bot_id: &str) -> Vec<ValidationError> {
+    validate_sender("bot_id", bot_id)
+}
+
+pub fn validate_webhook_name(name: &str) -> Vec<ValidationError> {
+    validate_sender("name", name)
+}
+
+// Whether it is (any capitalization of) SYSTEM_USER, which would pass for the server in a room
+pub fn is_reserved_name(name: &str) -> bool {
+    name.eq_ignore_ascii_case(SYSTEM_USER)
+}
+
+// Names that show up as who sent a message
+fn validate_sender(field: &str, name: &str) -> Vec<ValidationError> {
+    let mut errors = validate_name(field, name);
+    if is_reserved_name(name) {
+        errors.push(rule(field, "reserved", format!("\"{}\" is reserved for the server", SYSTEM_USER)));
+    }
+    errors
+}
+
+// Room and user names end up in commands (`/join <room_id> <password>`), so they are kept to
+// characters that can be typed and split on whitespace safely
+fn validate_name(field: &str, name: &str) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    let len = name.chars().count();
+
+    if len < NAME_MIN_LEN {
+        errors.push(rule(field, "min_length", format!("Must be at least {} characters", NAME_MIN_LEN)));
+    }
+    if len > NAME_MAX_LEN {
+        errors.push(rule(field, "max_length", format!("Must be at most {} characters", NAME_MAX_LEN)));
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        errors.push(rule(
+            field,
+            "charset",
+            "May only contain letters, numbers, '_' and '-'".to_string(),
+        ));
+    }
+    if name.starts_with(['_', '-']) {
+        errors.push(rule(field, "start_character", "Must start with a letter or number".to_string()));
+    }
+    errors
+}
+
+// The documented policy: minimum 8 characters, one uppercase letter and one special character
+pub fn validate_password(field: &str, password: &str) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    let len = password.chars().count();
+
+    if len < PASSWORD_MIN_LEN {
+        errors.push(rule(field, "min_length", format!("Must be at least {} characters", PASSWORD_MIN_LEN)));
+    }
+    if len > PASSWORD_MAX_LEN {
+        errors.push(rule(field, "max_length", format!("Must be at most {} characters", PASSWORD_MAX_LEN)));
+    }
+    if !password.chars().any(|c| c.is_uppercase()) {
+        errors.push(rule(field, "uppercase", "Must contain an uppercase letter".to_string()));
+    }
+    if !password.chars().any(|c| !c.is_alphanumeric() && !c.is_whitespace()) {
+        errors.push(rule(field, "special_character", "Must contain a special character".to_string()));
+    }
+    // Passwords are typed as command arguments, which are split on whitespace
+    if password.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        errors.push(rule(field, "no_whitespace", "Must not contain spaces or control characters".to_string()));
+    }
+    errors
+}
+
+// Public rooms can't have a password, private rooms need one, and unlisted rooms may go without
+pub fn validate_room_password(visibility: RoomVisibility, password: Option<&str>) -> Vec<ValidationError> {
+    let field = "room_password";
+    match (visibility, password) {
+        (RoomVisibility::Public, None) | (RoomVisibility::Unlisted, None) => Vec::new(),
+        (RoomVisibility::Public, Some(_)) => {
+            vec![rule(field, "not_allowed", "Public rooms don't have a password".to_string())]
+        }
+        (RoomVisibility::Private, None) => {
+            vec![rule(field, "required", "Private rooms need a password".to_string())]
+        }
+        (_, Some(password)) => validate_password(field, password),
+    }
+}
+
+// The numbers in a room settings change, for the ones being changed. `max_history_len` is the
+// server's CHAT_ROOM_HISTORY, rooms can keep fewer messages but not more.
+pub fn validate_room_limits(
+    slow_mode_secs: Option<u64>,
+    history_len: Option<usize>,
+    max_history_len: usize,
+) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    if slow_mode_secs.is_some_and(|secs| secs > SLOW_MODE_MAX_SECS) {
+        errors.push(rule(
+            "slow_mode_secs",
+            "max",
+            format!("Must be at most {} seconds", SLOW_MODE_MAX_SECS),
+        ));
+    }
+    if history_len.is_some_and(|len| len > max_history_len) {
+        errors.push(rule(
+            "history_len",
+            "max",
+            format!("Must be at most {} messages", max_history_len),
+        ));
+    }
+    errors
+}
+
+fn rule(field: &str, rule: &str, message: String) -> ValidationError {
+    ValidationError {
+        field: field.to_string(),
+        rule: rule.to_string(),
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(errors: &[ValidationError]) -> Vec<&str> {
+        errors.iter().map(|error| error.rule.as_str()).collect()
+    }
+
+    #[test]
+    fn accepts_valid_names() {
+        for name in ["bob", "alice_99", "book-club", "A1b", &"x".repeat(NAME_MAX_LEN)] {
+            assert!(validate_room_id(name).is_empty(), "{}", name);
+            assert!(validate_user_id(name).is_empty(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn name_length_limits() {
+        assert_eq!(rules(&validate_room_id("ab")), ["min_length"]);
+        assert_eq!(rules(&validate_room_id(&"x".repeat(NAME_MAX_LEN + 1))), ["max_length"]);
+        assert_eq!(rules(&validate_room_id("")), ["min_length"]);
+    }
+
+    #[test]
+    fn name_charset() {
+        assert_eq!(rules(&validate_room_id("book club")), ["charset"]);
+        assert_eq!(rules(&validate_room_id("caf\u{e9}s")), ["charset"]);
+        assert_eq!(rules(&validate_room_id("a/b/c")), ["charset"]);
+        assert_eq!(rules(&validate_room_id("_lounge")), ["start_character"]);
+        assert_eq!(rules(&validate_room_id("-lounge")), ["start_character"]);
+    }
+
+    #[test]
+    fn reports_every_broken_rule_with_its_field() {
+        let errors = validate_user_id("_ ");
+        assert_eq!(rules(&errors), ["min_length", "charset", "start_character"]);
+        assert!(errors.iter().all(|error| error.field == "user_id"));
+    }
+
+    #[test]
+    fn system_user_is_reserved_for_senders_only() {
+        assert_eq!(rules(&validate_user_id("system")), ["reserved"]);
+        assert_eq!(rules(&validate_user_id("SyStEm")), ["reserved"]);
+        assert_eq!(rules(&validate_bot_id("System")), ["reserved"]);
+        assert_eq!(rules(&validate_webhook_name("SYSTEM")), ["reserved"]);
+        assert!(validate_user_id("systems").is_empty());
+        // a room may be called that, it never sends anything
+        assert!(validate_room_id("system").is_empty());
+    }
+
+    #[test]
+    fn accepts_valid_passwords() {
+        assert!(validate_password("password", "Passw0rd!").is_empty());
+        assert!(validate_password("password", "ABCDEFG#").is_empty());
+        assert!(validate_password("password", &format!("A!{}", "a".repeat(PASSWORD_MAX_LEN - 2))).is_empty());
+    }
+
+    #[test]
+    fn password_rules() {
+        assert_eq!(rules(&validate_password("password", "Sh0rt!")), ["min_length"]);
+        assert_eq!(rules(&validate_password("password", "nouppercase1!")), ["uppercase"]);
+        assert_eq!(rules(&validate_password("password", "NoSpecial123")), ["special_character"]);
+        assert_eq!(rules(&validate_password("password", "With Space!")), ["no_whitespace"]);
+        assert_eq!(rules(&validate_password("password", "Tab\tbed!!")), ["no_whitespace"]);
+        let too_long = format!("A!{}", "a".repeat(PASSWORD_MAX_LEN - 1));
+        assert_eq!(rules(&validate_password("password", &too_long)), ["max_length"]);
+    }
+
+    #[test]
+    fn password_errors_name_the_field_they_were_given() {
+        let errors = validate_password("room_password", "weak");
+        assert_eq!(rules(&errors), ["min_length", "uppercase", "special_character"]);
+        assert!(errors.iter().all(|error| error.field == "room_password"));
+    }
+
+    #[test]
+    fn room_password_depends_on_visibility() {
+        assert!(validate_room_password(RoomVisibility::Public, None).is_empty());
+        assert!(validate_room_password(RoomVisibility::Unlisted, None).is_empty());
+        assert!(validate_room_password(RoomVisibility::Private, Some("Passw0rd!")).is_empty());
+        assert!(validate_room_password(RoomVisibility::Unlisted, Some("Passw0rd!")).is_empty());
+        assert_eq!(rules(&validate_room_password(RoomVisibility::Public, Some("Passw0rd!"))), ["not_allowed"]);
+        assert_eq!(rules(&validate_room_password(RoomVisibility::Private, None)), ["required"]);
+        assert_eq!(rules(&validate_room_password(RoomVisibility::Private, Some("weak"))).len(), 3);
+    }
+
+    #[test]
+    fn room_limits() {
+        assert!(validate_room_limits(None, None, 100).is_empty());
+        assert!(validate_room_limits(Some(SLOW_MODE_MAX_SECS), Some(100), 100).is_empty());
+        assert_eq!(rules(&validate_room_limits(Some(SLOW_MODE_MAX_SECS + 1), None, 100)), ["max"]);
+        assert_eq!(rules(&validate_room_limits(None, Some(101), 100)), ["max"]);
+    }
+}